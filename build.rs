@@ -0,0 +1,36 @@
+//! Bakes the git commit hash into the binary, so `/version` can report it.
+
+use std::{fs, path::Path, process::Command};
+
+fn main() {
+    let git_hash = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=THROTTLE_GIT_HASH={}", git_hash);
+    // Rebuild if HEAD moves. Committing moves the branch HEAD points to, rather than HEAD itself.
+    // The branch is found either in its own file, or in `packed-refs`.
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        let git_dir = Path::new(&git_dir);
+        let head = git_dir.join("HEAD");
+        let mut watched = vec![git_dir.join("packed-refs")];
+        if let Ok(content) = fs::read_to_string(&head) {
+            if let Some(branch) = content.trim().strip_prefix("ref: ") {
+                watched.push(git_dir.join(branch));
+            }
+        }
+        watched.push(head);
+        for path in watched.iter().filter(|path| path.exists()) {
+            println!("cargo:rerun-if-changed={}", path.display());
+        }
+    }
+}
+
+/// Output of git, called with `args`. `None` if git is missing, or fails, e.g. outside of a
+/// repository.
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_owned())
+}
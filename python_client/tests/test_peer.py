@@ -1,3 +1,4 @@
+import json
 from datetime import timedelta
 from time import sleep
 
@@ -72,3 +73,22 @@ def test_multiple_peer_recovery_after_server_reboot():
         assert client.remainder("A") == 0
         assert client.remainder("B") == 0
         assert client.remainder("C") == 0
+
+
+def test_handoff():
+    """
+    A scheduler acquires a lock and hands the peer to a worker, which releases it using
+    only the handoff.
+    """
+    with throttle_client(b"[semaphores]\nA=1") as client:
+        scheduler = Peer(client=client, expiration_time=timedelta(minutes=1))
+        assert scheduler.acquire("A")
+        handoff = json.loads(json.dumps(scheduler.handoff()))
+
+        worker = Peer.take_over(client, handoff)
+        assert worker.id == scheduler.id
+        assert worker.acquired == {"A": 1}
+        assert worker.expiration_time == timedelta(minutes=1)
+        worker.heartbeat()
+        worker.remove_from_server()
+        assert client.remainder("A") == 1
@@ -11,3 +11,12 @@ def test_client():
     b = pickle.loads(serialized)
 
     assert a.base_url == b.base_url
+
+
+def test_client_with_token():
+    """The token must survive pickling, so workers can use it, too."""
+
+    a = Client(f"https://dummy-endpoint", token="secret")
+    b = pickle.loads(pickle.dumps(a))
+
+    assert b.token == "secret"
@@ -1,6 +1,6 @@
 import json
 from datetime import timedelta
-from typing import Any, Dict
+from typing import Any, Dict, Optional
 
 import requests
 from tenacity import (  # type: ignore
@@ -64,14 +64,26 @@ class Client:
     level interface look at the `lock` context manager.
     """
 
-    def __init__(self, base_url: str):
+    def __init__(self, base_url: str, token: Optional[str] = None):
         """
         * `base_url`: Url to the throttle server. E.g. https://my_throttle_instance:8000
         * `expiration_time`: If the heartbeat does not prolong the lifetime of this peer
         its locks are going to be released, after this timeout.
+        * `token`: Bearer token sent with every request, if the server is configured with
+        `auth_tokens`. Locks acquired with it are recorded in the audit log of the server,
+        together with the name of the token.
         """
 
         self.base_url = base_url
+        self.token = token
+
+    def _headers(self) -> Dict[str, str]:
+        """
+        Headers sent with every request.
+        """
+        if self.token is None:
+            return {}
+        return {"Authorization": f"Bearer {self.token}"}
 
     def _retrying(self) -> Any:
         """
@@ -98,11 +110,24 @@ class Client:
         _translate_domain_errors(response)
         return response
 
-    def new_peer(self, expires_in: timedelta) -> int:
+    def new_peer(
+        self,
+        expires_in: timedelta,
+        peer_id: Optional[int] = None,
+        metadata: Optional[Dict[str, str]] = None,
+        priority: int = 0,
+    ) -> int:
         """
         Register a new peer with the server.
 
         * `expires_in`: Retention time of the peer on the server.
+        * `peer_id`: Id chosen by the client. Makes retrying the request safe, since
+          no second peer is created on the server.
+        * `metadata`: Information about the client, e.g. `{"hostname": "worker-1"}`.
+          Valid keys are `client`, `hostname` and `note`. Shown in the listing of
+          peers and in the server logs.
+        * `priority`: Between 0 and 255. Once capacity frees up, pending locks of peers
+          with higher priority are acquired first.
 
         A peer is used to acquire locks and keep the leases to them alive. A Peer owns
         thelocks which it acquires and releasing it is going to release the owned locks
@@ -117,10 +142,21 @@ class Client:
         expires_in_str = _format_timedelta(expires_in)
 
         def send_new_peer():
-            body = {
+            body: Dict[str, Any] = {
                 "expires_in": expires_in_str,
             }
-            return requests.post(f"{self.base_url}/new_peer", json=body, timeout=30)
+            if peer_id is not None:
+                body["peer_id"] = peer_id
+            if metadata is not None:
+                body["metadata"] = metadata
+            if priority:
+                body["priority"] = priority
+            return requests.post(
+                f"{self.base_url}/new_peer",
+                json=body,
+                timeout=30,
+                headers=self._headers(),
+            )
 
         response = self._try_request(send_new_peer)
         # Return peer id
@@ -170,6 +206,7 @@ class Client:
                 f"{self.base_url}/peers/{peer_id}/{semaphore}?{querystr}",
                 json=count,
                 timeout=30,
+                headers=self._headers(),
             )
 
         response = self._try_request(send_acquire)
@@ -190,6 +227,7 @@ class Client:
                     "peer_id": peer_id,
                     "acquired": acquired,
                 },
+                headers=self._headers(),
             )
             return response
 
@@ -205,11 +243,14 @@ class Client:
         """
 
         def send_request():
-            response = requests.get(f"{self.base_url}/remainder?semaphore={semaphore}")
+            response = requests.get(
+                f"{self.base_url}/remainder?semaphore={semaphore}",
+                headers=self._headers(),
+            )
             return response
 
         response = self._try_request(send_request)
-        return int(response.text)
+        return response.json()["remainder"]
 
     def is_acquired(self, peer_id: int) -> bool:
         """
@@ -217,7 +258,10 @@ class Client:
         """
 
         def send_request():
-            response = requests.get(f"{self.base_url}/peers/{peer_id}/is_acquired")
+            response = requests.get(
+                f"{self.base_url}/peers/{peer_id}/is_acquired",
+                headers=self._headers(),
+            )
             return response
 
         response = self._try_request(send_request)
@@ -232,7 +276,9 @@ class Client:
         """
 
         def send_request():
-            response = requests.delete(f"{self.base_url}/peers/{peer_id}")
+            response = requests.delete(
+                f"{self.base_url}/peers/{peer_id}", headers=self._headers()
+            )
             return response
 
         self._try_request(send_request)
@@ -243,7 +289,10 @@ class Client:
         """
 
         def send_request():
-            response = requests.delete(f"{self.base_url}/peers/{peer_id}/{semaphore}")
+            response = requests.delete(
+                f"{self.base_url}/peers/{peer_id}/{semaphore}",
+                headers=self._headers(),
+            )
             return response
 
         self._try_request(send_request)
@@ -256,23 +305,39 @@ class Client:
         """
 
         def send_request():
-            response = requests.post(self.base_url + "/remove_expired", timeout=30)
+            response = requests.post(
+                self.base_url + "/remove_expired",
+                timeout=30,
+                headers=self._headers(),
+            )
             return response
 
         response = self._try_request(send_request)
         # Number of expired peers
         return json.loads(response.text)
 
-    def heartbeat(self, peer_id: int, expires_in: timedelta):
+    def heartbeat(
+        self,
+        peer_id: int,
+        expires_in: timedelta,
+        acquired: Optional[Dict[str, int]] = None,
+    ):
         """
         Sends a PUT request to the server, updating the expiration timestamp.
+
+        * `acquired`: Locks the peer believes to hold. Semaphores configured with
+        `strict_amounts` revoke the peer, if these differ from the acquired locks.
         """
 
         def send_request():
+            body: Dict[str, Any] = {"expires_in": _format_timedelta(expires_in)}
+            if acquired is not None:
+                body["acquired"] = acquired
             response = requests.put(
                 f"{self.base_url}/peers/{peer_id}",
-                json={"expires_in": _format_timedelta(expires_in)},
+                json=body,
                 timeout=30,
+                headers=self._headers(),
             )
             return response
 
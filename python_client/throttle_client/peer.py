@@ -1,6 +1,6 @@
 from datetime import timedelta
 from threading import Event, Thread, current_thread
-from typing import Dict, Optional
+from typing import Any, Dict, Optional
 
 import requests
 
@@ -47,6 +47,36 @@ class Peer:
     def from_server_url(cls, baseurl: str):
         return cls(client=Client(base_url=baseurl))
 
+    @classmethod
+    def take_over(cls, client: Client, handoff: Dict[str, Any], **kwargs):
+        """
+        Continues with a peer another process acquired locks for, e.g. a worker taking over
+        the locks its scheduler acquired on its behalf. No request is sent to the server.
+        From here on the new owner is responsible for heartbeat and release.
+
+        * `handoff`: Created by `Peer.handoff` of the previous owner.
+        """
+        return cls(
+            client=client,
+            id=handoff["id"],
+            acquired=dict(handoff["acquired"]),
+            expiration_time=timedelta(milliseconds=handoff["expiration_time_ms"]),
+            **kwargs,
+        )
+
+    def handoff(self) -> Dict[str, Any]:
+        """
+        Everything another process needs to take over this peer and its locks via
+        `Peer.take_over`. JSON serializable, so it can be passed e.g. as part of a job
+        description. The previous owner should stop sending heartbeats for the peer and
+        must not release it.
+        """
+        return {
+            "id": self.id,
+            "acquired": dict(self.acquired),
+            "expiration_time_ms": int(self.expiration_time.total_seconds() * 1000),
+        }
+
     def acquire(
         self, semaphore: str, count: int = 1, block_for: timedelta = None
     ) -> bool:
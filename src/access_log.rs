@@ -0,0 +1,135 @@
+//! Access log. One record for each request, with method, path, status, response time and remote
+//! address, so complaints about slow requests can be correlated with what the server saw. Records
+//! are logged with target `throttle::access`, at info level, or warn level if the server failed to
+//! answer the request.
+
+use crate::logging::AccessLogConfig;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::StatusCode,
+    Error,
+};
+use log::{info, warn};
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const TARGET: &str = "throttle::access";
+
+/// Decides which requests are logged and logs them.
+#[derive(Clone)]
+pub struct AccessLog {
+    enabled: bool,
+    /// Paths of requests which are never logged, e.g. `/metrics`.
+    exclude: Arc<Vec<String>>,
+}
+
+impl AccessLog {
+    pub fn new(cfg: &AccessLogConfig) -> Self {
+        AccessLog {
+            enabled: cfg.enabled,
+            exclude: Arc::new(cfg.exclude.clone()),
+        }
+    }
+
+    /// `true` if requests to `path` are logged.
+    fn is_logged(&self, path: &str) -> bool {
+        self.enabled && !self.exclude.iter().any(|excluded| excluded == path)
+    }
+
+    /// Middleware logging each request once it has been answered.
+    pub fn log<S, B>(
+        &self,
+        req: ServiceRequest,
+        srv: &mut S,
+    ) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    {
+        let start = Instant::now();
+        // Rendered before calling the service, which takes ownership of the request.
+        let request = if self.is_logged(req.path()) {
+            let remote = req.connection_info().remote().unwrap_or("-").to_owned();
+            let path = req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.path().to_owned(), |path| path.as_str().to_owned());
+            Some(format!("{} \"{} {}\"", remote, req.method(), path))
+        } else {
+            None
+        };
+        let response = srv.call(req);
+        async move {
+            let response = response.await;
+            if let Some(request) = request {
+                let elapsed = start.elapsed();
+                match &response {
+                    Ok(response) => log_response(
+                        &request,
+                        response.status(),
+                        response.response().error(),
+                        elapsed,
+                    ),
+                    Err(error) => log_response(
+                        &request,
+                        error.as_response_error().status_code(),
+                        Some(error),
+                        elapsed,
+                    ),
+                }
+            }
+            response
+        }
+    }
+}
+
+/// Logs the answer to `request`. Server errors carry the error, so the status code is not the only
+/// clue.
+fn log_response(request: &str, status: StatusCode, error: Option<&Error>, elapsed: Duration) {
+    let line = line(request, status, elapsed);
+    match error {
+        Some(error) if status.is_server_error() => warn!(target: TARGET, "{}: {}", line, error),
+        _ if status.is_server_error() => warn!(target: TARGET, "{}", line),
+        _ => info!(target: TARGET, "{}", line),
+    }
+}
+
+/// E.g. `127.0.0.1:51234 "PUT /peers/42/A" 200 1.042ms`
+fn line(request: &str, status: StatusCode, elapsed: Duration) -> String {
+    format!(
+        "{} {} {:.3}ms",
+        request,
+        status.as_u16(),
+        elapsed.as_secs_f64() * 1000.
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excluded_paths() {
+        let access_log = AccessLog::new(&AccessLogConfig::default());
+        assert!(access_log.is_logged("/peers/42"));
+        assert!(!access_log.is_logged("/metrics"));
+
+        let disabled = AccessLog::new(&AccessLogConfig {
+            enabled: false,
+            exclude: Vec::new(),
+        });
+        assert!(!disabled.is_logged("/peers/42"));
+    }
+
+    #[test]
+    fn format_line() {
+        let line = line(
+            "127.0.0.1:51234 \"PUT /peers/42/A\"",
+            StatusCode::OK,
+            Duration::from_micros(1042),
+        );
+        assert_eq!(line, "127.0.0.1:51234 \"PUT /peers/42/A\" 200 1.042ms");
+    }
+}
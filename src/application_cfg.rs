@@ -1,12 +1,17 @@
-//! Application configuration, and how it is read from a TOML file.
+//! Application configuration, and how it is read from a TOML or a YAML file.
 
-use crate::logging::LoggingConfig;
-use serde::{de, Deserialize};
+use crate::{
+    error::ThrottleError, logging::LoggingConfig, rate_limit::RateLimitCfg, socket, tls::TlsCfg,
+};
+use actix_web::http::{Method, Uri};
+use serde::{de, de::DeserializeOwned, Deserialize};
 use std::{
     collections::HashMap,
-    fs::File,
+    convert::TryFrom,
+    fmt,
+    fs::{self, File},
     io::{self, Read},
-    path::Path,
+    path::{Path, PathBuf},
     time::Duration,
 };
 
@@ -39,6 +44,65 @@ pub struct SemaphoreCfg {
     pub max: i64,
     /// While holding a mutex at level N one may only acquire mutices at lower levels.
     pub level: i32,
+    /// If `true`, a heartbeat claiming a different lock count for this semaphore than the one
+    /// acquired causes the peer to be revoked.
+    pub strict_amounts: bool,
+    /// If `false`, peers holding or waiting for a lock to this semaphore never expire. They are
+    /// only freed by explicitly releasing them.
+    pub expires: bool,
+    /// If `true`, revenants are never restored with a lock to this semaphore, if it would overbook
+    /// it. The lock is lost and the peer has to wait for it again.
+    pub strict_restore: bool,
+    /// Upper bound for the count of a single lock. Keeps one peer from taking the whole semaphore.
+    /// `None` means only `max` bounds it.
+    pub max_amount: Option<i64>,
+    /// `expires_in` of peers created together with a lock to this semaphore, should the client
+    /// omit it.
+    pub default_expires_in: Option<Duration>,
+}
+
+impl Default for SemaphoreCfg {
+    fn default() -> Self {
+        SemaphoreCfg {
+            max: 0,
+            level: 0,
+            strict_amounts: false,
+            expires: true,
+            strict_restore: false,
+            max_amount: None,
+            default_expires_in: None,
+        }
+    }
+}
+
+impl SemaphoreCfg {
+    // Locks expire by default
+    fn expires_default() -> bool {
+        SemaphoreCfg::default().expires
+    }
+
+    /// `AmountTooLarge` if a single lock with count `amount` exceeds `max_amount`.
+    pub fn check_amount(&self, amount: i64) -> Result<(), ThrottleError> {
+        match self.max_amount {
+            Some(max_amount) if amount > max_amount => Err(ThrottleError::AmountTooLarge {
+                asked: amount,
+                max_amount,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `InvalidSemaphoreName` if `name` is empty or contains '/'. Names are used as segments of the URL
+/// paths. Applies to configured semaphores as well as to those added at runtime.
+pub fn check_semaphore_name(name: &str) -> Result<(), ThrottleError> {
+    if name.is_empty() || name.contains('/') {
+        Err(ThrottleError::InvalidSemaphoreName {
+            name: name.to_owned(),
+        })
+    } else {
+        Ok(())
+    }
 }
 
 impl<'de> de::Deserialize<'de> for SemaphoreCfg {
@@ -46,47 +110,318 @@ impl<'de> de::Deserialize<'de> for SemaphoreCfg {
     where
         D: de::Deserializer<'de>,
     {
-        struct SemaphoreVisitor;
+        deserializer.deserialize_any(SemaphoreVisitor { name: None })
+    }
+}
+
+/// Deserializes a `SemaphoreCfg`. Knows the name of the semaphore, if deserialized as part of
+/// `Semaphores`, so errors can tell which one is wrong.
+struct SemaphoreVisitor<'a> {
+    name: Option<&'a str>,
+}
+
+impl SemaphoreVisitor<'_> {
+    /// Error for values which are neither a full count, nor a verbose configuration. E.g.
+    /// `expected integer full count for semaphore 'gpu', got string`.
+    fn unexpected<E: de::Error>(&self, got: &str) -> E {
+        match self.name {
+            Some(name) => E::custom(format_args!(
+                "expected integer full count for semaphore '{}', got {}",
+                name, got
+            )),
+            None => E::custom(format_args!("expected integer full count, got {}", got)),
+        }
+    }
+}
+
+impl<'de> de::Visitor<'de> for SemaphoreVisitor<'_> {
+    type Value = SemaphoreCfg;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str(
+            "a semaphore count like 42 or a verbose semaphore configuration like { max = 42 }",
+        )?;
+        if let Some(name) = self.name {
+            write!(formatter, " for semaphore '{}'", name)?;
+        }
+        Ok(())
+    }
+
+    fn visit_i64<E>(self, i: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(SemaphoreCfg {
+            max: i,
+            ..SemaphoreCfg::default()
+        })
+    }
 
+    // YAML tells positive integers as unsigned.
+    fn visit_u64<E>(self, u: u64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        let max =
+            i64::try_from(u).map_err(|_| E::invalid_value(de::Unexpected::Unsigned(u), &self))?;
+        self.visit_i64(max)
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Err(self.unexpected("float"))
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Err(self.unexpected("boolean"))
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Err(self.unexpected("string"))
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Err(self.unexpected("nothing"))
+    }
+
+    fn visit_seq<V>(self, _: V) -> Result<Self::Value, V::Error>
+    where
+        V: de::SeqAccess<'de>,
+    {
+        Err(self.unexpected("array"))
+    }
+
+    fn visit_map<V>(self, map: V) -> Result<Self::Value, V::Error>
+    where
+        V: de::MapAccess<'de>,
+    {
         /// Repetition of Semaphore, but with derived `Deserialize` Trait.
         #[derive(Deserialize)]
         pub struct Verbose {
             max: i64,
             #[serde(default)]
             level: i32,
+            #[serde(default)]
+            strict_amounts: bool,
+            #[serde(default = "SemaphoreCfg::expires_default")]
+            expires: bool,
+            #[serde(default)]
+            strict_restore: bool,
+            #[serde(default)]
+            max_amount: Option<i64>,
+            #[serde(with = "humantime_serde", default)]
+            default_expires_in: Option<Duration>,
         }
 
-        impl<'de> de::Visitor<'de> for SemaphoreVisitor {
-            type Value = SemaphoreCfg;
+        let mvd = de::value::MapAccessDeserializer::new(map);
+        Verbose::deserialize(mvd).map(
+            |Verbose {
+                 max,
+                 level,
+                 strict_amounts,
+                 expires,
+                 strict_restore,
+                 max_amount,
+                 default_expires_in,
+             }| SemaphoreCfg {
+                max,
+                level,
+                strict_amounts,
+                expires,
+                strict_restore,
+                max_amount,
+                default_expires_in,
+            },
+        )
+    }
+}
 
-            fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                formatter.write_str(
-                    "a semaphore count like 42 or a verbose semaphore configuration like \
-                    { max = 42 }",
-                )
-            }
+pub type Semaphores = HashMap<String, SemaphoreCfg>;
 
-            fn visit_i64<E>(self, i: i64) -> Result<Self::Value, E>
-            where
-                E: de::Error,
-            {
-                Ok(SemaphoreCfg { max: i, level: 0 })
-            }
+/// Deserializes `Semaphores`, passing the name of each semaphore on to its `SemaphoreVisitor`.
+fn deserialize_semaphores<'de, D>(deserializer: D) -> Result<Semaphores, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    struct SemaphoresVisitor;
+
+    /// Seed deserializing the configuration of the semaphore named `.0`.
+    struct Named<'a>(&'a str);
+
+    impl<'de> de::DeserializeSeed<'de> for Named<'_> {
+        type Value = SemaphoreCfg;
+
+        fn deserialize<D>(self, deserializer: D) -> Result<SemaphoreCfg, D::Error>
+        where
+            D: de::Deserializer<'de>,
+        {
+            deserializer.deserialize_any(SemaphoreVisitor { name: Some(self.0) })
+        }
+    }
+
+    impl<'de> de::Visitor<'de> for SemaphoresVisitor {
+        type Value = Semaphores;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+            formatter.write_str("semaphores by name, like { A = 42 }")
+        }
 
-            fn visit_map<V>(self, map: V) -> Result<Self::Value, V::Error>
-            where
-                V: de::MapAccess<'de>,
-            {
-                let mvd = de::value::MapAccessDeserializer::new(map);
-                Verbose::deserialize(mvd).map(|Verbose { max, level }| SemaphoreCfg { max, level })
+        fn visit_map<V>(self, mut map: V) -> Result<Self::Value, V::Error>
+        where
+            V: de::MapAccess<'de>,
+        {
+            let mut semaphores = Semaphores::new();
+            while let Some(name) = map.next_key::<String>()? {
+                let semaphore = map.next_value_seed(Named(&name))?;
+                semaphores.insert(name, semaphore);
             }
+            Ok(semaphores)
         }
+    }
+
+    deserializer.deserialize_map(SemaphoresVisitor)
+}
 
-        deserializer.deserialize_any(SemaphoreVisitor)
+/// Something noteworthy about a configuration, found by `ApplicationCfg::check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// The server must not start with this configuration.
+    Error(String),
+    /// The server starts, but likely does not behave as intended.
+    Warning(String),
+}
+
+impl Finding {
+    pub fn is_error(&self) -> bool {
+        matches!(self, Finding::Error(_))
     }
 }
 
-pub type Semaphores = HashMap<String, SemaphoreCfg>;
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::Error(message) => write!(f, "Error: {}", message),
+            Finding::Warning(message) => write!(f, "Warning: {}", message),
+        }
+    }
+}
+
+/// Prefix of environment variables overriding the full count of a semaphore, e.g.
+/// `THROTTLE_SEMAPHORE_A=42`.
+const SEMAPHORE_ENV_PREFIX: &str = "THROTTLE_SEMAPHORE_";
+
+/// Name of the semaphore as it appears in environment variables. ASCII letters are upper cased,
+/// every character other than ASCII letters and digits is replaced with `_`. E.g. `db-pool.eu`
+/// becomes `DB_POOL_EU`.
+fn env_name(semaphore: &str) -> String {
+    semaphore
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// What to do with requests asking for an `expires_in` above `max_expires_in`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExceedingExpiresIn {
+    /// Apply `max_expires_in` instead.
+    #[default]
+    Clamp,
+    /// Answer with `409 Conflict`.
+    Reject,
+}
+
+/// Kind of ids the server generates for new peers. Clients may always choose ids of either kind.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerIdKind {
+    /// Random unsigned 64 bit integers. The default.
+    #[default]
+    Int,
+    /// Random (version 4) UUIDs. Easier to tell apart in logs, and safe to mix with ids chosen by
+    /// clients.
+    Uuid,
+    /// Integers counting up from `1`. Predictable, so tests may assert on them. Ids already chosen
+    /// by clients are skipped.
+    Sequential,
+}
+
+/// Settings of the HTTP interface. `[http]` section in the configuration file.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HttpCfg {
+    /// Origins allowed to access throttle from within a browser, e.g. a dashboard. Empty disables
+    /// CORS, which is the default.
+    #[serde(default)]
+    pub cors_allowed_origins: Vec<String>,
+    /// HTTP methods allowed for cross origin requests. Only `GET` by default, so dashboards stay
+    /// read only.
+    #[serde(default = "HttpCfg::cors_allowed_methods_default")]
+    pub cors_allowed_methods: Vec<String>,
+    /// Bearer tokens by the name of their principal. If any is configured, mutating requests must
+    /// carry one of them. Empty by default, i.e. everyone is trusted.
+    #[serde(default)]
+    pub auth_tokens: HashMap<String, String>,
+    /// If `true`, read only requests must carry a token, too. Default is `false`.
+    #[serde(default)]
+    pub auth_reads: bool,
+    /// Paths which never require a token, so monitoring keeps working.
+    #[serde(default = "HttpCfg::auth_public_paths_default")]
+    pub auth_public_paths: Vec<String>,
+    /// TCP address to listen on, e.g. `0.0.0.0:8000`. `--address` and `--port` take precedence.
+    /// Defaults to `127.0.0.1:8000`.
+    #[serde(default)]
+    pub bind: Option<String>,
+    /// Number of worker threads serving requests. `--workers` takes precedence. Defaults to one
+    /// per CPU core.
+    #[serde(default)]
+    pub workers: Option<usize>,
+    /// Time idle connections are kept open. Zero closes connections after each request. Defaults
+    /// to five seconds.
+    #[serde(with = "humantime_serde", default)]
+    pub keep_alive: Option<Duration>,
+}
+
+impl Default for HttpCfg {
+    fn default() -> Self {
+        HttpCfg {
+            cors_allowed_origins: Vec::new(),
+            cors_allowed_methods: vec!["GET".to_owned()],
+            auth_tokens: HashMap::new(),
+            auth_reads: false,
+            auth_public_paths: vec!["/health".to_owned(), "/metrics".to_owned()],
+            bind: None,
+            workers: None,
+            keep_alive: None,
+        }
+    }
+}
+
+impl HttpCfg {
+    fn cors_allowed_methods_default() -> Vec<String> {
+        HttpCfg::default().cors_allowed_methods
+    }
+
+    fn auth_public_paths_default() -> Vec<String> {
+        HttpCfg::default().auth_public_paths
+    }
+}
 
 #[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct ApplicationCfg {
@@ -95,18 +430,72 @@ pub struct ApplicationCfg {
         default = "ApplicationCfg::litter_collection_interval_default"
     )]
     pub litter_collection_interval: Duration,
-    #[serde(default = "HashMap::new")]
+    /// Upper bound for the `expires_in` of peers. Keeps crashed clients from blocking semaphores
+    /// for a long time. `None` means unbounded.
+    #[serde(with = "humantime_serde", default)]
+    pub max_expires_in: Option<Duration>,
+    #[serde(default)]
+    pub exceeding_expires_in: ExceedingExpiresIn,
+    /// Kind of ids generated for new peers. Either `int` (default), `uuid` or `sequential`.
+    #[serde(default)]
+    pub peer_ids: PeerIdKind,
+    #[serde(default = "HashMap::new", deserialize_with = "deserialize_semaphores")]
     pub semaphores: Semaphores,
     #[serde(default = "LoggingConfig::default")]
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub http: HttpCfg,
+    /// Limits the rate at which each client may acquire locks, if present.
+    pub rate_limit: Option<RateLimitCfg>,
+    /// Serve via HTTPS rather than plain HTTP, if present.
+    pub tls: Option<TlsCfg>,
+    /// Address to listen on, e.g. `127.0.0.1:8000`, or a unix domain socket, e.g.
+    /// `unix:/run/throttle.sock`. Takes precedence over the command line. Either TCP or a unix
+    /// domain socket is served, never both.
+    pub listen: Option<String>,
+    /// Requests still in flight after receiving `SIGTERM` are dropped after this grace period.
+    #[serde(
+        with = "humantime_serde",
+        default = "ApplicationCfg::shutdown_timeout_default"
+    )]
+    pub shutdown_timeout: Duration,
+    /// Permissions of the unix domain socket. Default is `0o660`.
+    #[serde(default = "ApplicationCfg::socket_mode_default")]
+    pub socket_mode: u32,
+    /// Directory with further `*.toml`, `*.yaml` or `*.yml` files defining semaphores, e.g.
+    /// `/etc/throttle/conf.d`. Relative paths are relative to the directory of the configuration
+    /// file.
+    pub include_dir: Option<PathBuf>,
+    /// If present, requesting a lock to an unknown semaphore creates it with this full count,
+    /// rather than failing with `UnknownSemaphore`. Meant for development. Off by default.
+    pub default_semaphore_count: Option<i64>,
+}
+
+/// Contents of a file in `include_dir`. Only semaphores may be defined there.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Included {
+    #[serde(default, deserialize_with = "deserialize_semaphores")]
+    semaphores: Semaphores,
 }
 
 impl Default for ApplicationCfg {
     fn default() -> ApplicationCfg {
         ApplicationCfg {
             litter_collection_interval: Duration::from_secs(300), // 5min
+            max_expires_in: None,
+            exceeding_expires_in: ExceedingExpiresIn::Clamp,
+            peer_ids: PeerIdKind::Int,
             semaphores: HashMap::new(),
             logging: LoggingConfig::default(),
+            http: HttpCfg::default(),
+            rate_limit: None,
+            tls: None,
+            listen: None,
+            shutdown_timeout: Duration::from_secs(30),
+            socket_mode: 0o660,
+            include_dir: None,
+            default_semaphore_count: None,
         }
     }
 }
@@ -117,14 +506,24 @@ impl ApplicationCfg {
         ApplicationCfg::default().litter_collection_interval
     }
 
+    fn shutdown_timeout_default() -> Duration {
+        ApplicationCfg::default().shutdown_timeout
+    }
+
+    fn socket_mode_default() -> u32 {
+        ApplicationCfg::default().socket_mode
+    }
+
     /// Checks for a file named `application.cfg` in the working directory. It is then used to
     /// create a new configuration. If the file can not be found a default configuration is created.
+    /// Files ending in `.yaml` or `.yml` are read as YAML, all others as TOML.
     pub fn init(path: &Path) -> Result<ApplicationCfg, io::Error> {
         match File::open(path) {
             Ok(mut file) => {
                 let mut buffer = String::new();
                 file.read_to_string(&mut buffer)?;
-                let cfg = toml::from_str(&buffer)?;
+                let mut cfg = ApplicationCfg::parse(path, &buffer)?;
+                cfg.merge_included(path)?;
                 Ok(cfg)
             }
             Err(e) => {
@@ -143,6 +542,299 @@ impl ApplicationCfg {
             }
         }
     }
+
+    /// Overrides the full counts of semaphores with environment variables like
+    /// `THROTTLE_SEMAPHORE_<NAME>=<count>`. `<NAME>` is matched against the names of the
+    /// configured semaphores, as mangled by `env_name`. Should no configured semaphore match, a new
+    /// one named `<NAME>` is added. Other settings of overridden semaphores are kept.
+    ///
+    /// Returns the overridden semaphores with their new counts, so they can be logged. Counts which
+    /// are not non-negative integers, and names matching more than one semaphore are errors.
+    pub fn override_semaphores(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Vec<(String, i64)>, String> {
+        let mut overrides = Vec::new();
+        for (key, value) in vars {
+            if !key.starts_with(SEMAPHORE_ENV_PREFIX) {
+                continue;
+            }
+            let suffix = &key[SEMAPHORE_ENV_PREFIX.len()..];
+            if suffix.is_empty() {
+                return Err(format!("{} lacks the name of a semaphore.", key));
+            }
+            let max = value
+                .trim()
+                .parse::<i64>()
+                .ok()
+                .filter(|&max| max >= 0)
+                .ok_or_else(|| {
+                    format!(
+                        "{}: Invalid count '{}'. Expected a non-negative integer.",
+                        key, value
+                    )
+                })?;
+            let mangled = env_name(suffix);
+            let matching: Vec<String> = self
+                .semaphores
+                .keys()
+                .filter(|name| env_name(name) == mangled)
+                .cloned()
+                .collect();
+            let name = match matching.len() {
+                0 => suffix.to_owned(),
+                1 => matching.into_iter().next().unwrap(),
+                _ => {
+                    return Err(format!(
+                        "{} is ambiguous. It matches the semaphores {:?}.",
+                        key, matching
+                    ))
+                }
+            };
+            self.semaphores.entry(name.clone()).or_default().max = max;
+            overrides.push((name, max));
+        }
+        overrides.sort();
+        Ok(overrides)
+    }
+
+    /// Validates the configuration beyond what deserializing it already checks. Used both at
+    /// startup and by `--check`, so the two agree. Findings are ordered errors first, and each by
+    /// the name of the semaphore. Loading the TLS certificate is left to `tls::server_config`.
+    pub fn check(&self) -> Vec<Finding> {
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+        if self.semaphores.is_empty() {
+            warnings.push(String::from("No semaphores configured."));
+        }
+        let mut names: Vec<_> = self.semaphores.keys().collect();
+        names.sort();
+        for name in names {
+            let semaphore = &self.semaphores[name];
+            if let Err(error) = check_semaphore_name(name) {
+                errors.push(error.to_string());
+            }
+            if semaphore.max < 0 {
+                errors.push(format!(
+                    "Full count of semaphore '{}' must not be negative. Found: {}.",
+                    name, semaphore.max
+                ));
+            } else if semaphore.max == 0 {
+                warnings.push(format!(
+                    "Semaphore '{}' has a full count of 0. It is closed until the count is raised.",
+                    name
+                ));
+            }
+            match semaphore.max_amount {
+                Some(max_amount) if max_amount < 1 => errors.push(format!(
+                    "max_amount of semaphore '{}' must be at least 1. Found: {}.",
+                    name, max_amount
+                )),
+                Some(max_amount) if max_amount > semaphore.max => warnings.push(format!(
+                    "max_amount {} of semaphore '{}' exceeds its full count of {}.",
+                    max_amount, name, semaphore.max
+                )),
+                _ => (),
+            }
+            if !semaphore.expires && semaphore.max > 1 {
+                warnings.push(format!(
+                    "Semaphore '{}' with a full count of {} does not expire. Leaked locks are \
+                    never freed, unless released explicitly.",
+                    name, semaphore.max
+                ));
+            }
+        }
+        errors.extend(self.logging.problems());
+        warnings.extend(self.logging.warnings());
+        match self.listen.as_deref() {
+            Some(listen) if socket::unix_socket_path(listen).is_some() && self.tls.is_some() => {
+                errors.push(String::from("TLS is not supported on unix domain sockets."));
+            }
+            Some(listen) if socket::unix_socket_path(listen).is_some() => (),
+            Some(listen) if socket::host_and_port(listen).is_none() => {
+                errors.push(format!(
+                    "listen = '{}' is neither `host:port`, nor `unix:<path>`.",
+                    listen
+                ));
+            }
+            _ => (),
+        }
+        if let Some(bind) = self.http.bind.as_deref() {
+            if self.listen.is_some() {
+                errors.push(String::from(
+                    "Specify either `listen` or `bind` in the [http] section, not both.",
+                ));
+            }
+            if socket::host_and_port(bind).is_none() {
+                errors.push(format!("bind = '{}' is not of the form `host:port`.", bind));
+            }
+        }
+        if let Some(count) = self.default_semaphore_count.filter(|&count| count < 0) {
+            errors.push(format!(
+                "default_semaphore_count must not be negative. Found: {}.",
+                count
+            ));
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            if rate_limit.per_second == 0 {
+                errors.push(String::from(
+                    "per_second in the [rate_limit] section must be at least 1.",
+                ));
+            }
+            if rate_limit.burst == 0 {
+                errors.push(String::from(
+                    "burst in the [rate_limit] section must be at least 1.",
+                ));
+            }
+        }
+        // `actix-cors` panics at startup on either of these, rather than telling which is wrong.
+        for origin in &self.http.cors_allowed_origins {
+            // Browsers send origins without path, so anything else would never match.
+            let is_origin = Uri::try_from(origin.as_str()).is_ok_and(|uri| {
+                match (uri.scheme(), uri.authority()) {
+                    (Some(scheme), Some(authority)) => {
+                        format!("{}://{}", scheme, authority) == *origin
+                    }
+                    _ => false,
+                }
+            });
+            if !is_origin {
+                errors.push(format!(
+                    "cors_allowed_origins entry '{}' is not an origin like \
+                    `https://dashboard.example.com`.",
+                    origin
+                ));
+            }
+        }
+        for method in &self.http.cors_allowed_methods {
+            if Method::from_bytes(method.as_bytes()).is_err() {
+                errors.push(format!(
+                    "cors_allowed_methods entry '{}' is not an HTTP method.",
+                    method
+                ));
+            }
+        }
+        if self.http.workers == Some(0) {
+            errors.push(String::from("At least one worker is required."));
+        }
+        if let Some(tls) = &self.tls {
+            for path in &[&tls.cert_path, &tls.key_path] {
+                if !path.is_file() {
+                    errors.push(format!("TLS file {} does not exist.", path.display()));
+                }
+            }
+        }
+        errors
+            .into_iter()
+            .map(Finding::Error)
+            .chain(warnings.into_iter().map(Finding::Warning))
+            .collect()
+    }
+
+    /// `override_semaphores` with the environment of the process. Values which are not valid
+    /// unicode end up as invalid counts, rather than being ignored.
+    pub fn override_semaphores_from_env(&mut self) -> Result<Vec<(String, i64)>, String> {
+        let vars = std::env::vars_os().map(|(key, value)| {
+            (
+                key.to_string_lossy().into_owned(),
+                value.to_string_lossy().into_owned(),
+            )
+        });
+        self.override_semaphores(vars)
+    }
+
+    /// Parses `text` in the format indicated by the extension of `path`. Errors name the file and
+    /// the offending key.
+    fn parse(path: &Path, text: &str) -> Result<ApplicationCfg, io::Error> {
+        parse_as(path, text)
+    }
+
+    /// Merges the semaphores defined in the files of `include_dir` into `semaphores`. Files are
+    /// read in the order of their names, so the outcome does not depend on the file system. A
+    /// semaphore defined more than once is an error naming both files. `path` is the path of the
+    /// configuration file itself.
+    fn merge_included(&mut self, path: &Path) -> Result<(), io::Error> {
+        let dir = match &self.include_dir {
+            // `join` keeps absolute paths as they are.
+            Some(dir) => path.parent().unwrap_or_else(|| Path::new("")).join(dir),
+            None => return Ok(()),
+        };
+        let mut files = Vec::new();
+        let entries = fs::read_dir(&dir)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", dir.display(), e)))?;
+        for entry in entries {
+            let file = entry?.path();
+            let extension = file.extension().and_then(|extension| extension.to_str());
+            if matches!(extension, Some("toml") | Some("yaml") | Some("yml")) && file.is_file() {
+                files.push(file);
+            }
+        }
+        files.sort();
+        // File each semaphore has been defined in, so duplicates can name both.
+        let mut origins: HashMap<String, PathBuf> = self
+            .semaphores
+            .keys()
+            .map(|name| (name.clone(), path.to_owned()))
+            .collect();
+        for file in files {
+            let text = fs::read_to_string(&file)?;
+            let included: Included = parse_as(&file, &text)?;
+            for (name, semaphore) in included.semaphores {
+                if let Some(origin) = origins.get(&name) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Semaphore '{}' is defined in both {} and {}.",
+                            name,
+                            origin.display(),
+                            file.display()
+                        ),
+                    ));
+                }
+                origins.insert(name.clone(), file.clone());
+                self.semaphores.insert(name, semaphore);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses `text` in the format indicated by the extension of `path`. Files ending in `.yaml` or
+/// `.yml` are read as YAML, all others as TOML.
+fn parse_as<T: DeserializeOwned>(path: &Path, text: &str) -> Result<T, io::Error> {
+    let is_yaml = matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some("yaml") | Some("yml")
+    );
+    let result = if is_yaml {
+        serde_yaml::from_str(text).map_err(|e| format!("{}: {}", path.display(), e))
+    } else {
+        toml::from_str(text).map_err(|e| toml_error(path, &e))
+    };
+    result.map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// Puts the location in front of the message, the way compilers do, so editors can jump to it.
+/// E.g. ``throttle.toml:3:7: expected integer full count for semaphore 'gpu', got string for key
+/// `semaphores.gpu` ``.
+fn toml_error(path: &Path, error: &toml::de::Error) -> String {
+    let message = error.to_string();
+    match error.line_col() {
+        Some((line, column)) => {
+            // Rather than repeating it at the end of the message
+            let message = message
+                .rfind(" at line ")
+                .map_or(&message[..], |at| &message[..at]);
+            format!(
+                "{}:{}:{}: {}",
+                path.display(),
+                line + 1,
+                column + 1,
+                message
+            )
+        }
+        None => format!("{}: {}", path.display(), message),
+    }
 }
 
 #[cfg(test)]
@@ -166,6 +858,15 @@ mod tests {
         assert_eq!(actual.semaphores.get("A").unwrap().max, 1);
     }
 
+    #[test]
+    fn parse_litter_collection_interval() {
+        let cfg: ApplicationCfg = toml::from_str("").unwrap();
+        assert_eq!(cfg.litter_collection_interval, Duration::from_secs(300));
+        let cfg: ApplicationCfg = toml::from_str("litter_collection_interval = \"0s\"").unwrap();
+        assert_eq!(cfg.litter_collection_interval, Duration::from_secs(0));
+        assert!(toml::from_str::<ApplicationCfg>("litter_collection_interval = 10").is_err());
+    }
+
     #[test]
     fn simple_and_verbose_configuration() {
         let simple = "
@@ -185,6 +886,71 @@ mod tests {
         assert_eq!(simple, verbose);
     }
 
+    #[test]
+    fn parse_max_expires_in() {
+        let cfg = "max_expires_in = \"1h\"\n\
+                   exceeding_expires_in = \"reject\"\n\
+                  ";
+        let cfg: ApplicationCfg = toml::from_str(cfg).unwrap();
+        assert_eq!(cfg.max_expires_in, Some(Duration::from_secs(3600)));
+        assert_eq!(cfg.exceeding_expires_in, ExceedingExpiresIn::Reject);
+    }
+
+    #[test]
+    fn parse_peer_ids() {
+        let cfg: ApplicationCfg = toml::from_str("").unwrap();
+        assert_eq!(cfg.peer_ids, PeerIdKind::Int);
+        let cfg: ApplicationCfg = toml::from_str("peer_ids = \"uuid\"").unwrap();
+        assert_eq!(cfg.peer_ids, PeerIdKind::Uuid);
+        let cfg: ApplicationCfg = toml::from_str("peer_ids = \"sequential\"").unwrap();
+        assert_eq!(cfg.peer_ids, PeerIdKind::Sequential);
+    }
+
+    #[test]
+    fn semaphores_expire_by_default() {
+        let cfg = "
+                  [semaphores]\n\
+                  A = 1\n\
+                  B = { max=1 }\n\
+                  C = { max=1, expires=false }\n\
+                  ";
+        let cfg: ApplicationCfg = toml::from_str(cfg).unwrap();
+        assert!(cfg.semaphores["A"].expires);
+        assert!(cfg.semaphores["B"].expires);
+        assert!(!cfg.semaphores["C"].expires);
+    }
+
+    #[test]
+    fn parse_semaphore_limits_and_defaults() {
+        let cfg = "
+                  [semaphores]\n\
+                  A = 5\n\
+                  [semaphores.B]\n\
+                  max = 5\n\
+                  max_amount = 2\n\
+                  default_expires_in = \"30s\"\n\
+                  [semaphores.C]\n\
+                  max = 1\n\
+                  max_amount = 0\n\
+                  ";
+        let cfg: ApplicationCfg = toml::from_str(cfg).unwrap();
+        assert_eq!(cfg.semaphores["A"].max_amount, None);
+        assert_eq!(cfg.semaphores["A"].default_expires_in, None);
+        assert_eq!(cfg.semaphores["B"].max_amount, Some(2));
+        assert_eq!(
+            cfg.semaphores["B"].default_expires_in,
+            Some(Duration::from_secs(30))
+        );
+        assert!(cfg.semaphores["B"].check_amount(2).is_ok());
+        assert!(cfg.semaphores["B"].check_amount(3).is_err());
+        assert_eq!(
+            cfg.check(),
+            vec![Finding::Error(String::from(
+                "max_amount of semaphore 'C' must be at least 1. Found: 0."
+            ))]
+        );
+    }
+
     /// Verify that the default configuration used in case of a missing file is identical to the
     /// configuration obtained from an empty toml file.
     #[test]
@@ -207,6 +973,296 @@ mod tests {
         assert!(actual.logging.gelf.is_some());
     }
 
+    #[test]
+    fn parse_http_config() {
+        let cfg = "[http]\n\
+                   cors_allowed_origins = [\"https://dashboard.internal\"]\n\
+                  ";
+        let cfg: ApplicationCfg = toml::from_str(cfg).unwrap();
+        assert_eq!(
+            cfg.http.cors_allowed_origins,
+            ["https://dashboard.internal"]
+        );
+        // Read only by default
+        assert_eq!(cfg.http.cors_allowed_methods, ["GET"]);
+    }
+
+    #[test]
+    fn parse_bind_workers_and_keep_alive() {
+        let cfg = "[http]\n\
+                   bind = \"0.0.0.0:9000\"\n\
+                   workers = 4\n\
+                   keep_alive = \"75s\"\n\
+                  ";
+        let cfg: ApplicationCfg = toml::from_str(cfg).unwrap();
+        assert_eq!(cfg.http.bind.as_deref(), Some("0.0.0.0:9000"));
+        assert_eq!(cfg.http.workers, Some(4));
+        assert_eq!(cfg.http.keep_alive, Some(Duration::from_secs(75)));
+        assert!(!cfg.check().iter().any(Finding::is_error));
+
+        let cfg = "listen = \"127.0.0.1:8000\"\n\
+                   [http]\n\
+                   bind = \"0.0.0.0\"\n\
+                   workers = 0\n\
+                  ";
+        let cfg: ApplicationCfg = toml::from_str(cfg).unwrap();
+        let errors = cfg.check().into_iter().filter(Finding::is_error).count();
+        assert_eq!(errors, 3);
+    }
+
+    #[test]
+    fn rate_limit_must_admit_requests() {
+        let cfg = "[rate_limit]\n\
+                   per_second = 10\n\
+                   burst = 20\n\
+                  ";
+        let cfg: ApplicationCfg = toml::from_str(cfg).unwrap();
+        assert!(!cfg.check().iter().any(Finding::is_error));
+
+        let cfg = "[rate_limit]\n\
+                   per_second = 0\n\
+                   burst = 0\n\
+                  ";
+        let cfg: ApplicationCfg = toml::from_str(cfg).unwrap();
+        let errors: Vec<_> = cfg.check().into_iter().filter(Finding::is_error).collect();
+        assert_eq!(
+            errors,
+            vec![
+                Finding::Error(String::from(
+                    "per_second in the [rate_limit] section must be at least 1."
+                )),
+                Finding::Error(String::from(
+                    "burst in the [rate_limit] section must be at least 1."
+                )),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_yaml_file() {
+        let cfg = "\
+litter_collection_interval: 100ms
+semaphores:
+  A: 42
+  B:
+    max: 1
+    level: 1
+    strict_amounts: true
+logging:
+  gelf:
+    name: MyThrottleServer.net
+    host: my_graylog_instance.cloud
+    port: 12201
+    level: DEBUG
+";
+        let actual = ApplicationCfg::parse(Path::new("throttle.yaml"), cfg).unwrap();
+        assert_eq!(
+            actual.litter_collection_interval,
+            Duration::from_millis(100)
+        );
+        assert_eq!(actual.semaphores["A"].max, 42);
+        assert_eq!(
+            actual.semaphores["B"],
+            SemaphoreCfg {
+                max: 1,
+                level: 1,
+                strict_amounts: true,
+                ..SemaphoreCfg::default()
+            }
+        );
+        assert!(actual.logging.gelf.is_some());
+    }
+
+    #[test]
+    fn yaml_errors_name_file_and_key() {
+        let cfg = "semaphores:\n  A:\n    max: many\n";
+        let error = ApplicationCfg::parse(Path::new("throttle.yml"), cfg).unwrap_err();
+        let message = error.to_string();
+        assert!(message.starts_with("throttle.yml: "), "{}", message);
+        assert!(message.contains("semaphores.A"), "{}", message);
+    }
+
+    /// Each file in `tests/config` starts with a comment like `# error: 5: <message>`, pinning the
+    /// line and the message of the error reading it. So errors keep telling what is wrong, rather
+    /// than regressing into the raw output of serde.
+    #[test]
+    fn invalid_configuration_files() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/config");
+        let mut num_fixtures = 0;
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let text = fs::read_to_string(&path).unwrap();
+            let header = text.lines().next().unwrap();
+            assert!(header.starts_with("# error: "), "{}", path.display());
+            let (line, message) = header["# error: ".len()..].split_once(": ").unwrap();
+
+            let error = ApplicationCfg::init(&path).unwrap_err().to_string();
+            let location = format!("{}:{}:", path.display(), line);
+            assert!(error.starts_with(&location), "{}", error);
+            assert!(error.contains(message), "{}", error);
+            num_fixtures += 1;
+        }
+        assert!(num_fixtures >= 6);
+    }
+
+    #[test]
+    fn merge_semaphores_from_include_dir() {
+        let dir = std::env::temp_dir().join("throttle-include-dir");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        let main = dir.join("throttle.toml");
+        fs::write(&main, "include_dir = \"conf.d\"\n[semaphores]\nA = 1\n").unwrap();
+        fs::write(dir.join("conf.d/build.toml"), "[semaphores]\nB = 2\n").unwrap();
+        fs::write(dir.join("conf.d/gpu.yaml"), "semaphores:\n  C: 3\n").unwrap();
+        fs::write(dir.join("conf.d/README.md"), "Not a configuration").unwrap();
+
+        let cfg = ApplicationCfg::init(&main).unwrap();
+        assert_eq!(cfg.semaphores.len(), 3);
+        assert_eq!(cfg.semaphores["B"].max, 2);
+        assert_eq!(cfg.semaphores["C"].max, 3);
+
+        // The later file in order of names is the one blamed.
+        fs::write(dir.join("conf.d/other.toml"), "[semaphores]\nB = 5\n").unwrap();
+        let message = ApplicationCfg::init(&main).unwrap_err().to_string();
+        assert!(message.contains("'B'"), "{}", message);
+        assert!(message.contains("build.toml"), "{}", message);
+        assert!(message.ends_with("other.toml."), "{}", message);
+
+        // Only semaphores may be defined in included files.
+        fs::remove_file(dir.join("conf.d/other.toml")).unwrap();
+        fs::write(dir.join("conf.d/listen.toml"), "listen = \"0.0.0.0:80\"\n").unwrap();
+        assert!(ApplicationCfg::init(&main).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn override_semaphores_from_environment() {
+        let mut cfg: ApplicationCfg = toml::from_str(
+            "[semaphores]\n\
+             db-pool = { max=3, level=1 }\n\
+             B = 1\n",
+        )
+        .unwrap();
+        let vars = vec![
+            (
+                String::from("THROTTLE_SEMAPHORE_DB_POOL"),
+                String::from("5"),
+            ),
+            (String::from("THROTTLE_SEMAPHORE_New"), String::from("2")),
+            (String::from("THROTTLE_LOG"), String::from("DEBUG")),
+        ];
+
+        let overrides = cfg.override_semaphores(vars).unwrap();
+
+        assert_eq!(
+            overrides,
+            [(String::from("New"), 2), (String::from("db-pool"), 5)]
+        );
+        // The level is kept
+        assert_eq!(
+            cfg.semaphores["db-pool"],
+            SemaphoreCfg {
+                max: 5,
+                level: 1,
+                ..SemaphoreCfg::default()
+            }
+        );
+        assert_eq!(cfg.semaphores["B"].max, 1);
+        assert_eq!(cfg.semaphores["New"].max, 2);
+    }
+
+    #[test]
+    fn invalid_semaphore_overrides() {
+        let mut cfg: ApplicationCfg = toml::from_str("[semaphores]\na-b = 1\na_b = 1\n").unwrap();
+        let var = |key: &str, value: &str| vec![(key.to_owned(), value.to_owned())];
+
+        assert!(cfg
+            .override_semaphores(var("THROTTLE_SEMAPHORE_C", "many"))
+            .is_err());
+        assert!(cfg
+            .override_semaphores(var("THROTTLE_SEMAPHORE_C", "-1"))
+            .is_err());
+        assert!(cfg
+            .override_semaphores(var("THROTTLE_SEMAPHORE_", "1"))
+            .is_err());
+        // Both semaphores are named `A_B` in the environment.
+        assert!(cfg
+            .override_semaphores(var("THROTTLE_SEMAPHORE_A_B", "2"))
+            .is_err());
+        assert!(!cfg.semaphores.contains_key("C"));
+    }
+
+    #[test]
+    fn check_configuration() {
+        let cfg: ApplicationCfg = toml::from_str(
+            "listen = \"localhost\"\n\
+             [semaphores]\n\
+             A = 1\n\
+             B = -1\n\
+             C = 0\n\
+             \"D/E\" = 1\n",
+        )
+        .unwrap();
+        let findings = cfg.check();
+        let errors = findings.iter().filter(|f| f.is_error()).count();
+        assert_eq!(errors, 3, "{:?}", findings);
+        assert_eq!(
+            findings.last().unwrap(),
+            &Finding::Warning(String::from(
+                "Semaphore 'C' has a full count of 0. It is closed until the count is raised."
+            ))
+        );
+
+        let cfg: ApplicationCfg =
+            toml::from_str("listen = \"0.0.0.0:8000\"\n[semaphores]\nA = 1\n").unwrap();
+        assert!(cfg.check().is_empty());
+    }
+
+    #[test]
+    fn check_cors() {
+        let cfg: ApplicationCfg = toml::from_str(
+            "[http]\n\
+             cors_allowed_origins = [\"https://dashboard.internal:8080\", \"dashboard\", \
+             \"https://dashboard.internal/\", \"https://bad host\"]\n\
+             cors_allowed_methods = [\"GET\", \"PUT\", \"GE T\", \"\"]\n\
+             [semaphores]\n\
+             A = 1\n",
+        )
+        .unwrap();
+        let errors: Vec<_> = cfg.check().into_iter().filter(Finding::is_error).collect();
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+        assert_eq!(
+            errors[0],
+            Finding::Error(String::from(
+                "cors_allowed_origins entry 'dashboard' is not an origin like \
+                `https://dashboard.example.com`."
+            ))
+        );
+    }
+
+    /// Unix domain sockets are no `host:port`, but fine nonetheless. Only TLS is not.
+    #[test]
+    fn check_listen() {
+        let errors = |cfg: &str| -> Vec<Finding> {
+            let cfg: ApplicationCfg = toml::from_str(cfg).unwrap();
+            cfg.check().into_iter().filter(Finding::is_error).collect()
+        };
+        assert!(errors("listen = \"unix:/run/throttle.sock\"").is_empty());
+        assert_eq!(
+            errors(
+                "listen = \"unix:/run/throttle.sock\"\n\
+                 [tls]\n\
+                 cert_path = \"tests/tls/cert.pem\"\n\
+                 key_path = \"tests/tls/key.pem\"\n"
+            ),
+            vec![Finding::Error(String::from(
+                "TLS is not supported on unix domain sockets."
+            ))]
+        );
+        assert_eq!(errors("listen = \"localhost\"").len(), 1);
+    }
+
     #[test]
     fn parse_console_logging_config() {
         let cfg = "[logging.stderr]\n\
@@ -0,0 +1,229 @@
+//! Optional bearer token authentication. If tokens are configured, mutating requests must carry an
+//! `Authorization: Bearer <token>` header. Read only requests are left unauthenticated, unless
+//! configured otherwise. The name the token is configured with, becomes the principal recorded in
+//! the log of administrative changes.
+
+use crate::application_cfg::HttpCfg;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{header::WWW_AUTHENTICATE, HeaderValue, Method, StatusCode},
+    Error, HttpMessage, HttpResponse, ResponseError,
+};
+use serde_json::json;
+use std::{future::Future, sync::Arc};
+use thiserror::Error;
+
+const BEARER: &str = "Bearer ";
+
+/// Name of the token the request has been authenticated with. Stored in the extensions of the
+/// request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal(pub String);
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Missing bearer token.")]
+    Missing,
+    #[error("Invalid bearer token.")]
+    Invalid,
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::Unauthorized()
+            .header(WWW_AUTHENTICATE, "Bearer")
+            .json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// Decides which requests need a token and checks it.
+#[derive(Clone)]
+pub struct Authentication {
+    /// Principal and token
+    tokens: Arc<Vec<(String, String)>>,
+    /// If `true` read only requests need a token, too.
+    reads: bool,
+    /// Paths which never need a token, e.g. `/health`.
+    public_paths: Arc<Vec<String>>,
+}
+
+impl Authentication {
+    pub fn new(cfg: &HttpCfg) -> Self {
+        Authentication {
+            tokens: Arc::new(
+                cfg.auth_tokens
+                    .iter()
+                    .map(|(principal, token)| (principal.clone(), token.clone()))
+                    .collect(),
+            ),
+            reads: cfg.auth_reads,
+            public_paths: Arc::new(cfg.auth_public_paths.clone()),
+        }
+    }
+
+    /// `true` if the request must not pass without a valid token.
+    fn requires_token(&self, method: &Method, path: &str) -> bool {
+        if self.tokens.is_empty() || self.public_paths.iter().any(|public| public == path) {
+            return false;
+        }
+        match *method {
+            // Preflight requests of browsers never carry credentials.
+            Method::OPTIONS => false,
+            Method::GET | Method::HEAD => self.reads,
+            _ => true,
+        }
+    }
+
+    /// Principal of the token in the `Authorization` header.
+    fn authenticate(&self, authorization: Option<&HeaderValue>) -> Result<&str, AuthError> {
+        let token = authorization
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| value.starts_with(BEARER))
+            .map(|value| &value[BEARER.len()..])
+            .ok_or(AuthError::Missing)?;
+        self.tokens
+            .iter()
+            .find(|(_, expected)| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+            .map(|(principal, _)| principal.as_str())
+            .ok_or(AuthError::Invalid)
+    }
+
+    /// Middleware answering requests without a valid token with `401 Unauthorized`, if one is
+    /// required. The principal of valid tokens is stored in the extensions of the request.
+    pub fn check<S, B>(
+        &self,
+        req: ServiceRequest,
+        srv: &mut S,
+    ) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    {
+        let required = self.requires_token(req.method(), req.path());
+        let principal = self
+            .authenticate(req.headers().get("authorization"))
+            .map(str::to_owned);
+        let response = match principal {
+            Ok(principal) => {
+                req.extensions_mut().insert(Principal(principal));
+                Ok(srv.call(req))
+            }
+            Err(error) if required => Err(req.error_response(error)),
+            Err(_) => Ok(srv.call(req)),
+        };
+        async move {
+            match response {
+                Ok(future) => future.await,
+                Err(response) => Ok(response),
+            }
+        }
+    }
+}
+
+/// Compares two byte strings in time independent of their content, so response times do not leak
+/// how much of a guessed token is correct.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{get, post, test, App};
+
+    #[get("/remainder")]
+    async fn read() -> &'static str {
+        "Read"
+    }
+
+    #[post("/semaphores")]
+    async fn write() -> &'static str {
+        "Written"
+    }
+
+    fn authentication() -> Authentication {
+        let mut cfg = HttpCfg::default();
+        cfg.auth_tokens
+            .insert("ops".to_owned(), "secret".to_owned());
+        Authentication::new(&cfg)
+    }
+
+    #[test]
+    fn compare_tokens() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    #[test]
+    fn no_tokens_no_authentication() {
+        let auth = Authentication::new(&HttpCfg::default());
+        assert!(!auth.requires_token(&Method::POST, "/semaphores"));
+    }
+
+    /// Public paths pass without a token, even if reads require one.
+    #[actix_rt::test]
+    async fn public_paths() {
+        let mut cfg = HttpCfg::default();
+        cfg.auth_tokens
+            .insert("ops".to_owned(), "secret".to_owned());
+        cfg.auth_reads = true;
+        cfg.auth_public_paths = vec!["/remainder".to_owned()];
+        let auth = Authentication::new(&cfg);
+        assert!(!auth.requires_token(&Method::GET, "/remainder"));
+        assert!(auth.requires_token(&Method::GET, "/metrics"));
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| auth.check(req, srv))
+                .service(read)
+                .service(write),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/remainder").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Rejected before it is routed, so no handler is needed.
+        let req = test::TestRequest::with_uri("/metrics").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn bearer_token() {
+        let auth = authentication();
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| auth.check(req, srv))
+                .service(read)
+                .service(write),
+        )
+        .await;
+
+        let req = test::TestRequest::with_uri("/remainder").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post().uri("/semaphores").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post()
+            .uri("/semaphores")
+            .header("Authorization", "Bearer wrong")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post()
+            .uri("/semaphores")
+            .header("Authorization", "Bearer secret")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
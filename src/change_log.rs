@@ -0,0 +1,206 @@
+//! Bounded in-memory log of administrative changes to the runtime configuration. Runtime changes
+//! are not persisted, so a restart loses them. The log allows auditors to answer who changed what
+//! and when. Locks acquired by authenticated principals are written to the audit trail, too.
+
+use crate::{auth::Principal, leases::PeerId, state::State};
+use actix_web::{
+    get,
+    web::{Data, Json},
+    HttpRequest,
+};
+use log::info;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{HashSet, VecDeque},
+    time::SystemTime,
+};
+
+/// Number of changes kept in memory. Older ones are dropped.
+const CAPACITY: usize = 1000;
+
+/// Header used to correlate a change with the request causing it.
+const X_REQUEST_ID: &str = "x-request-id";
+
+/// A single administrative change of the runtime configuration.
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    #[serde(with = "humantime_serde")]
+    pub timestamp: SystemTime,
+    /// Name of the administrative operation, e.g. `set_max`.
+    pub operation: String,
+    /// The thing changed, e.g. the name of a semaphore.
+    pub subject: String,
+    pub old: Value,
+    pub new: Value,
+    /// Authenticated principal issuing the change, if known.
+    pub principal: Option<String>,
+    /// Value of the `X-Request-Id` header of the request causing the change, if any.
+    pub request_id: Option<String>,
+}
+
+/// Keeps the latest changes and which subjects have been overridden at runtime.
+#[derive(Debug, Default)]
+pub struct ChangeLog {
+    entries: VecDeque<Change>,
+    /// `(operation, subject)` of every change since startup. Each one is going to be lost on
+    /// restart.
+    overrides: HashSet<(String, String)>,
+}
+
+impl ChangeLog {
+    /// Appends `change`, dropping the oldest entry if the log is full.
+    pub fn push(&mut self, change: Change) {
+        self.overrides
+            .insert((change.operation.clone(), change.subject.clone()));
+        if self.entries.len() == CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(change);
+    }
+
+    /// Changes, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = &Change> {
+        self.entries.iter()
+    }
+
+    /// Number of distinct settings overridden at runtime, which are not persisted.
+    pub fn num_overrides(&self) -> usize {
+        self.overrides.len()
+    }
+}
+
+/// A lock acquired by an authenticated principal for a peer. A scheduler acquiring locks on behalf
+/// of the workers it hands the peer ids to, is recorded as principal, the worker by its peer id.
+#[derive(Debug, Serialize)]
+pub struct Acquisition<'a> {
+    #[serde(with = "humantime_serde")]
+    pub timestamp: SystemTime,
+    pub principal: String,
+    pub peer_id: PeerId,
+    pub semaphore: &'a str,
+    pub amount: i64,
+    /// Value of the `X-Request-Id` header of the request acquiring the lock, if any.
+    pub request_id: Option<String>,
+}
+
+impl<'a> Acquisition<'a> {
+    /// `None` if the request is not authenticated, as there is no principal to record.
+    pub fn new(
+        req: &HttpRequest,
+        peer_id: PeerId,
+        semaphore: &'a str,
+        amount: i64,
+    ) -> Option<Self> {
+        Some(Acquisition {
+            timestamp: SystemTime::now(),
+            principal: principal(req)?,
+            peer_id,
+            semaphore,
+            amount,
+            request_id: request_id(req),
+        })
+    }
+}
+
+/// Writes a lock acquired by an authenticated principal to the audit trail. Unlike administrative
+/// changes it is not kept in memory, as acquisitions are far too frequent.
+pub fn record_acquisition(req: &HttpRequest, peer_id: PeerId, semaphore: &str, amount: i64) {
+    if let Some(acquisition) = Acquisition::new(req, peer_id, semaphore, amount) {
+        info!(target: "throttle::audit", "{}", serde_json::to_string(&acquisition).unwrap());
+    }
+}
+
+/// Value of the `X-Request-Id` header.
+fn request_id(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+/// Name of the token the request has been authenticated with.
+fn principal(req: &HttpRequest) -> Option<String> {
+    req.extensions()
+        .get::<Principal>()
+        .map(|principal| principal.0.clone())
+}
+
+/// Records an administrative change. Every admin handler changing the runtime configuration must
+/// funnel through this function, so the log is complete.
+pub fn record(
+    req: &HttpRequest,
+    state: &State,
+    operation: &str,
+    subject: &str,
+    old: Value,
+    new: Value,
+) {
+    let change = Change {
+        timestamp: SystemTime::now(),
+        operation: operation.to_owned(),
+        subject: subject.to_owned(),
+        old,
+        new,
+        principal: principal(req),
+        request_id: request_id(req),
+    };
+    // Audit trail. Ends up in whatever log sink is configured.
+    info!(target: "throttle::audit", "{}", serde_json::to_string(&change).unwrap());
+    state.record_change(change);
+}
+
+/// Administrative changes to the runtime configuration, oldest first.
+#[get("/admin/changes")]
+async fn changes(state: Data<State>) -> Json<Vec<Change>> {
+    Json(state.changes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    fn change(operation: &str, subject: &str) -> Change {
+        Change {
+            timestamp: SystemTime::now(),
+            operation: operation.to_owned(),
+            subject: subject.to_owned(),
+            old: json!(8),
+            new: json!(4),
+            principal: None,
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn bounded_log() {
+        let mut log = ChangeLog::default();
+        for _ in 0..CAPACITY + 1 {
+            log.push(change("set_max", "A"));
+        }
+        log.push(change("set_max", "B"));
+        assert_eq!(log.entries().count(), CAPACITY);
+        assert_eq!(log.entries().last().unwrap().subject, "B");
+        assert_eq!(log.num_overrides(), 2);
+    }
+
+    /// Only authenticated requests end up in the audit trail. They name both the principal and
+    /// the peer the lock has been acquired for.
+    #[test]
+    fn acquisition_on_behalf_of_peer() {
+        let req = TestRequest::default().to_http_request();
+        assert!(Acquisition::new(&req, PeerId::Int(7), "A", 2).is_none());
+
+        let req = TestRequest::default()
+            .header(X_REQUEST_ID, "42")
+            .to_http_request();
+        req.extensions_mut()
+            .insert(Principal("scheduler".to_owned()));
+        let acquisition = Acquisition::new(&req, PeerId::Int(7), "A", 2).unwrap();
+        assert_eq!(acquisition.principal, "scheduler");
+        assert_eq!(acquisition.peer_id, PeerId::Int(7));
+        assert_eq!(acquisition.request_id.as_deref(), Some("42"));
+    }
+}
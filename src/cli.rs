@@ -1,6 +1,12 @@
+use crate::socket;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+/// Address to bind to, if neither given at the command line, nor in the configuration file.
+const DEFAULT_ADDRESS: &str = "127.0.0.1";
+/// Port to listen on, if neither given at the command line, nor in the configuration file.
+const DEFAULT_PORT: u16 = 8000;
+
 /// Arguments passed at the command line
 #[derive(StructOpt)]
 #[structopt(
@@ -8,19 +14,56 @@ use structopt::StructOpt;
     about = "A service providing semaphores for distributed systems."
 )]
 pub struct Cli {
-    /// Address to bind to
-    #[structopt(long = "address", default_value = "127.0.0.1")]
-    pub address: String,
-    /// Port on which the server listens to requests
-    #[structopt(long = "port", default_value = "8000")]
-    pub port: u16,
-    /// Path to TOML configuration file
+    /// Address to bind to. Takes precedence over `bind` in the configuration file. [default:
+    /// 127.0.0.1]
+    #[structopt(long = "address")]
+    pub address: Option<String>,
+    /// Port on which the server listens to requests. Takes precedence over `bind` in the
+    /// configuration file. [default: 8000]
+    #[structopt(long = "port")]
+    pub port: Option<u16>,
+    /// Number of worker threads. Takes precedence over `workers` in the configuration file.
+    /// Defaults to one per CPU core.
+    #[structopt(long = "workers")]
+    pub workers: Option<usize>,
+    /// Path to TOML configuration file. Files ending in `.yaml` or `.yml` are read as YAML.
     #[structopt(long = "configuration", short = "c", default_value = "throttle.toml")]
     pub configuration: PathBuf,
+    /// Validates the given configuration file and exits, without starting the server. Exits with
+    /// `1` if the server would refuse to start with it.
+    #[structopt(long = "check")]
+    pub check: Option<PathBuf>,
 }
 
 impl Cli {
-    pub fn endpoint(&self) -> String {
-        format!("{}:{}", self.address, self.port)
+    /// Address and port to listen on. `--address` and `--port` take precedence over `bind` from the
+    /// configuration file. Parts given by neither fall back to `127.0.0.1:8000`.
+    pub fn endpoint(&self, bind: Option<&str>) -> String {
+        let (host, port) = bind
+            .and_then(socket::host_and_port)
+            .unwrap_or((DEFAULT_ADDRESS, DEFAULT_PORT));
+        format!(
+            "{}:{}",
+            self.address.as_deref().unwrap_or(host),
+            self.port.unwrap_or(port)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_takes_precedence_over_bind() {
+        let cli = Cli::from_iter(&["throttle"]);
+        assert_eq!(cli.endpoint(None), "127.0.0.1:8000");
+        assert_eq!(cli.endpoint(Some("0.0.0.0:9000")), "0.0.0.0:9000");
+
+        let cli = Cli::from_iter(&["throttle", "--port", "8080"]);
+        assert_eq!(cli.endpoint(Some("0.0.0.0:9000")), "0.0.0.0:8080");
+
+        let cli = Cli::from_iter(&["throttle", "--address", "localhost", "--port", "8080"]);
+        assert_eq!(cli.endpoint(Some("0.0.0.0:9000")), "localhost:8080");
     }
 }
@@ -0,0 +1,46 @@
+//! Source of the current time for `State`. Expiration and waiting are measured against it, so tests
+//! can advance time explicitly, rather than sleeping.
+
+use std::time::Instant;
+
+/// Tells the current time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic clock of the operating system. Used outside of tests.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Stands still, until advanced explicitly.
+#[cfg(test)]
+pub struct ManualClock {
+    now: std::sync::Mutex<Instant>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    /// Starts at the current instant of the system clock.
+    pub fn new() -> Self {
+        ManualClock {
+            now: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: std::time::Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
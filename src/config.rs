@@ -0,0 +1,124 @@
+//! `/config` route. Tells operators which configuration the server is actually running with, after
+//! command line arguments, environment variables and reloads have been applied.
+
+use crate::state::State;
+use actix_web::{
+    get,
+    web::{Data, Json},
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+/// Settings decided once at startup.
+#[derive(Clone, Debug)]
+pub struct Startup {
+    /// Path of the configuration file
+    pub configuration: PathBuf,
+    /// Address the server listens on, e.g. `http://127.0.0.1:8000` or `unix:/run/throttle.sock`.
+    pub listening: String,
+    /// `None` if there is one worker per CPU core.
+    pub workers: Option<usize>,
+    /// `None` if the default of the HTTP server is used.
+    pub keep_alive: Option<Duration>,
+    pub litter_collection_interval: Duration,
+    /// Full count of semaphores created on the fly. `None` if unknown semaphores are an error.
+    pub default_semaphore_count: Option<i64>,
+}
+
+/// Configuration of a single semaphore, as it is currently in effect.
+#[derive(Serialize)]
+struct SemaphoreEntry {
+    max: i64,
+    level: i32,
+    strict_amounts: bool,
+    expires: bool,
+    strict_restore: bool,
+    max_amount: Option<i64>,
+    default_expires_in_sec: Option<f64>,
+}
+
+/// Response body of the `/config` route
+#[derive(Serialize)]
+struct EffectiveCfg {
+    configuration: String,
+    listening: String,
+    /// `null` if there is one worker per CPU core.
+    workers: Option<usize>,
+    /// `null` if the default of the HTTP server is used.
+    keep_alive_sec: Option<f64>,
+    litter_collection_interval_sec: f64,
+    /// `null` if unknown semaphores are an error, rather than created on the fly.
+    default_semaphore_count: Option<i64>,
+    /// Sorted by name. Includes changes made at runtime.
+    semaphores: BTreeMap<String, SemaphoreEntry>,
+}
+
+/// Configuration the server is running with. Bearer tokens are never shown.
+#[get("/config")]
+async fn config(startup: Data<Startup>, state: Data<State>) -> Json<EffectiveCfg> {
+    let semaphores = state
+        .list_semaphores()
+        .into_iter()
+        .map(|(name, (cfg, _counts))| {
+            let entry = SemaphoreEntry {
+                max: cfg.max,
+                level: cfg.level,
+                strict_amounts: cfg.strict_amounts,
+                expires: cfg.expires,
+                strict_restore: cfg.strict_restore,
+                max_amount: cfg.max_amount,
+                default_expires_in_sec: cfg.default_expires_in.map(|d| d.as_secs_f64()),
+            };
+            (name, entry)
+        })
+        .collect();
+    Json(EffectiveCfg {
+        configuration: startup.configuration.display().to_string(),
+        listening: startup.listening.clone(),
+        workers: startup.workers,
+        keep_alive_sec: startup.keep_alive.map(|d| d.as_secs_f64()),
+        litter_collection_interval_sec: startup.litter_collection_interval.as_secs_f64(),
+        default_semaphore_count: startup.default_semaphore_count,
+        semaphores,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application_cfg::{SemaphoreCfg, Semaphores};
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn show_effective_configuration() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("B"),
+            SemaphoreCfg {
+                max: 2,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(String::from("A"), SemaphoreCfg::default());
+        let state = Data::new(State::new(semaphores));
+        let startup = Data::new(Startup {
+            configuration: PathBuf::from("throttle.toml"),
+            listening: String::from("http://0.0.0.0:8000"),
+            workers: Some(4),
+            keep_alive: None,
+            litter_collection_interval: Duration::from_secs(300),
+            default_semaphore_count: None,
+        });
+        let mut app =
+            test::init_service(App::new().app_data(state).app_data(startup).service(config)).await;
+
+        let req = test::TestRequest::with_uri("/config").to_request();
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body["listening"], "http://0.0.0.0:8000");
+        assert_eq!(body["workers"], 4);
+        assert!(body["keep_alive_sec"].is_null());
+        assert_eq!(body["semaphores"]["B"]["max"], 2);
+        let names: Vec<_> = body["semaphores"].as_object().unwrap().keys().collect();
+        assert_eq!(names, ["A", "B"]);
+    }
+}
@@ -0,0 +1,22 @@
+//! Cross origin resource sharing, so dashboards running in a browser may query throttle directly.
+
+use crate::application_cfg::HttpCfg;
+use actix_cors::{Cors, CorsFactory};
+use actix_web::middleware::Condition;
+
+/// How long browsers may cache the answer to a preflight request, in seconds.
+const MAX_AGE: usize = 3600;
+
+/// CORS middleware for the origins and methods configured in `[http]`. Disabled if no origin is
+/// configured, since `actix-cors` would allow any origin otherwise.
+pub fn cors(cfg: &HttpCfg) -> Condition<CorsFactory> {
+    let mut cors = Cors::new();
+    for origin in &cfg.cors_allowed_origins {
+        cors = cors.allowed_origin(origin);
+    }
+    let cors = cors
+        .allowed_methods(cfg.cors_allowed_methods.iter().map(String::as_str))
+        .max_age(MAX_AGE)
+        .finish();
+    Condition::new(!cfg.cors_allowed_origins.is_empty(), cors)
+}
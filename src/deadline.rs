@@ -0,0 +1,326 @@
+//! Callers may limit the time a request is allowed to take, by sending a deadline along with it.
+//! This way a blocking request never outlives the budget of the caller.
+//!
+//! Two headers are understood:
+//!
+//! * `X-Deadline`: An absolute point in time in RFC 3339 format. E.g. `2020-05-01T12:00:00Z`.
+//! * `grpc-timeout`: A relative timeout in the style of gRPC. An integer with at most eight digits
+//!   followed by a unit. E.g. `100m` for 100 milliseconds. Valid units are `H` (hours), `M`
+//!   (minutes), `S` (seconds), `m` (milliseconds), `u` (microseconds) and `n` (nanoseconds).
+//!
+//! Should both be present, the earlier deadline wins.
+
+use actix_web::{
+    dev::{Payload, Service, ServiceRequest, ServiceResponse},
+    http::{HeaderMap, StatusCode},
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use futures::future::{ready, Ready};
+use serde_json::json;
+use std::{
+    future::Future,
+    time::{Duration, Instant, SystemTime},
+};
+use thiserror::Error;
+
+const X_DEADLINE: &str = "x-deadline";
+const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+/// Errors caused by the deadline headers of a request.
+#[derive(Debug, Error)]
+pub enum DeadlineError {
+    #[error("Malformed {header} header: {value:?}")]
+    Malformed { header: &'static str, value: String },
+    #[error("Deadline of request exceeded before it could be processed.")]
+    Exceeded,
+}
+
+impl ResponseError for DeadlineError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DeadlineError::Malformed { .. } => StatusCode::BAD_REQUEST,
+            DeadlineError::Exceeded => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+/// Deadline of a request. Extract it in handlers, to limit the time spent blocking.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// Reads the deadline from the headers of a request. `now` is used to convert relative
+    /// timeouts and wall clock time into an `Instant`.
+    pub fn from_headers(headers: &HeaderMap, now: Instant) -> Result<Self, DeadlineError> {
+        let absolute = headers
+            .get(X_DEADLINE)
+            .map(|value| {
+                let value = value.to_str().unwrap_or_default();
+                let deadline =
+                    humantime::parse_rfc3339_weak(value).map_err(|_| DeadlineError::Malformed {
+                        header: X_DEADLINE,
+                        value: value.to_owned(),
+                    })?;
+                // A deadline in the past is not malformed, yet exceeded. Map it to `now`.
+                let remaining = deadline
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default();
+                Ok(now + remaining)
+            })
+            .transpose()?;
+        let relative = headers
+            .get(GRPC_TIMEOUT)
+            .map(|value| {
+                let value = value.to_str().unwrap_or_default();
+                parse_grpc_timeout(value)
+                    .map(|timeout| now + timeout)
+                    .ok_or_else(|| DeadlineError::Malformed {
+                        header: GRPC_TIMEOUT,
+                        value: value.to_owned(),
+                    })
+            })
+            .transpose()?;
+        let earliest = match (absolute, relative) {
+            (Some(a), Some(r)) => Some(a.min(r)),
+            (a, r) => a.or(r),
+        };
+        Ok(Deadline(earliest))
+    }
+
+    /// `true` if the deadline has passed at `now`. `false` if there is no deadline.
+    pub fn is_exceeded(&self, now: Instant) -> bool {
+        self.0.map(|deadline| deadline <= now).unwrap_or(false)
+    }
+
+    /// Limits `timeout`, so waiting for it does not outlive the deadline.
+    pub fn limit(&self, timeout: Duration, now: Instant) -> Duration {
+        match self.0 {
+            Some(deadline) => timeout.min(deadline.saturating_duration_since(now)),
+            None => timeout,
+        }
+    }
+}
+
+impl FromRequest for Deadline {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(req
+            .extensions()
+            .get::<Deadline>()
+            .copied()
+            .unwrap_or_default()))
+    }
+}
+
+/// Parses gRPC style timeouts. E.g. `100m` for 100 milliseconds.
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = digits.parse().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(amount * 60 * 60),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    };
+    Some(timeout)
+}
+
+/// Middleware parsing the deadline headers of each request. The deadline is stored in the
+/// extensions of the request, so handlers can extract it. Requests those deadline has already
+/// passed on arrival are answered with `504 Gateway Timeout`.
+pub fn check<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> impl Future<Output = Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    let now = Instant::now();
+    let checked = Deadline::from_headers(req.headers(), now).and_then(|deadline| {
+        if deadline.is_exceeded(now) {
+            Err(DeadlineError::Exceeded)
+        } else {
+            Ok(deadline)
+        }
+    });
+    let response = match checked {
+        Ok(deadline) => {
+            req.extensions_mut().insert(deadline);
+            Ok(srv.call(req))
+        }
+        Err(error) => Err(req.error_response(error)),
+    };
+    async move {
+        match response {
+            Ok(future) => future.await,
+            Err(response) => Ok(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        application_cfg::{SemaphoreCfg, Semaphores},
+        semaphore_service::acquire,
+        state::State,
+    };
+    use actix_web::{get, http::HeaderValue, test, web::Data, App};
+
+    #[get("/")]
+    async fn index() -> &'static str {
+        "Hello"
+    }
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            actix_web::http::HeaderName::from_static(name),
+            HeaderValue::from_static(value),
+        );
+        headers
+    }
+
+    #[test]
+    fn absent_headers() {
+        let now = Instant::now();
+        let deadline = Deadline::from_headers(&HeaderMap::new(), now).unwrap();
+        assert_eq!(deadline, Deadline(None));
+        assert!(!deadline.is_exceeded(now));
+        let timeout = Duration::from_secs(10);
+        assert_eq!(deadline.limit(timeout, now), timeout);
+    }
+
+    #[test]
+    fn grpc_timeout_formats() {
+        assert_eq!(parse_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(parse_grpc_timeout("10S"), Some(Duration::from_secs(10)));
+        assert_eq!(parse_grpc_timeout("100m"), Some(Duration::from_millis(100)));
+        assert_eq!(parse_grpc_timeout("5u"), Some(Duration::from_micros(5)));
+        assert_eq!(parse_grpc_timeout("7n"), Some(Duration::from_nanos(7)));
+        // Unknown unit, missing unit, too many digits, signs
+        assert_eq!(parse_grpc_timeout("10s"), None);
+        assert_eq!(parse_grpc_timeout("10"), None);
+        assert_eq!(parse_grpc_timeout("123456789S"), None);
+        assert_eq!(parse_grpc_timeout("-1S"), None);
+    }
+
+    #[test]
+    fn malformed_headers() {
+        let now = Instant::now();
+        assert!(matches!(
+            Deadline::from_headers(&headers("grpc-timeout", "soon"), now),
+            Err(DeadlineError::Malformed { .. })
+        ));
+        assert!(matches!(
+            Deadline::from_headers(&headers("x-deadline", "tomorrow"), now),
+            Err(DeadlineError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn earlier_deadline_wins() {
+        let now = Instant::now();
+        let mut both = headers("x-deadline", "2999-01-01T00:00:00Z");
+        both.insert(
+            actix_web::http::HeaderName::from_static("grpc-timeout"),
+            HeaderValue::from_static("1S"),
+        );
+        let deadline = Deadline::from_headers(&both, now).unwrap();
+        assert_eq!(deadline, Deadline(Some(now + Duration::from_secs(1))));
+    }
+
+    #[test]
+    fn deadline_in_the_past_is_exceeded() {
+        let now = Instant::now();
+        let deadline =
+            Deadline::from_headers(&headers("x-deadline", "2000-01-01T00:00:00Z"), now).unwrap();
+        assert!(deadline.is_exceeded(now));
+    }
+
+    #[test]
+    fn limit_timeout_to_deadline() {
+        let now = Instant::now();
+        let deadline = Deadline::from_headers(&headers("grpc-timeout", "100m"), now).unwrap();
+        assert_eq!(
+            deadline.limit(Duration::from_secs(10), now),
+            Duration::from_millis(100)
+        );
+        assert_eq!(
+            deadline.limit(Duration::from_millis(10), now),
+            Duration::from_millis(10)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn exceeded_on_arrival() {
+        let mut app = test::init_service(App::new().wrap_fn(check).service(index)).await;
+        let req = test::TestRequest::with_uri("/")
+            .header("X-Deadline", "2000-01-01T00:00:00Z")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = test::read_body(resp).await;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            json!({"error": "Deadline of request exceeded before it could be processed."})
+        );
+
+        let req = test::TestRequest::with_uri("/")
+            .header("grpc-timeout", "soon")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::with_uri("/").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    /// A deadline shorter than `block_for` must stop the request from blocking.
+    #[actix_rt::test]
+    async fn deadline_shorter_than_block_for() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                level: 0,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let one_min = Duration::from_secs(60);
+        let blocker = state.new_peer(one_min);
+        state.acquire(blocker, "A", 1, None, None).await.unwrap();
+        let peer = state.new_peer(one_min);
+
+        let mut app =
+            test::init_service(App::new().wrap_fn(check).app_data(state).service(acquire)).await;
+        let req = test::TestRequest::put()
+            .uri(&format!("/peers/{}/A?block_for=1h", peer))
+            .header("grpc-timeout", "100m")
+            .set_json(&1)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    }
+}
@@ -0,0 +1,174 @@
+//! Content negotiation between JSON and MessagePack. Clients acquiring and releasing locks at a high
+//! frequency may prefer the more compact MessagePack encoding. Request bodies are decoded as
+//! MessagePack if the `Content-Type` is `application/msgpack`, responses are encoded as
+//! MessagePack if the `Accept` header asks for it. Everything else is JSON.
+
+use actix_web::{
+    dev::{HttpResponseBuilder, Payload},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        HeaderValue, StatusCode,
+    },
+    web::Bytes,
+    Error, FromRequest, HttpRequest, HttpResponse, Responder, ResponseError,
+};
+use futures::future::{ready, FutureExt, LocalBoxFuture, Ready};
+use serde::{de::DeserializeOwned, Serialize};
+use std::ops::Deref;
+use thiserror::Error;
+
+const MSGPACK: &str = "application/msgpack";
+
+/// Malformed request bodies
+#[derive(Debug, Error)]
+pub enum EncodingError {
+    #[error("Malformed JSON body: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Malformed MessagePack body: {0}")]
+    MsgPack(#[from] rmp_serde::decode::Error),
+}
+
+impl ResponseError for EncodingError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+/// `true` if the header names MessagePack as media type. `application/x-msgpack` is understood
+/// too, as many clients still use it.
+fn is_msgpack(header: Option<&HeaderValue>) -> bool {
+    header
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains(MSGPACK) || value.contains("application/x-msgpack"))
+}
+
+/// Request body, decoded from either JSON or MessagePack, depending on its `Content-Type`. Use it
+/// like `web::Json`.
+pub struct Body<T>(pub T);
+
+impl<T> Body<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Body<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> FromRequest for Body<T>
+where
+    T: DeserializeOwned + 'static,
+{
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let msgpack = is_msgpack(req.headers().get(CONTENT_TYPE));
+        let bytes = Bytes::from_request(req, payload);
+        async move {
+            let bytes = bytes.await?;
+            let body = if msgpack {
+                rmp_serde::from_read_ref(&bytes).map_err(EncodingError::from)?
+            } else {
+                serde_json::from_slice(&bytes).map_err(EncodingError::from)?
+            };
+            Ok(Body(body))
+        }
+        .boxed_local()
+    }
+}
+
+/// Serializes `value` into the body of the response, as MessagePack if the request accepts it and
+/// as JSON otherwise.
+pub fn encode(
+    req: &HttpRequest,
+    mut builder: HttpResponseBuilder,
+    value: &impl Serialize,
+) -> HttpResponse {
+    if is_msgpack(req.headers().get(ACCEPT)) {
+        match rmp_serde::to_vec_named(value) {
+            Ok(body) => builder.content_type(MSGPACK).body(body),
+            Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+        }
+    } else {
+        builder.json(value)
+    }
+}
+
+/// Response body, encoded as MessagePack or JSON depending on the `Accept` header of the request.
+/// Use it like `web::Json`.
+pub struct Encoded<T>(pub T);
+
+impl<T: Serialize> Responder for Encoded<T> {
+    type Error = Error;
+    type Future = Ready<Result<HttpResponse, Error>>;
+
+    fn respond_to(self, req: &HttpRequest) -> Self::Future {
+        ready(Ok(encode(req, HttpResponse::Ok(), &self.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use serde::Deserialize;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Lock {
+        semaphore: String,
+        count: i64,
+    }
+
+    #[actix_rt::test]
+    async fn decode_msgpack_body() {
+        let lock = Lock {
+            semaphore: "A".to_owned(),
+            count: 2,
+        };
+        let (req, mut payload) = test::TestRequest::post()
+            .header(CONTENT_TYPE, MSGPACK)
+            .set_payload(rmp_serde::to_vec_named(&lock).unwrap())
+            .to_http_parts();
+        let body = Body::<Lock>::from_request(&req, &mut payload)
+            .await
+            .unwrap();
+        assert_eq!(body.into_inner(), lock);
+
+        // Malformed bodies are a bad request
+        let (req, mut payload) = test::TestRequest::post()
+            .header(CONTENT_TYPE, MSGPACK)
+            .set_payload(vec![0xc1])
+            .to_http_parts();
+        let error = Body::<Lock>::from_request(&req, &mut payload)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.as_response_error().status_code(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_rt::test]
+    async fn encode_as_accepted() {
+        let req = test::TestRequest::default()
+            .header(ACCEPT, MSGPACK)
+            .to_http_request();
+        let resp = Encoded(42).respond_to(&req).await.unwrap();
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), MSGPACK);
+
+        let req = test::TestRequest::default().to_http_request();
+        let resp = Encoded(42).respond_to(&req).await.unwrap();
+        assert_eq!(
+            resp.headers().get(CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+    }
+}
@@ -1,7 +1,8 @@
+use std::{collections::HashMap, time::Duration};
 use thiserror::Error;
 
 /// Enumerates errors which can occur interacting with server state.
-#[derive(Debug, Error, Clone, Copy)]
+#[derive(Debug, Error, Clone)]
 pub enum ThrottleError {
     #[error("Unknown semaphore")]
     UnknownSemaphore,
@@ -22,6 +23,64 @@ pub enum ThrottleError {
     InvalidLockCount { count: i64 },
     #[error("Restore is not allowed to change existing peers.")]
     ChangeThroughRestore,
-    #[error("Shrinking the count of an existing lock is currently not implemented.")]
+    #[error("Acquiring a smaller count for an existing lock. Use PATCH on the lock to shrink it.")]
     ShrinkingLockCount,
+    #[error("Only acquired locks can grow.")]
+    NotAcquired,
+    #[error("Not enough capacity left to grow the lock.")]
+    InsufficientCapacity,
+    #[error("Lock can not be acquired right away. Remainder of the semaphore is {remainder:?}.")]
+    Unavailable { remainder: i64 },
+    #[error(
+        "Only peers holding exactly one acquired lock can be split. The amounts must sum up to its \
+        count."
+    )]
+    InvalidSplit,
+    #[error("Peer has been split into new peers or revoked.")]
+    Gone,
+    #[error("A semaphore with this name already exists.")]
+    SemaphoreExists,
+    #[error("Semaphore name {name:?} is invalid. Names must neither be empty, nor contain '/'.")]
+    InvalidSemaphoreName { name: String },
+    #[error("Semaphore is in use. Peers still hold or wait for locks to it.")]
+    SemaphoreInUse,
+    #[error("Metadata fields must not be longer than 256 bytes.")]
+    InvalidMetadata,
+    #[error("A peer with this id already exists and holds locks.")]
+    PeerExists,
+    #[error("Lock has been released by an operator.")]
+    ForceReleased,
+    #[error("expires_in must not exceed the configured maximum of {max:?}.")]
+    ExpiresInTooLong { max: Duration },
+    #[error("expires_in of {expires_in:?} reaches past the end of time.")]
+    InvalidExpiry { expires_in: Duration },
+    #[error(
+        "Lock asks for count {asked:?}, yet a single lock to this semaphore is limited to \
+        {max_amount:?}."
+    )]
+    AmountTooLarge { asked: i64, max_amount: i64 },
+    #[error(
+        "expires_in is required, since the semaphore does not configure a default_expires_in."
+    )]
+    MissingExpiresIn,
+    #[error("Too many requests. Retry after {retry_after:?}.")]
+    RateLimited { retry_after: Duration },
+    #[error("Server is shutting down. Repeat the request once it is back.")]
+    ShuttingDown,
+    #[error("Semaphore is frozen. No new locks are granted until it is unfrozen.")]
+    Frozen,
+    #[error(
+        "Semaphore is closed. Its full count is 0, so no new locks are granted until it is raised."
+    )]
+    Closed,
+    #[error("Peer revoked. Heartbeat claimed {claimed:?}, but acquired are {acquired:?}.")]
+    Revoked {
+        acquired: HashMap<String, i64>,
+        claimed: HashMap<String, i64>,
+    },
+    #[error(
+        "Locks to {semaphores:?} are lost, as restoring them would overbook these semaphores. The \
+        lock to the first one is pending, the others must be acquired again."
+    )]
+    LocksLost { semaphores: Vec<String> },
 }
@@ -0,0 +1,153 @@
+//! Clients watching a semaphore, e.g. dashboards, may subscribe to changes of its counts, rather
+//! than polling `/remainder`. Changes are streamed as Server-Sent Events.
+
+use crate::{error::ThrottleError, leases::Counts, poison, state::State, wakers::NOTIFY};
+use actix_web::{
+    get,
+    web::{Bytes, Data, Path},
+    Error, HttpResponse,
+};
+use futures::{
+    future::ready,
+    stream::{self, StreamExt},
+};
+use serde::Serialize;
+use std::{collections::HashMap, sync::Mutex};
+use tokio::sync::broadcast::{self, Receiver, RecvError, Sender};
+
+/// Number of events buffered for each subscriber. Subscribers lagging further behind miss events,
+/// which they can detect by a gap in the sequence numbers.
+const CAPACITY: usize = 256;
+
+/// The counts of a semaphore changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Event {
+    pub semaphore: String,
+    /// Increases by one with each event for the same semaphore.
+    pub sequence: u64,
+    /// Full count of the semaphore
+    pub max: i64,
+    /// Sum of all acquired locks
+    pub acquired: i64,
+    /// Sum of all pending locks
+    pub pending: i64,
+}
+
+/// Broadcasts changes of the counts of semaphores to all subscribers.
+pub struct Events {
+    sender: Sender<Event>,
+    /// Latest event published for each semaphore. Used to skip publishing counts which did not
+    /// change.
+    latest: Mutex<HashMap<String, Event>>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        let (sender, _receiver) = broadcast::channel(CAPACITY);
+        Events {
+            sender,
+            latest: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `false` if nobody listens. Computing the counts can be skipped then.
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() != 0
+    }
+
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Publishes the counts of the semaphore, unless they are the same as in the latest event.
+    ///
+    /// Returns the latest event for the semaphore.
+    pub fn publish(&self, semaphore: &str, max: i64, counts: &Counts) -> Event {
+        let mut latest = poison::lock(&self.latest, "the latest events");
+        let event = latest.entry(semaphore.to_owned()).or_insert_with(|| Event {
+            semaphore: semaphore.to_owned(),
+            sequence: 0,
+            max,
+            acquired: counts.acquired,
+            pending: counts.pending,
+        });
+        if event.sequence == 0
+            || (event.max, event.acquired, event.pending) != (max, counts.acquired, counts.pending)
+        {
+            event.sequence += 1;
+            event.max = max;
+            event.acquired = counts.acquired;
+            event.pending = counts.pending;
+            // An error only means there is nobody listening.
+            if self.sender.send(event.clone()).is_ok() {
+                NOTIFY.with_label_values(&["semaphore"]).inc();
+            }
+        }
+        event.clone()
+    }
+}
+
+/// Formats an event according to the Server-Sent Events specification.
+fn to_sse(event: &Event) -> Result<Bytes, Error> {
+    let data = serde_json::to_string(event).unwrap();
+    let text = format!("id: {}\nevent: counts\ndata: {}\n\n", event.sequence, data);
+    Ok(Bytes::from(text))
+}
+
+/// Stream of Server-Sent Events. Emits the current counts of the semaphore right away, and then
+/// an event whenever they change. Answers `404 Not Found` for unknown semaphores.
+#[get("/semaphores/{name}/events")]
+async fn events(path: Path<String>, state: Data<State>) -> HttpResponse {
+    let semaphore = path.into_inner();
+    let (current, receiver) = match state.watch(&semaphore) {
+        Ok(watch) => watch,
+        Err(ThrottleError::UnknownSemaphore) => {
+            return HttpResponse::NotFound().body("Unknown semaphore")
+        }
+        Err(error) => return HttpResponse::from_error(error.into()),
+    };
+    let changes = stream::unfold(receiver, move |mut receiver| {
+        let semaphore = semaphore.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) if event.semaphore == semaphore => return Some((event, receiver)),
+                    // Events of other semaphores
+                    Ok(_) => continue,
+                    // Clients notice the gap in sequence numbers.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+    let body = stream::once(ready(current))
+        .chain(changes)
+        .map(|event| to_sse(&event));
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .streaming(Box::pin(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_notifications_of_watchers() {
+        let notifications = || NOTIFY.with_label_values(&["semaphore"]).get();
+        let broadcast = Events::new();
+        let mut counts = Counts::default();
+
+        // Nobody listening, so nobody is notified.
+        let before = notifications();
+        broadcast.publish("A", 1, &counts);
+        assert_eq!(notifications(), before);
+
+        let _receiver = broadcast.subscribe();
+        counts.acquired = 1;
+        let before = notifications();
+        broadcast.publish("A", 1, &counts);
+        assert!(notifications() > before);
+    }
+}
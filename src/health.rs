@@ -1,22 +1,59 @@
-use actix_web::get;
+use crate::state::State;
+use actix_web::{get, web::Data, HttpResponse};
+use serde::Serialize;
 
-/// Health check used to see if server is running
+/// Body of the health check
+#[derive(Serialize)]
+struct Health {
+    /// `ok`, or the reason why the server is unhealthy
+    status: &'static str,
+    uptime_sec: f64,
+    /// Number of peers in the ledger
+    peers: usize,
+    /// Number of settings overridden at runtime. These are lost on restart.
+    runtime_overrides: usize,
+}
+
+/// Health check used by load balancers to see if the server is running. Fails with `503 Service
+/// Unavailable` if the litter collection did not run for several intervals.
 #[get("/health")]
-async fn health() -> &'static str {
-    "Ok"
+async fn health(state: Data<State>) -> HttpResponse {
+    let peers = state.num_peers();
+    let status = if state.litter_collection_status().is_stale(state.now()) {
+        "litter collection is stale"
+    } else {
+        "ok"
+    };
+    let health = Health {
+        status,
+        uptime_sec: state.uptime().as_secs_f64(),
+        peers,
+        runtime_overrides: state.num_runtime_overrides(),
+    };
+    if status == "ok" {
+        HttpResponse::Ok().json(health)
+    } else {
+        HttpResponse::ServiceUnavailable().json(health)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::application_cfg::Semaphores;
     use actix_web::{http::StatusCode, test, App};
 
     #[actix_rt::test]
     async fn health_route() {
-        let mut app = test::init_service(App::new().service(health)).await;
+        let state = Data::new(State::new(Semaphores::new()));
+        let mut app = test::init_service(App::new().app_data(state).service(health)).await;
         let req = test::TestRequest::with_uri("/health").to_request();
         let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
 
-        assert_eq!(resp.status(), StatusCode::OK)
+        let req = test::TestRequest::with_uri("/health").to_request();
+        let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body["status"], "ok");
+        assert_eq!(body["peers"], 0);
     }
 }
@@ -0,0 +1,83 @@
+//! Generation of ids for new peers. Random by default, so ids of different peers are unlikely to be
+//! confused with each other, even across restarts of the server. Tests may prefer predictable ids.
+
+use crate::{application_cfg::PeerIdKind, leases::PeerId};
+use rand::random;
+use uuid::Uuid;
+
+/// Source of ids for new peers.
+pub trait IdGenerator: Send {
+    /// A new peer id for which `is_taken` returns `false`.
+    fn generate(&mut self, is_taken: &dyn Fn(PeerId) -> bool) -> PeerId;
+}
+
+/// Id generator as chosen by the `peer_ids` option of the configuration.
+pub fn from_kind(kind: PeerIdKind) -> Box<dyn IdGenerator> {
+    match kind {
+        PeerIdKind::Int => Box::new(RandomIds { uuid: false }),
+        PeerIdKind::Uuid => Box::new(RandomIds { uuid: true }),
+        PeerIdKind::Sequential => Box::new(SequentialIds::default()),
+    }
+}
+
+/// Random integers, or random UUIDs. Integers by default.
+#[derive(Default)]
+pub struct RandomIds {
+    uuid: bool,
+}
+
+impl IdGenerator for RandomIds {
+    fn generate(&mut self, is_taken: &dyn Fn(PeerId) -> bool) -> PeerId {
+        // Collisions are unlikely, but clients may choose ids, too.
+        loop {
+            let candidate = if self.uuid {
+                PeerId::Uuid(Uuid::new_v4())
+            } else {
+                PeerId::Int(random())
+            };
+            if !is_taken(candidate) {
+                return candidate;
+            }
+        }
+    }
+}
+
+/// Integers counting up from `1`. Meant for tests, which want to predict ids. Ids already chosen by
+/// clients are skipped.
+#[derive(Default)]
+pub struct SequentialIds {
+    last: u64,
+}
+
+impl IdGenerator for SequentialIds {
+    fn generate(&mut self, is_taken: &dyn Fn(PeerId) -> bool) -> PeerId {
+        loop {
+            self.last += 1;
+            let candidate = PeerId::Int(self.last);
+            if !is_taken(candidate) {
+                return candidate;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequential_ids() {
+        let mut ids = SequentialIds::default();
+        let is_taken = |_| false;
+        assert_eq!(ids.generate(&is_taken), PeerId::Int(1));
+        assert_eq!(ids.generate(&is_taken), PeerId::Int(2));
+    }
+
+    #[test]
+    fn sequential_ids_skip_taken() {
+        let mut ids = SequentialIds::default();
+        let is_taken = |id| id == PeerId::Int(1) || id == PeerId::Int(2);
+        assert_eq!(ids.generate(&is_taken), PeerId::Int(3));
+        assert_eq!(ids.generate(&is_taken), PeerId::Int(4));
+    }
+}
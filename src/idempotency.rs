@@ -0,0 +1,71 @@
+//! Clients may send an `Idempotency-Key` header along with requests creating peers. Repeating the
+//! request with the same key, e.g. after a timeout, yields the peer created by the first one,
+//! rather than a new one.
+
+use crate::leases::PeerId;
+use actix_web::HttpRequest;
+use std::{collections::HashMap, time::Instant};
+
+/// Name of the header holding the idempotency key
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Reads the idempotency key from the headers of the request, if any.
+pub fn key(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Remembers which peer has been created for which idempotency key.
+#[derive(Debug, Default)]
+pub struct IdempotencyKeys {
+    /// Peer created for each key and the instant after which the key is forgotten.
+    entries: HashMap<String, (PeerId, Instant)>,
+}
+
+impl IdempotencyKeys {
+    /// Peer created for `key`, unless it has been forgotten by `now`.
+    pub fn get(&self, key: &str, now: Instant) -> Option<PeerId> {
+        self.entries
+            .get(key)
+            .filter(|(_, valid_until)| *valid_until >= now)
+            .map(|&(peer_id, _)| peer_id)
+    }
+
+    /// Remembers the peer created for `key` until `valid_until`.
+    pub fn insert(&mut self, key: &str, peer_id: PeerId, valid_until: Instant) {
+        self.entries.insert(key.to_owned(), (peer_id, valid_until));
+    }
+
+    /// Forgets `key`, e.g. because the peer created for it has been removed again.
+    pub fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    /// Forgets all keys which are no longer valid at `now`.
+    pub fn remove_expired(&mut self, now: Instant) {
+        self.entries
+            .retain(|_, (_, valid_until)| *valid_until >= now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn forget_expired_keys() {
+        let now = Instant::now();
+        let mut keys = IdempotencyKeys::default();
+        keys.insert("a", PeerId::Int(1), now + Duration::from_secs(1));
+        keys.insert("b", PeerId::Int(2), now + Duration::from_secs(3));
+        assert_eq!(keys.get("a", now), Some(PeerId::Int(1)));
+
+        let later = now + Duration::from_secs(2);
+        assert_eq!(keys.get("a", later), None);
+        keys.remove_expired(later);
+        assert_eq!(keys.entries.len(), 1);
+        assert_eq!(keys.get("b", later), Some(PeerId::Int(2)));
+    }
+}
@@ -1,37 +1,111 @@
-use crate::error::ThrottleError;
-use rand::random;
+use crate::{
+    error::ThrottleError,
+    id_generator::{IdGenerator, RandomIds},
+    lock_metrics::{
+        ACQUIRES, ACQUISITION_WAIT, EXPIRATIONS, EXPIRED_PENDING, PROMOTIONS, REVENANTS,
+    },
+};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use std::{
-    cmp::Ordering,
-    collections::HashMap,
+    cmp::{Ordering, Reverse},
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    ops::Bound,
+    str::FromStr,
+    sync::Arc,
     time::{Duration, Instant},
 };
+use uuid::Uuid;
+
+/// Maximum length in bytes of each field of `Metadata`.
+pub const MAX_METADATA_LEN: usize = 256;
+
+/// Optional information about a peer supplied by the client. Helps operators to find out which
+/// host or job holds a lock.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Free form text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl Metadata {
+    /// Fields present, with their names.
+    fn fields(&self) -> impl Iterator<Item = (&'static str, &str)> + '_ {
+        vec![
+            ("client", &self.client),
+            ("hostname", &self.hostname),
+            ("note", &self.note),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+    }
+
+    /// Rejects fields longer than `MAX_METADATA_LEN` bytes.
+    pub fn validate(&self) -> Result<(), ThrottleError> {
+        if self
+            .fields()
+            .any(|(_name, value)| value.len() > MAX_METADATA_LEN)
+        {
+            Err(ThrottleError::InvalidMetadata)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields: Vec<_> = self
+            .fields()
+            .map(|(name, value)| format!("{}={:?}", name, value))
+            .collect();
+        write!(f, "{}", fields.join(" "))
+    }
+}
+
+/// Peer id followed by its metadata, if any. Used in log messages.
+pub fn describe(peer_id: PeerId, metadata: Option<&Metadata>) -> String {
+    match metadata {
+        Some(metadata) => format!("{} ({})", peer_id, metadata),
+        None => peer_id.to_string(),
+    }
+}
 
 /// Peers hold locks to semaphores, while holding this locks they have access to the semaphore.
 struct Lock {
-    /// Name of the resource the semaphore protects
-    semaphore: String,
+    /// Name of the resource the semaphore protects. Interned by `Leases`.
+    semaphore: Arc<str>,
     /// The semapohre count is decreased by `count` if the lease is active.
     count: i64,
-    /// Instant of lock creation. Used to implement fairness of semaphores.
+    /// Instant of lock creation. Used to tell how long a lock has been pending.
     since: Instant,
+    /// Position of the lock in the order of arrival. Unlike `since` no two locks share the same
+    /// value, even on platforms with a coarse clock. Used to implement fairness of semaphores.
+    arrival: u64,
 }
 
 impl Lock {
     /// Semaphore count of this peer regardless of wether the lock is acquired or pending.
     fn count(&self, semaphore: &str) -> i64 {
-        if self.semaphore == semaphore {
+        if &*self.semaphore == semaphore {
             self.count
         } else {
             0
         }
     }
 
-    /// Increments the suitable entries in `counts`.
-    fn update_counts_pending(&self, counts: &mut HashMap<String, Counts>) {
-        let mut counts = counts
-            .get_mut(&self.semaphore)
-            .expect("All available Semaphores must be prefilled in counts.");
-        counts.pending += self.count;
+    /// Adds this pending lock to the counts of its semaphore.
+    fn add_pending_to(&self, counts: &mut Counts) {
+        // Many large pending locks could overflow the sum, so saturate.
+        counts.pending = counts.pending.saturating_add(self.count);
         // If there already has been a minimum, compare. Otherwise just use `self.since`.
         counts.longest_pending_since = counts
             .longest_pending_since
@@ -41,12 +115,21 @@ impl Lock {
 
     /// Creation timestamp of the lock
     fn since(&self, semaphore: &str) -> Option<Instant> {
-        if self.semaphore == semaphore {
+        if &*self.semaphore == semaphore {
             Some(self.since)
         } else {
             None
         }
     }
+
+    /// Position in the order of arrival, if the lock is to `semaphore`.
+    fn arrival(&self, semaphore: &str) -> Option<u64> {
+        if &*self.semaphore == semaphore {
+            Some(self.arrival)
+        } else {
+            None
+        }
+    }
 }
 
 /// Accumulated counts for an indiviual Semaphore
@@ -56,6 +139,13 @@ pub struct Counts {
     pub acquired: i64,
     /// Accumulated count of pending leases.
     pub pending: i64,
+    /// Number of peers holding or waiting for a lock to the semaphore.
+    pub peers: i64,
+    /// Number of peers holding a lock to the semaphore.
+    pub active_peers: i64,
+    /// Number of peers waiting for a lock to the semaphore. A peer waiting to grow its lock counts
+    /// as both active and pending.
+    pub pending_peers: i64,
     /// The earliest pending lock
     longest_pending_since: Option<Instant>,
 }
@@ -69,24 +159,139 @@ impl Counts {
     }
 }
 
+/// Wether all the locks of a peer are acquired, or if it is still waiting for one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerState {
+    /// All locks of the peer are acquired
+    Acquired,
+    /// The peer has a pending lock
+    Pending,
+}
+
+/// Snapshot of a peer's state
+#[derive(Debug, Clone, Copy)]
+pub struct PeerStatus {
+    pub state: PeerState,
+    /// Time left until the peer expires
+    pub expires_in: Duration,
+}
+
+/// Snapshot of a single lock, as listed by `Leases::list`.
+#[derive(Debug, Clone)]
+pub struct LockInfo {
+    pub peer_id: PeerId,
+    pub semaphore: String,
+    pub count: i64,
+    /// `Acquired` or `Pending`
+    pub state: PeerState,
+    /// Time left until the peer expires
+    pub expires_in: Duration,
+    /// `false` if the peer never expires, because it holds a lock to a semaphore which does not.
+    pub expires: bool,
+    pub metadata: Option<Metadata>,
+    /// Pending locks of peers with higher priority are acquired first.
+    pub priority: u8,
+}
+
+/// Restricts the locks listed by `Leases::list`. `None` matches everything.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LockFilter<'a> {
+    pub semaphore: Option<&'a str>,
+    pub state: Option<PeerState>,
+}
+
+impl LockFilter<'_> {
+    fn matches(&self, semaphore: &str, state: PeerState) -> bool {
+        self.semaphore.is_none_or(|s| s == semaphore) && self.state.is_none_or(|s| s == state)
+    }
+}
+
+/// Outcome of releasing a single lock of a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Released {
+    /// An acquired lock has been released
+    Acquired,
+    /// A pending lock has been released
+    Pending,
+    /// The peer held no lock to the semaphore
+    NotHeld,
+}
+
+/// Snapshot of a single peer
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub acquired: HashMap<String, i64>,
+    /// Semaphore and count of the pending lock, if any.
+    pub pending: Option<(String, i64)>,
+    pub status: PeerStatus,
+    pub metadata: Option<Metadata>,
+    /// Position of the pending lock among all pending locks to the same semaphore, in the order
+    /// they are resolved. Starts at `1`.
+    pub position: Option<usize>,
+}
+
+/// Sum of the acquired lock counts for each semaphore. Maintained incrementally with every change
+/// to the ledger, so reading the count of a semaphore does not need to scan all peers.
+#[derive(Default)]
+struct ActiveCounts(HashMap<Arc<str>, i64>);
+
+impl ActiveCounts {
+    fn get(&self, semaphore: &str) -> i64 {
+        self.0.get(semaphore).copied().unwrap_or(0)
+    }
+
+    /// Adds `delta` to the count of `semaphore`. Semaphores without acquired locks have no entry.
+    fn add(&mut self, semaphore: &Arc<str>, delta: i64) {
+        if delta == 0 {
+            return;
+        }
+        let count = self.0.entry(semaphore.clone()).or_insert(0);
+        *count += delta;
+        if *count == 0 {
+            self.0.remove(&**semaphore);
+        }
+    }
+
+    /// Counts all locks in `acquired`, e.g. of a peer inserted into the ledger.
+    fn add_all(&mut self, acquired: &HashMap<Arc<str>, i64>) {
+        for (semaphore, &count) in acquired {
+            self.add(semaphore, count);
+        }
+    }
+
+    /// Stops counting the locks in `acquired`, e.g. of a peer removed from the ledger.
+    fn remove_all(&mut self, acquired: &HashMap<Arc<str>, i64>) {
+        for (semaphore, &count) in acquired {
+            self.add(semaphore, -count);
+        }
+    }
+}
+
 /// A peer holds leases to semaphores, which may either be active or pending and share a common
 /// expiration time.
 struct Peer {
-    /// A peer may acquire locks to multiple semaphores.
-    acquired: HashMap<String, i64>,
+    /// A peer may acquire locks to multiple semaphores. Names are interned by `Leases`.
+    acquired: HashMap<Arc<str>, i64>,
     /// Only one lock can be pending for any given peer.
     pending: Option<Lock>,
     /// Instant upon which the lease may be removed by litter collection.
     valid_until: Instant,
+    /// Information supplied by the client about itself.
+    metadata: Option<Metadata>,
+    /// If capacity frees up, pending locks of peers with higher priority are acquired first.
+    priority: u8,
 }
 
 impl Peer {
     /// Creates a new Peer instance with no locks associated.
-    fn new(valid_until: Instant, acquired: HashMap<String, i64>) -> Self {
+    fn new(valid_until: Instant, acquired: HashMap<Arc<str>, i64>) -> Self {
         Self {
             acquired,
             pending: None,
             valid_until,
+            metadata: None,
+            priority: 0,
         }
     }
 
@@ -94,37 +299,59 @@ impl Peer {
         self.acquired.get(semaphore).copied().unwrap_or(0)
     }
 
-    /// Semaphore count of this peer regardless of wether the lock is acquired or pending.
+    /// Semaphore count of this peer regardless of wether the lock is acquired or pending. A pending
+    /// lock to a semaphore which is also acquired, is a request to grow the acquired lock. The
+    /// demand is the sum of both.
     fn count_demand(&self, semaphore: &str) -> i64 {
-        // Look in pending
         let pending = self
             .pending
             .as_ref()
             .map(|l| l.count(semaphore))
             .unwrap_or(0);
-        if pending != 0 {
-            pending
-        } else {
-            // Not found in pending, look in acquired
-            self.count_acquired(semaphore)
-        }
+        pending + self.count_acquired(semaphore)
+    }
+
+    /// Names of all semaphores the peer holds or waits for a lock to. Each one only once.
+    fn semaphores(&self) -> impl Iterator<Item = &str> {
+        let pending = self
+            .pending
+            .as_ref()
+            .map(|lock| &*lock.semaphore)
+            .filter(move |semaphore| !self.acquired.contains_key(*semaphore));
+        self.acquired
+            .keys()
+            .map(|semaphore| &**semaphore)
+            .chain(pending)
     }
 
     /// Increments the suitable entries in `counts`.
     fn update_counts(&self, counts: &mut HashMap<String, Counts>) {
-        for (semaphore, count) in &self.acquired {
-            counts
+        for semaphore in self.semaphores() {
+            let counts = counts
                 .get_mut(semaphore)
-                .expect("Only known semaphores must be managed by peers.")
-                .acquired += count;
+                .expect("Only known semaphores must be managed by peers.");
+            self.add_counts_to(semaphore, counts);
         }
-        if let Some(lock) = &self.pending {
-            lock.update_counts_pending(counts);
+    }
+
+    /// Adds the locks of this peer to `semaphore` to `counts`.
+    fn add_counts_to(&self, semaphore: &str, counts: &mut Counts) {
+        let pending = self.pending.as_ref().filter(|l| &*l.semaphore == semaphore);
+        if let Some(count) = self.acquired.get(semaphore) {
+            counts.acquired = counts.acquired.saturating_add(*count);
+            counts.active_peers += 1;
+        }
+        if let Some(lock) = pending {
+            lock.add_pending_to(counts);
+            counts.pending_peers += 1;
+        }
+        if self.acquired.contains_key(semaphore) || pending.is_some() {
+            counts.peers += 1;
         }
     }
 
     /// Empties the peer. Returns name of associated semaphores, if available.
-    fn clear(&mut self) -> Vec<String> {
+    fn clear(&mut self) -> Vec<Arc<str>> {
         self.pending
             .take()
             .map(|l| l.semaphore)
@@ -133,20 +360,9 @@ impl Peer {
             .collect()
     }
 
-    // Check wether the peer is expired. If so its `lock` will be released.
-    //
-    // # Return
-    //
-    // Outer `Option` indicates wether the peer is still valid. If so it is `None`. If not the inner
-    // Option holds the name of the semaphores if available.
-    fn remove_expired(&mut self, now: Instant) -> Option<Vec<String>> {
-        if self.valid_until < now {
-            // Peer is expired
-            Some(self.clear())
-        } else {
-            // Peer is still valid
-            None
-        }
+    /// `false` if any lock of the peer is held to a semaphore, which does not expire.
+    fn expires(&self, expires: impl Fn(&str) -> bool) -> bool {
+        self.semaphores().all(expires)
     }
 
     /// True if the locks associated with this peer are acquired
@@ -154,12 +370,24 @@ impl Peer {
         self.pending.is_none()
     }
 
-    /// Adds a lock for the semaphore to the peer
+    /// Acquired, if all locks associated with this peer are acquired, pending otherwise.
+    fn state(&self) -> PeerState {
+        if self.all_acquired() {
+            PeerState::Acquired
+        } else {
+            PeerState::Pending
+        }
+    }
+
+    /// Adds a lock for the semaphore to the peer. `arrival` orders pending locks, `now` tells since
+    /// when they are pending.
     fn add_lock(
         &mut self,
-        semaphore: String,
+        semaphore: Arc<str>,
         count: i64,
         acquired: bool,
+        arrival: u64,
+        now: Instant,
     ) -> Result<(), ThrottleError> {
         if self.pending.is_some() {
             return Err(ThrottleError::AlreadyPending);
@@ -168,11 +396,11 @@ impl Peer {
             let prev = self.acquired.insert(semaphore, count);
             debug_assert!(prev.is_none());
         } else {
-            let since = Instant::now();
             self.pending = Some(Lock {
                 semaphore,
                 count,
-                since,
+                since: now,
+                arrival,
             });
         }
         Ok(())
@@ -183,6 +411,20 @@ impl Peer {
         self.pending.as_ref().and_then(|lock| lock.since(semaphore))
     }
 
+    /// Position of the pending lock to the semaphore in the order of arrival.
+    fn pending_arrival(&self, semaphore: &str) -> Option<u64> {
+        self.pending
+            .as_ref()
+            .and_then(|lock| lock.arrival(semaphore))
+    }
+
+    /// Orders pending locks to the same semaphore. The smallest key is resolved first. `None` if no
+    /// lock to the semaphore is pending.
+    fn queue_key(&self, semaphore: &str) -> Option<(Reverse<u8>, u64)> {
+        let arrival = self.pending_arrival(semaphore)?;
+        Some((Reverse(self.priority), arrival))
+    }
+
     /// If the count of remainder is sufficient the pending lock is going to be promoted to
     /// acquired. The remainder is decremented independent of wether the lease could be acquired or
     /// not.
@@ -191,14 +433,14 @@ impl Peer {
     ///
     /// `true` if the lock has been acquired.
     fn try_resolve(&mut self, remainder: &mut i64) -> bool {
-        *remainder -= self.pending.as_ref().unwrap().count;
+        *remainder = remainder.saturating_sub(self.pending.as_ref().unwrap().count);
         if *remainder >= 0 {
             let lock = self
                 .pending
                 .take()
                 .expect("Peer without pending lock must not be resolved.");
-            let prev = self.acquired.insert(lock.semaphore, lock.count);
-            debug_assert!(prev.is_none());
+            // Pending locks to an already acquired semaphore grow the acquired lock.
+            *self.acquired.entry(lock.semaphore).or_insert(0) += lock.count;
             true
         } else {
             false
@@ -206,23 +448,75 @@ impl Peer {
     }
 
     /// Relase a pending or acquired lock, to a semaphore.
+    fn release_lock(&mut self, semaphore: &str) -> Released {
+        if self.acquired.remove(semaphore).is_some() {
+            // Drop any pending request to grow the released lock.
+            if self.pending_since(semaphore).is_some() {
+                self.pending = None;
+            }
+            Released::Acquired
+        } else if self.pending_since(semaphore).is_some() {
+            self.pending = None;
+            Released::Pending
+        } else {
+            Released::NotHeld
+        }
+    }
+
+    /// Grows the count of an acquired lock to `amount`. If `acquired` is `false` the additional
+    /// count is going to be pending since `now`, ordered by `arrival`.
+    fn grow_lock(
+        &mut self,
+        semaphore: &str,
+        amount: i64,
+        acquired: bool,
+        arrival: u64,
+        now: Instant,
+    ) {
+        let current = self.count_acquired(semaphore);
+        debug_assert!(self.pending.is_none() && current != 0 && current < amount);
+        if acquired {
+            *self.acquired.get_mut(semaphore).unwrap() = amount;
+        } else {
+            // Reuse the name of the acquired lock, rather than allocating a new one.
+            let (semaphore, _) = self.acquired.get_key_value(semaphore).unwrap();
+            self.pending = Some(Lock {
+                semaphore: semaphore.clone(),
+                count: amount - current,
+                since: now,
+                arrival,
+            });
+        }
+    }
+
+    /// Shrinks the demand for `semaphore` to `amount`. A pending request to grow the lock shrinks
+    /// first. Only once it is gone, the acquired count is reduced.
     ///
-    /// # Return
-    ///
-    /// `true` if a pending lock has been released.
-    fn release_lock(&mut self, semaphore: &str) -> bool {
-        let prev = self.acquired.remove(semaphore);
-        if prev.is_none() {
-            self.pending.take().is_some()
+    /// Returns the change of the acquired count, i.e. zero or a negative number.
+    fn shrink_lock(&mut self, semaphore: &str, amount: i64) -> i64 {
+        let current = self.count_acquired(semaphore);
+        debug_assert!(0 < amount && amount < self.count_demand(semaphore));
+        if amount > current {
+            self.pending.as_mut().unwrap().count = amount - current;
+            0
         } else {
-            false
+            // Drop any pending request to grow the lock.
+            if self.pending_since(semaphore).is_some() {
+                self.pending = None;
+            }
+            *self.acquired.get_mut(semaphore).unwrap() = amount;
+            amount - current
         }
     }
 
     /// Assert that restoring this peer to the specfied locks is vaild. I.e the peer must not have a
     /// pending lock and have the same locks acquired. So apart from the timestamp nothing changes.
     fn assert_restore_valid(&self, acquired: &HashMap<String, i64>) -> Result<(), ThrottleError> {
-        if self.pending.is_none() && acquired == &self.acquired {
+        let same_locks = acquired.len() == self.acquired.len()
+            && acquired
+                .iter()
+                .all(|(semaphore, count)| self.acquired.get(semaphore.as_str()) == Some(count));
+        if self.pending.is_none() && same_locks {
             Ok(())
         } else {
             Err(ThrottleError::ChangeThroughRestore)
@@ -238,8 +532,170 @@ impl Peer {
     }
 }
 
-/// Every peer has a unique PeerId associated with it for bookkeeping.
-pub type PeerId = u64;
+/// Locks of a peer at the time it has been removed from the ledger
+#[derive(Debug, Clone, Default)]
+pub struct RemovedPeer {
+    pub acquired: HashMap<Arc<str>, i64>,
+    /// Semaphore and count of the lock the peer has been waiting for, if any.
+    pub pending: Option<(Arc<str>, i64)>,
+}
+
+impl RemovedPeer {
+    /// Names of all semaphores the peer held or waited for a lock to. Pending locks to these may be
+    /// resolvable now.
+    pub fn semaphores(&self) -> impl Iterator<Item = &str> {
+        self.acquired
+            .keys()
+            .chain(self.pending.as_ref().map(|(semaphore, _count)| semaphore))
+            .map(|semaphore| &**semaphore)
+    }
+}
+
+/// Outcome of restoring a peer
+#[derive(Debug, Default)]
+pub struct Restored {
+    /// Semaphores with more acquired than their full count, due to the restored locks.
+    pub overbooked: Vec<String>,
+    /// Semaphores configured with `strict_restore`, to which the locks could not be restored, in
+    /// alphabetical order. The lock to the first one is pending.
+    pub lost: Vec<String>,
+}
+
+/// Full count and lock level of the semaphore a lock is requested for, as configured.
+#[derive(Debug, Clone, Copy)]
+pub struct SemaphoreLimits {
+    pub max: i64,
+    pub level: i32,
+}
+
+/// A peer to restore, as described by the revenant itself.
+pub struct Revenant<'a> {
+    pub peer_id: PeerId,
+    /// Locks the peer believes to hold, by semaphore.
+    pub acquired: &'a HashMap<String, i64>,
+    pub valid_until: Instant,
+    pub metadata: Option<Metadata>,
+    pub priority: u8,
+}
+
+/// `Gone` if `peer_id` has been retired, `UnknownPeer` otherwise.
+fn unknown(retired: &HashMap<PeerId, Instant>, peer_id: PeerId) -> ThrottleError {
+    if retired.contains_key(&peer_id) {
+        ThrottleError::Gone
+    } else {
+        ThrottleError::UnknownPeer
+    }
+}
+
+/// Appends the acquired and the pending locks of `peer` matching `filter` to `locks`.
+fn push_locks(
+    locks: &mut Vec<LockInfo>,
+    peer_id: PeerId,
+    peer: &Peer,
+    now: Instant,
+    expires: bool,
+    filter: LockFilter,
+) {
+    let expires_in = peer.valid_until.saturating_duration_since(now);
+    let lock_info = |semaphore: &Arc<str>, count, state| LockInfo {
+        peer_id,
+        semaphore: semaphore.to_string(),
+        count,
+        state,
+        expires_in,
+        expires,
+        metadata: peer.metadata.clone(),
+        priority: peer.priority,
+    };
+    for (semaphore, &count) in &peer.acquired {
+        if filter.matches(semaphore, PeerState::Acquired) {
+            locks.push(lock_info(semaphore, count, PeerState::Acquired));
+        }
+    }
+    if let Some(lock) = &peer.pending {
+        if filter.matches(&lock.semaphore, PeerState::Pending) {
+            locks.push(lock_info(&lock.semaphore, lock.count, PeerState::Pending));
+        }
+    }
+}
+
+/// Every peer has a unique PeerId associated with it for bookkeeping. Either an unsigned 64 bit
+/// integer, or a UUID, depending on `peer_ids` in the configuration. Integers are serialized as
+/// numbers, UUIDs as strings. Clients may choose ids of either kind, regardless of the
+/// configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PeerId {
+    Int(u64),
+    Uuid(Uuid),
+}
+
+impl PeerId {
+    /// Smallest possible peer id. Useful as a bound for ranges.
+    const MIN: PeerId = PeerId::Int(0);
+}
+
+impl From<u64> for PeerId {
+    fn from(id: u64) -> Self {
+        PeerId::Int(id)
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerId::Int(id) => write!(f, "{}", id),
+            PeerId::Uuid(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+impl FromStr for PeerId {
+    type Err = uuid::Error;
+
+    /// Parses both integers, e.g. `42`, and UUIDs, e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(id) => Ok(PeerId::Int(id)),
+            Err(_) => Ok(PeerId::Uuid(s.parse()?)),
+        }
+    }
+}
+
+impl Serialize for PeerId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            PeerId::Int(id) => serializer.serialize_u64(*id),
+            PeerId::Uuid(id) => serializer.collect_str(id),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PeerId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PeerIdVisitor;
+
+        impl<'de> Visitor<'de> for PeerIdVisitor {
+            type Value = PeerId;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("an unsigned 64 bit integer or a UUID")
+            }
+
+            fn visit_u64<E: de::Error>(self, id: u64) -> Result<PeerId, E> {
+                Ok(PeerId::Int(id))
+            }
+
+            fn visit_str<E: de::Error>(self, id: &str) -> Result<PeerId, E> {
+                id.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(PeerIdVisitor)
+    }
+}
+
+/// Peers removed by the litter collection, with the metadata they have been created with.
+type ExpiredPeers = Vec<(PeerId, Option<Metadata>)>;
 
 /// Does the bookeeping for all the peers, which 'lease' Semaphores by acquiring locks to them. This
 /// is a purely a bookeeping struct and does not provide any synchronization mechanisms. Rather they
@@ -247,12 +703,42 @@ pub type PeerId = u64;
 pub struct Leases {
     //  Peers holding pending or acquired leases to the semaphores
     ledger: HashMap<PeerId, Peer>,
+    /// Ids of peers which have been split into new ones or revoked, and the instant their lifetime
+    /// would have ended. Requests to these are answered with `Gone`, rather than `UnknownPeer`, so
+    /// clients do not try to restore them.
+    retired: HashMap<PeerId, Instant>,
+    /// Semaphores which are frozen. No new locks are granted for these, and pending locks are not
+    /// resolved until they are unfrozen.
+    frozen: HashSet<String>,
+    /// Every semaphore name any peer ever locked. Peers share these, rather than each owning a copy
+    /// of the name.
+    names: HashSet<Arc<str>>,
+    /// Number of pending locks created so far. Orders pending locks by arrival.
+    arrivals: u64,
+    /// Sum of acquired locks for each semaphore. Must always equal the sum over the ledger.
+    active: ActiveCounts,
+    /// `valid_until` and id of every peer in the ledger, except the overdue ones. Ordered by
+    /// expiration, so expired peers are found without scanning the whole ledger.
+    expiries: BTreeSet<(Instant, PeerId)>,
+    /// Peers past their `valid_until`, which are kept alive by locks to semaphores which do not
+    /// expire. Checked again on every call to `remove_expired`.
+    overdue: HashSet<PeerId>,
+    /// Source of ids for new peers
+    ids: Box<dyn IdGenerator>,
 }
 
 impl Leases {
     pub fn new() -> Self {
         Leases {
             ledger: HashMap::new(),
+            retired: HashMap::new(),
+            frozen: HashSet::new(),
+            names: HashSet::new(),
+            arrivals: 0,
+            active: ActiveCounts::default(),
+            expiries: BTreeSet::new(),
+            overdue: HashSet::new(),
+            ids: Box::new(RandomIds::default()),
         }
     }
 
@@ -262,26 +748,73 @@ impl Leases {
     ///
     /// The id identifying the new peer. Used as a key in this datastructure to access and
     /// manipulate its state.
-    pub fn new_peer(&mut self, valid_until: Instant) -> PeerId {
+    pub fn new_peer(
+        &mut self,
+        valid_until: Instant,
+        metadata: Option<Metadata>,
+        priority: u8,
+    ) -> PeerId {
         let id = self.new_unique_peer_id();
         let acquired = HashMap::new();
-        let old = self.ledger.insert(id, Peer::new(valid_until, acquired));
-        // There should not be any preexisting entry with this id
-        debug_assert!(old.is_none());
+        let mut peer = Peer::new(valid_until, acquired);
+        peer.metadata = metadata;
+        peer.priority = priority;
+        self.insert_peer(id, peer);
         id
     }
 
+    /// Creates a new empty peer with an id chosen by the client. Creating a peer with the same id
+    /// again only prolongs its expiration and updates the metadata, if provided, so clients can
+    /// safely retry requests.
+    ///
+    /// # Return
+    ///
+    /// `PeerExists` if a peer with this id already holds or waits for locks. `Gone` if the id
+    /// belonged to a peer which has been split.
+    pub fn new_peer_with_id(
+        &mut self,
+        peer_id: PeerId,
+        valid_until: Instant,
+        metadata: Option<Metadata>,
+        priority: u8,
+    ) -> Result<(), ThrottleError> {
+        if self.retired.contains_key(&peer_id) {
+            return Err(ThrottleError::Gone);
+        }
+        match self.ledger.get_mut(&peer_id) {
+            Some(peer) => {
+                if !peer.acquired.is_empty() || peer.pending.is_some() {
+                    return Err(ThrottleError::PeerExists);
+                }
+                peer.priority = priority;
+                if metadata.is_some() {
+                    peer.metadata = metadata;
+                }
+                self.update_valid_until(peer_id, valid_until)?;
+            }
+            None => {
+                let mut peer = Peer::new(valid_until, HashMap::new());
+                peer.metadata = metadata;
+                peer.priority = priority;
+                self.insert_peer(peer_id, peer);
+            }
+        }
+        Ok(())
+    }
+
     /// Acquires a lock for a peer. If the count of the semaphore is high enough, the lease is going
     /// to be acquired, otherwise it remains pending. This method does not block.
     ///
     /// # Parameters
     ///
-    /// * `peer_id`: Should this be None, a new peer_id is going to be generated,
-    ///              otherwise the provided one is used.
+    /// * `peer_id`: Peer requesting the lock. Must exist.
     /// * `semaphore`: Name of the semaphore to which a lock is requested.
     /// * `amount`: Requested amount of the semaphore.
-    /// * `max`: A check is performed and the lock is only going to be acquired, if the total demand
-    /// for the requested semaphore would not exceed the `max`.
+    /// * `limits`: The lock is only going to be acquired, if the total demand for the requested
+    ///   semaphore would not exceed `limits.max`. `limits.level` is checked against the lock
+    ///   hierarchy.
+    /// * `now`: Pending locks are waiting since this instant.
+    /// * `lock_levels`: Level of each semaphore the peer already holds locks to.
     ///
     /// # Return
     ///
@@ -291,10 +824,11 @@ impl Leases {
         peer_id: PeerId,
         semaphore: &str,
         amount: i64,
-        max: i64,
-        level: i32,
+        limits: SemaphoreLimits,
+        now: Instant,
         lock_levels: impl Fn(&str) -> i32,
     ) -> Result<bool, ThrottleError> {
+        let SemaphoreLimits { max, level } = limits;
         if amount < 1 {
             return Err(ThrottleError::InvalidLockCount { count: amount });
         }
@@ -308,7 +842,6 @@ impl Leases {
         let previous_demand = peer.count_demand(semaphore);
         if previous_demand != 0 {
             match amount.cmp(&previous_demand) {
-                // TODO: Reduce lock count and notify if not pending.
                 Ordering::Less => return Err(ThrottleError::ShrinkingLockCount),
                 Ordering::Equal => return Ok(peer.all_acquired()),
                 Ordering::Greater => {
@@ -320,6 +853,14 @@ impl Leases {
             }
         }
 
+        if self.frozen.contains(semaphore) {
+            return Err(ThrottleError::Frozen);
+        }
+        // A full count of zero closes the semaphore. Pending locks keep waiting for it to reopen.
+        if max == 0 {
+            return Err(ThrottleError::Closed);
+        }
+
         // Check for lock hierarchy violation
         if let Some(current) = peer.level(lock_levels) {
             if current <= level {
@@ -340,11 +881,98 @@ impl Leases {
         // the remainder is 3.
         let acquired = self.demand_smaller_or_equal(semaphore, max - amount);
 
+        let arrival = self.next_arrival();
+        let name = self.intern(semaphore);
+        self.ledger.get_mut(&peer_id).unwrap().add_lock(
+            name.clone(),
+            amount,
+            acquired,
+            arrival,
+            now,
+        )?;
+        if acquired {
+            self.active.add(&name, amount);
+            ACQUISITION_WAIT
+                .with_label_values(&[semaphore])
+                .observe(0.0);
+            ACQUIRES.with_label_values(&[semaphore]).inc();
+        }
+
+        Ok(acquired)
+    }
+
+    /// Changes the count of a lock the peer already acquired to `amount`.
+    ///
+    /// Growing: Should there not be enough capacity left, the additional count is either pending
+    /// (if `wait` is `true`) or `InsufficientCapacity` is returned. The peer keeps its current
+    /// count while waiting. Growing never jumps the queue of pending peers.
+    ///
+    /// Shrinking takes effect immediately. Call `resolve_pending` afterwards, as the freed capacity
+    /// may allow pending locks to be acquired. A pending request to grow the lock shrinks first.
+    ///
+    /// # Return
+    ///
+    /// Returns `true` if, and only if, all locks of the peer are acquired.
+    pub fn change_amount(
+        &mut self,
+        peer_id: PeerId,
+        semaphore: &str,
+        amount: i64,
+        max: i64,
+        wait: bool,
+        now: Instant,
+    ) -> Result<bool, ThrottleError> {
+        if amount < 1 {
+            return Err(ThrottleError::InvalidLockCount { count: amount });
+        }
+        let peer = self
+            .ledger
+            .get(&peer_id)
+            .ok_or(ThrottleError::UnknownPeer)?;
+        if peer.count_acquired(semaphore) == 0 {
+            return Err(ThrottleError::NotAcquired);
+        }
+        let previous_demand = peer.count_demand(semaphore);
+        match amount.cmp(&previous_demand) {
+            Ordering::Less => {
+                // Interned already, as the peer holds a lock to it.
+                let name = self.intern(semaphore);
+                let peer = self.ledger.get_mut(&peer_id).unwrap();
+                let delta = peer.shrink_lock(semaphore, amount);
+                let all_acquired = peer.all_acquired();
+                self.active.add(&name, delta);
+                return Ok(all_acquired);
+            }
+            Ordering::Equal => return Ok(peer.all_acquired()),
+            Ordering::Greater => (),
+        }
+        if peer.pending.is_some() {
+            return Err(ThrottleError::AlreadyPending);
+        }
+        if self.frozen.contains(semaphore) {
+            return Err(ThrottleError::Frozen);
+        }
+        if max < amount {
+            return Err(ThrottleError::Never { asked: amount, max });
+        }
+        let delta = amount - previous_demand;
+        let acquired = self.demand_smaller_or_equal(semaphore, max - delta);
+        if !acquired && !wait {
+            return Err(ThrottleError::InsufficientCapacity);
+        }
+        let arrival = self.next_arrival();
         self.ledger
             .get_mut(&peer_id)
             .unwrap()
-            .add_lock(semaphore.to_owned(), amount, acquired)?;
-
+            .grow_lock(semaphore, amount, acquired, arrival, now);
+        if acquired {
+            let name = self.intern(semaphore);
+            self.active.add(&name, delta);
+            ACQUISITION_WAIT
+                .with_label_values(&[semaphore])
+                .observe(0.0);
+            ACQUIRES.with_label_values(&[semaphore]).inc();
+        }
         Ok(acquired)
     }
 
@@ -355,10 +983,14 @@ impl Leases {
     /// * `peer_id`: Peer for which the locks are restored.
     /// * `acquired`: Semaphore names and counts, acquired by this peer.
     /// * `valid_until`: The instant until the new peer remains valid (i.e. does not expire).
+    /// * `now`: A lock lost due to `strict_restore` is pending since this instant.
+    /// * `limits`: Full count of a semaphore, and wether it is configured with `strict_restore`.
     ///
     /// # Return
     ///
-    /// Returns `true` if, and only if, all locks of the peer are acquired.
+    /// Semaphores which are overbooked, because of the restored locks, and locks which have been
+    /// lost. Empty, if the peer already existed. `Gone` if the peer has been split, as its locks
+    /// live on in the new peers.
     ///
     /// This is useful, to restore revenants (i.e. Peers for which we receive a heartbeat after we
     /// removed them, due to expiration). This way we can restore them without having to change the
@@ -369,7 +1001,9 @@ impl Leases {
     /// be created through restore. This exception is made in order to be idempotent.
     ///
     /// This will acquire all the locks for the forgotten peer. This will always succeed indpedent
-    /// of current demand.
+    /// of current demand. The exception are semaphores configured with `strict_restore`, which are
+    /// never overbooked. Locks to these which do not fit are lost. The alphabetically first of them
+    /// is pending instead, the others are not restored at all.
     ///
     /// The peer to restore had the lock already acquired, so there is no point in checking it
     /// against the full semaphore count. The resource the semaphore is protecting is already being
@@ -378,67 +1012,302 @@ impl Leases {
     /// full count.
     pub fn restore(
         &mut self,
-        peer_id: PeerId,
-        acquired: &HashMap<String, i64>,
-        valid_until: Instant,
-    ) -> Result<(), ThrottleError> {
+        revenant: Revenant<'_>,
+        now: Instant,
+        limits: impl Fn(&str) -> (i64, bool),
+    ) -> Result<Restored, ThrottleError> {
+        let Revenant {
+            peer_id,
+            acquired,
+            valid_until,
+            metadata,
+            priority,
+        } = revenant;
+        if self.retired.contains_key(&peer_id) {
+            return Err(ThrottleError::Gone);
+        }
         if let Some(&count) = acquired.values().find(|&&c| c < 1) {
             return Err(ThrottleError::InvalidLockCount { count });
         }
+        // Like any other lock, a restored one may not exceed the full count of its semaphore.
+        for (semaphore, &count) in acquired {
+            let (max, _strict) = limits(semaphore);
+            if max < count {
+                return Err(ThrottleError::Never { asked: count, max });
+            }
+        }
 
+        let mut restored = Restored::default();
         // We don't want to allow changing existing peers through the restore route.
-        if let Some(prev) = self.ledger.get(&peer_id) {
+        if let Some(prev) = self.ledger.get_mut(&peer_id) {
             // A peer already exists. Check if it holds exactly the acquired locks, and does not
             // have a pending one.
-            prev.assert_restore_valid(&acquired)?;
+            prev.assert_restore_valid(acquired)?;
+            prev.priority = priority;
+            if metadata.is_some() {
+                prev.metadata = metadata;
+            }
         } else {
+            // Overbooking is bounded by the range of `i64`. Refuse revenants which would overflow
+            // the count, rather than wrapping around.
+            for (semaphore, &count) in acquired {
+                if self.count(semaphore).checked_add(count).is_none() {
+                    return Err(ThrottleError::Never {
+                        asked: count,
+                        max: limits(semaphore).0,
+                    });
+                }
+            }
+            // Counted only for new peers, since restoring is idempotent.
+            for semaphore in acquired.keys() {
+                REVENANTS.with_label_values(&[semaphore.as_str()]).inc();
+            }
+            // Locks to strict semaphores are only restored, if they fit within the full count.
+            restored.lost = acquired
+                .iter()
+                .filter(|&(semaphore, &count)| {
+                    let (max, strict) = limits(semaphore);
+                    strict && self.count(semaphore) + count > max
+                })
+                .map(|(semaphore, _)| semaphore.clone())
+                .collect();
+            restored.lost.sort();
+            let lost = &restored.lost;
+            let kept = acquired
+                .iter()
+                .filter(|&(semaphore, _)| !lost.contains(semaphore))
+                .map(|(semaphore, &count)| (self.intern(semaphore), count))
+                .collect();
             // Insert new peer
-            let peer = self
-                .ledger
-                .insert(peer_id, Peer::new(valid_until, acquired.clone()));
-            debug_assert!(peer.is_none());
+            let mut peer = Peer::new(valid_until, kept);
+            peer.metadata = metadata;
+            peer.priority = priority;
+            if let Some(semaphore) = restored.lost.first() {
+                // A peer may only wait for one lock at a time.
+                peer.pending = Some(Lock {
+                    semaphore: self.intern(semaphore),
+                    count: acquired[semaphore],
+                    since: now,
+                    arrival: self.next_arrival(),
+                });
+            }
+            restored.overbooked = peer
+                .acquired
+                .iter()
+                .filter(|&(semaphore, &count)| self.count(semaphore) + count > limits(semaphore).0)
+                .map(|(semaphore, _)| semaphore.to_string())
+                .collect();
+            self.insert_peer(peer_id, peer);
         }
+        self.debug_assert_counts();
 
-        Ok(())
+        Ok(restored)
+    }
+
+    /// Stops granting new locks to the semaphore. Existing locks are kept. Returns `false` if the
+    /// semaphore has already been frozen.
+    pub fn freeze(&mut self, semaphore: &str) -> bool {
+        self.frozen.insert(semaphore.to_owned())
+    }
+
+    /// Grants new locks to the semaphore again. Call `resolve_pending` afterwards. Returns `false`
+    /// if the semaphore has not been frozen.
+    pub fn unfreeze(&mut self, semaphore: &str) -> bool {
+        self.frozen.remove(semaphore)
+    }
+
+    /// `true` if the semaphore is frozen.
+    pub fn is_frozen(&self, semaphore: &str) -> bool {
+        self.frozen.contains(semaphore)
+    }
+
+    /// The shared instance of the semaphore name. Allocates it, only the first time the name is
+    /// seen.
+    fn intern(&mut self, semaphore: &str) -> Arc<str> {
+        if let Some(name) = self.names.get(semaphore) {
+            return name.clone();
+        }
+        let name: Arc<str> = Arc::from(semaphore);
+        self.names.insert(name.clone());
+        name
+    }
+
+    /// Number of peers in the ledger
+    pub fn num_peers(&self) -> usize {
+        self.ledger.len()
+    }
+
+    /// `true` if a new lock with `amount` to the semaphore would be acquired right away, rather than
+    /// becoming pending. Mirrors the check `acquire` performs for new locks.
+    pub fn can_acquire_now(&self, semaphore: &str, amount: i64, max: i64) -> bool {
+        amount <= max && self.demand_smaller_or_equal(semaphore, max - amount)
     }
 
     /// Aggregated count of active leases for the semaphore
     pub fn count(&self, semaphore: &str) -> i64 {
+        let count = self.active.get(semaphore);
+        debug_assert_eq!(
+            count,
+            self.recount(semaphore),
+            "Count of {} drifted",
+            semaphore
+        );
+        count
+    }
+
+    /// Aggregated count of active leases for the semaphore, computed by scanning the whole ledger.
+    /// Used to verify the incrementally maintained counts.
+    fn recount(&self, semaphore: &str) -> i64 {
         self.ledger
             .values()
             .map(|lease| lease.count_acquired(semaphore))
             .sum()
     }
 
-    /// Should a peer with `peer_id` be found, it is removed and the names of the semaphores it
-    /// holds is returned. If the `peer_id` has not been found `None` is returned.
-    pub fn remove_peer(&mut self, peer_id: PeerId) -> Option<Vec<String>> {
-        self.ledger.remove(&peer_id).map(|mut p| p.clear())
+    /// Panics in debug builds, if the incrementally maintained count of any semaphore differs from
+    /// the one computed from the ledger, or if the index of expiries misses a peer.
+    fn debug_assert_counts(&self) {
+        if cfg!(debug_assertions) {
+            assert_eq!(self.expiries.len() + self.overdue.len(), self.ledger.len());
+            let semaphores: HashSet<&str> = self
+                .ledger
+                .values()
+                .flat_map(|peer| peer.acquired.keys())
+                .chain(self.active.0.keys())
+                .map(|semaphore| &**semaphore)
+                .collect();
+            for semaphore in semaphores {
+                assert_eq!(
+                    self.active.get(semaphore),
+                    self.recount(semaphore),
+                    "Count of {} drifted",
+                    semaphore
+                );
+            }
+        }
+    }
+
+    /// Answers requests to `peer_id` with `Gone` until `valid_until`, like requests to a split
+    /// peer. Called for revoked peers, so clients can not restore the locks taken from them.
+    pub fn retire(&mut self, peer_id: PeerId, valid_until: Instant) {
+        self.retired.insert(peer_id, valid_until);
+    }
+
+    /// Should a peer with `peer_id` be found, it is removed and the locks it held are returned. If
+    /// the `peer_id` has not been found `None` is returned.
+    pub fn remove_peer(&mut self, peer_id: PeerId) -> Option<RemovedPeer> {
+        self.take_peer(peer_id).map(|peer| RemovedPeer {
+            acquired: peer.acquired,
+            pending: peer.pending.map(|lock| (lock.semaphore, lock.count)),
+        })
+    }
+
+    /// Inserts a new peer into the ledger, the index of expiries and the counts of its locks.
+    fn insert_peer(&mut self, peer_id: PeerId, peer: Peer) {
+        self.expiries.insert((peer.valid_until, peer_id));
+        self.active.add_all(&peer.acquired);
+        let old = self.ledger.insert(peer_id, peer);
+        // There should not be any preexisting entry with this id
+        debug_assert!(old.is_none());
+    }
+
+    /// Removes a peer from the ledger, the index of expiries and the counts of its locks.
+    fn take_peer(&mut self, peer_id: PeerId) -> Option<Peer> {
+        let peer = self.ledger.remove(&peer_id)?;
+        if !self.overdue.remove(&peer_id) {
+            self.expiries.remove(&(peer.valid_until, peer_id));
+        }
+        self.active.remove_all(&peer.acquired);
+        Some(peer)
+    }
+
+    /// Earliest instant a peer is going to expire. Peers which are overdue, but kept alive by locks
+    /// to semaphores which do not expire, are not considered. `None` if there is no such peer.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.expiries
+            .iter()
+            .next()
+            .map(|&(valid_until, _)| valid_until)
     }
 
     /// Acquires pending leases for the semaphore until its count is >= max. It acquires the locks
-    /// pending the longest first.
-    pub fn resolve_pending(&mut self, semaphore: &str, max: i64, resolved_peers: &mut Vec<PeerId>) {
-        let mut remainder = max - self.count(semaphore);
-        while let Some(peer_id) = self.resolve_highest_priority_pending(semaphore, &mut remainder) {
+    /// of peers with the highest priority first. Among peers with the same priority, the ones
+    /// which arrived first are acquired first.
+    ///
+    /// Resolving stops at the first lock which does not fit into the remainder, even if smaller
+    /// locks behind it would. This head of line blocking is deliberate: otherwise a steady stream
+    /// of small locks could starve a large one indefinitely.
+    ///
+    /// The time each acquired lock has been waiting until `now` is recorded in
+    /// `throttle_acquisition_wait_seconds`.
+    pub fn resolve_pending(
+        &mut self,
+        semaphore: &str,
+        max: i64,
+        now: Instant,
+        resolved_peers: &mut Vec<PeerId>,
+    ) {
+        // Nothing is granted by closed semaphores, not even pending locks.
+        if self.frozen.contains(semaphore) || max == 0 {
+            return;
+        }
+        let mut remainder = max.saturating_sub(self.count(semaphore));
+        let name = self.intern(semaphore);
+        while let Some((peer_id, since)) =
+            self.resolve_highest_priority_pending(&name, &mut remainder)
+        {
+            let priority = self.ledger[&peer_id].priority;
+            PROMOTIONS.with_label_values(&[&priority.to_string()]).inc();
+            ACQUISITION_WAIT
+                .with_label_values(&[semaphore])
+                .observe(now.saturating_duration_since(since).as_secs_f64());
+            ACQUIRES.with_label_values(&[semaphore]).inc();
             resolved_peers.push(peer_id);
         }
     }
 
-    /// Wether the peer has any pending leases.
+    /// Wether all locks of the peer are acquired, or if it is waiting for one.
     ///
     /// # Return
     ///
-    /// `true` if peer has any pending leases, `false` if not. Returns `UnknownPeer` if peer does
-    /// not exist.
-    pub fn has_pending(&self, peer_id: PeerId) -> Result<bool, ThrottleError> {
+    /// Returns `UnknownPeer` if peer does not exist.
+    pub fn peer_state(&self, peer_id: PeerId) -> Result<PeerState, ThrottleError> {
         self.ledger
             .get(&peer_id)
             .ok_or(ThrottleError::UnknownPeer)
-            .map(|peer| !peer.all_acquired())
+            .map(Peer::state)
+    }
+
+    /// Locks acquired by the peer.
+    ///
+    /// # Return
+    ///
+    /// May return `ThrottleError::UnknownPeer` if `peer_id` is not found.
+    pub fn acquired(&self, peer_id: PeerId) -> Result<&HashMap<Arc<str>, i64>, ThrottleError> {
+        self.ledger
+            .get(&peer_id)
+            .map(|peer| &peer.acquired)
+            .ok_or_else(|| self.unknown(peer_id))
+    }
+
+    /// State of the peer and time left until it expires.
+    ///
+    /// # Return
+    ///
+    /// May return `ThrottleError::UnknownPeer` if `peer_id` is not found.
+    pub fn status(&self, peer_id: PeerId, now: Instant) -> Result<PeerStatus, ThrottleError> {
+        let peer = self
+            .ledger
+            .get(&peer_id)
+            .ok_or_else(|| self.unknown(peer_id))?;
+        Ok(PeerStatus {
+            state: peer.state(),
+            expires_in: peer.valid_until.saturating_duration_since(now),
+        })
     }
 
-    /// Remove every lease, which is not valid until now.
+    /// Remove every lease, which is not valid until now. Peers holding locks to semaphores for which
+    /// `expires` returns `false` are never removed.
     ///
     /// Under ordinary circumstances leases should be explicitly removed. Yet a client may die due
     /// to an error and never get a chance to free the lease. Therfore we free this litter on
@@ -446,29 +1315,52 @@ impl Leases {
     ///
     /// # Return
     ///
-    /// (List of expired peers, affected_semaphores)
-    pub fn remove_expired(&mut self, now: Instant) -> (Vec<PeerId>, Vec<String>) {
+    /// (List of expired peers with their metadata, affected_semaphores)
+    pub fn remove_expired(
+        &mut self,
+        now: Instant,
+        expires: impl Fn(&str) -> bool,
+    ) -> (ExpiredPeers, Vec<Arc<str>>) {
         let mut expired_peers = Vec::new();
         let mut affected_semaphores = Vec::new();
-        self.ledger.retain(|peer_id, peer| {
-            if let Some(semaphores) = peer.remove_expired(now) {
+        // Only peers past their `valid_until` are looked at, rather than the whole ledger.
+        let due: Vec<PeerId> = self
+            .expiries
+            .range(..(now, PeerId::MIN))
+            .map(|&(_valid_until, peer_id)| peer_id)
+            .chain(self.overdue.iter().copied())
+            .collect();
+        for peer_id in due {
+            let peer = &self.ledger[&peer_id];
+            if peer.expires(&expires) {
                 // Peer is expired
-                expired_peers.push(*peer_id);
-                affected_semaphores.extend(semaphores);
-                // Don't retain this peer in the ledger
-                false
-            } else {
-                // Not expired, let's keep this one
-                true
+                let mut peer = self.take_peer(peer_id).unwrap();
+                if let Some(lock) = &peer.pending {
+                    EXPIRED_PENDING.with_label_values(&[&*lock.semaphore]).inc();
+                }
+                for semaphore in peer.acquired.keys() {
+                    EXPIRATIONS.with_label_values(&[&**semaphore]).inc();
+                }
+                expired_peers.push((peer_id, peer.metadata.take()));
+                affected_semaphores.extend(peer.clear());
+            } else if self.expiries.remove(&(peer.valid_until, peer_id)) {
+                // Kept alive by a lock to a semaphore which does not expire.
+                self.overdue.insert(peer_id);
             }
-        });
+        }
+        // Retired peers would have expired by now. Time to forget them.
+        self.retired.retain(|_, valid_until| *valid_until >= now);
         // Remove duplicates
         affected_semaphores.sort();
         affected_semaphores.dedup();
+        self.debug_assert_counts();
         (expired_peers, affected_semaphores)
     }
 
-    /// Called to increase the timestamp of a lease to prevent it from expiring.
+    /// Called to increase the timestamp of a lease to prevent it from expiring. Never shortens the
+    /// validity of a peer: if `valid_until` is earlier than the current deadline, the current
+    /// deadline is kept. Otherwise a short heartbeat of one client could cut the lease another
+    /// code path of the same client just prolonged.
     ///
     /// # Return
     ///
@@ -478,11 +1370,19 @@ impl Leases {
         peer_id: PeerId,
         valid_until: Instant,
     ) -> Result<(), ThrottleError> {
+        let retired = &self.retired;
         let peer = self
             .ledger
             .get_mut(&peer_id)
-            .ok_or(ThrottleError::UnknownPeer)?;
-        peer.valid_until = valid_until;
+            .ok_or_else(|| unknown(retired, peer_id))?;
+        if valid_until <= peer.valid_until {
+            return Ok(());
+        }
+        let previous = std::mem::replace(&mut peer.valid_until, valid_until);
+        if !self.overdue.remove(&peer_id) {
+            self.expiries.remove(&(previous, peer_id));
+        }
+        self.expiries.insert((valid_until, peer_id));
         Ok(())
     }
 
@@ -494,38 +1394,266 @@ impl Leases {
         }
     }
 
+    /// Locks and status of a single peer.
+    ///
+    /// # Return
+    ///
+    /// May return `ThrottleError::UnknownPeer` if `peer_id` is not found.
+    pub fn peer_info(&self, peer_id: PeerId, now: Instant) -> Result<PeerInfo, ThrottleError> {
+        let status = self.status(peer_id, now)?;
+        let peer = &self.ledger[&peer_id];
+        Ok(PeerInfo {
+            acquired: peer
+                .acquired
+                .iter()
+                .map(|(semaphore, &count)| (semaphore.to_string(), count))
+                .collect(),
+            pending: peer
+                .pending
+                .as_ref()
+                .map(|lock| (lock.semaphore.to_string(), lock.count)),
+            status,
+            metadata: peer.metadata.clone(),
+            position: self.position(peer),
+        })
+    }
+
+    /// Position of the pending lock of `peer` among all pending locks to the same semaphore, in
+    /// the order `resolve_pending` acquires them. Starts at `1`. `None` if nothing is pending.
+    fn position(&self, peer: &Peer) -> Option<usize> {
+        let semaphore = &peer.pending.as_ref()?.semaphore;
+        let key = peer.queue_key(semaphore)?;
+        let ahead = self
+            .ledger
+            .values()
+            .filter_map(|other| other.queue_key(semaphore))
+            .filter(|other| *other < key)
+            .count();
+        Some(ahead + 1)
+    }
+
+    /// Snapshot of all locks, acquired and pending, matching `filter`, ordered by peer id and
+    /// semaphore. `expires` tells for each semaphore, wether locks to it expire. At most `limit`
+    /// locks are listed. Locks past the limit are never copied out of the ledger.
+    pub fn list(
+        &self,
+        now: Instant,
+        expires: impl Fn(&str) -> bool,
+        filter: LockFilter,
+        limit: Option<usize>,
+    ) -> Vec<LockInfo> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut peer_ids: Vec<PeerId> = self.ledger.keys().copied().collect();
+        peer_ids.sort_unstable();
+        let mut locks = Vec::new();
+        for peer_id in peer_ids {
+            if locks.len() >= limit {
+                break;
+            }
+            let peer = &self.ledger[&peer_id];
+            let first = locks.len();
+            push_locks(
+                &mut locks,
+                peer_id,
+                peer,
+                now,
+                peer.expires(&expires),
+                filter,
+            );
+            locks[first..].sort_by(|a, b| {
+                (&a.semaphore, a.state == PeerState::Pending)
+                    .cmp(&(&b.semaphore, b.state == PeerState::Pending))
+            });
+        }
+        locks.truncate(limit);
+        locks
+    }
+
+    /// Locks of peers which expire within `within` from `now`, soonest first. Peers which never
+    /// expire are left out, as are peers without any locks. Only looks at the index of expiries,
+    /// rather than the whole ledger.
+    pub fn list_expiring(
+        &self,
+        now: Instant,
+        within: Duration,
+        expires: impl Fn(&str) -> bool,
+    ) -> Vec<LockInfo> {
+        let mut locks = Vec::new();
+        // `within` is chosen by the client. Anything too large to add to `now` covers every peer.
+        let until = match now.checked_add(within) {
+            Some(until) => Bound::Excluded((until, PeerId::MIN)),
+            None => Bound::Unbounded,
+        };
+        for &(_valid_until, peer_id) in self.expiries.range((Bound::Unbounded, until)) {
+            let peer = &self.ledger[&peer_id];
+            if peer.expires(&expires) {
+                push_locks(&mut locks, peer_id, peer, now, true, LockFilter::default());
+            }
+        }
+        locks
+    }
+
+    /// Earliest instant at which a peer holding an acquired lock to the semaphore expires. Capacity
+    /// is guaranteed to free up by then, unless the peer sends a heartbeat. `None` if no expiring
+    /// peer holds a lock to the semaphore.
+    pub fn earliest_expiry(
+        &self,
+        semaphore: &str,
+        expires: impl Fn(&str) -> bool,
+    ) -> Option<Instant> {
+        self.ledger
+            .values()
+            .filter(|peer| peer.count_acquired(semaphore) != 0 && peer.expires(&expires))
+            .map(|peer| peer.valid_until)
+            .min()
+    }
+
+    /// Accumulated counts for a single semaphore. The acquired count is the incrementally
+    /// maintained one. Peers and pending locks are not indexed by semaphore, so they are collected
+    /// in a single pass over the ledger.
+    pub fn counts(&self, semaphore: &str) -> Counts {
+        let mut counts = Counts::default();
+        for peer in self.ledger.values() {
+            peer.add_counts_to(semaphore, &mut counts);
+        }
+        counts.acquired = self.count(semaphore);
+        counts
+    }
+
     /// Release a lock associated with a peer.
     ///
     /// # Return
     ///
-    /// `true` if the lock had been pending.
+    /// Wether the lock had been acquired, pending or not held at all.
     pub fn release_lock(
         &mut self,
         peer_id: PeerId,
         semaphore: &str,
-    ) -> Result<bool, ThrottleError> {
-        let was_pending = self
+    ) -> Result<Released, ThrottleError> {
+        let retired = &self.retired;
+        let peer = self
             .ledger
             .get_mut(&peer_id)
-            .ok_or(ThrottleError::UnknownPeer)?
-            .release_lock(semaphore);
-        Ok(was_pending)
+            .ok_or_else(|| unknown(retired, peer_id))?;
+        let count = peer.count_acquired(semaphore);
+        let released = peer.release_lock(semaphore);
+        if released == Released::Acquired {
+            let name = self.intern(semaphore);
+            self.active.add(&name, -count);
+        }
+        Ok(released)
     }
 
-    /// Generates a random new peer id which does not collide with any preexisting
-    fn new_unique_peer_id(&self) -> PeerId {
-        loop {
-            let candidate = random();
-            if self.ledger.get(&candidate).is_none() {
-                return candidate;
-            }
+    /// Releases the locks of all peers to the semaphore. The peers themselves and their locks to
+    /// other semaphores are kept.
+    ///
+    /// # Return
+    ///
+    /// Peers which held a lock to the semaphore, and wether it had been acquired or pending.
+    pub fn release_semaphore(&mut self, semaphore: &str) -> Vec<(PeerId, Released)> {
+        let released = self
+            .ledger
+            .iter_mut()
+            .map(|(&peer_id, peer)| (peer_id, peer.release_lock(semaphore)))
+            .filter(|(_, released)| *released != Released::NotHeld)
+            .collect();
+        self.active.0.remove(semaphore);
+        self.debug_assert_counts();
+        released
+    }
+
+    /// Splits a peer holding exactly one acquired lock into new peers, each holding one of the
+    /// `amounts` of the same semaphore. The amounts must sum up to the count of the original lock,
+    /// so the total count of the semaphore never changes. The new peers share the expiration time
+    /// of the original one. The id of the original peer is retired.
+    ///
+    /// # Return
+    ///
+    /// Ids of the new peers, in the order of `amounts`.
+    pub fn split(
+        &mut self,
+        peer_id: PeerId,
+        amounts: &[i64],
+    ) -> Result<Vec<PeerId>, ThrottleError> {
+        if let Some(&count) = amounts.iter().find(|&&c| c < 1) {
+            return Err(ThrottleError::InvalidLockCount { count });
+        }
+        let peer = self
+            .ledger
+            .get(&peer_id)
+            .ok_or_else(|| self.unknown(peer_id))?;
+        if peer.pending.is_some() || peer.acquired.len() != 1 {
+            return Err(ThrottleError::InvalidSplit);
+        }
+        let (semaphore, &count) = peer.acquired.iter().next().unwrap();
+        // Summed checked, so huge amounts can not wrap around to the original count.
+        let sum = amounts
+            .iter()
+            .try_fold(0i64, |sum, &amount| sum.checked_add(amount));
+        if amounts.iter().any(|&amount| amount > count) || sum != Some(count) {
+            return Err(ThrottleError::InvalidSplit);
         }
+        let semaphore = semaphore.clone();
+        let valid_until = peer.valid_until;
+        // The new peers belong to the same client
+        let metadata = peer.metadata.clone();
+        let priority = peer.priority;
+
+        self.take_peer(peer_id);
+        self.retired.insert(peer_id, valid_until);
+        let new_peers = amounts
+            .iter()
+            .map(|&amount| {
+                let id = self.new_unique_peer_id();
+                let mut acquired = HashMap::new();
+                acquired.insert(semaphore.clone(), amount);
+                let mut peer = Peer::new(valid_until, acquired);
+                peer.metadata = metadata.clone();
+                peer.priority = priority;
+                self.insert_peer(id, peer);
+                id
+            })
+            .collect();
+        Ok(new_peers)
+    }
+
+    /// Peer id followed by its metadata, if any. Used in log messages.
+    pub fn describe(&self, peer_id: PeerId) -> String {
+        let metadata = self
+            .ledger
+            .get(&peer_id)
+            .and_then(|peer| peer.metadata.as_ref());
+        describe(peer_id, metadata)
+    }
+
+    /// Error for a peer id not found in the ledger.
+    fn unknown(&self, peer_id: PeerId) -> ThrottleError {
+        unknown(&self.retired, peer_id)
+    }
+
+    /// Position of the next pending lock in the order of arrival.
+    fn next_arrival(&mut self) -> u64 {
+        self.arrivals += 1;
+        self.arrivals
+    }
+
+    /// Replaces the source of ids for new peers.
+    pub fn set_id_generator(&mut self, ids: Box<dyn IdGenerator>) {
+        self.ids = ids;
+    }
+
+    /// Generates a new peer id which does not collide with any preexisting
+    fn new_unique_peer_id(&mut self) -> PeerId {
+        let ledger = &self.ledger;
+        let retired = &self.retired;
+        self.ids
+            .generate(&|id| ledger.contains_key(&id) || retired.contains_key(&id))
     }
 
     /// Returns wether the demand (i.e. the sum of pending and acquired lock counts) for this lease
     /// is smaller or equal to `max`.
     fn demand_smaller_or_equal(&self, semaphore: &str, max: i64) -> bool {
-        let mut demand = 0;
+        let mut demand: i64 = 0;
         debug_assert!(max >= 0);
         // If we would allow lock counts > semahpore counts in this method we'd need these lines. So
         // the condition is always false.
@@ -533,7 +1661,9 @@ impl Leases {
         //     return false;
         // }
         for peer in self.ledger.values() {
-            demand += peer.count_demand(semaphore);
+            // Saturate, so the sum of many large pending locks does not wrap around to a small
+            // demand.
+            demand = demand.saturating_add(peer.count_demand(semaphore));
             // early return
             if demand > max {
                 return false;
@@ -542,25 +1672,28 @@ impl Leases {
         true
     }
 
-    /// Return the pending lock with the highest priority for this semaphore. Since we have fair
-    /// semaphores, this is the peer waiting the longest. Returns `None` in case there are not any
-    /// pending locks.
-    fn resolve_highest_priority_pending<'a>(
-        &'a mut self,
-        semaphore: &str,
+    /// Return the pending lock with the highest priority for this semaphore. This is the peer with
+    /// the highest priority, and among those the one which arrived first. Returns `None` in case
+    /// there are not any pending locks.
+    fn resolve_highest_priority_pending(
+        &mut self,
+        semaphore: &Arc<str>,
         remainder: &mut i64,
-    ) -> Option<PeerId> {
+    ) -> Option<(PeerId, Instant)> {
         let min = self
             .ledger
             .iter_mut()
-            .filter_map(|(id, peer)| peer.pending_since(semaphore).map(|since| (id, peer, since)))
-            .min_by_key(|(_id, _peer, since)| *since);
+            .filter_map(|(id, peer)| Some((id, peer.queue_key(semaphore)?, peer)))
+            .min_by_key(|(_id, key, _peer)| *key);
 
-        if let Some((&id, peer, _since)) = min {
+        if let Some((&id, _key, peer)) = min {
+            let pending = peer.pending.as_ref().unwrap();
+            let (count, since) = (pending.count, pending.since);
             // Decrements the remainder of the amount, regardless of wether we acquire it or not
             // doing so prevents us from starving locks requesting big amounts.
             if peer.try_resolve(remainder) {
-                Some(id)
+                self.active.add(semaphore, count);
+                Some((id, since))
             } else {
                 None
             }
@@ -569,3 +1702,41 @@ impl Leases {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_share_semaphore_names() {
+        let mut leases = Leases::new();
+        let now = Instant::now();
+        let valid_until = now + Duration::from_secs(60);
+        for _ in 0..3 {
+            let peer_id = leases.new_peer(valid_until, None, 0);
+            let limits = SemaphoreLimits { max: 2, level: 0 };
+            leases.acquire(peer_id, "A", 1, limits, now, |_| 0).unwrap();
+        }
+        let name = leases.intern("A");
+        // Set of names, key of the active count, two acquired locks, one pending lock and `name`
+        assert_eq!(Arc::strong_count(&name), 6);
+    }
+    /// Names of semaphores are only interned, once a lock to them is held. Changing locks which do
+    /// not exist must not grow the set of names.
+    #[test]
+    fn change_unknown_lock_interns_no_name() {
+        let mut leases = Leases::new();
+        let now = Instant::now();
+        let unknown = PeerId::Int(42);
+        assert!(matches!(
+            leases.change_amount(unknown, "A", 2, 2, false, now),
+            Err(ThrottleError::UnknownPeer)
+        ));
+        let peer_id = leases.new_peer(now + Duration::from_secs(60), None, 0);
+        assert!(matches!(
+            leases.change_amount(peer_id, "B", 2, 2, false, now),
+            Err(ThrottleError::NotAcquired)
+        ));
+        assert!(leases.names.is_empty());
+    }
+}
@@ -1,20 +1,140 @@
-// Not detecting wether a bool is used to wait before a critical section is a known weakness of this
-// See: https://rust-lang.github.io/rust-clippy/master/index.html#mutex_atomic
-// We run into this here, so let's silence this lint for this file.
-#![allow(clippy::mutex_atomic)]
-
-use crate::state::State;
-use log::{debug, info, warn};
+use crate::{poison, state::State};
+use actix_web::{
+    get,
+    web::{Data, Json},
+};
+use log::{debug, error, info, warn};
+use serde::Serialize;
 use std::{
-    sync::{Arc, Condvar, Mutex},
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, PoisonError},
     thread::{spawn, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+/// Litter collection is considered stale, if it did not run for this many intervals.
+const STALE_AFTER_INTERVALS: u32 = 3;
+
+/// Outcome of a single run of the litter collection.
+#[derive(Clone, Copy, Debug)]
+pub struct Run {
+    /// Instant the run started.
+    pub start: Instant,
+    /// Time it took to remove the expired peers.
+    pub duration: Duration,
+    /// Number of removed peers. `None` if the run panicked.
+    pub num_removed: Option<usize>,
+}
+
+/// Bookkeeping about the litter collection. Allows us to detect it silently dying.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Status {
+    /// Configured interval and the instant the litter collection has been started. `None` if the
+    /// litter collection is not running.
+    started: Option<(Duration, Instant)>,
+    /// `None` if the litter collection did not run yet.
+    last_run: Option<Run>,
+}
+
+impl Status {
+    /// Remembers the interval and start of the litter collection.
+    pub fn start(&mut self, interval: Duration, now: Instant) {
+        self.started = Some((interval, now));
+    }
+
+    /// Remembers the outcome of the most recent run.
+    pub fn record(&mut self, run: Run) {
+        self.last_run = Some(run);
+    }
+
+    /// Time passed since the last run started, or if there has not been one yet, since the litter
+    /// collection has been started. `None` if the litter collection is not running.
+    pub fn age(&self, now: Instant) -> Option<Duration> {
+        let (_interval, started) = self.started?;
+        let last_activity = self.last_run.map(|run| run.start).unwrap_or(started);
+        Some(now.saturating_duration_since(last_activity))
+    }
+
+    /// `true` if the litter collection did not run for several intervals. This indicates that
+    /// expired peers are no longer removed.
+    pub fn is_stale(&self, now: Instant) -> bool {
+        match (self.started, self.age(now)) {
+            (Some((interval, _)), Some(age)) => age > interval * STALE_AFTER_INTERVALS,
+            _ => false,
+        }
+    }
+}
+
+/// Wakes the litter collection thread. Either to stop it, or because a peer is going to expire
+/// before the next planned run.
+#[derive(Default)]
+pub struct Wakeup {
+    plan: Mutex<Plan>,
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct Plan {
+    stopped: bool,
+    /// Instant the sleeping litter collection is going to wake up. `None` while it is running.
+    next_run: Option<Instant>,
+    /// Earliest expiry announced while the litter collection has been running.
+    announced: Option<Instant>,
+}
+
+impl Wakeup {
+    /// Tells the litter collection a peer is going to expire at `valid_until`. Wakes it early, if
+    /// this is before its next planned run.
+    pub fn expires_at(&self, valid_until: Instant) {
+        let mut plan = poison::lock(&self.plan, "the plan of the litter collection");
+        match plan.next_run {
+            Some(next_run) if valid_until < next_run => {
+                plan.next_run = Some(valid_until);
+                self.condvar.notify_all();
+            }
+            Some(_) => (),
+            None => {
+                let announced = plan.announced.map_or(valid_until, |a| a.min(valid_until));
+                plan.announced = Some(announced);
+            }
+        }
+    }
+
+    /// Cancels the sleep of the litter collection for good.
+    fn stop(&self) {
+        poison::lock(&self.plan, "the plan of the litter collection").stopped = true;
+        self.condvar.notify_all();
+    }
+
+    /// Blocks until `next_run`, or an earlier announced expiry. `now` tells the current time.
+    /// Returns `false` if the litter collection has been stopped.
+    fn sleep_until(&self, next_run: Instant, now: impl Fn() -> Instant) -> bool {
+        let mut plan = poison::lock(&self.plan, "the plan of the litter collection");
+        let next_run = plan.announced.take().map_or(next_run, |a| a.min(next_run));
+        plan.next_run = Some(next_run);
+        loop {
+            if plan.stopped {
+                return false;
+            }
+            let next_run = plan.next_run.unwrap();
+            let now = now();
+            if next_run <= now {
+                plan.next_run = None;
+                return true;
+            }
+            plan = self
+                .condvar
+                .wait_timeout(plan, next_run - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
 /// Collects expired leases asynchronously. If all goes well leases are removed by the clients via
 /// DELETE requests. Yet, clients crash and requests may never make it. To not leak semaphores in
-/// such cases, the litter collection checks for expired leases in regular intervals and removes
-/// them.
+/// such cases, the litter collection removes expired leases. It sleeps until the next peer
+/// expires, but never longer than the configured interval.
 ///
 /// It does not have a drop handler joining the spawned thread. So if stop is not called at the end
 /// of its lifetime the inner thread is deatched.
@@ -23,57 +143,243 @@ pub struct LitterCollection {
     // would be nicer to reuse the Execute we use to drive the server requests, but advanced
     // asycronous abstractions are not quite there yet. For the time being we can afford one extra
     // thread.
-    /// Used to wait for the next interval, or cancel execution of litter collection during waiting.
-    stopped: Arc<(Mutex<bool>, Condvar)>,
-    handle: JoinHandle<()>,
+    /// Owns the `Wakeup` used to cancel execution of litter collection during waiting.
+    state: Arc<State>,
+    /// `None` if the litter collection is disabled.
+    handle: Option<JoinHandle<()>>,
 }
 
 impl LitterCollection {
-    pub fn stop(self) {
-        // Tell litter collection thread to stop. This will cancel the wait between intervals.
-        // Attention: Take care, to not hold the lock over join. This would cause a deadlock.
-        *self.stopped.0.lock().unwrap() = true;
-        self.stopped.1.notify_all();
-        self.handle.join().unwrap();
+    pub fn stop(mut self) {
+        // Tell litter collection thread to stop. This will cancel the wait between runs.
+        self.state.litter_wakeup().stop();
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap();
+        }
     }
 }
 
-/// Starts a new thread that removes expired leases.
+/// Starts a new thread that removes expired leases. An `interval` of zero disables the litter
+/// collection. Expired peers are then only removed by `POST /remove_expired`.
 pub fn start(state: Arc<State>, interval: Duration) -> LitterCollection {
-    let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+    if interval == Duration::from_secs(0) {
+        warn!("Litter collection is disabled. Expired peers are only removed on request.");
+        return LitterCollection {
+            state,
+            handle: None,
+        };
+    }
     info!("Start litter collection with interval: {:?}", interval);
-    // Copy of stopped for litter collecting thread
-    let canceled = stopped.clone();
-    let handle = spawn(move || {
-        loop {
-            let done = canceled.0.lock().unwrap();
-            let done = {
-                // Introduce extra scope here, so we do not hold lock to done during execution
-                // of removed_expired.
-                let (done, _wait_timeout_result) = canceled.1.wait_timeout(done, interval).unwrap();
-                *done
-            };
-            if done {
-                break;
+    state.litter_collection_started(interval);
+    // Copy of state for litter collecting thread
+    let shared = state.clone();
+    let handle = spawn(move || run(&shared, interval, || shared.remove_expired()));
+    LitterCollection {
+        state,
+        handle: Some(handle),
+    }
+}
+
+/// Body of the litter collection thread. Returns once the litter collection is stopped.
+/// `remove_expired` is passed in, so tests can make runs fail.
+fn run(state: &State, interval: Duration, remove_expired: impl Fn() -> usize) {
+    // `true` after a panicking run. The peers it failed to remove are still overdue, so rather than
+    // retrying right away in a hot loop, we wait for a full interval.
+    let mut failed = false;
+    loop {
+        // Sleep until the next peer expires, but at most for one interval.
+        let latest = state.now() + interval;
+        let next_run = match state.next_expiry() {
+            Some(next) if !failed => next.min(latest),
+            _ => latest,
+        };
+        if !state.litter_wakeup().sleep_until(next_run, || state.now()) {
+            break;
+        }
+        // The start is compared against the clock of the state, the duration is measured in real
+        // time.
+        let start = state.now();
+        let stopwatch = Instant::now();
+        // Catch panics, so a single failing run does not stop litter collection for good.
+        // Attention: This only works for builds which unwind. Release builds are configured with
+        // `panic = 'abort'` and terminate the process instead.
+        let result = catch_unwind(AssertUnwindSafe(&remove_expired));
+        state.record_litter_collection(Run {
+            start,
+            duration: stopwatch.elapsed(),
+            num_removed: result.as_ref().ok().copied(),
+        });
+        failed = result.is_err();
+        match result {
+            Ok(0) => debug!("Litter collection did not find any expired leases."),
+            Ok(num_removed) => warn!("Litter collection removed {} expired leases", num_removed),
+            Err(_) => error!("Litter collection panicked. Retrying next interval."),
+        }
+    }
+}
+
+/// Outcome of the last litter collection run
+#[derive(Serialize)]
+struct LastRun {
+    /// Seconds passed since the run started.
+    age_sec: f64,
+    /// Time it took to remove the expired peers in seconds.
+    duration_sec: f64,
+    /// Number of removed peers. `null` if the run panicked.
+    removed: Option<usize>,
+}
+
+/// Response body of the `/litter` route
+#[derive(Serialize)]
+struct LitterCollectionInfo {
+    /// Configured interval in seconds. `null` if the litter collection is not running.
+    interval_sec: Option<f64>,
+    /// `true` if the litter collection did not run for several intervals.
+    stale: bool,
+    last_run: Option<LastRun>,
+    /// Seconds until the next peer expires. Negative if it is already overdue. `null` if there are
+    /// no peers which expire.
+    next_expiry_sec: Option<f64>,
+}
+
+/// Information about the last run of the litter collection.
+#[get("/litter")]
+async fn litter(state: Data<State>) -> Json<LitterCollectionInfo> {
+    let status = state.litter_collection_status();
+    let now = state.now();
+    Json(LitterCollectionInfo {
+        interval_sec: status
+            .started
+            .map(|(interval, _started)| interval.as_secs_f64()),
+        stale: status.is_stale(now),
+        last_run: status.last_run.map(|run| LastRun {
+            age_sec: now.saturating_duration_since(run.start).as_secs_f64(),
+            duration_sec: run.duration.as_secs_f64(),
+            removed: run.num_removed,
+        }),
+        next_expiry_sec: state.next_expiry().map(|next| {
+            if next > now {
+                next.duration_since(now).as_secs_f64()
             } else {
-                let num_removed = state.remove_expired();
-                if num_removed == 0 {
-                    debug!("Litter collection did not find any expired leases.")
-                } else {
-                    warn!("Litter collection removed {} expired leases", num_removed);
-                }
+                -now.duration_since(next).as_secs_f64()
             }
-        }
-    });
-    LitterCollection { stopped, handle }
+        }),
+    })
 }
 
-#[cfg(Debug)]
+#[cfg(debug_assertions)]
 impl Drop for LitterCollection {
     fn drop(&mut self) {
+        let plan = &self.state.litter_wakeup().plan;
         assert!(
-            *self.stopped.0.lock().unwrap(),
+            poison::lock(plan, "the plan of the litter collection").stopped,
             "Litter Collection has not been stopped before the end of its lifetime."
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn not_running_is_never_stale() {
+        let status = Status::default();
+        assert!(!status.is_stale(Instant::now()));
+    }
+
+    #[test]
+    fn stale_after_missing_several_intervals() {
+        let interval = Duration::from_secs(1);
+        let start = Instant::now();
+        let mut status = Status::default();
+        status.start(interval, start);
+        assert!(!status.is_stale(start + Duration::from_secs(2)));
+        assert!(status.is_stale(start + Duration::from_secs(4)));
+
+        // A run resets the age
+        status.record(Run {
+            start: start + Duration::from_secs(3),
+            duration: Duration::from_millis(1),
+            num_removed: Some(0),
+        });
+        assert!(!status.is_stale(start + Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn wake_up_for_earlier_expiry() {
+        let wakeup = Arc::new(Wakeup::default());
+        let start = Instant::now();
+        // Announced while running, before going to sleep
+        wakeup.expires_at(start + Duration::from_millis(10));
+        assert!(wakeup.sleep_until(start + Duration::from_secs(60), Instant::now));
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        // Announced while sleeping
+        let sleeper = wakeup.clone();
+        let start = Instant::now();
+        let handle =
+            spawn(move || sleeper.sleep_until(start + Duration::from_secs(60), Instant::now));
+        while wakeup.plan.lock().unwrap().next_run.is_none() {
+            std::thread::yield_now();
+        }
+        wakeup.expires_at(Instant::now() + Duration::from_millis(10));
+        assert!(handle.join().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn stop_cancels_sleep() {
+        let wakeup = Arc::new(Wakeup::default());
+        let sleeper = wakeup.clone();
+        let handle = spawn(move || {
+            sleeper.sleep_until(Instant::now() + Duration::from_secs(60), Instant::now)
+        });
+        wakeup.stop();
+        assert!(!handle.join().unwrap());
+    }
+
+    #[test]
+    fn failing_run_does_not_spin() {
+        let state = Arc::new(State::new(Default::default()));
+        // Overdue right away, so a retry would not wait at all.
+        state
+            .create_peer(None, Duration::from_secs(0), None, 0)
+            .unwrap();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let handle = {
+            let (state, runs) = (state.clone(), runs.clone());
+            spawn(move || {
+                run(&state, Duration::from_millis(100), || {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    panic!("Failing litter collection run");
+                })
+            })
+        };
+        std::thread::sleep(Duration::from_millis(350));
+        state.litter_wakeup().stop();
+        handle.join().unwrap();
+        // One run for the overdue peer, and one each interval afterwards.
+        let runs = runs.load(Ordering::SeqCst);
+        assert!((1..=5).contains(&runs), "{} runs", runs);
+        assert_eq!(
+            state
+                .litter_collection_status()
+                .last_run
+                .unwrap()
+                .num_removed,
+            None
+        );
+    }
+
+    #[test]
+    fn zero_interval_disables_litter_collection() {
+        let state = Arc::new(State::new(Default::default()));
+        let lc = start(state.clone(), Duration::from_secs(0));
+        assert!(lc.handle.is_none());
+        // Not running, so it can not be stale either.
+        assert!(state.litter_collection_status().age(state.now()).is_none());
+        lc.stop();
+    }
+}
@@ -0,0 +1,55 @@
+//! Counters and histograms about locks changing hands. Updated by `Leases`, which knows when this
+//! happens. Kept apart from the gauges in `state`, which are computed in bulk for each request to
+//! `/metrics`.
+
+use lazy_static::lazy_static;
+use prometheus::{HistogramVec, IntCounterVec};
+
+lazy_static! {
+    /// Pending locks promoted to acquired, by the priority of their peer. Low priorities may
+    /// starve, if higher ones keep the semaphore busy.
+    pub static ref PROMOTIONS: IntCounterVec = register_int_counter_vec!(
+        "throttle_promotions_total",
+        "Number of pending locks which have been acquired, by priority of the peer.",
+        &["priority"]
+    )
+    .expect("Error registering throttle_promotions_total metric");
+    /// Time from requesting a lock until it is acquired. Locks acquired right away are observed
+    /// with zero.
+    pub static ref ACQUISITION_WAIT: HistogramVec = register_histogram_vec!(
+        "throttle_acquisition_wait_seconds",
+        "Time pending locks waited until they were acquired.",
+        &["semaphore"],
+        vec![0.01, 0.1, 0.5, 1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0]
+    )
+    .expect("Error registering throttle_acquisition_wait_seconds metric");
+    /// Pending locks, those peers expired before they could be acquired. Kept apart from
+    /// `ACQUISITION_WAIT`, as these never have been acquired.
+    pub static ref EXPIRED_PENDING: IntCounterVec = register_int_counter_vec!(
+        "throttle_expired_pending_total",
+        "Number of pending locks, those peers expired before acquiring them.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_expired_pending_total metric");
+    /// Locks acquired, right away or after waiting. Rates tell how busy a semaphore is.
+    pub static ref ACQUIRES: IntCounterVec = register_int_counter_vec!(
+        "throttle_acquires_total",
+        "Number of locks which have been acquired.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_acquires_total metric");
+    /// Acquired locks freed by the litter collection. A spike usually means crashing clients.
+    pub static ref EXPIRATIONS: IntCounterVec = register_int_counter_vec!(
+        "throttle_expirations_total",
+        "Number of acquired locks, those peers expired.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_expirations_total metric");
+    /// Locks brought back by revenants, i.e. peers restored after they expired.
+    pub static ref REVENANTS: IntCounterVec = register_int_counter_vec!(
+        "throttle_revenants_total",
+        "Number of locks claimed by revenants.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_revenants_total metric");
+}
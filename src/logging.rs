@@ -1,8 +1,113 @@
-use env_logger;
-use failure::{Error, ResultExt};
-use gelf;
-use log;
-use serde::Deserialize;
+use crate::{change_log, encoding::Body, leases::PeerId, state::State};
+use actix_web::{
+    get, put,
+    web::{Data, Json},
+    HttpRequest,
+};
+use failure::{format_err, Error, ResultExt};
+use gelf::{self, Backend, UdpBackend, WireMessage};
+use lazy_static::lazy_static;
+use log::{self, warn, LevelFilter};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    iter::once,
+    mem,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Like `log!`, but attaches structured `Fields` to the record. E.g.
+/// `log_peer!(Level::Debug, Fields::peer(peer_id), "Created new peer {}.", peer_id)`.
+macro_rules! log_peer {
+    ($level:expr, $fields:expr, $($arg:tt)+) => {
+        if ::log::log_enabled!($level) {
+            let _attached = $crate::logging::attach($fields);
+            ::log::log!($level, $($arg)+);
+        }
+    };
+}
+
+/// Structured fields of a log record concerning a peer. GELF receives them as additional fields,
+/// e.g. `_peer_id`, so a single query finds every record about a peer, whatever the message says.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Fields<'a> {
+    peer_id: Option<PeerId>,
+    semaphore: Option<&'a str>,
+    amount: Option<i64>,
+}
+
+impl<'a> Fields<'a> {
+    pub fn peer(peer_id: PeerId) -> Self {
+        Fields {
+            peer_id: Some(peer_id),
+            ..Fields::default()
+        }
+    }
+
+    pub fn semaphore(self, semaphore: &'a str) -> Self {
+        Fields {
+            semaphore: Some(semaphore),
+            ..self
+        }
+    }
+
+    pub fn amount(self, amount: i64) -> Self {
+        Fields {
+            amount: Some(amount),
+            ..self
+        }
+    }
+
+    fn pairs(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = Vec::new();
+        if let Some(peer_id) = self.peer_id {
+            pairs.push(("peer_id", peer_id.to_string()));
+        }
+        if let Some(semaphore) = self.semaphore {
+            pairs.push(("semaphore", semaphore.to_owned()));
+        }
+        if let Some(amount) = self.amount {
+            pairs.push(("amount", amount.to_string()));
+        }
+        pairs
+    }
+}
+
+thread_local! {
+    /// Fields of the record currently logged on this thread. `log` 0.4 has no stable way to carry
+    /// them in the record itself.
+    static FIELDS: RefCell<Vec<(&'static str, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Attaches `fields` to every record logged on this thread, until the returned guard is dropped.
+/// Use `log_peer!` instead.
+pub fn attach(fields: Fields) -> Attached {
+    Attached {
+        previous: FIELDS.with(|current| current.replace(fields.pairs())),
+    }
+}
+
+/// Restores the fields attached before, once dropped.
+pub struct Attached {
+    previous: Vec<(&'static str, String)>,
+}
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        FIELDS.with(|current| current.replace(mem::take(&mut self.previous)));
+    }
+}
+
+/// Fields attached to the record currently logged on this thread.
+fn current_fields() -> Vec<(&'static str, String)> {
+    FIELDS.with(|current| current.borrow().clone())
+}
 
 /// Controls logging behaviour of throttle. Set via the configuration file
 #[derive(Deserialize, Default, PartialEq, Eq, Clone, Debug)]
@@ -11,6 +116,77 @@ pub struct LoggingConfig {
     pub gelf: Option<GelfConfig>,
     #[serde(default)]
     pub stderr: StdErrConfig,
+    /// Configures logging into a file, which is rotated once it grows too large.
+    pub file: Option<FileConfig>,
+    #[serde(default)]
+    pub access: AccessLogConfig,
+    /// Levels by module path, e.g. `throttle::state = "DEBUG"`. Applied on top of the level of
+    /// each backend. Also applies to the modules within.
+    #[serde(default)]
+    pub filters: BTreeMap<String, LevelFilter>,
+}
+
+impl LoggingConfig {
+    /// Problems which would keep the configured logging from working, e.g. an empty host.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Some(gelf) = &self.gelf {
+            if gelf.host.trim().is_empty() {
+                problems.push(String::from("logging.gelf.host must not be empty."));
+            }
+            if gelf.name.trim().is_empty() {
+                problems.push(String::from("logging.gelf.name must not be empty."));
+            }
+        }
+        if let Some(file) = &self.file {
+            if file.path.as_os_str().is_empty() {
+                problems.push(String::from("logging.file.path must not be empty."));
+            }
+            if file.max_size == 0 {
+                problems.push(String::from(
+                    "logging.file.max_size must be at least 1 byte.",
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Findings which do not keep logging from working, e.g. filters which never match.
+    pub fn warnings(&self) -> Vec<String> {
+        self.filters
+            .keys()
+            .filter(|target| !is_module_path(target))
+            .map(|target| {
+                format!(
+                    "logging.filters: '{}' is not a module path like 'throttle::state'. It never \
+                    matches.",
+                    target
+                )
+            })
+            .collect()
+    }
+}
+
+/// `true` if `target` looks like a module path, e.g. `actix_web::middleware`.
+fn is_module_path(target: &str) -> bool {
+    target.split("::").all(|segment| {
+        let mut chars = segment.chars();
+        matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+/// Level of the longest entry in `levels` matching `target`. Entries match their own target and
+/// the modules within.
+fn level_for(levels: &BTreeMap<String, LevelFilter>, target: &str) -> Option<LevelFilter> {
+    levels
+        .iter()
+        .filter(|(prefix, _)| {
+            target.starts_with(prefix.as_str())
+                && (target.len() == prefix.len() || target[prefix.len()..].starts_with("::"))
+        })
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, level)| *level)
 }
 
 #[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
@@ -23,6 +199,105 @@ pub struct GelfConfig {
     level: log::LevelFilter,
     /// E.g. "12201"
     port: u16,
+    /// Host is resolved again after this interval passed, so we notice it moving to a different
+    /// address. E.g. "5min".
+    #[serde(
+        with = "humantime_serde",
+        default = "GelfConfig::resolve_interval_default"
+    )]
+    resolve_interval: Duration,
+    /// Host is resolved again after this many consecutive failures to send a message.
+    #[serde(default = "GelfConfig::resolve_after_failures_default")]
+    resolve_after_failures: u32,
+}
+
+impl GelfConfig {
+    fn resolve_interval_default() -> Duration {
+        Duration::from_secs(300) // 5min
+    }
+
+    fn resolve_after_failures_default() -> u32 {
+        3
+    }
+}
+
+/// Address of the backend and when we resolved it
+struct Resolved {
+    backend: UdpBackend<String>,
+    at: Instant,
+    consecutive_failures: u32,
+    /// `true` once resolving failed, until it succeeds again. We only warn about the first failure,
+    /// as every record sent while the name can not be resolved would cause another one.
+    unresolvable: bool,
+}
+
+/// Sends messages via UDP, like `gelf::UdpBackend`, but resolves the hostname of the destination
+/// again in regular intervals, or after several consecutive failures. This way we follow the
+/// destination then it moves behind its DNS name.
+struct ResolvingUdpBackend {
+    /// Destination, e.g. "my_graylog_instance.cloud:12201"
+    destination: String,
+    resolve_interval: Duration,
+    resolve_after_failures: u32,
+    resolved: Mutex<Resolved>,
+}
+
+impl ResolvingUdpBackend {
+    /// Resolves the destination immediately, so a wrong configuration is reported at startup.
+    fn new(config: &GelfConfig) -> Result<Self, Error> {
+        let destination = format!("{}:{}", config.host, config.port);
+        let backend = UdpBackend::new(destination.clone())
+            .context("Error creating GELF UDP logging backend")?;
+        Ok(ResolvingUdpBackend {
+            destination,
+            resolve_interval: config.resolve_interval,
+            resolve_after_failures: config.resolve_after_failures,
+            resolved: Mutex::new(Resolved {
+                backend,
+                at: Instant::now(),
+                consecutive_failures: 0,
+                unresolvable: false,
+            }),
+        })
+    }
+}
+
+impl Backend for ResolvingUdpBackend {
+    fn log_message(&self, msg: WireMessage) -> gelf::Result<()> {
+        let mut resolved = self.resolved.lock().unwrap();
+        let mut failure = None;
+        if resolved.at.elapsed() >= self.resolve_interval
+            || resolved.consecutive_failures >= self.resolve_after_failures
+        {
+            // Keep using the old address if resolving fails. Maybe it is still good.
+            match UdpBackend::new(self.destination.clone()) {
+                Ok(backend) => {
+                    resolved.backend = backend;
+                    resolved.consecutive_failures = 0;
+                    resolved.unresolvable = false;
+                }
+                Err(e) => {
+                    if !mem::replace(&mut resolved.unresolvable, true) {
+                        failure = Some(e);
+                    }
+                }
+            }
+            resolved.at = Instant::now();
+        }
+        let result = resolved.backend.log_message(msg);
+        if result.is_err() {
+            resolved.consecutive_failures += 1;
+        } else {
+            resolved.consecutive_failures = 0;
+        }
+        // The warning ends up in this very backend, so release the lock first. It does not warn
+        // again, since `unresolvable` is already set.
+        drop(resolved);
+        if let Some(e) = failure {
+            warn!("Failed to resolve {}: {}", self.destination, e);
+        }
+        result
+    }
 }
 
 #[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
@@ -39,24 +314,620 @@ impl Default for StdErrConfig {
     }
 }
 
-/// Initialize GELF logger if `logging_config.json` is found in the working directory.
-pub fn init(config: &LoggingConfig) -> Result<(), Error> {
+/// Configures the access log, one record for each request.
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct AccessLogConfig {
+    /// Default is `true`. Records are logged at info level with target `throttle::access`.
+    #[serde(default = "AccessLogConfig::enabled_default")]
+    pub enabled: bool,
+    /// Requests to these paths are not logged, e.g. because they are scraped frequently.
+    #[serde(default = "AccessLogConfig::exclude_default")]
+    pub exclude: Vec<String>,
+}
+
+impl AccessLogConfig {
+    fn enabled_default() -> bool {
+        true
+    }
+
+    fn exclude_default() -> Vec<String> {
+        vec![String::from("/metrics"), String::from("/health")]
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig {
+            enabled: AccessLogConfig::enabled_default(),
+            exclude: AccessLogConfig::exclude_default(),
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FileConfig {
+    /// Log records are appended to this file. Missing parent directories are created.
+    pub path: PathBuf,
+    /// E.g. "INFO" or "DEBUG"
+    #[serde(default = "FileConfig::level_default")]
+    level: LevelFilter,
+    /// The file is rotated before it grows beyond this many bytes.
+    #[serde(default = "FileConfig::max_size_default")]
+    max_size: u64,
+    /// Number of rotated files to keep. They are named like the log file, with `.1` (newest) to
+    /// `.<rotated_files>` (oldest) appended. `0` discards the old content on rotation.
+    #[serde(default = "FileConfig::rotated_files_default")]
+    rotated_files: u32,
+}
+
+impl FileConfig {
+    fn level_default() -> LevelFilter {
+        LevelFilter::Info
+    }
+
+    fn max_size_default() -> u64 {
+        10 * 1024 * 1024 // 10 MiB
+    }
+
+    fn rotated_files_default() -> u32 {
+        5
+    }
+}
+
+/// Log file we currently write to.
+struct Current {
+    writer: BufWriter<File>,
+    /// Size of the file in bytes, including everything still in the buffer.
+    size: u64,
+}
+
+/// Appends log records to a file. Once the file would grow beyond its maximum size, it is renamed
+/// to `<path>.1`, previously rotated files are shifted by one, and a new file is started.
+pub struct RotatingFile {
+    path: PathBuf,
+    level: LevelFilter,
+    max_size: u64,
+    rotated_files: u32,
+    current: Mutex<Current>,
+}
+
+impl RotatingFile {
+    /// Opens the log file right away, so an unwritable path is reported at startup.
+    pub fn open(config: &FileConfig) -> Result<Self, Error> {
+        if let Some(parent) = config.path.parent() {
+            fs::create_dir_all(parent).map_err(|e| {
+                format_err!("Couldn't create directory {}: {}", parent.display(), e)
+            })?;
+        }
+        let current = Current::open(&config.path)
+            .map_err(|e| format_err!("Couldn't open log file {}: {}", config.path.display(), e))?;
+        Ok(RotatingFile {
+            path: config.path.clone(),
+            level: config.level,
+            max_size: config.max_size,
+            rotated_files: config.rotated_files,
+            current: Mutex::new(current),
+        })
+    }
+
+    fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap();
+        let len = line.len() as u64;
+        // An empty file is never rotated, so a single huge line still makes it into the log.
+        if current.size != 0 && current.size + len > self.max_size {
+            current.writer.flush()?;
+            self.rotate()?;
+            *current = Current::open(&self.path)?;
+        }
+        current.writer.write_all(line.as_bytes())?;
+        current.size += len;
+        Ok(())
+    }
+
+    /// Shifts `<path>.1` to `<path>.2` and so forth, dropping the oldest one, then moves the log
+    /// file itself to `<path>.1`.
+    fn rotate(&self) -> io::Result<()> {
+        if self.rotated_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for n in (1..self.rotated_files).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        name.into()
+    }
+}
+
+impl Current {
+    fn open(path: &Path) -> io::Result<Current> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Current {
+            writer: BufWriter::new(file),
+            size,
+        })
+    }
+}
+
+impl log::Log for RotatingFile {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        let line = format!(
+            "[{} {:<5} {}] {}\n",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        // There is nowhere else to log to, if writing the log fails.
+        if let Err(e) = self.write_line(&line) {
+            eprintln!("Failed to write to log file {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn flush(&self) {
+        if let Err(e) = self.current.lock().unwrap().writer.flush() {
+            eprintln!("Failed to flush log file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Sends records to GELF, with their `Fields` as additional fields.
+struct Gelf(gelf::Logger);
+
+impl log::Log for Gelf {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let mut message = gelf::Message::from(record);
+        for (key, value) in current_fields() {
+            // Only fails for reserved keys, like `id`.
+            let _ = message.set_metadata(key, value);
+        }
+        self.0.log_message(message);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Log levels in effect. Levels set at runtime take precedence over the configured ones, for every
+/// backend.
+#[derive(Clone, Debug, Serialize)]
+pub struct LogLevels {
+    /// Most verbose level admitted by the configuration, i.e. any backend or filter.
+    configured: LevelFilter,
+    /// Set at runtime for all targets not listed in `targets`. `None` if the configured filters
+    /// apply.
+    level: Option<LevelFilter>,
+    /// Set at runtime by target, e.g. `throttle::state`. Also applies to the modules within.
+    targets: BTreeMap<String, LevelFilter>,
+}
+
+impl LogLevels {
+    /// Level set at runtime for `target`. The longest matching target wins. `None` if the
+    /// configured filters apply.
+    fn level_for(&self, target: &str) -> Option<LevelFilter> {
+        level_for(&self.targets, target).or(self.level)
+    }
+
+    /// Most verbose level any record may be logged with. `log::max_level` must not be lower.
+    fn max(&self) -> LevelFilter {
+        self.targets
+            .values()
+            .copied()
+            .chain(self.level)
+            .chain(once(self.configured))
+            .max()
+            .unwrap()
+    }
+
+    /// Sets the level for `target`, or for all targets if `None`. A level of `None` removes the one
+    /// set at runtime. Returns the previous level set at runtime.
+    fn set(&mut self, target: Option<&str>, level: Option<LevelFilter>) -> Option<LevelFilter> {
+        match (target, level) {
+            (None, level) => mem::replace(&mut self.level, level),
+            (Some(target), Some(level)) => self.targets.insert(target.to_owned(), level),
+            (Some(target), None) => self.targets.remove(target),
+        }
+    }
+}
+
+lazy_static! {
+    /// Behind a lock rather than in the installed logger, because `log` only hands out the logger
+    /// as a `&dyn Log`.
+    static ref LEVELS: RwLock<LogLevels> = RwLock::new(LogLevels {
+        configured: LevelFilter::Off,
+        level: None,
+        targets: BTreeMap::new(),
+    });
+}
+
+/// A backend, together with the filter it has been configured with.
+struct Sink {
+    /// `true` if the configuration admits records with this metadata to the backend.
+    configured: Box<dyn Fn(&log::Metadata) -> bool + Send + Sync>,
+    /// Most verbose level admitted by `configured`.
+    max_level: LevelFilter,
+    logger: Box<dyn log::Log>,
+}
+
+impl Sink {
+    fn with_level(level: LevelFilter, logger: Box<dyn log::Log>) -> Self {
+        Sink {
+            configured: Box::new(move |metadata| metadata.level() <= level),
+            max_level: level,
+            logger,
+        }
+    }
+
+    /// `true` if `metadata` passes `level`, which takes precedence over the configured level of
+    /// the backend.
+    fn admits(&self, level: Option<LevelFilter>, metadata: &log::Metadata) -> bool {
+        match level {
+            Some(level) => metadata.level() <= level,
+            None => (self.configured)(metadata),
+        }
+    }
+}
+
+/// Forwards each record to every backend admitting it. Allows logging to a file in addition to
+/// GELF or stderr, and changing levels at runtime.
+struct Loggers {
+    sinks: Vec<Sink>,
+    /// `filters` of the logging configuration
+    filters: BTreeMap<String, LevelFilter>,
+}
+
+impl Loggers {
+    /// Level for `target` applying to all backends. Levels set at runtime win over the configured
+    /// filters. `None` if each backend uses the level it has been configured with.
+    fn level_for(&self, target: &str) -> Option<LevelFilter> {
+        LEVELS
+            .read()
+            .unwrap()
+            .level_for(target)
+            .or_else(|| level_for(&self.filters, target))
+    }
+}
+
+impl log::Log for Loggers {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        let level = self.level_for(metadata.target());
+        self.sinks.iter().any(|sink| sink.admits(level, metadata))
+    }
+
+    fn log(&self, record: &log::Record) {
+        let level = self.level_for(record.target());
+        for sink in &self.sinks {
+            if sink.admits(level, record.metadata()) {
+                sink.logger.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for sink in &self.sinks {
+            sink.logger.flush();
+        }
+    }
+}
+
+/// Installs the GELF logger if configured, otherwise logs to stderr. Records are also written to
+/// `file`, if given.
+pub fn init(config: &LoggingConfig, file: Option<RotatingFile>) -> Result<(), Error> {
+    let mut sinks = Vec::new();
     if let Some(ref config) = config.gelf {
-        let backend = gelf::UdpBackend::new(format!("{}:{}", config.host, config.port))
-            .context("Error creating GELF UDP logging backend")?;
+        let backend = ResolvingUdpBackend::new(config)?;
         let mut logger =
             gelf::Logger::new(Box::new(backend)).context("Error creating GELF logger.")?;
         logger.set_hostname(config.name.as_str());
-        logger
-            .install(config.level)
-            .context("Failed to install logger")?;
+        sinks.push(Sink::with_level(config.level, Box::new(Gelf(logger))));
     } else {
         eprintln!(
             "Gelf logger config not found => Using environment logger writing to stderr instead."
         );
         let environment =
             env_logger::Env::default().filter_or("THROTTLE_LOG", config.stderr.level.as_str());
-        env_logger::from_env(environment).init();
+        // Only used to decide which records the environment asks for. The writing logger admits
+        // everything, so levels set at runtime may be more verbose.
+        let configured = env_logger::Builder::from_env(environment).build();
+        let logger = env_logger::Builder::new()
+            .filter_level(LevelFilter::Trace)
+            .build();
+        sinks.push(Sink {
+            max_level: configured.filter(),
+            configured: Box::new(move |metadata| log::Log::enabled(&configured, metadata)),
+            logger: Box::new(logger),
+        });
     }
+    if let Some(file) = file {
+        sinks.push(Sink::with_level(file.level, Box::new(file)));
+    }
+    let configured = sinks
+        .iter()
+        .map(|sink| sink.max_level)
+        .chain(config.filters.values().copied())
+        .max()
+        .unwrap_or(LevelFilter::Off);
+    let loggers = Loggers {
+        sinks,
+        filters: config.filters.clone(),
+    };
+    log::set_boxed_logger(Box::new(loggers)).context("Failed to install logger")?;
+    let mut levels = LEVELS.write().unwrap();
+    levels.configured = configured;
+    log::set_max_level(levels.max());
     Ok(())
 }
+
+/// Body of `PUT /admin/log_level`
+#[derive(Deserialize)]
+struct SetLogLevel {
+    /// E.g. "DEBUG". `null` removes the level set at runtime, so the configured filter applies
+    /// again.
+    level: Option<LevelFilter>,
+    /// E.g. `throttle::state`. If omitted, the level applies to all targets.
+    target: Option<String>,
+}
+
+/// Log levels currently in effect.
+#[get("/admin/log_level")]
+async fn log_level() -> Json<LogLevels> {
+    Json(LEVELS.read().unwrap().clone())
+}
+
+/// Changes the log level at runtime, for all targets or a single one, e.g. to debug an incident
+/// without restarting the server. Applies to every backend. The change is not persisted and lost
+/// on restart.
+#[put("/admin/log_level")]
+async fn set_log_level(
+    req: HttpRequest,
+    body: Body<SetLogLevel>,
+    state: Data<State>,
+) -> Json<LogLevels> {
+    let target = body.target.as_deref();
+    let (old, levels) = {
+        let mut levels = LEVELS.write().unwrap();
+        let old = levels.set(target, body.level);
+        log::set_max_level(levels.max());
+        (old, levels.clone())
+    };
+    // Don't hold the lock while logging the change, the logger needs it.
+    change_log::record(
+        &req,
+        &state,
+        "set_log_level",
+        target.unwrap_or("*"),
+        json!(old),
+        json!(body.level),
+    );
+    Json(levels)
+}
+
+/// Writes out log records which are still buffered, e.g. before the process exits.
+pub fn flush() {
+    log::logger().flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, Log};
+    use std::sync::Arc;
+
+    /// Remembers the messages of all records it is handed.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl Log for Capture {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn gelf_resolve_defaults() {
+        let cfg = "name = \"MyThrottleServer.net\"\n\
+                   host = \"my_graylog_instance.cloud\"\n\
+                   port = 12201\n\
+                   level = \"DEBUG\"\n\
+                  ";
+        let actual: GelfConfig = toml::from_str(cfg).unwrap();
+        assert_eq!(actual.resolve_interval, Duration::from_secs(300));
+        assert_eq!(actual.resolve_after_failures, 3);
+    }
+
+    #[test]
+    fn gelf_resolve_interval() {
+        let cfg = "name = \"MyThrottleServer.net\"\n\
+                   host = \"my_graylog_instance.cloud\"\n\
+                   port = 12201\n\
+                   level = \"DEBUG\"\n\
+                   resolve_interval = \"30s\"\n\
+                   resolve_after_failures = 5\n\
+                  ";
+        let actual: GelfConfig = toml::from_str(cfg).unwrap();
+        assert_eq!(actual.resolve_interval, Duration::from_secs(30));
+        assert_eq!(actual.resolve_after_failures, 5);
+    }
+
+    /// Keeps sending to the old address, if the name can no longer be resolved. Does not deadlock
+    /// warning about it.
+    #[test]
+    fn unresolvable_gelf_destination() {
+        let backend = ResolvingUdpBackend {
+            destination: String::from("throttle.invalid:12201"),
+            resolve_interval: Duration::from_secs(300),
+            resolve_after_failures: 0,
+            resolved: Mutex::new(Resolved {
+                backend: UdpBackend::new(String::from("127.0.0.1:12201")).unwrap(),
+                at: Instant::now(),
+                consecutive_failures: 0,
+                unresolvable: false,
+            }),
+        };
+        let logger = gelf::Logger::new(Box::new(gelf::NullBackend::new())).unwrap();
+        for _ in 0..2 {
+            let message = WireMessage::new(gelf::Message::new(String::from("Hello")), &logger);
+            backend.log_message(message).unwrap();
+        }
+        assert!(backend.resolved.lock().unwrap().unresolvable);
+    }
+
+    #[test]
+    fn runtime_level_by_target() {
+        let mut levels = LogLevels {
+            configured: LevelFilter::Warn,
+            level: None,
+            targets: BTreeMap::new(),
+        };
+        assert_eq!(levels.level_for("throttle::state"), None);
+
+        levels.set(Some("throttle"), Some(LevelFilter::Info));
+        levels.set(Some("throttle::state"), Some(LevelFilter::Debug));
+        levels.set(None, Some(LevelFilter::Error));
+        assert_eq!(
+            levels.level_for("throttle::state"),
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(
+            levels.level_for("throttle::state::x"),
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(
+            levels.level_for("throttle::leases"),
+            Some(LevelFilter::Info)
+        );
+        // Not a module of `throttle`
+        assert_eq!(levels.level_for("throttle_x"), Some(LevelFilter::Error));
+        assert_eq!(levels.max(), LevelFilter::Debug);
+
+        assert_eq!(
+            levels.set(Some("throttle::state"), None),
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(levels.set(None, None), Some(LevelFilter::Error));
+        assert_eq!(levels.level_for("actix_web"), None);
+        assert_eq!(levels.max(), LevelFilter::Info);
+    }
+
+    #[test]
+    fn filter_by_module() {
+        let capture = Capture::default();
+        let mut filters = BTreeMap::new();
+        filters.insert(String::from("throttle::state"), LevelFilter::Debug);
+        filters.insert(String::from("actix_web"), LevelFilter::Warn);
+        let loggers = Loggers {
+            sinks: vec![Sink::with_level(
+                LevelFilter::Info,
+                Box::new(capture.clone()),
+            )],
+            filters,
+        };
+        let log = |target: &str, level: Level, message: &str| {
+            loggers.log(
+                &log::Record::builder()
+                    .target(target)
+                    .level(level)
+                    .args(format_args!("{}", message))
+                    .build(),
+            )
+        };
+
+        log("throttle::state", Level::Debug, "allowed debug");
+        log("throttle::leases", Level::Debug, "filtered debug");
+        log("throttle::leases", Level::Info, "allowed info");
+        log("actix_web::middleware", Level::Info, "filtered info");
+        log("actix_web::middleware", Level::Warn, "allowed warning");
+        assert_eq!(
+            *capture.0.lock().unwrap(),
+            ["allowed debug", "allowed info", "allowed warning"]
+        );
+    }
+
+    #[test]
+    fn warn_about_invalid_module_paths() {
+        let cfg = "[filters]\n\
+                   \"throttle::state\" = \"DEBUG\"\n\
+                   actix_web = \"WARN\"\n\
+                   \"throttle/state\" = \"DEBUG\"\n\
+                  ";
+        let actual: LoggingConfig = toml::from_str(cfg).unwrap();
+        assert_eq!(actual.filters.len(), 3);
+        let warnings = actual.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("'throttle/state'"));
+    }
+
+    #[test]
+    fn attach_fields() {
+        let fields = Fields::peer(PeerId::Int(42)).semaphore("A").amount(2);
+        let attached = {
+            let _attached = attach(fields);
+            current_fields()
+        };
+        assert_eq!(
+            attached,
+            [
+                ("peer_id", String::from("42")),
+                ("semaphore", String::from("A")),
+                ("amount", String::from("2"))
+            ]
+        );
+        // Records logged afterwards carry no fields.
+        assert!(current_fields().is_empty());
+    }
+
+    #[test]
+    fn file_defaults() {
+        let cfg = "path = \"/var/log/throttle/throttle.log\"\n";
+        let actual: FileConfig = toml::from_str(cfg).unwrap();
+        assert_eq!(actual.level, LevelFilter::Info);
+        assert_eq!(actual.max_size, 10 * 1024 * 1024);
+        assert_eq!(actual.rotated_files, 5);
+    }
+
+    #[test]
+    fn rotate_log_file() {
+        let dir = std::env::temp_dir().join("throttle-rotate-log-file");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("logs").join("throttle.log");
+        let config = FileConfig {
+            path: path.clone(),
+            level: LevelFilter::Info,
+            max_size: 10,
+            rotated_files: 2,
+        };
+        // Creates the missing `logs` directory.
+        let file = RotatingFile::open(&config).unwrap();
+        for line in &["one\n", "two\n", "three\n", "four\n"] {
+            file.write_line(line).unwrap();
+        }
+        log::Log::flush(&file);
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "four\n");
+        assert_eq!(fs::read_to_string(file.rotated(1)).unwrap(), "three\n");
+        assert_eq!(fs::read_to_string(file.rotated(2)).unwrap(), "one\ntwo\n");
+        assert!(!file.rotated(3).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
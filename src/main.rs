@@ -3,32 +3,61 @@
 //! ## Endpoints
 //!
 //! * `/`: Prints a plain text greeting message, so users now what kind of server is running.
-//! * `/health`: Always returns 200 ok
+//! * `/health`: Uptime and ledger size. Fails unless the ledger is accessible and the litter
+//!   collection keeps running
+//! * `/litter`: Information about the last run of the litter collection
+//! * `/admin/changes`: Log of administrative changes to the runtime configuration
+//! * `/admin/log_level`: Read and change log levels at runtime
 //! * `/metrics`: Endpoint for prometheus metrics
 //! * `/favicon`: Returns throttle Icon
+//! * `/version`: Version, git hash and features of the binary
+//! * `/config`: Configuration the server is running with
 //!
 //! Http interface for acquiring and releasing semaphores is not stable yet.
 #[macro_use]
 extern crate prometheus;
 use actix_web::{get, web, web::Data, App, HttpServer};
 use log::{info, warn};
-use std::io;
+use std::{io, path::Path};
 use structopt::StructOpt;
 
-use crate::cli::Cli;
+use crate::{
+    application_cfg::{ApplicationCfg, Finding},
+    cli::Cli,
+};
 
+mod access_log;
 mod application_cfg;
+mod auth;
+mod change_log;
 mod cli;
+mod clock;
+mod config;
+mod cors;
+mod deadline;
+mod encoding;
 mod error;
+mod events;
 mod favicon;
 mod health;
+mod id_generator;
+mod idempotency;
 mod leases;
 mod litter_collection;
+mod lock_metrics;
+#[macro_use]
 mod logging;
 mod metrics;
 mod not_found;
+mod openapi;
+mod poison;
+mod rate_limit;
+mod reload;
 mod semaphore_service;
+mod shutdown;
+mod socket;
 mod state;
+mod tls;
 mod version;
 mod wakers;
 
@@ -37,35 +66,173 @@ async fn index() -> &'static str {
     "Hello from Throttle!"
 }
 
+/// Validates the configuration at `path` without starting the server, for `--check`. Tells the
+/// findings on standard error. Returns the exit code: `1` if the server would refuse to start, `0`
+/// otherwise.
+fn check(path: &Path) -> i32 {
+    // `ApplicationCfg::init` would fall back to an empty configuration.
+    if !path.is_file() {
+        eprintln!("Error: {} not found.", path.display());
+        return 1;
+    }
+    let mut cfg = match ApplicationCfg::init(path) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+    };
+    if let Err(e) = cfg.override_semaphores_from_env() {
+        eprintln!("Error: {}", e);
+        return 1;
+    }
+    let findings = cfg.check();
+    for finding in &findings {
+        eprintln!("{}", finding);
+    }
+    let mut failed = findings.iter().any(Finding::is_error);
+    // Only worth trying, if the files exist.
+    if !failed {
+        if let Some(tls) = &cfg.tls {
+            if let Err(e) = tls::server_config(tls) {
+                eprintln!("Error: {}", e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        1
+    } else {
+        eprintln!("{} is valid.", path.display());
+        0
+    }
+}
+
 #[actix_rt::main]
 async fn main() -> io::Result<()> {
     let opt = Cli::from_args();
+    if let Some(path) = &opt.check {
+        std::process::exit(check(path));
+    }
 
-    let application_cfg = match application_cfg::ApplicationCfg::init(&opt.configuration) {
+    let mut application_cfg = match ApplicationCfg::init(&opt.configuration) {
         Ok(cfg) => cfg,
         Err(e) => {
-            eprintln!(
-                "Couldn't parse {}.cfg:\n{}",
-                opt.configuration.to_string_lossy(),
-                e
-            );
+            eprintln!("Couldn't parse configuration:\n{}", e);
+            return Ok(());
+        }
+    };
+    let overrides = match application_cfg.override_semaphores_from_env() {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            eprintln!("Couldn't apply semaphore counts from environment:\n{}", e);
             return Ok(());
         }
     };
+    // Same validation as `--check`.
+    let findings = application_cfg.check();
+    if findings.iter().any(Finding::is_error) {
+        for finding in findings.iter().filter(|finding| finding.is_error()) {
+            eprintln!("{}", finding);
+        }
+        return Ok(());
+    }
 
-    logging::init(&application_cfg.logging).unwrap_or_else(|e| {
+    // Fail early, rather than losing log records which have been asked to go into a file.
+    let log_file = match application_cfg
+        .logging
+        .file
+        .as_ref()
+        .map(logging::RotatingFile::open)
+        .transpose()
+    {
+        Ok(log_file) => log_file,
+        Err(e) => {
+            eprintln!("Couldn't set up logging to file:\n{}", e);
+            return Ok(());
+        }
+    };
+    logging::init(&application_cfg.logging, log_file).unwrap_or_else(|e| {
         eprintln!("Error during initialization of logging backend:\n{}", e);
     });
+    for (name, max) in overrides {
+        info!(
+            "Full count of semaphore '{}' set to {} by environment.",
+            name, max
+        );
+    }
+
+    // Fail early, rather than serving plain HTTP if TLS has been asked for.
+    let tls = match application_cfg
+        .tls
+        .as_ref()
+        .map(tls::server_config)
+        .transpose()
+    {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("Couldn't load TLS configuration:\n{}", e);
+            logging::flush();
+            std::process::exit(1);
+        }
+    };
+
+    let unix_socket = application_cfg
+        .listen
+        .as_deref()
+        .and_then(socket::unix_socket_path);
+    // `listen` takes precedence over the address given at the command line.
+    let endpoint = match application_cfg.listen.as_deref() {
+        Some(listen) if unix_socket.is_none() => listen.to_owned(),
+        _ => opt.endpoint(application_cfg.http.bind.as_deref()),
+    };
+    let listening = match (unix_socket, &tls) {
+        (Some(path), _) => format!("unix:{}", path.display()),
+        (None, Some(_)) => format!("https://{}", endpoint),
+        (None, None) => format!("http://{}", endpoint),
+    };
+    let workers = opt.workers.or(application_cfg.http.workers);
+    let keep_alive = application_cfg.http.keep_alive;
+    if workers == Some(0) {
+        eprintln!("At least one worker is required.");
+        logging::flush();
+        std::process::exit(1);
+    }
+    let startup = Data::new(config::Startup {
+        configuration: opt.configuration.clone(),
+        listening: listening.clone(),
+        workers,
+        keep_alive,
+        litter_collection_interval: application_cfg.litter_collection_interval,
+        default_semaphore_count: application_cfg.default_semaphore_count,
+    });
 
     info!("Hello From Throttle");
 
-    if application_cfg.semaphores.is_empty() {
-        warn!("No semaphores configured.")
+    for finding in findings {
+        if let Finding::Warning(message) = finding {
+            warn!("{}", message);
+        }
     }
 
     // We only want to use one Map of semaphores across all worker threads. To do this we wrap it in
     // `Data` which uses an `Arc` to share it between threads.
-    let state = Data::new(state::State::new(application_cfg.semaphores));
+    let mut state = state::State::new(application_cfg.semaphores);
+    if let Some(max) = application_cfg.max_expires_in {
+        state.limit_expires_in(max, application_cfg.exceeding_expires_in);
+    }
+    if let Some(rate_limit) = application_cfg.rate_limit {
+        state.limit_rate(rate_limit);
+    }
+    state.peer_ids(application_cfg.peer_ids);
+    if let Some(count) = application_cfg.default_semaphore_count {
+        info!(
+            "Unknown semaphores are created with full count {}, once requested.",
+            count
+        );
+        state.auto_create_semaphores(count);
+    }
+    let state = Data::new(state);
 
     // Copy a reference to state, before moving it into the closure. We need it later to start the
     // litter collection.
@@ -75,31 +242,123 @@ async fn main() -> io::Result<()> {
     // unknown resource. I.e. We would see nothing instead of `num_404 0` in the metrics route,
     // before the first request to an unknown resource.
     not_found::initialize_metrics();
+    wakers::initialize_metrics();
+    version::initialize_metrics();
 
-    let server_terminated = HttpServer::new(move || {
+    let http_cfg = application_cfg.http;
+    let auth = auth::Authentication::new(&http_cfg);
+    let access_log = access_log::AccessLog::new(&application_cfg.logging.access);
+    let server = HttpServer::new(move || {
+        let auth = auth.clone();
+        let access_log = access_log.clone();
         App::new()
+            .wrap_fn(move |req, srv| auth.check(req, srv))
+            .wrap(cors::cors(&http_cfg))
+            .wrap_fn(deadline::check)
+            // Outermost, so rejected requests and the time spent in other middleware are logged.
+            .wrap_fn(move |req, srv| access_log.log(req, srv))
             .app_data(state.clone())
+            .app_data(startup.clone())
             .service(index)
             .service(health::health)
+            .service(litter_collection::litter)
+            .service(change_log::changes)
+            .service(logging::log_level)
+            .service(logging::set_log_level)
             .service(metrics::metrics)
             .service(favicon::favicon)
             .service(version::get_version)
+            .service(config::config)
+            .service(openapi::openapi)
             .service(semaphore_service::new_peer)
+            .service(semaphore_service::acquire_new_peer)
+            .service(semaphore_service::try_acquire)
             .service(semaphore_service::acquire)
             .service(semaphore_service::remainder)
+            .service(semaphore_service::remainders)
+            .service(semaphore_service::list_semaphores)
+            .service(semaphore_service::get_semaphore)
+            .service(semaphore_service::set_max)
+            .service(semaphore_service::add_semaphore)
+            .service(semaphore_service::remove_semaphore)
+            .service(semaphore_service::release_semaphore)
+            .service(semaphore_service::freeze)
+            .service(events::events)
+            .service(semaphore_service::unfreeze)
             .service(semaphore_service::release)
             .service(semaphore_service::restore)
             .service(semaphore_service::remove_expired)
             .service(semaphore_service::put_peer)
+            .service(semaphore_service::list_peers)
+            // Before `get_peer`, so `expiring` is not taken for a peer id.
+            .service(semaphore_service::list_expiring)
+            .service(semaphore_service::get_peer)
             .service(semaphore_service::is_acquired)
             .service(semaphore_service::release_lock)
+            .service(semaphore_service::change_amount)
+            .service(semaphore_service::split)
             .default_service(
                 // 404 for GET requests
                 web::resource("").route(web::get().to(not_found::not_found)),
             )
-    })
-    .bind(&opt.endpoint())?
-    .run();
+    });
+    let server = match workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    let server = match keep_alive {
+        // Zero disables keep alive.
+        Some(keep_alive) => server.keep_alive(keep_alive.as_secs() as usize),
+        None => server,
+    };
+    let socket_mode = application_cfg.socket_mode;
+    let bind = || -> io::Result<_> {
+        match (unix_socket, tls) {
+            #[cfg(unix)]
+            (Some(path), _) => {
+                socket::remove_stale(path)?;
+                let server = server.bind_uds(path)?;
+                socket::set_mode(path, socket_mode)?;
+                Ok(server)
+            }
+            #[cfg(not(unix))]
+            (Some(_), _) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Unix domain sockets are only supported on unix.",
+            )),
+            (None, Some(config)) => server.bind_rustls(&endpoint, config),
+            (None, None) => server.bind(&endpoint),
+        }
+    };
+    // E.g. the port is already in use.
+    let server = match bind() {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Couldn't listen on {}:\n{}", listening, e);
+            logging::flush();
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "Listening on {} with {} workers and keep alive {}.",
+        listening,
+        workers.map_or_else(|| String::from("one per core"), |n| n.to_string()),
+        keep_alive.map_or_else(|| String::from("default"), |d| format!("{:?}", d))
+    );
+    let server_terminated = server
+        // We handle signals ourselves, to answer blocked requests before stopping.
+        .disable_signals()
+        .shutdown_timeout(application_cfg.shutdown_timeout.as_secs())
+        .run();
+    actix_rt::spawn(shutdown::on_signal(
+        server_terminated.clone(),
+        state_ref_lc.clone().into_inner(),
+    ));
+    #[cfg(unix)]
+    actix_rt::spawn(reload::on_hangup(
+        opt.configuration.clone(),
+        state_ref_lc.clone().into_inner(),
+    ));
 
     // Removes expired peers asynchrounously. We start litter collection after the server. Would we
     // start `lc` before the `.run` method, the ?-operator after `.bind` might early return and
@@ -114,5 +373,8 @@ async fn main() -> io::Result<()> {
     // Stop litter collection.
     lc.stop();
 
+    // Don't lose the last records before exiting.
+    logging::flush();
+
     result
 }
@@ -0,0 +1,103 @@
+//! OpenAPI 3 description of the HTTP API, served at `/openapi.json`. Helps authors of clients in
+//! other languages to get the shapes of bodies and error answers right. The document is maintained
+//! by hand in `openapi.json`. A test makes sure every route is documented.
+
+use actix_web::{get, HttpResponse};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use version::version;
+
+/// Statically linked into the binary, like the favicon.
+const OPENAPI: &str = include_str!("openapi.json");
+
+lazy_static! {
+    /// The document, with the version of this binary filled in.
+    static ref DOCUMENT: String = {
+        let mut document: Value =
+            serde_json::from_str(OPENAPI).expect("openapi.json must be valid JSON");
+        document["info"]["version"] = json!(version!());
+        document.to_string()
+    };
+}
+
+#[get("/openapi.json")]
+async fn openapi() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(DOCUMENT.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Modules defining routes served by `main`.
+    const SOURCES: &[&str] = &[
+        include_str!("main.rs"),
+        include_str!("change_log.rs"),
+        include_str!("config.rs"),
+        include_str!("events.rs"),
+        include_str!("favicon.rs"),
+        include_str!("health.rs"),
+        include_str!("litter_collection.rs"),
+        include_str!("logging.rs"),
+        include_str!("metrics.rs"),
+        include_str!("openapi.rs"),
+        include_str!("semaphore_service.rs"),
+        include_str!("version.rs"),
+    ];
+
+    /// Method and path of every route attribute in `source`, e.g. `("get", "/peers/{id}")`. Routes
+    /// defined within tests are skipped.
+    fn routes(source: &str) -> Vec<(&'static str, String)> {
+        let source = source.split("#[cfg(test)]").next().unwrap();
+        let mut routes = Vec::new();
+        for line in source.lines() {
+            for &method in &["get", "post", "put", "patch", "delete"] {
+                let attribute = format!("#[{}(\"", method);
+                let line = line.trim();
+                if line.starts_with(&attribute) {
+                    let path = &line[attribute.len()..];
+                    let end = path.find('"').unwrap();
+                    routes.push((method, path[..end].to_owned()));
+                }
+            }
+        }
+        routes
+    }
+
+    #[test]
+    fn every_route_is_documented() {
+        let document: Value = serde_json::from_str(&DOCUMENT).unwrap();
+        let routes: Vec<_> = SOURCES.iter().flat_map(|source| routes(source)).collect();
+        // Guard against the scan silently finding nothing.
+        assert!(routes.len() > 30);
+        for (method, path) in routes {
+            assert!(
+                document["paths"][&path][method].is_object(),
+                "{} {} is missing in openapi.json",
+                method.to_uppercase(),
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn references_resolve() {
+        let document: Value = serde_json::from_str(&DOCUMENT).unwrap();
+        let mut pending = vec![&document];
+        while let Some(value) = pending.pop() {
+            match value {
+                Value::Object(map) => {
+                    if let Some(Value::String(reference)) = map.get("$ref") {
+                        let pointer = &reference[1..];
+                        assert!(document.pointer(pointer).is_some(), "{}", reference);
+                    }
+                    pending.extend(map.values());
+                }
+                Value::Array(values) => pending.extend(values),
+                _ => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Access to locks regardless of poisoning. A panic while holding a lock poisons it, and every
+//! later attempt to lock it fails. Rather than failing every following request until a restart, we
+//! carry on with the data as the panic left it. The poisoning is logged once and cleared, so
+//! operators learn about the panic, but `/health` does not fail forever.
+
+use log::error;
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Exclusive access to `mutex`. `name` tells operators which lock a panic poisoned.
+pub fn lock<'a, T>(mutex: &'a Mutex<T>, name: &str) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        error!(
+            "Lock of {} has been poisoned by a panic. Continuing with its last state.",
+            name
+        );
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Shared access to `rw_lock`. `name` tells operators which lock a panic poisoned.
+pub fn read<'a, T>(rw_lock: &'a RwLock<T>, name: &str) -> RwLockReadGuard<'a, T> {
+    rw_lock.read().unwrap_or_else(|poisoned| {
+        error!(
+            "Lock of {} has been poisoned by a panic. Continuing with its last state.",
+            name
+        );
+        rw_lock.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Exclusive access to `rw_lock`. `name` tells operators which lock a panic poisoned.
+pub fn write<'a, T>(rw_lock: &'a RwLock<T>, name: &str) -> RwLockWriteGuard<'a, T> {
+    rw_lock.write().unwrap_or_else(|poisoned| {
+        error!(
+            "Lock of {} has been poisoned by a panic. Continuing with its last state.",
+            name
+        );
+        rw_lock.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread::spawn};
+
+    #[test]
+    fn clear_poisoning() {
+        let mutex = Arc::new(Mutex::new(1));
+        let poisoner = mutex.clone();
+        let result = spawn(move || {
+            let mut value = poisoner.lock().unwrap();
+            *value = 2;
+            panic!("Panic while holding the lock");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(mutex.is_poisoned());
+
+        assert_eq!(*lock(&mutex, "test"), 2);
+        assert!(!mutex.is_poisoned());
+    }
+}
@@ -0,0 +1,113 @@
+//! Token bucket rate limiting of requests acquiring locks, keyed by client. Keeps a single client
+//! in a tight retry loop from starving everyone else through contention on the ledger. Releasing
+//! locks and heartbeats are never limited, so clients can always clean up.
+
+use crate::poison;
+use lazy_static::lazy_static;
+use prometheus::IntCounter;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    static ref RATE_LIMITED: IntCounter = register_int_counter!(
+        "throttle_rate_limited_total",
+        "Number of requests rejected, because the client exceeded its rate limit."
+    )
+    .expect("Error registering throttle_rate_limited_total metric");
+}
+
+/// `[rate_limit]` section of the configuration file
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitCfg {
+    /// Sustained number of requests per second and client
+    pub per_second: u32,
+    /// Number of requests a client may issue in a burst, after being idle.
+    pub burst: u32,
+}
+
+/// Tokens left for a single client
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    /// Tokens have last been refilled at this instant.
+    refilled: Instant,
+}
+
+/// A token bucket for each client
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Tokens refilled per second
+    per_second: f64,
+    /// Capacity of each bucket
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(cfg: RateLimitCfg) -> Self {
+        RateLimiter {
+            per_second: cfg.per_second.into(),
+            burst: cfg.burst.into(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the bucket of `client`. If it is empty, returns the time until the next
+    /// token is available.
+    pub fn check(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = poison::lock(&self.buckets, "the rate limit buckets");
+        let bucket = buckets.entry(client.to_owned()).or_insert(Bucket {
+            tokens: self.burst,
+            refilled: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.refilled = now;
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            Ok(())
+        } else {
+            RATE_LIMITED.inc();
+            let missing = 1. - bucket.tokens;
+            Err(Duration::from_secs_f64(missing / self.per_second))
+        }
+    }
+
+    /// Forgets clients whose buckets have been refilled completely by `now`. These behave the same
+    /// as clients never seen before.
+    pub fn remove_idle(&self, now: Instant) {
+        let (per_second, burst) = (self.per_second, self.burst);
+        poison::lock(&self.buckets, "the rate limit buckets").retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens + elapsed * per_second < burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::new(RateLimitCfg {
+            per_second: 2,
+            burst: 2,
+        });
+        let now = Instant::now();
+        assert!(limiter.check("a", now).is_ok());
+        assert!(limiter.check("a", now).is_ok());
+        assert_eq!(limiter.check("a", now), Err(Duration::from_millis(500)));
+        // Other clients have their own bucket
+        assert!(limiter.check("b", now).is_ok());
+        // Tokens are refilled over time
+        assert!(limiter.check("a", now + Duration::from_millis(500)).is_ok());
+
+        limiter.remove_idle(now + Duration::from_secs(2));
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,58 @@
+//! Reloading the semaphores from the configuration file on `SIGHUP`, so full counts can be changed
+//! without a restart dropping every lease. All other settings, e.g. logging, are only read at
+//! startup.
+
+use crate::{application_cfg::ApplicationCfg, state::State};
+use log::{error, info};
+use std::path::Path;
+
+/// Reads the configuration file at `path` again and applies its semaphores to `state`. Overrides
+/// from the environment are applied, just like at startup. Errors are logged and leave the
+/// semaphores unchanged.
+pub fn reload(path: &Path, state: &State) {
+    // `ApplicationCfg::init` falls back to an empty configuration, which would remove every
+    // semaphore not in use.
+    if !path.is_file() {
+        error!(
+            "Configuration {} not found. Semaphores are left unchanged.",
+            path.display()
+        );
+        return;
+    }
+    let mut cfg = match ApplicationCfg::init(path) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            error!("Couldn't reload configuration: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = cfg.override_semaphores_from_env() {
+        error!("Couldn't reload configuration: {}", e);
+        return;
+    }
+    let reload = state.reload(cfg.semaphores);
+    info!(
+        "Reloaded semaphores from {}. Added: {:?}, changed: {:?}, removed: {:?}, kept while in \
+        use: {:?}.",
+        path.display(),
+        reload.added,
+        reload
+            .changed
+            .iter()
+            .map(|(name, _, _)| name)
+            .collect::<Vec<_>>(),
+        reload.removed,
+        reload.in_use
+    );
+}
+
+/// Reloads the semaphores each time the process receives `SIGHUP`.
+#[cfg(unix)]
+pub async fn on_hangup(path: std::path::PathBuf, state: std::sync::Arc<State>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup()).expect("Error installing handler for SIGHUP");
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP.");
+        reload(&path, &state);
+    }
+}
@@ -3,62 +3,225 @@
 //! deserialize paramaters and serialize respones, or deciding on which HTTP methods to map the
 //! functions.
 
-use crate::{error::ThrottleError, leases::PeerId, state::State};
+use crate::{
+    application_cfg::SemaphoreCfg,
+    auth::Principal,
+    change_log,
+    deadline::Deadline,
+    encoding::{encode, Body, Encoded},
+    error::ThrottleError,
+    idempotency,
+    leases::{LockFilter, LockInfo, Metadata, PeerId, PeerState, Released},
+    state::{SemaphoreSnapshot, State},
+};
 use actix_web::{
     delete, get,
-    http::StatusCode,
-    post, put,
-    web::{Data, Json, Path, Query},
-    HttpResponse, ResponseError,
+    http::{header::RETRY_AFTER, HeaderName, HeaderValue, StatusCode},
+    patch, post, put,
+    web::{Data, Path, Query},
+    HttpRequest, HttpResponse, ResponseError,
 };
 use log::debug;
-use serde::Deserialize;
-use std::{collections::HashMap, time::Duration};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
 impl ResponseError for ThrottleError {
     fn status_code(&self) -> StatusCode {
         match self {
             ThrottleError::UnknownPeer
             | ThrottleError::UnknownSemaphore
-            | ThrottleError::InvalidLockCount { .. } => StatusCode::BAD_REQUEST,
+            | ThrottleError::InvalidLockCount { .. }
+            | ThrottleError::InvalidMetadata
+            | ThrottleError::InvalidExpiry { .. }
+            | ThrottleError::InvalidSemaphoreName { .. }
+            | ThrottleError::MissingExpiresIn => StatusCode::BAD_REQUEST,
             ThrottleError::Never { .. }
             | ThrottleError::Deadlock { .. }
             | ThrottleError::ChangeThroughRestore
-            | ThrottleError::AlreadyPending => StatusCode::CONFLICT,
-            ThrottleError::ShrinkingLockCount => StatusCode::NOT_IMPLEMENTED,
+            | ThrottleError::AlreadyPending
+            | ThrottleError::Revoked { .. }
+            | ThrottleError::NotAcquired
+            | ThrottleError::InsufficientCapacity
+            | ThrottleError::Unavailable { .. }
+            | ThrottleError::InvalidSplit
+            | ThrottleError::SemaphoreExists
+            | ThrottleError::SemaphoreInUse
+            | ThrottleError::PeerExists
+            | ThrottleError::ForceReleased
+            | ThrottleError::LocksLost { .. }
+            | ThrottleError::ExpiresInTooLong { .. }
+            | ThrottleError::AmountTooLarge { .. }
+            | ThrottleError::ShrinkingLockCount => StatusCode::CONFLICT,
+            ThrottleError::Gone => StatusCode::GONE,
+            ThrottleError::Frozen | ThrottleError::Closed => StatusCode::LOCKED,
+            ThrottleError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ThrottleError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            ThrottleError::RateLimited { retry_after } => {
+                response.header(RETRY_AFTER, retry_after_sec(*retry_after).to_string());
+            }
+            // A JSON body tells clients apart from a `503` answered by a proxy in front of us.
+            ThrottleError::ShuttingDown => {
+                return response
+                    .header(
+                        RETRY_AFTER,
+                        retry_after_sec(SHUTDOWN_RETRY_AFTER).to_string(),
+                    )
+                    .json(json!({ "error": self.to_string() }));
+            }
+            // Tells the client which of its locks differ, without parsing the message.
+            ThrottleError::Revoked { acquired, claimed } => {
+                return response.json(json!({
+                    "error": self.to_string(),
+                    "acquired": acquired,
+                    "claimed": claimed,
+                }));
+            }
+            _ => (),
+        }
+        response
+            .content_type("text/plain; charset=utf-8")
+            .body(self.to_string())
+    }
 }
 
 type Locks = HashMap<String, i64>;
 
+/// Upper bound for the time a single request may block. Clients asking for longer are answered
+/// after this time and are expected to repeat the request.
+const MAX_BLOCK_FOR: Duration = Duration::from_secs(5 * 60);
+
+/// Suggested to clients blocked while the server shuts down. Enough for a restart.
+const SHUTDOWN_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Header echoing the `expires_in` applied to the peer, in seconds. Smaller than the requested one,
+/// if that exceeded the configured `max_expires_in`.
+const EXPIRES_IN_SEC: &str = "x-expires-in-sec";
+
+/// Adds the `X-Expires-In-Sec` header to the response, so clients notice clamping.
+fn echo_expires_in(mut response: HttpResponse, expires_in: Option<Duration>) -> HttpResponse {
+    if let Some(expires_in) = expires_in {
+        response.headers_mut().insert(
+            HeaderName::from_static(EXPIRES_IN_SEC),
+            HeaderValue::from(expires_in.as_secs()),
+        );
+    }
+    response
+}
+
 /// Strict alias around `Duration`. Yet it serializes from a human readable representation.
 #[derive(Deserialize, Clone, Copy)]
 struct HumanDuration(#[serde(with = "humantime_serde")] Duration);
 
-/// Used as a query parameter in requests. E.g. `?expires_in=5m`.
+/// Peer id as part of the path, e.g. `/peers/42` or `/peers/67e55044-10b1-426f-9247-bb680e5fe0c8`.
+/// The path deserializer can not tell numbers from strings, so the segment is always parsed from
+/// its text.
+struct PeerIdSegment(PeerId);
+
+impl<'de> Deserialize<'de> for PeerIdSegment {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map(PeerIdSegment).map_err(de::Error::custom)
+    }
+}
+
+/// Body of a request to create a new peer
 #[derive(Deserialize)]
-struct ExpiresIn {
+struct NewPeer {
     #[serde(with = "humantime_serde")]
     expires_in: Duration,
+    /// Id chosen by the client. Makes retrying the request safe, as no second peer is created.
+    peer_id: Option<PeerId>,
+    /// Information about the client, e.g. its hostname
+    metadata: Option<Metadata>,
+    /// Once capacity frees up, pending locks of peers with higher priority are acquired first.
+    #[serde(default)]
+    priority: u8,
 }
 
-/// Create a new peer with no acquired locks.
+/// Create a new peer with no acquired locks. Repeating the request with the same
+/// `Idempotency-Key` header yields the same peer.
 ///
 /// Returns id of the new peer
 #[post("/new_peer")]
-async fn new_peer(body: Json<ExpiresIn>, state: Data<State>) -> Json<PeerId> {
-    Json(state.new_peer(body.expires_in))
+async fn new_peer(
+    req: HttpRequest,
+    body: Body<NewPeer>,
+    state: Data<State>,
+) -> Result<HttpResponse, ThrottleError> {
+    let body = body.into_inner();
+    let expires_in = state.effective_expires_in(body.expires_in)?;
+    let peer_id = match idempotency::key(&req) {
+        Some(key) => {
+            state
+                .create_peer_once(key, body.peer_id, expires_in, body.metadata, body.priority)?
+                .0
+        }
+        None => state.create_peer(body.peer_id, expires_in, body.metadata, body.priority)?,
+    };
+    Ok(echo_expires_in(
+        encode(&req, HttpResponse::Ok(), &peer_id),
+        Some(expires_in),
+    ))
+}
+
+/// A lock of a peer, e.g. the one it has been waiting for.
+#[derive(Serialize)]
+struct LockCount {
+    semaphore: String,
+    count: i64,
 }
 
+/// Answer to `DELETE /peers/{id}`
+#[derive(Serialize)]
+struct PeerReleased {
+    /// `false` if the peer has not been found, e.g. because it already expired.
+    released: bool,
+    /// `true` if the peer held acquired locks, i.e. releasing it freed capacity.
+    was_active: bool,
+    /// Locks the peer held
+    acquired: Locks,
+    /// Lock the peer has been waiting for, if any
+    pending: Option<LockCount>,
+}
+
+/// Removes the peer and all its locks. Tells the client which locks it held, so it can check
+/// them against its own bookkeeping.
 #[delete("/peers/{id}")]
-async fn release(path: Path<PeerId>, state: Data<State>) -> HttpResponse {
-    if state.release(*path) {
-        HttpResponse::Ok().json("Peer released")
-    } else {
-        // Post condition of lease not being there is satisfied, let's make this request 200 still.
-        HttpResponse::Ok().json("Peer not found")
-    }
+async fn release(path: Path<PeerIdSegment>, state: Data<State>) -> Encoded<PeerReleased> {
+    // Post condition of the peer not being there is satisfied, even if it has not been found. So
+    // this is a `200 OK` either way.
+    let answer = match state.release(path.0) {
+        Some(removed) => PeerReleased {
+            released: true,
+            was_active: !removed.acquired.is_empty(),
+            acquired: removed
+                .acquired
+                .iter()
+                .map(|(semaphore, &count)| (semaphore.to_string(), count))
+                .collect(),
+            pending: removed.pending.map(|(semaphore, count)| LockCount {
+                semaphore: semaphore.to_string(),
+                count,
+            }),
+        },
+        None => PeerReleased {
+            released: false,
+            was_active: false,
+            acquired: Locks::new(),
+            pending: None,
+        },
+    };
+    Encoded(answer)
 }
 
 /// Used as a query parameter in requests. E.g. `?expires_in=5m`.
@@ -74,39 +237,276 @@ struct AcquireQuery {
 /// This function is supposed to be called repeatedly from client side, until the lock is acquired
 /// It also updates the expiration timeout to prevent the litter collection from removing the peer
 /// while it is pending. Having repeated short lived requests is preferable over one long running,
-/// as many proxies, firewalls, and Gateways might kill them.
+/// as many proxies, firewalls, and Gateways might kill them. Blocking never outlives the deadline
+/// of the request.
 #[put("/peers/{id}/{semaphore}")]
 async fn acquire(
-    path: Path<(PeerId, String)>,
+    req: HttpRequest,
+    path: Path<(PeerIdSegment, String)>,
     query: Query<AcquireQuery>,
-    body: Json<i64>,
+    body: Body<i64>,
+    deadline: Deadline,
     state: Data<State>,
 ) -> HttpResponse {
+    if let Err(error) = state.check_rate(&client(&req)) {
+        return HttpResponse::from_error(error.into());
+    }
     let amount = body.0;
-    let peer_id = path.0;
-    let semaphore = &path.1;
-    // Turn `Option<HumantimeDuratino>` into `Option<Duration>`.
-    let wait_for = query.block_for.map(|hd| hd.0);
-    let expires_in = query.expires_in.map(|hd| hd.0);
-    match state
+    let (PeerIdSegment(peer_id), semaphore) = path.into_inner();
+    let semaphore = &semaphore;
+    let wait_for = wait_for(query.block_for, deadline);
+    let expires_in = match query
+        .expires_in
+        .map(|hd| state.effective_expires_in(hd.0))
+        .transpose()
+    {
+        Ok(expires_in) => expires_in,
+        Err(error) => return HttpResponse::from_error(error.into()),
+    };
+    let response = match state
         .acquire(peer_id, semaphore, amount, wait_for, expires_in)
         .await
     {
-        Ok(true) => HttpResponse::Ok().json(peer_id),
-        Ok(false) => HttpResponse::Accepted().json(peer_id),
+        Ok(true) => {
+            change_log::record_acquisition(&req, peer_id, semaphore, amount);
+            encode(&req, HttpResponse::Ok(), &peer_id)
+        }
+        Ok(false) => pending(&req, &state, peer_id, semaphore),
+        Err(error) => return HttpResponse::from_error(error.into()),
+    };
+    echo_expires_in(response, expires_in)
+}
+
+/// Key the rate limit is accounted to. The principal of the bearer token if the request is
+/// authenticated, the IP address of the client otherwise.
+fn client(req: &HttpRequest) -> String {
+    if let Some(principal) = req.extensions().get::<Principal>() {
+        return principal.0.clone();
+    }
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default()
+}
+
+/// `202 Accepted` for a pending lock. The `Retry-After` header tells the client the number of
+/// seconds until the earliest acquired lock to the semaphore expires, i.e. until capacity is
+/// guaranteed to free up. It is omitted if there is no such lock.
+fn pending(req: &HttpRequest, state: &State, peer_id: PeerId, semaphore: &str) -> HttpResponse {
+    let mut response = HttpResponse::Accepted();
+    if let Some(retry_after) = state.retry_after(semaphore) {
+        response.header(RETRY_AFTER, retry_after_sec(retry_after).to_string());
+    }
+    encode(req, response, &peer_id)
+}
+
+/// Whole seconds, rounded up, as `Retry-After` does not allow fractions.
+fn retry_after_sec(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() != 0)
+}
+
+/// Time a request is going to block, given the `block_for` query parameter. Never exceeds
+/// `MAX_BLOCK_FOR` or the deadline of the request.
+fn wait_for(block_for: Option<HumanDuration>, deadline: Deadline) -> Option<Duration> {
+    block_for.map(|hd| deadline.limit(hd.0.min(MAX_BLOCK_FOR), Instant::now()))
+}
+
+/// Body of a request to create a new peer and acquire a lock for it
+#[derive(Deserialize)]
+struct NewPeerWithLock {
+    /// Falls back to `default_expires_in` of the semaphore, if omitted.
+    #[serde(with = "humantime_serde", default)]
+    expires_in: Option<Duration>,
+    semaphore: String,
+    count: i64,
+    /// Id chosen by the client. Makes retrying the request safe, as no second peer is created.
+    peer_id: Option<PeerId>,
+    metadata: Option<Metadata>,
+    #[serde(default)]
+    priority: u8,
+}
+
+/// Query parameters of `POST /acquire`. E.g. `?block_for=30s`.
+#[derive(Deserialize)]
+struct BlockQuery {
+    block_for: Option<HumanDuration>,
+}
+
+/// Creates a new peer and acquires a lock for it, saving a round trip in the common uncontended
+/// case. Answers `201 Created` with the peer id, if the lock has been acquired, or `202 Accepted`
+/// if it is still pending, after blocking for at most `block_for`. Pending peers use `PUT
+/// /peers/{id}/{semaphore}` to wait further.
+///
+/// Repeating the request with the same `Idempotency-Key` header does not create another peer, but
+/// answers with the state of the lock of the first one.
+#[post("/acquire")]
+async fn acquire_new_peer(
+    req: HttpRequest,
+    body: Body<NewPeerWithLock>,
+    query: Query<BlockQuery>,
+    deadline: Deadline,
+    state: Data<State>,
+) -> HttpResponse {
+    if let Err(error) = state.check_rate(&client(&req)) {
+        return HttpResponse::from_error(error.into());
+    }
+    let body = body.into_inner();
+    let expires_in = match state.expires_in_for(&body.semaphore, body.expires_in) {
+        Ok(expires_in) => expires_in,
+        Err(error) => return HttpResponse::from_error(error.into()),
+    };
+    let key = idempotency::key(&req);
+    let peer = match key {
+        Some(key) => {
+            state.create_peer_once(key, body.peer_id, expires_in, body.metadata, body.priority)
+        }
+        None => state
+            .create_peer(body.peer_id, expires_in, body.metadata, body.priority)
+            .map(|peer_id| (peer_id, true)),
+    };
+    let (peer_id, created) = match peer {
+        Ok(peer) => peer,
+        // The client repeats the request for a peer it created before. Since `acquire` is
+        // idempotent, we just carry on.
+        Err(ThrottleError::PeerExists) => (body.peer_id.unwrap(), false),
+        Err(error) => return HttpResponse::from_error(error.into()),
+    };
+    let wait_for = wait_for(query.block_for, deadline);
+    // Waiting does not hold the lock to the ledger. See `State::acquire`.
+    match state
+        .acquire(
+            peer_id,
+            &body.semaphore,
+            body.count,
+            wait_for,
+            Some(expires_in),
+        )
+        .await
+    {
+        Ok(true) => {
+            change_log::record_acquisition(&req, peer_id, &body.semaphore, body.count);
+            echo_expires_in(
+                encode(&req, HttpResponse::Created(), &peer_id),
+                Some(expires_in),
+            )
+        }
+        Ok(false) => echo_expires_in(
+            pending(&req, &state, peer_id, &body.semaphore),
+            Some(expires_in),
+        ),
+        Err(error) => {
+            // Don't leave an empty peer behind for the litter collection.
+            if created {
+                state.release(peer_id);
+                if let Some(key) = key {
+                    state.forget_idempotency_key(key);
+                }
+            }
+            HttpResponse::from_error(error.into())
+        }
+    }
+}
+
+/// Creates a new peer holding a lock, but only if the lock can be acquired right away. Answers
+/// `201 Created` with the peer id, or `409 Conflict` with the remainder of the semaphore. In the
+/// latter case no peer is left behind, so there is nothing to release.
+#[post("/try_acquire")]
+async fn try_acquire(
+    req: HttpRequest,
+    body: Body<NewPeerWithLock>,
+    state: Data<State>,
+) -> HttpResponse {
+    if let Err(error) = state.check_rate(&client(&req)) {
+        return HttpResponse::from_error(error.into());
+    }
+    let body = body.into_inner();
+    let expires_in = match state.expires_in_for(&body.semaphore, body.expires_in) {
+        Ok(expires_in) => expires_in,
+        Err(error) => return HttpResponse::from_error(error.into()),
+    };
+    match state.try_acquire(
+        body.peer_id,
+        expires_in,
+        body.metadata,
+        body.priority,
+        &body.semaphore,
+        body.count,
+    ) {
+        Ok(peer_id) => {
+            change_log::record_acquisition(&req, peer_id, &body.semaphore, body.count);
+            echo_expires_in(
+                encode(&req, HttpResponse::Created(), &peer_id),
+                Some(expires_in),
+            )
+        }
+        Err(error) => HttpResponse::from_error(error.into()),
+    }
+}
+
+/// Body of a request to split a peer
+#[derive(Deserialize)]
+struct Split {
+    amounts: Vec<i64>,
+}
+
+/// Splits a peer holding exactly one acquired lock into new peers, each holding one of the amounts
+/// in the body. The amounts must sum up to the count of the original lock. Answers with the ids of
+/// the new peers. Afterwards requests to the original peer are answered with `410 Gone`.
+#[post("/peers/{id}/split")]
+async fn split(
+    path: Path<PeerIdSegment>,
+    body: Body<Split>,
+    state: Data<State>,
+) -> Result<Encoded<Vec<PeerId>>, ThrottleError> {
+    state.split(path.0, &body.amounts).map(Encoded)
+}
+
+/// Query parameters for growing a lock. E.g. `?wait=true`.
+#[derive(Deserialize)]
+struct GrowQuery {
+    #[serde(default)]
+    wait: bool,
+}
+
+/// Changes the count of an acquired lock to the count in the body.
+///
+/// Growing answers `409 Conflict` if there is not enough capacity left, unless the `wait` query
+/// parameter is set. In this case the additional count becomes pending and `202 Accepted` is
+/// returned. The peer keeps its current count in the meantime. Use `is_acquired` to find out if the
+/// lock has grown.
+///
+/// Shrinking takes effect immediately, and lets pending locks of other peers acquire the freed
+/// capacity.
+#[patch("/peers/{id}/{semaphore}")]
+async fn change_amount(
+    req: HttpRequest,
+    path: Path<(PeerIdSegment, String)>,
+    query: Query<GrowQuery>,
+    body: Body<i64>,
+    state: Data<State>,
+) -> HttpResponse {
+    let (PeerIdSegment(peer_id), semaphore) = path.into_inner();
+    let semaphore = &semaphore;
+    match state.change_amount(peer_id, semaphore, body.0, query.wait) {
+        Ok(true) => encode(&req, HttpResponse::Ok(), &peer_id),
+        Ok(false) => encode(&req, HttpResponse::Accepted(), &peer_id),
         Err(error) => HttpResponse::from_error(error.into()),
     }
 }
 
+/// Releases a single lock of a peer. The peer itself and its other locks are kept.
 #[delete("/peers/{id}/{semaphore}")]
 async fn release_lock(
-    path: Path<(PeerId, String)>,
+    path: Path<(PeerIdSegment, String)>,
     state: Data<State>,
-) -> Result<&'static str, ThrottleError> {
-    let peer_id = path.0;
-    let semaphore = path.1.as_str();
-    state.release_lock(peer_id, semaphore)?;
-    Ok("Ok")
+) -> Result<Encoded<&'static str>, ThrottleError> {
+    let (PeerIdSegment(peer_id), semaphore) = path.into_inner();
+    let semaphore = semaphore.as_str();
+    let answer = match state.release_lock(peer_id, semaphore)? {
+        Released::Acquired | Released::Pending => "Lock released",
+        // Post condition of lock not being held is satisfied, so this is still a success.
+        Released::NotHeld => "Lock not held",
+    };
+    Ok(Encoded(answer))
 }
 
 #[derive(Deserialize)]
@@ -115,6 +515,10 @@ pub struct Restore {
     expires_in: Duration,
     peer_id: PeerId,
     acquired: Locks,
+    /// Information about the client, e.g. its hostname
+    metadata: Option<Metadata>,
+    #[serde(default)]
+    priority: u8,
 }
 
 /// Called by the client, after receiving `Unknown Peer`. Restores the state of the peer. The
@@ -123,10 +527,18 @@ pub struct Restore {
 /// the semaphores full count, or violating fairness.
 #[post("/restore")]
 pub async fn restore(
-    body: Json<Restore>,
+    body: Body<Restore>,
     state: Data<State>,
 ) -> Result<&'static str, ThrottleError> {
-    state.restore(body.peer_id, body.expires_in, &body.acquired)?;
+    let body = body.into_inner();
+    let expires_in = state.effective_expires_in(body.expires_in)?;
+    state.restore(
+        body.peer_id,
+        expires_in,
+        &body.acquired,
+        body.metadata,
+        body.priority,
+    )?;
     Ok("Ok")
 }
 
@@ -134,38 +546,1306 @@ pub async fn restore(
 #[derive(Deserialize)]
 struct Remainder {
     semaphore: String,
+    /// Answer with the plain remainder, rather than a `RemainderInfo`. For clients predating it.
+    #[serde(default)]
+    plain: bool,
 }
 
-/// Get the remainder of a semaphore
+/// Answer of `GET /remainder`. Spells out overbooking, so a negative remainder does not come as a
+/// surprise.
+#[derive(Serialize)]
+struct RemainderInfo {
+    /// Full count minus the sum of acquired locks. Negative if overbooked.
+    remainder: i64,
+    /// `true` if the acquired locks exceed the full count, e.g. due to revenants.
+    overbooked: bool,
+    full_count: i64,
+    /// Sum of all acquired locks
+    active: i64,
+}
+
+/// Get the remainder of a semaphore. With `?plain=true` the answer is just the number.
 #[get("/remainder")]
-async fn remainder(
-    query: Query<Remainder>,
+async fn remainder(req: HttpRequest, query: Query<Remainder>, state: Data<State>) -> HttpResponse {
+    if query.plain {
+        return match state.remainder(&query.semaphore) {
+            Ok(remainder) => encode(&req, HttpResponse::Ok(), &remainder),
+            Err(error) => HttpResponse::from_error(error.into()),
+        };
+    }
+    match state.semaphore(&query.semaphore) {
+        Some(SemaphoreSnapshot { cfg, counts, .. }) => encode(
+            &req,
+            HttpResponse::Ok(),
+            &RemainderInfo {
+                remainder: cfg.max.saturating_sub(counts.acquired),
+                overbooked: counts.acquired > cfg.max,
+                full_count: cfg.max,
+                active: counts.acquired,
+            },
+        ),
+        None => HttpResponse::from_error(ThrottleError::UnknownSemaphore.into()),
+    }
+}
+
+/// Remainders of all semaphores, e.g. `{"A": 3, "B": 0}`. Cheaper than asking for each semaphore
+/// individually.
+#[get("/remainders")]
+async fn remainders(state: Data<State>) -> Encoded<HashMap<String, i64>> {
+    Encoded(state.remainders())
+}
+
+/// A single lock as listed by `GET /peers`
+#[derive(Serialize)]
+struct LockListing {
+    peer_id: PeerId,
+    semaphore: String,
+    count: i64,
+    /// `acquired` or `pending`
+    state: PeerState,
+    /// Seconds left until the peer expires
+    expires_in_sec: f64,
+    /// `false` for peers, which never expire
+    expires: bool,
+    /// Information about the client, if supplied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
+    /// Priority of the peer. `0` unless specified otherwise.
+    priority: u8,
+}
+
+impl From<LockInfo> for LockListing {
+    fn from(lock: LockInfo) -> Self {
+        LockListing {
+            peer_id: lock.peer_id,
+            semaphore: lock.semaphore,
+            count: lock.count,
+            state: lock.state,
+            expires_in_sec: lock.expires_in.as_secs_f64(),
+            expires: lock.expires,
+            metadata: lock.metadata,
+            priority: lock.priority,
+        }
+    }
+}
+
+/// Query parameters of `GET /peers`. E.g. `?semaphore=A&state=pending&limit=100`.
+#[derive(Deserialize)]
+struct ListPeersQuery {
+    /// Only list locks to this semaphore
+    semaphore: Option<String>,
+    /// Only list `acquired` or `pending` locks
+    state: Option<PeerState>,
+    /// List at most this many locks
+    limit: Option<usize>,
+}
+
+/// Lists all acquired and pending locks of all peers, ordered by peer id. Helps to find out who
+/// holds what. Filtering by an unknown semaphore yields an empty list, rather than an error.
+#[get("/peers")]
+async fn list_peers(query: Query<ListPeersQuery>, state: Data<State>) -> Encoded<Vec<LockListing>> {
+    let filter = LockFilter {
+        semaphore: query.semaphore.as_deref(),
+        state: query.state,
+    };
+    let locks = state
+        .list_locks(filter, query.limit)
+        .into_iter()
+        .map(LockListing::from)
+        .collect();
+    Encoded(locks)
+}
+
+/// Query parameters of `GET /peers/expiring`. E.g. `?within=30s`.
+#[derive(Deserialize)]
+struct ExpiringQuery {
+    within: HumanDuration,
+}
+
+/// Lists the locks of all peers expiring within the given duration, soonest first. Peers which
+/// already missed their deadline, but have not been collected yet, are listed, too.
+#[get("/peers/expiring")]
+async fn list_expiring(
+    query: Query<ExpiringQuery>,
+    state: Data<State>,
+) -> Encoded<Vec<LockListing>> {
+    let locks = state
+        .list_expiring(query.within.0)
+        .into_iter()
+        .map(LockListing::from)
+        .collect();
+    Encoded(locks)
+}
+
+/// Body of `PUT /semaphores/{name}`
+#[derive(Deserialize)]
+struct SetMax {
+    max: i64,
+}
+
+/// Changes the full count of a semaphore at runtime. Increasing it may resolve pending locks right
+/// away. Decreasing it may leave the semaphore overbooked, until enough locks are released. The
+/// change is not persisted and lost on restart.
+#[put("/semaphores/{name}")]
+async fn set_max(
+    req: HttpRequest,
+    path: Path<String>,
+    body: Body<SetMax>,
+    state: Data<State>,
+) -> Result<Encoded<i64>, ThrottleError> {
+    let old = state.set_max(&path, body.max)?;
+    change_log::record(&req, &state, "set_max", &path, json!(old), json!(body.max));
+    Ok(Encoded(body.max))
+}
+
+/// Body of `POST /semaphores`
+#[derive(Deserialize)]
+struct NewSemaphore {
+    name: String,
+    max: i64,
+    #[serde(default)]
+    level: i32,
+}
+
+/// Adds a new semaphore at runtime. Answers `409 Conflict` if it already exists, and `400 Bad
+/// Request` for names the configuration would reject, too. The change is not persisted and lost on
+/// restart.
+#[post("/semaphores")]
+async fn add_semaphore(
+    req: HttpRequest,
+    body: Body<NewSemaphore>,
+    state: Data<State>,
+) -> Result<HttpResponse, ThrottleError> {
+    let cfg = SemaphoreCfg {
+        max: body.max,
+        level: body.level,
+        ..SemaphoreCfg::default()
+    };
+    state.add_semaphore(&body.name, cfg)?;
+    let new = json!({ "max": body.max, "level": body.level });
+    change_log::record(&req, &state, "add_semaphore", &body.name, Value::Null, new);
+    Ok(encode(&req, HttpResponse::Created(), &body.name))
+}
+
+/// Removes a semaphore at runtime. Answers `409 Conflict` as long as any peer holds or waits for a
+/// lock to it.
+#[delete("/semaphores/{name}")]
+async fn remove_semaphore(
+    req: HttpRequest,
+    path: Path<String>,
+    state: Data<State>,
+) -> Result<Encoded<&'static str>, ThrottleError> {
+    let cfg = state.remove_semaphore(&path)?;
+    let old = json!({ "max": cfg.max, "level": cfg.level });
+    change_log::record(&req, &state, "remove_semaphore", &path, old, Value::Null);
+    Ok(Encoded("Semaphore removed"))
+}
+
+/// Stops granting new locks to a semaphore, e.g. before maintenance of the protected resource.
+/// Existing locks are kept. Acquiring new locks is answered with `423 Locked`.
+#[post("/semaphores/{name}/freeze")]
+async fn freeze(
+    req: HttpRequest,
+    path: Path<String>,
+    state: Data<State>,
+) -> Result<Encoded<&'static str>, ThrottleError> {
+    if state.freeze(&path)? {
+        change_log::record(&req, &state, "freeze", &path, json!(false), json!(true));
+    }
+    Ok(Encoded("Semaphore frozen"))
+}
+
+/// Grants new locks to a frozen semaphore again. Pending locks are resolved right away.
+#[post("/semaphores/{name}/unfreeze")]
+async fn unfreeze(
+    req: HttpRequest,
+    path: Path<String>,
     state: Data<State>,
-) -> Result<Json<i64>, ThrottleError> {
-    state.remainder(&query.semaphore).map(Json)
+) -> Result<Encoded<&'static str>, ThrottleError> {
+    if state.unfreeze(&path)? {
+        change_log::record(&req, &state, "freeze", &path, json!(true), json!(false));
+    }
+    Ok(Encoded("Semaphore unfrozen"))
+}
+
+/// Answer to `DELETE /semaphores/{name}/peers`
+#[derive(Serialize)]
+struct ForceReleased {
+    /// Number of acquired locks released
+    acquired: usize,
+    /// Number of pending locks released
+    pending: usize,
+}
+
+/// Releases the locks of all peers to a semaphore, e.g. to get rid of zombie processes which keep
+/// heartbeating. Locks of the same peers to other semaphores are kept. Requests pending for a
+/// released lock are answered with `409 Conflict`.
+#[delete("/semaphores/{name}/peers")]
+async fn release_semaphore(
+    req: HttpRequest,
+    path: Path<String>,
+    state: Data<State>,
+) -> Result<Encoded<ForceReleased>, ThrottleError> {
+    let (acquired, pending) = state.release_semaphore(&path)?;
+    let old = json!({ "acquired": acquired, "pending": pending });
+    let new = json!({ "acquired": 0, "pending": 0 });
+    change_log::record(&req, &state, "release_semaphore", &path, old, new);
+    Ok(Encoded(ForceReleased { acquired, pending }))
+}
+
+/// A pending lock
+#[derive(Serialize)]
+struct PendingLock {
+    semaphore: String,
+    count: i64,
+    /// Position among all pending locks to the same semaphore, in the order they are acquired.
+    /// `1` is next in line.
+    position: usize,
+}
+
+/// Answer to `GET /peers/{id}`
+#[derive(Serialize)]
+struct PeerDescription {
+    acquired: Locks,
+    pending: Option<PendingLock>,
+    /// `acquired` or `pending`
+    state: PeerState,
+    /// Seconds left until the peer expires
+    expires_in_sec: f64,
+    /// Information about the client, if supplied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
+}
+
+/// Locks and state of a single peer. Answers `404 Not Found` for unknown peers. Cheap enough to
+/// poll, if blocking on `acquire` is not an option.
+#[get("/peers/{id}")]
+async fn get_peer(req: HttpRequest, path: Path<PeerIdSegment>, state: Data<State>) -> HttpResponse {
+    match state.peer_info(path.0) {
+        Ok(info) => {
+            let position = info.position;
+            encode(
+                &req,
+                HttpResponse::Ok(),
+                &PeerDescription {
+                    acquired: info.acquired,
+                    pending: info.pending.map(|(semaphore, count)| PendingLock {
+                        semaphore,
+                        count,
+                        position: position.unwrap_or(1),
+                    }),
+                    state: info.status.state,
+                    expires_in_sec: info.status.expires_in.as_secs_f64(),
+                    metadata: info.metadata,
+                },
+            )
+        }
+        // Other routes answer `400 Bad Request` for unknown peers, to make clients restore them.
+        // For inspecting a peer, not finding it is a perfectly fine answer though.
+        Err(ThrottleError::UnknownPeer) => HttpResponse::NotFound().body("Unknown peer"),
+        Err(error) => HttpResponse::from_error(error.into()),
+    }
+}
+
+/// Entry of `GET /semaphores`
+#[derive(Serialize)]
+struct SemaphoreSummary {
+    /// Full count of the semaphore
+    max: i64,
+    /// Sum of all acquired locks
+    acquired: i64,
+    /// Sum of all pending locks
+    pending: i64,
+    /// Number of peers holding a lock
+    active_peers: i64,
+    /// Number of peers waiting for a lock
+    pending_peers: i64,
+    /// `true` if more is acquired than the full count allows, e.g. due to revenants.
+    overbooked: bool,
+    /// `true` if the full count is zero. No locks are granted until it is raised.
+    closed: bool,
+}
+
+/// All configured semaphores with their full count and the sums of acquired and pending locks.
+#[get("/semaphores")]
+async fn list_semaphores(state: Data<State>) -> Encoded<HashMap<String, SemaphoreSummary>> {
+    let summaries = state
+        .list_semaphores()
+        .into_iter()
+        .map(|(name, (cfg, counts))| {
+            let summary = SemaphoreSummary {
+                max: cfg.max,
+                acquired: counts.acquired,
+                pending: counts.pending,
+                active_peers: counts.active_peers,
+                pending_peers: counts.pending_peers,
+                overbooked: counts.acquired > cfg.max,
+                closed: cfg.max == 0,
+            };
+            (name, summary)
+        })
+        .collect();
+    Encoded(summaries)
+}
+
+/// Everything known about a single semaphore
+#[derive(Serialize)]
+struct SemaphoreInfo {
+    /// Full count of the semaphore
+    max: i64,
+    level: i32,
+    strict_amounts: bool,
+    expires: bool,
+    strict_restore: bool,
+    /// Upper bound for the count of a single lock
+    max_amount: Option<i64>,
+    /// `expires_in` of peers acquiring a lock via `POST /acquire`, if omitted by the client
+    default_expires_in_sec: Option<f64>,
+    /// Sum of all acquired locks
+    acquired: i64,
+    /// Sum of all pending locks
+    pending: i64,
+    /// Number of peers holding or waiting for a lock to the semaphore
+    peers: i64,
+    /// Number of peers holding a lock
+    active_peers: i64,
+    /// Number of peers waiting for a lock
+    pending_peers: i64,
+    /// Full count minus acquired. Negative if overbooked.
+    remainder: i64,
+    /// `true` if more is acquired than the full count allows, e.g. due to revenants.
+    overbooked: bool,
+    /// Time the longest pending peer is waiting for a lock
+    longest_pending_sec: f64,
+    /// No new locks are granted to frozen semaphores
+    frozen: bool,
+    /// `true` if the full count is zero. No locks are granted until it is raised.
+    closed: bool,
+}
+
+/// Configuration and state of a single semaphore. Answers `404 Not Found` for unknown semaphores.
+#[get("/semaphores/{name}")]
+async fn get_semaphore(req: HttpRequest, path: Path<String>, state: Data<State>) -> HttpResponse {
+    match state.semaphore(&path) {
+        Some(SemaphoreSnapshot {
+            cfg,
+            counts,
+            frozen,
+            now,
+        }) => encode(
+            &req,
+            HttpResponse::Ok(),
+            &SemaphoreInfo {
+                max: cfg.max,
+                level: cfg.level,
+                strict_amounts: cfg.strict_amounts,
+                expires: cfg.expires,
+                strict_restore: cfg.strict_restore,
+                max_amount: cfg.max_amount,
+                default_expires_in_sec: cfg.default_expires_in.map(|d| d.as_secs_f64()),
+                acquired: counts.acquired,
+                pending: counts.pending,
+                peers: counts.peers,
+                active_peers: counts.active_peers,
+                pending_peers: counts.pending_peers,
+                remainder: cfg.max.saturating_sub(counts.acquired),
+                overbooked: counts.acquired > cfg.max,
+                longest_pending_sec: counts.longest_pending(now).as_secs_f64(),
+                frozen,
+                closed: cfg.max == 0,
+            },
+        ),
+        None => HttpResponse::NotFound().body("Unknown semaphore"),
+    }
+}
+
+/// Query parameters of `GET /peers/{id}/is_acquired`
+#[derive(Deserialize)]
+struct IsAcquiredQuery {
+    /// Answer with an `Acquisition` rather than a plain boolean.
+    #[serde(default)]
+    extended: bool,
+}
+
+/// Extended answer of `GET /peers/{id}/is_acquired?extended=true`
+#[derive(Serialize)]
+struct Acquisition {
+    /// `true` if all locks of the peer are acquired.
+    acquired: bool,
+    /// Semaphore of the pending lock, if any
+    semaphore: Option<String>,
+    /// Count of the pending lock, if any
+    count: Option<i64>,
+    /// Current remainder of the semaphore the peer is waiting for, if any. Allows clients to show
+    /// how far they are from acquiring the lock.
+    remainder: Option<i64>,
+    /// Position of the pending lock among all pending locks to the same semaphore, if any. `1` is
+    /// next in line.
+    position: Option<usize>,
+    /// Seconds until the earliest acquired lock to the semaphore expires. Same as the
+    /// `Retry-After` header of `202 Accepted` answers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_sec: Option<u64>,
 }
 
 /// Returns wether all the locks of the peer have been acquired. This route will not block, but
-/// return immediatly.
+/// return immediatly. It does not alter the peer in any way, so it is suitable for polling.
+///
+/// With `?extended=true` the answer also contains the pending lock and the remainder of its
+/// semaphore. In this variant unknown peers are answered with `404 Not Found`.
 #[get("/peers/{id}/is_acquired")]
-async fn is_acquired(path: Path<PeerId>, state: Data<State>) -> Result<Json<bool>, ThrottleError> {
-    state.is_acquired(*path).map(Json)
+async fn is_acquired(
+    req: HttpRequest,
+    path: Path<PeerIdSegment>,
+    query: Query<IsAcquiredQuery>,
+    state: Data<State>,
+) -> HttpResponse {
+    if !query.extended {
+        // Keep answering `400 Bad Request` for unknown peers, so clients restore them.
+        return match state.is_acquired(path.0) {
+            Ok(acquired) => encode(&req, HttpResponse::Ok(), &acquired),
+            Err(error) => HttpResponse::from_error(error.into()),
+        };
+    }
+    match state.pending_lock(path.0) {
+        Ok(pending) => {
+            let acquisition = match pending {
+                Some((semaphore, count, position, remaining)) => Acquisition {
+                    acquired: false,
+                    retry_after_sec: state.retry_after(&semaphore).map(retry_after_sec),
+                    semaphore: Some(semaphore),
+                    count: Some(count),
+                    remainder: Some(remaining),
+                    position: Some(position),
+                },
+                None => Acquisition {
+                    acquired: true,
+                    semaphore: None,
+                    count: None,
+                    remainder: None,
+                    position: None,
+                    retry_after_sec: None,
+                },
+            };
+            encode(&req, HttpResponse::Ok(), &acquisition)
+        }
+        Err(ThrottleError::UnknownPeer) => HttpResponse::NotFound().body("Unknown peer"),
+        Err(error) => HttpResponse::from_error(error.into()),
+    }
 }
 
 /// Manually remove all expired semapahores. Usefull for testing
 #[post("/remove_expired")]
-async fn remove_expired(state: Data<State>) -> Json<usize> {
+async fn remove_expired(state: Data<State>) -> Encoded<usize> {
     debug!("Remove expired triggered");
-    Json(state.remove_expired())
+    Encoded(state.remove_expired())
+}
+
+/// Body of a heartbeat
+#[derive(Deserialize)]
+struct HeartbeatBody {
+    #[serde(with = "humantime_serde")]
+    expires_in: Duration,
+    /// Locks the client believes to hold. Checked against the acquired locks for semaphores
+    /// configured with `strict_amounts`.
+    acquired: Option<Locks>,
 }
 
+/// Response to a heartbeat
+#[derive(Serialize)]
+struct Heartbeat {
+    /// `acquired` or `pending`
+    state: PeerState,
+    /// Seconds left until the peer expires
+    expires_in_sec: f64,
+}
+
+/// Heartbeat prolonging the lifetime of a peer. Answers with its current state. `expires_in_sec`
+/// reflects clamping to the configured `max_expires_in`.
 #[put("/peers/{id}")]
 async fn put_peer(
-    path: Path<PeerId>,
-    body: Json<ExpiresIn>,
+    path: Path<PeerIdSegment>,
+    body: Body<HeartbeatBody>,
     state: Data<State>,
-) -> Result<&'static str, ThrottleError> {
-    let peer_id = *path;
-    state.heartbeat(peer_id, body.expires_in)?;
-    Ok("Ok")
+) -> Result<Encoded<Heartbeat>, ThrottleError> {
+    let peer_id = path.0;
+    let expires_in = state.effective_expires_in(body.expires_in)?;
+    let status = state.heartbeat(peer_id, expires_in, body.acquired.as_ref())?;
+    Ok(Encoded(Heartbeat {
+        state: status.state,
+        expires_in_sec: status.expires_in.as_secs_f64(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        application_cfg::{ExceedingExpiresIn, PeerIdKind, Semaphores},
+        id_generator::SequentialIds,
+        rate_limit::RateLimitCfg,
+    };
+    use actix_web::{test, App};
+
+    #[actix_rt::test]
+    async fn semaphore_details() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 3,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let one_min = Duration::from_secs(60);
+        let peer = state.new_peer(one_min);
+        state.acquire(peer, "A", 2, None, None).await.unwrap();
+        state.freeze("A").unwrap();
+
+        let mut app = test::init_service(App::new().app_data(state).service(get_semaphore)).await;
+        let req = test::TestRequest::with_uri("/semaphores/A").to_request();
+        let info: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(info["max"], json!(3));
+        assert_eq!(info["acquired"], json!(2));
+        assert_eq!(info["peers"], json!(1));
+        assert_eq!(info["remainder"], json!(1));
+        assert_eq!(info["frozen"], json!(true));
+
+        let req = test::TestRequest::with_uri("/semaphores/B").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn change_full_count() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let one_min = Duration::from_secs(60);
+        let first = state.new_peer(one_min);
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        let second = state.new_peer(one_min);
+        state.acquire(second, "A", 1, None, None).await.unwrap();
+
+        let mut app = test::init_service(App::new().app_data(state.clone()).service(set_max)).await;
+        let req = test::TestRequest::put()
+            .uri("/semaphores/A")
+            .set_json(&json!({"max": 2}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // Pending lock is acquired right away
+        assert!(state.is_acquired(second).unwrap());
+        assert_eq!(state.changes().len(), 1);
+        assert_eq!(state.num_runtime_overrides(), 1);
+
+        // Shrinking overbooks the semaphore
+        state.set_max("A", 1).unwrap();
+        assert_eq!(state.remainder("A").unwrap(), -1);
+    }
+
+    #[actix_rt::test]
+    async fn inspect_peer() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 3,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let peer = state.new_peer(Duration::from_secs(60));
+        state.acquire(peer, "A", 2, None, None).await.unwrap();
+
+        let mut app = test::init_service(App::new().app_data(state).service(get_peer)).await;
+        let req = test::TestRequest::with_uri(&format!("/peers/{}", peer)).to_request();
+        let info: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(info["acquired"], json!({"A": 2}));
+        assert_eq!(info["pending"], Value::Null);
+        assert_eq!(info["state"], json!("acquired"));
+
+        let unknown = PeerId::Int(u64::MAX);
+        let req = test::TestRequest::with_uri(&format!("/peers/{}", unknown)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn list_no_semaphores() {
+        let state = Data::new(State::new(Semaphores::new()));
+        let mut app = test::init_service(App::new().app_data(state).service(list_semaphores)).await;
+        let req = test::TestRequest::with_uri("/semaphores").to_request();
+        let semaphores: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(semaphores, json!({}));
+    }
+
+    #[actix_rt::test]
+    async fn add_semaphore_rejects_invalid_names() {
+        let state = Data::new(State::new(Semaphores::new()));
+        let mut app =
+            test::init_service(App::new().app_data(state.clone()).service(add_semaphore)).await;
+        for (name, status) in &[
+            ("", StatusCode::BAD_REQUEST),
+            ("A/B", StatusCode::BAD_REQUEST),
+            ("A", StatusCode::CREATED),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/semaphores")
+                .set_json(&json!({ "name": name, "max": 1 }))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), *status, "{:?}", name);
+        }
+        assert_eq!(state.remainder("A").unwrap(), 1);
+        assert!(state.remainder("A/B").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn all_remainders() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 3,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(
+            String::from("B"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let peer = state.new_peer(Duration::from_secs(60));
+        state.acquire(peer, "B", 1, None, None).await.unwrap();
+
+        let mut app = test::init_service(App::new().app_data(state).service(remainders)).await;
+        let req = test::TestRequest::with_uri("/remainders").to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body, json!({"A": 3, "B": 0}));
+    }
+
+    #[actix_rt::test]
+    async fn overbooked_remainder() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let mut acquired = HashMap::new();
+        acquired.insert(String::from("A"), 1);
+        for peer_id in 1..=2 {
+            state
+                .restore(
+                    PeerId::Int(peer_id),
+                    Duration::from_secs(60),
+                    &acquired,
+                    None,
+                    0,
+                )
+                .unwrap();
+        }
+
+        let mut app = test::init_service(App::new().app_data(state).service(remainder)).await;
+        let req = test::TestRequest::with_uri("/remainder?semaphore=A").to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(
+            body,
+            json!({"remainder": -1, "overbooked": true, "full_count": 1, "active": 2})
+        );
+        let req = test::TestRequest::with_uri("/remainder?semaphore=A&plain=true").to_request();
+        let body: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(body, json!(-1));
+    }
+
+    #[actix_rt::test]
+    async fn poll_extended_acquisition() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let one_min = Duration::from_secs(60);
+        let first = state.new_peer(one_min);
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        let second = state.new_peer(one_min);
+        state.acquire(second, "A", 1, None, None).await.unwrap();
+
+        let mut app = test::init_service(App::new().app_data(state).service(is_acquired)).await;
+        let req =
+            test::TestRequest::with_uri(&format!("/peers/{}/is_acquired?extended=true", second))
+                .to_request();
+        let acquisition: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(
+            acquisition,
+            json!({
+                "acquired": false,
+                "semaphore": "A",
+                "count": 1,
+                "remainder": 0,
+                "position": 1,
+                "retry_after_sec": 60
+            })
+        );
+
+        let unknown = PeerId::Int(u64::MAX);
+        let req =
+            test::TestRequest::with_uri(&format!("/peers/{}/is_acquired?extended=true", unknown))
+                .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        // Plain variant keeps telling clients to restore the peer
+        let req =
+            test::TestRequest::with_uri(&format!("/peers/{}/is_acquired", unknown)).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn list_locks() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::with_id_generator(
+            semaphores,
+            Box::new(SequentialIds::default()),
+        ));
+        let one_min = Duration::from_secs(60);
+        let first = state.new_peer(one_min);
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        let second = state.new_peer(one_min);
+        state.acquire(second, "A", 1, None, None).await.unwrap();
+
+        let mut app = test::init_service(App::new().app_data(state).service(list_peers)).await;
+        let req = test::TestRequest::with_uri("/peers").to_request();
+        let locks: Value = test::read_response_json(&mut app, req).await;
+        let locks = locks.as_array().unwrap();
+        assert_eq!(locks.len(), 2);
+        assert_eq!(locks[0]["peer_id"], json!(1));
+        assert_eq!(locks[1]["peer_id"], json!(2));
+        let pending = locks
+            .iter()
+            .find(|l| l["peer_id"] == json!(second))
+            .unwrap();
+        assert_eq!(pending["state"], json!("pending"));
+        assert_eq!(pending["count"], json!(1));
+        // Absent metadata is omitted rather than `null`
+        assert!(pending.get("metadata").is_none());
+    }
+
+    #[actix_rt::test]
+    async fn filter_locks() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(
+            String::from("B"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let one_min = Duration::from_secs(60);
+        for semaphore in &["A", "A", "A", "B"] {
+            let peer = state.new_peer(one_min);
+            state.acquire(peer, semaphore, 1, None, None).await.unwrap();
+        }
+
+        let mut app = test::init_service(App::new().app_data(state).service(list_peers)).await;
+        let count = |locks: Value| locks.as_array().unwrap().len();
+        let req = test::TestRequest::with_uri("/peers?semaphore=A&state=pending").to_request();
+        assert_eq!(count(test::read_response_json(&mut app, req).await), 2);
+        let req = test::TestRequest::with_uri("/peers?semaphore=A&limit=1").to_request();
+        assert_eq!(count(test::read_response_json(&mut app, req).await), 1);
+        // Unknown semaphores are not an error
+        let req = test::TestRequest::with_uri("/peers?semaphore=Typo").to_request();
+        assert_eq!(count(test::read_response_json(&mut app, req).await), 0);
+    }
+
+    #[actix_rt::test]
+    async fn acquire_with_new_peer() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::with_id_generator(
+            semaphores,
+            Box::new(SequentialIds::default()),
+        ));
+        let mut app =
+            test::init_service(App::new().app_data(state.clone()).service(acquire_new_peer)).await;
+        let body = json!({"expires_in": "1m", "semaphore": "A", "count": 1});
+
+        let req = test::TestRequest::post()
+            .uri("/acquire")
+            .set_json(&body)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let peer: PeerId = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(peer, PeerId::Int(1));
+
+        let req = test::TestRequest::post()
+            .uri("/acquire?block_for=10ms")
+            .set_json(&body)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        // The first peer expires within a minute
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "60");
+        let peer: PeerId = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(peer, PeerId::Int(2));
+
+        // Failing to acquire the lock must not leak the new peer
+        let body = json!({"expires_in": "1m", "semaphore": "Unknown", "count": 1});
+        let req = test::TestRequest::post()
+            .uri("/acquire")
+            .set_json(&body)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(state.num_peers(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn try_acquire_leaves_no_pending_peer() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 3,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::with_id_generator(
+            semaphores,
+            Box::new(SequentialIds::default()),
+        ));
+        let mut app =
+            test::init_service(App::new().app_data(state.clone()).service(try_acquire)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/try_acquire")
+            .set_json(&json!({"expires_in": "1m", "semaphore": "A", "count": 2}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let peer: PeerId = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(peer, PeerId::Int(1));
+
+        // Only one left, so this is turned down, rather than becoming pending.
+        let req = test::TestRequest::post()
+            .uri("/try_acquire")
+            .set_json(&json!({"expires_in": "1m", "semaphore": "A", "count": 2}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body).unwrap().contains("is 1."));
+        assert_eq!(state.num_peers(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn semaphore_limits_and_defaults() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 5,
+                max_amount: Some(2),
+                default_expires_in: Some(Duration::from_secs(30)),
+                ..Default::default()
+            },
+        );
+        semaphores.insert(String::from("B"), SemaphoreCfg::default());
+        let state = Data::new(State::new(semaphores));
+        let mut app =
+            test::init_service(App::new().app_data(state.clone()).service(acquire_new_peer)).await;
+
+        // expires_in falls back to the default of the semaphore
+        let req = test::TestRequest::post()
+            .uri("/acquire")
+            .set_json(&json!({"semaphore": "A", "count": 2}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers().get(EXPIRES_IN_SEC).unwrap(), "30");
+
+        // Larger than max_amount, even though there would be capacity left
+        let req = test::TestRequest::post()
+            .uri("/acquire")
+            .set_json(&json!({"semaphore": "A", "count": 3}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // B has no default, so expires_in must be specified
+        let req = test::TestRequest::post()
+            .uri("/acquire")
+            .set_json(&json!({"semaphore": "B", "count": 1}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(state.num_peers(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn rate_limit_acquire() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 10,
+                ..Default::default()
+            },
+        );
+        let mut state = State::new(semaphores);
+        state.limit_rate(RateLimitCfg {
+            per_second: 1,
+            burst: 1,
+        });
+        let state = Data::new(state);
+        let mut app =
+            test::init_service(App::new().app_data(state.clone()).service(acquire_new_peer)).await;
+        let body = json!({"expires_in": "1m", "semaphore": "A", "count": 1});
+
+        let req = test::TestRequest::post()
+            .uri("/acquire")
+            .set_json(&body)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::post()
+            .uri("/acquire")
+            .set_json(&body)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "1");
+        assert_eq!(state.num_peers(), 1);
+    }
+
+    /// Blocked clients are told to come back once the server has been restarted.
+    #[actix_rt::test]
+    async fn acquire_during_shutdown() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let holder = state.new_peer(Duration::from_secs(60));
+        state.acquire(holder, "A", 1, None, None).await.unwrap();
+        state.shut_down();
+        let mut app =
+            test::init_service(App::new().app_data(state.clone()).service(acquire_new_peer)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/acquire?block_for=1s")
+            .set_json(&json!({"expires_in": "1m", "semaphore": "A", "count": 1}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "5");
+        let body: Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(
+            body,
+            json!({"error": "Server is shutting down. Repeat the request once it is back."})
+        );
+    }
+
+    #[actix_rt::test]
+    async fn revoke_peer() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 2,
+                strict_amounts: true,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let peer = state.new_peer(Duration::from_secs(60));
+        state.acquire(peer, "A", 2, None, None).await.unwrap();
+        let mut app =
+            test::init_service(App::new().app_data(state.clone()).service(put_peer)).await;
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/peers/{}", peer))
+            .set_json(&json!({"expires_in": "1m", "acquired": {"A": 1}}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let body: Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(body["acquired"], json!({"A": 2}));
+        assert_eq!(body["claimed"], json!({"A": 1}));
+
+        // Further heartbeats tell the client not to restore the peer
+        let req = test::TestRequest::put()
+            .uri(&format!("/peers/{}", peer))
+            .set_json(&json!({"expires_in": "1m"}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+    }
+
+    #[actix_rt::test]
+    async fn repeat_idempotency_key() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let mut app =
+            test::init_service(App::new().app_data(state.clone()).service(acquire_new_peer)).await;
+        let body = json!({"expires_in": "1m", "semaphore": "A", "count": 1});
+
+        let mut peers = Vec::new();
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri("/acquire")
+                .header("Idempotency-Key", "job-42")
+                .set_json(&body)
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            // The lock of the first peer is reported as acquired both times
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            let peer: PeerId = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+            peers.push(peer);
+        }
+        assert_eq!(peers[0], peers[1]);
+        assert_eq!(state.num_peers(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn uuid_peer_ids() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let mut state = State::new(semaphores);
+        state.peer_ids(PeerIdKind::Uuid);
+        let state = Data::new(state);
+        let mut app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(new_peer)
+                .service(acquire)
+                .service(release),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/new_peer")
+            .set_json(&json!({"expires_in": "1m"}))
+            .to_request();
+        let peer: Value = test::read_response_json(&mut app, req).await;
+        // UUIDs are serialized as strings
+        let peer = peer.as_str().unwrap();
+        assert!(matches!(peer.parse(), Ok(PeerId::Uuid(_))));
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/peers/{}/A", peer))
+            .set_json(&json!(1))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Integer ids still parse. This one is merely unknown, rather than a malformed path.
+        let req = test::TestRequest::put()
+            .uri("/peers/42/A")
+            .set_json(&json!(1))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/peers/{}", peer))
+            .to_request();
+        test::call_service(&mut app, req).await;
+        assert_eq!(state.num_peers(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn release_tells_held_locks() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 2,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let one_min = Duration::from_secs(60);
+        let active = state.new_peer(one_min);
+        state.acquire(active, "A", 2, None, None).await.unwrap();
+        let pending = state.new_peer(one_min);
+        state.acquire(pending, "A", 1, None, None).await.unwrap();
+        let mut app = test::init_service(App::new().app_data(state).service(release)).await;
+
+        let delete = |peer: PeerId| {
+            test::TestRequest::delete()
+                .uri(&format!("/peers/{}", peer))
+                .to_request()
+        };
+        let body: Value = test::read_response_json(&mut app, delete(pending)).await;
+        assert_eq!(
+            body,
+            json!({
+                "released": true,
+                "was_active": false,
+                "acquired": {},
+                "pending": {"semaphore": "A", "count": 1}
+            })
+        );
+        let body: Value = test::read_response_json(&mut app, delete(active)).await;
+        assert_eq!(
+            body,
+            json!({"released": true, "was_active": true, "acquired": {"A": 2}, "pending": null})
+        );
+        // Releasing again is fine, but there is nothing left to release
+        let body: Value = test::read_response_json(&mut app, delete(active)).await;
+        assert_eq!(body["released"], json!(false));
+    }
+
+    #[actix_rt::test]
+    async fn list_metadata() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = Data::new(State::new(semaphores));
+        let metadata = Metadata {
+            hostname: Some(String::from("worker-1")),
+            ..Default::default()
+        };
+        let peer = state
+            .create_peer(None, Duration::from_secs(60), Some(metadata), 0)
+            .unwrap();
+        state.acquire(peer, "A", 1, None, None).await.unwrap();
+
+        let mut app = test::init_service(App::new().app_data(state).service(list_peers)).await;
+        let req = test::TestRequest::with_uri("/peers").to_request();
+        let locks: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(locks[0]["metadata"], json!({"hostname": "worker-1"}));
+    }
+
+    #[actix_rt::test]
+    async fn reject_oversized_metadata() {
+        let state = Data::new(State::new(Semaphores::new()));
+        let mut app = test::init_service(App::new().app_data(state).service(new_peer)).await;
+        let req = test::TestRequest::post()
+            .uri("/new_peer")
+            .set_json(&json!({"expires_in": "1m", "metadata": {"note": "x".repeat(257)}}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn limit_expires_in() {
+        let mut state = State::new(Semaphores::new());
+        state.limit_expires_in(Duration::from_secs(3600), ExceedingExpiresIn::Clamp);
+        let mut app =
+            test::init_service(App::new().app_data(Data::new(state)).service(new_peer)).await;
+        let req = test::TestRequest::post()
+            .uri("/new_peer")
+            .set_json(&json!({"expires_in": "30days"}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(EXPIRES_IN_SEC).unwrap(), "3600");
+
+        let mut state = State::new(Semaphores::new());
+        state.limit_expires_in(Duration::from_secs(3600), ExceedingExpiresIn::Reject);
+        let mut app =
+            test::init_service(App::new().app_data(Data::new(state)).service(new_peer)).await;
+        let req = test::TestRequest::post()
+            .uri("/new_peer")
+            .set_json(&json!({"expires_in": "30days"}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+
+    /// Without `max_expires_in` a huge `expires_in` would overflow the deadline of the peer.
+    #[actix_rt::test]
+    async fn expires_in_past_the_end_of_time() {
+        let state = Data::new(State::new(Semaphores::new()));
+        let peer = state.new_peer(Duration::from_secs(60));
+        let mut app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(new_peer)
+                .service(put_peer),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/new_peer")
+            .set_json(&json!({"expires_in": "400000000000years"}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/peers/{}", peer))
+            .set_json(&json!({"expires_in": "400000000000years"}))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = test::read_body(resp).await;
+        assert!(body.starts_with(b"expires_in of"));
+        assert_eq!(state.num_peers(), 1);
+    }
 }
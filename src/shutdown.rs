@@ -0,0 +1,33 @@
+//! Graceful shutdown. On `SIGTERM` or `Ctrl+C` requests blocked waiting for a lock are answered
+//! with `503 Service Unavailable`, so clients can tell the shutdown from a network failure. Then the
+//! server stops accepting connections and finishes the requests in flight, within the configured
+//! `shutdown_timeout`.
+
+use crate::state::State;
+use actix_web::dev::Server;
+use futures::future::{self, FutureExt};
+use log::warn;
+use std::sync::Arc;
+use tokio::signal;
+
+/// Completes once the process is asked to terminate.
+async fn terminate() {
+    let ctrl_c = signal::ctrl_c().map(|_| ());
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm =
+            signal(SignalKind::terminate()).expect("Error installing handler for SIGTERM");
+        future::select(Box::pin(ctrl_c), Box::pin(sigterm.recv())).await;
+    }
+    #[cfg(not(unix))]
+    ctrl_c.await;
+}
+
+/// Waits for a signal to terminate and shuts down `server` gracefully.
+pub async fn on_signal(server: Server, state: Arc<State>) {
+    terminate().await;
+    state.shut_down();
+    warn!("Stopping server.");
+    server.stop(true).await;
+}
@@ -0,0 +1,70 @@
+//! Addresses to listen on. Besides a TCP port, throttle may listen on a unix domain socket. Useful
+//! if all clients run on the same host, e.g. build agents throttling local compilations.
+
+use std::path::Path;
+
+/// Prefix of `listen` values naming a unix domain socket, e.g. `unix:/run/throttle.sock`.
+const UNIX_PREFIX: &str = "unix:";
+
+/// Path of the unix domain socket, if `listen` names one. `None` for TCP addresses.
+pub fn unix_socket_path(listen: &str) -> Option<&Path> {
+    listen.strip_prefix(UNIX_PREFIX).map(Path::new)
+}
+
+/// Splits a TCP address like `127.0.0.1:8000` or `[::1]:8000` into host and port. `None` if the
+/// port is missing or not a number.
+pub fn host_and_port(address: &str) -> Option<(&str, u16)> {
+    let colon = address.rfind(':')?;
+    let port = address[colon + 1..].parse().ok()?;
+    Some((&address[..colon], port))
+}
+
+/// Removes a socket file left behind by a previous run, which did not shut down cleanly. Binding
+/// would fail otherwise. Files which are not sockets are left alone.
+#[cfg(unix)]
+pub fn remove_stale(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Sets the permissions of the socket file, e.g. `0o660` to grant access to a group only.
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+    std::fs::set_permissions(path, Permissions::from_mode(mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen() {
+        assert_eq!(
+            unix_socket_path("unix:/run/throttle.sock"),
+            Some(Path::new("/run/throttle.sock"))
+        );
+        assert_eq!(unix_socket_path("127.0.0.1:8000"), None);
+    }
+
+    #[test]
+    fn split_host_and_port() {
+        assert_eq!(host_and_port("0.0.0.0:8000"), Some(("0.0.0.0", 8000)));
+        assert_eq!(host_and_port("[::1]:80"), Some(("[::1]", 80)));
+        assert_eq!(host_and_port("localhost"), None);
+        assert_eq!(host_and_port("localhost:http"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keep_regular_files() {
+        let path = std::env::temp_dir().join("throttle-not-a-socket");
+        std::fs::write(&path, "keep me").unwrap();
+        remove_stale(&path).unwrap();
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,66 +1,406 @@
 use crate::{
-    application_cfg::Semaphores,
+    application_cfg::{
+        check_semaphore_name, ExceedingExpiresIn, PeerIdKind, SemaphoreCfg, Semaphores,
+    },
+    change_log::{Change, ChangeLog},
+    clock::{Clock, SystemClock},
     error::ThrottleError,
-    leases::{Counts, Leases, PeerId},
+    events::{Event, Events},
+    id_generator::{self, IdGenerator, RandomIds},
+    idempotency::IdempotencyKeys,
+    leases::{
+        describe, Counts, Leases, LockFilter, LockInfo, Metadata, PeerId, PeerInfo, PeerState,
+        PeerStatus, Released, RemovedPeer, Revenant, SemaphoreLimits,
+    },
+    litter_collection,
+    logging::Fields,
+    poison,
+    rate_limit::{RateLimitCfg, RateLimiter},
     wakers::Wakers,
 };
 use lazy_static::lazy_static;
-use log::{debug, warn};
-use prometheus::IntGaugeVec;
+use log::{debug, info, warn, Level};
+use prometheus::{Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
 use std::{
     collections::HashMap,
     mem::drop,
-    sync::Mutex,
+    sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
     time::{Duration, Instant},
 };
-use tokio::time;
+use tokio::sync::broadcast::Receiver;
 
 /// State of the Semaphore service, shared between threads
 ///
 /// This class combines the confguration of the `semaphores`, with the state of the peers in
 /// `leases` and makes them consumable in an asynchronous, mulithreaded consumer.
 pub struct State {
-    /// All known semaphores and their full count
-    semaphores: Semaphores,
+    /// All known semaphores and their full count. Full counts may change at runtime. Always acquire
+    /// this before `leases`, never the other way round.
+    semaphores: RwLock<Semaphores>,
     /// Bookeeping for leases, protected by mutex so multiple threads (i.e. requests) can manipulate
     /// it. Must not contain any leases not configured in semaphores.
     leases: Mutex<Leases>,
     /// Peer id and weak references to mutex for each pending request.
     wakers: Wakers,
+    /// Changes of the counts of semaphores, for clients watching them.
+    events: Events,
+    /// Peers created for idempotency keys. Never hold this while acquiring `leases`, so requests
+    /// with keys do not queue up behind the ledger.
+    idempotency_keys: Mutex<IdempotencyKeys>,
+    /// Bookkeeping about the litter collection, so we can tell if it silently died.
+    litter_collection: Mutex<litter_collection::Status>,
+    /// Told about every new expiry, so the litter collection wakes up in time to reap the peer.
+    litter_wakeup: litter_collection::Wakeup,
+    /// Administrative changes to the runtime configuration.
+    changes: Mutex<ChangeLog>,
+    /// Instant the state has been created. Used to report uptime.
+    started: Instant,
+    /// Expiration and the age of pending locks are measured against this clock.
+    clock: Arc<dyn Clock>,
+    /// Upper bound for `expires_in` and what to do with requests exceeding it.
+    max_expires_in: Option<(Duration, ExceedingExpiresIn)>,
+    /// Limits the rate at which each client may acquire locks. `None` means unlimited.
+    rate_limiter: Option<RateLimiter>,
+    /// Full count of semaphores created on the fly, if a lock to an unknown one is requested.
+    /// `None` means unknown semaphores are an error.
+    default_semaphore_count: Option<i64>,
 }
 
 impl State {
     /// Creates the state required for the semaphore service
     pub fn new(semaphores: Semaphores) -> State {
+        Self::with_id_generator(semaphores, Box::new(RandomIds::default()))
+    }
+
+    /// Like `new`, but new peers are identified by ids from `ids`. Tests use `SequentialIds` to
+    /// predict them.
+    pub fn with_id_generator(semaphores: Semaphores, ids: Box<dyn IdGenerator>) -> State {
+        let mut leases = Leases::new();
+        leases.set_id_generator(ids);
         State {
-            leases: Mutex::new(Leases::new()),
-            semaphores,
+            leases: Mutex::new(leases),
+            semaphores: RwLock::new(semaphores),
             wakers: Wakers::new(),
+            events: Events::new(),
+            idempotency_keys: Mutex::new(IdempotencyKeys::default()),
+            litter_collection: Mutex::new(litter_collection::Status::default()),
+            litter_wakeup: litter_collection::Wakeup::default(),
+            changes: Mutex::new(ChangeLog::default()),
+            started: Instant::now(),
+            clock: Arc::new(SystemClock),
+            max_expires_in: None,
+            rate_limiter: None,
+            default_semaphore_count: None,
+        }
+    }
+
+    /// Replaces the system clock, e.g. with a `ManualClock` in tests. Call this before sharing the
+    /// state.
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Current time, as told by the clock of the state.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Limits the `expires_in` requested for peers to `max`. Call this before sharing the state.
+    pub fn limit_expires_in(&mut self, max: Duration, exceeding: ExceedingExpiresIn) {
+        self.max_expires_in = Some((max, exceeding));
+    }
+
+    /// Chooses wether new peers are identified by random integers, random UUIDs or sequential
+    /// integers. Call this before sharing the state.
+    pub fn peer_ids(&mut self, kind: PeerIdKind) {
+        self.leases
+            .get_mut()
+            .unwrap()
+            .set_id_generator(id_generator::from_kind(kind));
+    }
+
+    /// Limits the rate at which each client may acquire locks. Call this before sharing the state.
+    pub fn limit_rate(&mut self, cfg: RateLimitCfg) {
+        self.rate_limiter = Some(RateLimiter::new(cfg));
+    }
+
+    /// Creates unknown semaphores with full count `count`, once a lock to them is requested. Call
+    /// this before sharing the state.
+    pub fn auto_create_semaphores(&mut self, count: i64) {
+        self.default_semaphore_count = Some(count);
+    }
+
+    /// Adds `semaphore` with the default count, if it is unknown and auto creation is enabled.
+    /// Must be called before acquiring the lock to `semaphores`.
+    fn create_if_unknown(&self, semaphore: &str) {
+        let max = match self.default_semaphore_count {
+            Some(max) => max,
+            None => return,
+        };
+        if self.semaphores().contains_key(semaphore) {
+            return;
+        }
+        let mut semaphores = self.semaphores_mut();
+        // Another request may have created it, while we did not hold the lock.
+        if !semaphores.contains_key(semaphore) {
+            semaphores.insert(
+                semaphore.to_owned(),
+                SemaphoreCfg {
+                    max,
+                    ..Default::default()
+                },
+            );
+            AUTO_CREATED.inc();
+            info!("Semaphore '{}' created with full count {}.", semaphore, max);
+        }
+    }
+
+    /// `RateLimited` if `client` exceeded its rate of acquiring locks.
+    pub fn check_rate(&self, client: &str) -> Result<(), ThrottleError> {
+        match &self.rate_limiter {
+            Some(limiter) => limiter
+                .check(client, self.now())
+                .map_err(|retry_after| ThrottleError::RateLimited { retry_after }),
+            None => Ok(()),
+        }
+    }
+
+    /// The `expires_in` actually applied, if a client asks for `expires_in`. Either clamped to the
+    /// configured maximum, or `ExpiresInTooLong`, if the request should be rejected.
+    pub fn effective_expires_in(&self, expires_in: Duration) -> Result<Duration, ThrottleError> {
+        match self.max_expires_in {
+            Some((max, _)) if expires_in <= max => Ok(expires_in),
+            Some((max, ExceedingExpiresIn::Clamp)) => {
+                debug!("expires_in of {:?} clamped to {:?}.", expires_in, max);
+                Ok(max)
+            }
+            Some((max, ExceedingExpiresIn::Reject)) => Err(ThrottleError::ExpiresInTooLong { max }),
+            None => Ok(expires_in),
         }
     }
 
+    /// `effective_expires_in` of a peer created together with a lock to `semaphore`. Falls back to
+    /// the `default_expires_in` of the semaphore, if the client does not specify one.
+    pub fn expires_in_for(
+        &self,
+        semaphore: &str,
+        expires_in: Option<Duration>,
+    ) -> Result<Duration, ThrottleError> {
+        let expires_in = match expires_in {
+            Some(expires_in) => expires_in,
+            None => {
+                self.create_if_unknown(semaphore);
+                self.semaphores()
+                    .get(semaphore)
+                    .ok_or(ThrottleError::UnknownSemaphore)?
+                    .default_expires_in
+                    .ok_or(ThrottleError::MissingExpiresIn)?
+            }
+        };
+        self.effective_expires_in(expires_in)
+    }
+
     /// Creates a new peer.
+    #[cfg(test)]
     pub fn new_peer(&self, expires_in: Duration) -> PeerId {
-        let mut leases = self.leases.lock().unwrap();
-        let valid_until = Instant::now() + expires_in;
-        let peer_id = leases.new_peer(valid_until);
-        debug!("Created new peer {}.", peer_id);
+        let mut leases = self.leases();
+        let valid_until = valid_until(self.now(), expires_in).unwrap();
+        let peer_id = leases.new_peer(valid_until, None, 0);
+        self.litter_wakeup.expires_at(valid_until);
+        log_peer!(
+            Level::Debug,
+            Fields::peer(peer_id),
+            "Created new peer {}.",
+            peer_id
+        );
         peer_id
     }
 
+    /// Creates a new peer, with metadata supplied by the client.
+    ///
+    /// * `peer_id`: Id chosen by the client. Creating a peer with the same id again is idempotent,
+    ///   as long as the peer does not hold any locks yet. If `None` a random id is generated.
+    /// * `metadata`: Information about the client, shown in listings and log messages.
+    /// * `priority`: Once capacity frees up, pending locks of peers with higher priority are
+    ///   acquired first.
+    pub fn create_peer(
+        &self,
+        peer_id: Option<PeerId>,
+        expires_in: Duration,
+        metadata: Option<Metadata>,
+        priority: u8,
+    ) -> Result<PeerId, ThrottleError> {
+        if let Some(metadata) = &metadata {
+            metadata.validate()?;
+        }
+        let mut leases = self.leases();
+        let valid_until = valid_until(self.now(), expires_in)?;
+        let peer_id = match peer_id {
+            Some(peer_id) => {
+                leases.new_peer_with_id(peer_id, valid_until, metadata, priority)?;
+                peer_id
+            }
+            None => leases.new_peer(valid_until, metadata, priority),
+        };
+        self.litter_wakeup.expires_at(valid_until);
+        log_peer!(
+            Level::Debug,
+            Fields::peer(peer_id),
+            "Created new peer {}.",
+            leases.describe(peer_id)
+        );
+        Ok(peer_id)
+    }
+
+    /// Same as `create_peer`, unless a peer has already been created for `idempotency_key`. In
+    /// that case the id of the existing peer is returned. Concurrent requests with the same key
+    /// end up with the same peer. Keys are remembered for `expires_in`.
+    ///
+    /// Returns the id of the peer and `true` if it has been created by this call.
+    pub fn create_peer_once(
+        &self,
+        idempotency_key: &str,
+        peer_id: Option<PeerId>,
+        expires_in: Duration,
+        metadata: Option<Metadata>,
+        priority: u8,
+    ) -> Result<(PeerId, bool), ThrottleError> {
+        let now = self.now();
+        let key_valid_until = valid_until(now, expires_in)?;
+        let known =
+            poison::lock(&self.idempotency_keys, "the idempotency keys").get(idempotency_key, now);
+        let existing = match known {
+            Some(peer_id) => peer_id,
+            None => {
+                // The keys are not locked while creating the peer, so unrelated requests do not
+                // wait on the ledger. A concurrent request with the same key may win the race
+                // though, in which case the peer created here is discarded again.
+                let created = self.create_peer(peer_id, expires_in, metadata, priority);
+                let mut keys = poison::lock(&self.idempotency_keys, "the idempotency keys");
+                match (keys.get(idempotency_key, now), created) {
+                    (Some(existing), created) => {
+                        drop(keys);
+                        // The winner may have taken the id requested by the client already.
+                        if let Ok(created) = created {
+                            self.leases().remove_peer(created);
+                        }
+                        existing
+                    }
+                    (None, Err(error)) => return Err(error),
+                    (None, Ok(created)) => {
+                        keys.insert(idempotency_key, created, key_valid_until);
+                        return Ok((created, true));
+                    }
+                }
+            }
+        };
+        log_peer!(
+            Level::Debug,
+            Fields::peer(existing),
+            "Repeated idempotency key for peer {}.",
+            existing
+        );
+        Ok((existing, false))
+    }
+
+    /// Creates a new peer holding a lock to `semaphore`, but only if the lock can be acquired right
+    /// away. Otherwise nothing is inserted into the ledger and `Unavailable` tells the remainder of
+    /// the semaphore. Meant for opportunistic work, which is skipped if no capacity is free.
+    pub fn try_acquire(
+        &self,
+        peer_id: Option<PeerId>,
+        expires_in: Duration,
+        metadata: Option<Metadata>,
+        priority: u8,
+        semaphore: &str,
+        amount: i64,
+    ) -> Result<PeerId, ThrottleError> {
+        if amount < 1 {
+            return Err(ThrottleError::InvalidLockCount { count: amount });
+        }
+        if let Some(metadata) = &metadata {
+            metadata.validate()?;
+        }
+        self.create_if_unknown(semaphore);
+        let semaphores = self.semaphores();
+        let sem = *semaphores
+            .get(semaphore)
+            .ok_or(ThrottleError::UnknownSemaphore)?;
+        if sem.max == 0 {
+            return Err(ThrottleError::Closed);
+        }
+        if sem.max < amount {
+            return Err(ThrottleError::Never {
+                asked: amount,
+                max: sem.max,
+            });
+        }
+        sem.check_amount(amount)?;
+        let mut leases = self.leases();
+        if leases.is_frozen(semaphore) {
+            return Err(ThrottleError::Frozen);
+        }
+        if !leases.can_acquire_now(semaphore, amount, sem.max) {
+            let remainder = sem.max.saturating_sub(leases.count(semaphore));
+            log_peer!(
+                Level::Debug,
+                Fields::default().semaphore(semaphore).amount(amount),
+                "Lock to '{}' with count {} is not available right now.",
+                semaphore,
+                amount
+            );
+            return Err(ThrottleError::Unavailable { remainder });
+        }
+        let now = self.now();
+        let valid_until = valid_until(now, expires_in)?;
+        let peer_id = match peer_id {
+            Some(peer_id) => {
+                leases.new_peer_with_id(peer_id, valid_until, metadata, priority)?;
+                peer_id
+            }
+            None => leases.new_peer(valid_until, metadata, priority),
+        };
+        self.litter_wakeup.expires_at(valid_until);
+        // A new peer holds no locks, so neither lock hierarchies nor pending locks are in the way.
+        let limits = SemaphoreLimits {
+            max: sem.max,
+            level: sem.level,
+        };
+        let acquired = leases.acquire(peer_id, semaphore, amount, limits, now, |s| {
+            semaphores.get(s).unwrap().level
+        })?;
+        debug_assert!(acquired, "Capacity has been checked while holding the lock");
+        self.publish_counts(&semaphores, &leases);
+        log_peer!(
+            Level::Debug,
+            Fields::peer(peer_id).semaphore(semaphore).amount(amount),
+            "Peer {} acquired lock to '{}' with count {}.",
+            leases.describe(peer_id),
+            semaphore,
+            amount
+        );
+        Ok(peer_id)
+    }
+
+    /// Forgets the peer created for `idempotency_key`, e.g. because it has been removed again.
+    pub fn forget_idempotency_key(&self, idempotency_key: &str) {
+        poison::lock(&self.idempotency_keys, "the idempotency keys").remove(idempotency_key);
+    }
+
     /// Sets the lock count for this peer and semaphore to `amount`. Should the remainder of the
     /// semaphore allow it.
     ///
     /// * `peer_id`: Identifies the peer for which we are setting the lock count
     /// * `semaphore`: Name of the semaphore to which we want to acquire the lock count.
     /// * `amount`: The count of the lock. Outside of revenants (i.e. expired peers, which do
-    /// return). Throttle is goingt to see to it that the combined lock count is not going beyond
-    /// the configured max count.
+    ///   return). Throttle is goingt to see to it that the combined lock count is not going beyond
+    ///   the configured max count.
     /// * `wait_for`: The future returns as soon as the lock could be acquireod or after the
-    /// duration has elapsed, even if the lock could not be acquired. If set to `None`, the future
-    /// returns immediatly.
-    /// * `expires_in`: Used to prolong the expiration timestamp of the affected peer. This saves us
-    /// an extra heartbeat request.
+    ///   duration has elapsed, even if the lock could not be acquired. If set to `None`, the
+    ///   future returns immediatly.
+    /// * `expires_in`: Used to prolong the expiration timestamp of the affected peer. This saves
+    ///   us an extra heartbeat request.
     pub async fn acquire(
         &self,
         peer_id: PeerId,
@@ -69,58 +409,162 @@ impl State {
         wait_for: Option<Duration>,
         expires_in: Option<Duration>,
     ) -> Result<bool, ThrottleError> {
-        let sem = self
-            .semaphores
+        self.create_if_unknown(semaphore);
+        let fields = Fields::peer(peer_id).semaphore(semaphore).amount(amount);
+        // Keep holding the locks to `semaphores` and `leases` until the waker is registered, so we
+        // don't miss the call to `resolve_with`. Scoped, so both are released before we wait.
+        // Otherwise, we might deadlock.
+        let (peer, acquire_or_timeout) = {
+            let semaphores = self.semaphores();
+            let sem = *semaphores
+                .get(semaphore)
+                .ok_or(ThrottleError::UnknownSemaphore)?;
+            let max = sem.max;
+            // Return early if lease can never be acquired. Closed semaphores may be reopened, so
+            // `Leases::acquire` tells these apart.
+            if max != 0 && max < amount {
+                return Err(ThrottleError::Never { asked: amount, max });
+            }
+            sem.check_amount(amount)?;
+            let mut leases = self.leases();
+            let now = self.now();
+            // Peers holding locks to semaphores which do not expire, do not need to be prolonged.
+            if let Some(expires_in) = expires_in.filter(|_| sem.expires) {
+                let valid_until = valid_until(now, expires_in)?;
+                leases.update_valid_until(peer_id, valid_until)?;
+                self.litter_wakeup.expires_at(valid_until);
+            }
+            let limits = SemaphoreLimits {
+                max,
+                level: sem.level,
+            };
+            let acquired = leases.acquire(peer_id, semaphore, amount, limits, now, |s| {
+                semaphores.get(s).unwrap().level
+            })?;
+            self.publish_counts(&semaphores, &leases);
+            let peer = leases.describe(peer_id);
+            if acquired {
+                // Resolve this immediatly, if we can
+                log_peer!(
+                    Level::Debug,
+                    fields,
+                    "Peer {} acquired lock to '{}' with count {}.",
+                    peer,
+                    semaphore,
+                    amount
+                );
+                return Ok(true);
+            }
+            log_peer!(
+                Level::Debug,
+                fields,
+                "Peer {} waiting for lock to '{}' with count {}.",
+                peer,
+                semaphore,
+                amount
+            );
+            // We could not acquire the lock immediatly. Are we going to wait for it?
+            match wait_for {
+                Some(wait_for) => (
+                    peer,
+                    self.wakers.wait_for_resolving(peer_id, semaphore, wait_for),
+                ),
+                None => return Ok(false),
+            }
+        };
+        // `None` indicates a timeout.
+        match acquire_or_timeout.await {
+            // Locks could be acquired
+            Some(Ok(())) => {
+                log_peer!(
+                    Level::Debug,
+                    fields,
+                    "Peer {} acquired lock to '{}' with count {}.",
+                    peer,
+                    semaphore,
+                    amount
+                );
+                Ok(true)
+            }
+            // Failure
+            Some(Err(e)) => Err(e),
+            // Lock could not be acquired in time
+            None => Ok(false),
+        }
+    }
+
+    /// Changes the count of a lock the peer already acquired to `amount`. If growing and there is
+    /// not enough capacity left and `wait` is `true`, the additional count is pending until it can
+    /// be acquired. The peer keeps its current count in the meantime. Shrinking takes effect
+    /// immediately and may resolve pending locks of other peers.
+    ///
+    /// Returns `true` if all locks of the peer are acquired.
+    pub fn change_amount(
+        &self,
+        peer_id: PeerId,
+        semaphore: &str,
+        amount: i64,
+        wait: bool,
+    ) -> Result<bool, ThrottleError> {
+        let semaphores = self.semaphores();
+        let sem = semaphores
             .get(semaphore)
             .ok_or(ThrottleError::UnknownSemaphore)?;
+        sem.check_amount(amount)?;
         let max = sem.max;
-        let level = sem.level;
-        // Return early if lease can never be acquired
-        if max < amount {
-            return Err(ThrottleError::Never { asked: amount, max });
-        }
-        let mut leases = self.leases.lock().unwrap();
-        if let Some(expires_in) = expires_in {
-            let valid_until = Instant::now() + expires_in;
-            leases.update_valid_until(peer_id, valid_until)?;
-        }
-        let acquired = leases.acquire(peer_id, semaphore, amount, max, level, |s| {
-            self.semaphores.get(s).unwrap().level
-        })?;
+        let mut leases = self.leases();
+        let acquired = leases.change_amount(peer_id, semaphore, amount, max, wait, self.now())?;
+        // Shrinking may free up capacity for pending locks.
+        let mut resolved_peers = Vec::new();
+        leases.resolve_pending(semaphore, max, self.now(), &mut resolved_peers);
+        self.publish_counts(&semaphores, &leases);
+        drop(leases);
+        self.wakers.resolve_with(&resolved_peers, Ok(()));
+        let acquired = acquired || resolved_peers.contains(&peer_id);
+        let fields = Fields::peer(peer_id).semaphore(semaphore).amount(amount);
         if acquired {
-            // Resolve this immediatly, if we can
-            debug!("Peer {} acquired lock to '{}'.", peer_id, semaphore);
-            Ok(true)
+            log_peer!(
+                Level::Debug,
+                fields,
+                "Peer {} changed lock to '{}' to count {}.",
+                peer_id,
+                semaphore,
+                amount
+            );
         } else {
-            debug!("Peer {} waiting for lock to '{}'", peer_id, semaphore);
-
-            // We could not acquire the lock immediatly. Are we going to wait for it?
-            if let Some(wait_for) = wait_for {
-                // keep holding the lock to `leases` until everything is registered. So we don't miss
-                // the call to `resolve_with`.
-
-                let acquire_or_timeout =
-                    time::timeout(wait_for, self.wakers.wait_for_resolving(peer_id));
+            log_peer!(
+                Level::Debug,
+                fields,
+                "Peer {} waiting to grow lock to '{}' to count {}.",
+                peer_id,
+                semaphore,
+                amount
+            );
+        }
+        Ok(acquired)
+    }
 
-                // Release lock on leases, while waiting for acquire_or_timeout! Otherwise, we might
-                // deadlock.
-                drop(leases);
-                // The outer `Err` indicates a timeout.
-                match acquire_or_timeout.await {
-                    // Locks could be acquired
-                    Ok(Ok(())) => {
-                        debug!("Peer {} acquired lock to '{}'.", peer_id, semaphore);
-                        Ok(true)
-                    }
-                    // Failure
-                    Ok(Err(e)) => Err(e),
-                    // Lock could not be acquired in time
-                    Err(_) => Ok(false),
-                }
-            } else {
-                Ok(acquired)
-            }
+    /// Splits a peer holding exactly one acquired lock into new peers with the specified amounts.
+    /// The total count of the semaphore is not changed. Returns the ids of the new peers.
+    pub fn split(&self, peer_id: PeerId, amounts: &[i64]) -> Result<Vec<PeerId>, ThrottleError> {
+        let new_peers = self.leases().split(peer_id, amounts)?;
+        log_peer!(
+            Level::Debug,
+            Fields::peer(peer_id),
+            "Peer {} split into {:?}.",
+            peer_id,
+            new_peers
+        );
+        for &new_peer in &new_peers {
+            log_peer!(
+                Level::Debug,
+                Fields::peer(new_peer),
+                "Peer {} split off peer {}.",
+                new_peer,
+                peer_id
+            );
         }
+        Ok(new_peers)
     }
 
     /// Removes leases outdated due to timestamp. Wakes threads waiting for pending leases if any
@@ -128,75 +572,263 @@ impl State {
     ///
     /// Returns number of (now removed) expired leases
     pub fn remove_expired(&self) -> usize {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.remove_idle(self.now());
+        }
         let (expired_peers, resolved_peers) = {
-            let mut leases = self.leases.lock().unwrap();
-            let (expired_peers, affected_semaphores) = leases.remove_expired(Instant::now());
+            let semaphores = self.semaphores();
+            let mut leases = self.leases();
+            let now = self.now();
+            let (expired_peers, affected_semaphores) =
+                leases.remove_expired(now, |semaphore| expires(&semaphores, semaphore));
             // It is not enough to notify only the requests for the removed peers, as other peers
             // might be able to acquire their locks due to the removal of these.
             let mut resolved_peers = Vec::new();
             for semaphore in affected_semaphores {
                 leases.resolve_pending(
                     &semaphore,
-                    self.semaphores.get(&semaphore).unwrap().max,
+                    semaphores.get(&*semaphore).unwrap().max,
+                    now,
                     &mut resolved_peers,
                 )
             }
+            self.publish_counts(&semaphores, &leases);
             (expired_peers, resolved_peers)
         };
+        LITTER_COLLECTED.inc_by(expired_peers.len() as i64);
         if !expired_peers.is_empty() {
-            warn!("Removed {} peers due to expiration.", expired_peers.len());
+            // One record for each peer, so each carries its id as a field.
+            for (peer_id, metadata) in &expired_peers {
+                log_peer!(
+                    Level::Warn,
+                    Fields::peer(*peer_id),
+                    "Peer {} removed due to expiration.",
+                    describe(*peer_id, metadata.as_ref())
+                );
+            }
+            let expired_peers: Vec<_> = expired_peers.iter().map(|(peer_id, _)| *peer_id).collect();
             self.wakers.resolve_with(&resolved_peers, Ok(()));
             self.wakers
                 .resolve_with(&expired_peers, Err(ThrottleError::UnknownPeer));
         }
+        poison::lock(&self.idempotency_keys, "the idempotency keys").remove_expired(self.now());
         expired_peers.len()
     }
 
-    /// Restore peer
+    /// Restore peer. Fails with `LocksLost` if locks to semaphores configured with `strict_restore`
+    /// do not fit. The peer is restored nonetheless, waiting for the first of these.
     pub fn restore(
         &self,
         peer_id: PeerId,
         expires_in: Duration,
         acquired: &HashMap<String, i64>,
+        metadata: Option<Metadata>,
+        priority: u8,
     ) -> Result<(), ThrottleError> {
-        warn!(
-            "Revenant Peer {}. Has locks: {}",
-            peer_id,
+        log_peer!(
+            Level::Warn,
+            Fields::peer(peer_id),
+            "Revenant peer {}. Has locks: {}",
+            describe(peer_id, metadata.as_ref()),
             !acquired.is_empty()
         );
+        if let Some(metadata) = &metadata {
+            metadata.validate()?;
+        }
 
         for semaphore in acquired.keys() {
+            self.create_if_unknown(semaphore);
+        }
+        let semaphores = self.semaphores();
+        for (semaphore, &amount) in acquired {
             // Assert semaphore exists. We want to give the client an error and also do not want to
             // allow any Unknown Semaphore into `leases`. Also we want to fail fast, before
             // acquiring the lock to `leases`.
-            let _max = *self
-                .semaphores
+            semaphores
                 .get(semaphore)
-                .ok_or(ThrottleError::UnknownSemaphore)?;
+                .ok_or(ThrottleError::UnknownSemaphore)?
+                .check_amount(amount)?;
         }
 
-        let mut leases = self.leases.lock().unwrap();
-        let valid_until = Instant::now() + expires_in;
+        let mut leases = self.leases();
 
         // Acquired all locks for the peer
-        leases.restore(peer_id, &acquired, valid_until)?;
+        let now = self.now();
+        let valid_until = valid_until(now, expires_in)?;
+        let revenant = Revenant {
+            peer_id,
+            acquired,
+            valid_until,
+            metadata,
+            priority,
+        };
+        let restored = leases.restore(revenant, now, |s| {
+            let cfg = semaphores.get(s).unwrap();
+            (cfg.max, cfg.strict_restore)
+        })?;
+        self.litter_wakeup.expires_at(valid_until);
+        for semaphore in restored.overbooked {
+            log_peer!(
+                Level::Warn,
+                Fields::peer(peer_id)
+                    .semaphore(&semaphore)
+                    .amount(acquired[&semaphore]),
+                "Revenant peer {} overbooked semaphore '{}'. Restored count: {}, acquired: {}, \
+                full count: {}.",
+                leases.describe(peer_id),
+                semaphore,
+                acquired[&semaphore],
+                leases.count(&semaphore),
+                semaphores.get(&semaphore).unwrap().max
+            );
+            OVERBOOKED.with_label_values(&[&semaphore]).inc();
+        }
+        self.publish_counts(&semaphores, &leases);
 
-        Ok(())
+        if restored.lost.is_empty() {
+            Ok(())
+        } else {
+            log_peer!(
+                Level::Warn,
+                Fields::peer(peer_id),
+                "Revenant peer {} lost its locks to {:?}, to not overbook them.",
+                leases.describe(peer_id),
+                restored.lost
+            );
+            Err(ThrottleError::LocksLost {
+                semaphores: restored.lost,
+            })
+        }
     }
 
-    pub fn heartbeat(&self, peer_id: PeerId, expires_in: Duration) -> Result<(), ThrottleError> {
-        let mut leases = self.leases.lock().unwrap();
+    /// Prolongs the lifetime of the peer.
+    ///
+    /// Returns the state of the peer after the update. `claimed` are the locks the client believes
+    /// to hold. Should they differ from the acquired ones for any semaphore configured with
+    /// `strict_amounts`, the peer is removed and `ThrottleError::Revoked` is returned. From then on
+    /// requests to the peer are answered with `Gone`, so clients do not restore it. Differences
+    /// for other semaphores are only logged and counted, so clients can be rolled out before
+    /// turning on `strict_amounts`. Claiming a count smaller than one is answered with
+    /// `InvalidLockCount`, since such a lock can never be held.
+    pub fn heartbeat(
+        &self,
+        peer_id: PeerId,
+        expires_in: Duration,
+        claimed: Option<&HashMap<String, i64>>,
+    ) -> Result<PeerStatus, ThrottleError> {
+        if let Some(&count) = claimed.and_then(|claimed| claimed.values().find(|&&c| c < 1)) {
+            return Err(ThrottleError::InvalidLockCount { count });
+        }
+        let semaphores = self.semaphores();
+        let mut leases = self.leases();
         // Determine valid_until after acquiring lock, in case we block for a long time.
-        let valid_until = Instant::now() + expires_in;
+        let now = self.now();
+        let valid_until = valid_until(now, expires_in)?;
+        if let Some(claimed) = claimed {
+            let acquired = leases.acquired(peer_id)?;
+            let differs = |name: &str| {
+                claimed.get(name).copied().unwrap_or(0) != acquired.get(name).copied().unwrap_or(0)
+            };
+            let mismatch = semaphores
+                .iter()
+                .filter(|(_, cfg)| cfg.strict_amounts)
+                .any(|(name, _)| differs(name.as_str()));
+            for (name, _) in semaphores
+                .iter()
+                .filter(|(name, cfg)| !cfg.strict_amounts && differs(name.as_str()))
+            {
+                let held = acquired.get(name.as_str()).copied().unwrap_or(0);
+                log_peer!(
+                    Level::Warn,
+                    Fields::peer(peer_id).semaphore(name).amount(held),
+                    "Peer {} claims {} locks to semaphore '{}', but holds {}.",
+                    peer_id,
+                    claimed.get(name).copied().unwrap_or(0),
+                    name,
+                    held
+                );
+                HEARTBEAT_MISMATCH.with_label_values(&[name.as_str()]).inc();
+            }
+            if mismatch {
+                let error = ThrottleError::Revoked {
+                    acquired: acquired
+                        .iter()
+                        .map(|(semaphore, &count)| (semaphore.to_string(), count))
+                        .collect(),
+                    claimed: claimed.clone(),
+                };
+                log_peer!(
+                    Level::Warn,
+                    Fields::peer(peer_id),
+                    "Revoking peer {}: {}",
+                    peer_id,
+                    error
+                );
+                let (resolved_peers, _removed) =
+                    remove_peer(&semaphores, &mut leases, peer_id, now)
+                        .expect("Peer must exist, we just looked at its locks");
+                leases.retire(peer_id, valid_until);
+                self.publish_counts(&semaphores, &leases);
+                drop(leases);
+                self.wakers.resolve_with(&resolved_peers, Ok(()));
+                // Pending requests of the revoked peer must not wait for it to expire.
+                self.wakers
+                    .resolve_with(&[peer_id], Err(ThrottleError::UnknownPeer));
+                return Err(error);
+            }
+        }
         leases.update_valid_until(peer_id, valid_until)?;
-        Ok(())
+        self.litter_wakeup.expires_at(valid_until);
+        leases.status(peer_id, now)
+    }
+
+    /// Locks and status of a single peer.
+    pub fn peer_info(&self, peer_id: PeerId) -> Result<PeerInfo, ThrottleError> {
+        self.leases().peer_info(peer_id, self.now())
+    }
+
+    /// Snapshot of at most `limit` acquired and pending locks matching `filter`, ordered by peer
+    /// id.
+    pub fn list_locks(&self, filter: LockFilter, limit: Option<usize>) -> Vec<LockInfo> {
+        let semaphores = self.semaphores();
+        let leases = self.leases();
+        leases.list(
+            self.now(),
+            |semaphore| expires(&semaphores, semaphore),
+            filter,
+            limit,
+        )
+    }
+
+    /// Locks of peers expiring within `within`, soonest first. Tells which locks would be orphaned
+    /// by a restart, or which clients stopped sending heartbeats.
+    pub fn list_expiring(&self, within: Duration) -> Vec<LockInfo> {
+        let semaphores = self.semaphores();
+        let leases = self.leases();
+        leases.list_expiring(self.now(), within, |semaphore| {
+            expires(&semaphores, semaphore)
+        })
+    }
+
+    /// Configuration and current state of a semaphore. Everything is read under one guard, so the
+    /// fields are consistent with each other. `None` if the semaphore is unknown.
+    pub fn semaphore(&self, name: &str) -> Option<SemaphoreSnapshot> {
+        let semaphores = self.semaphores();
+        let cfg = *semaphores.get(name)?;
+        let leases = self.leases();
+        Some(SemaphoreSnapshot {
+            cfg,
+            counts: leases.counts(name),
+            frozen: leases.is_frozen(name),
+            now: self.now(),
+        })
     }
 
     pub fn remainder(&self, semaphore: &str) -> Result<i64, ThrottleError> {
-        if let Some(sem) = self.semaphores.get(semaphore) {
-            let leases = self.leases.lock().unwrap();
-            let count = leases.count(&semaphore);
-            Ok(sem.max - count)
+        if let Some(sem) = self.semaphores().get(semaphore) {
+            let leases = self.leases();
+            let count = leases.count(semaphore);
+            Ok(sem.max.saturating_sub(count))
         } else {
             warn!("Unknown semaphore requested");
             Err(ThrottleError::UnknownSemaphore)
@@ -205,86 +837,590 @@ impl State {
 
     /// Removes a peer from bookeeping and releases all acquired leases.
     ///
-    /// Returns `false` should the peer not be found and `true` otherwise. `false` could occur due
-    /// to e.g. the peer already being removed by litter collection.
-    pub fn release(&self, peer_id: PeerId) -> bool {
-        let mut leases = self.leases.lock().unwrap();
-        match leases.remove_peer(peer_id) {
-            Some(semaphores) => {
-                // Keep book about all peers, those locks have been acquired, so we can notify their pending
-                // requests.
-                let mut resolved_peers = Vec::new();
-                for semaphore in semaphores {
-                    let sem = self
-                        .semaphores
-                        .get(&semaphore)
-                        .expect("An active semaphore must always be configured");
-                    leases.resolve_pending(&semaphore, sem.max, &mut resolved_peers);
-                }
-                drop(leases); // Don't hold this longer than we need to.
+    /// Returns the locks the peer held, or `None` should the peer not be found. `None` could occur
+    /// due to e.g. the peer already being removed by litter collection.
+    pub fn release(&self, peer_id: PeerId) -> Option<RemovedPeer> {
+        let semaphores = self.semaphores();
+        let mut leases = self.leases();
+        match remove_peer(&semaphores, &mut leases, peer_id, self.now()) {
+            Some((resolved_peers, removed)) => {
+                self.publish_counts(&semaphores, &leases);
+                // Don't hold these longer than we need to.
+                drop(leases);
+                drop(semaphores);
                 self.wakers.resolve_with(&resolved_peers, Ok(()));
-                true
+                for semaphore in removed.acquired.keys() {
+                    RELEASES.with_label_values(&[&**semaphore]).inc();
+                }
+                if let Some((semaphore, count)) = &removed.pending {
+                    // Often a client giving up on waiting. Worth keeping track of.
+                    log_peer!(
+                        Level::Warn,
+                        Fields::peer(peer_id).semaphore(semaphore).amount(*count),
+                        "Peer {} released while waiting for a lock to '{}' with count {}.",
+                        peer_id,
+                        semaphore,
+                        count
+                    );
+                } else {
+                    log_peer!(
+                        Level::Debug,
+                        Fields::peer(peer_id),
+                        "Peer {} released.",
+                        peer_id
+                    );
+                }
+                Some(removed)
             }
             None => {
-                warn!("Deletion of unknown peer.");
-                false
+                log_peer!(
+                    Level::Warn,
+                    Fields::peer(peer_id),
+                    "Deletion of unknown peer {}.",
+                    peer_id
+                );
+                None
+            }
+        }
+    }
+
+    /// Changes the full count of a semaphore at runtime. Increasing it may resolve pending locks.
+    /// Decreasing it below the sum of acquired locks leaves the semaphore overbooked, until enough
+    /// locks are released.
+    ///
+    /// Returns the previous full count.
+    pub fn set_max(&self, semaphore: &str, max: i64) -> Result<i64, ThrottleError> {
+        if max < 0 {
+            return Err(ThrottleError::InvalidLockCount { count: max });
+        }
+        let mut semaphores = self.semaphores_mut();
+        let sem = semaphores
+            .get_mut(semaphore)
+            .ok_or(ThrottleError::UnknownSemaphore)?;
+        let old = sem.max;
+        sem.max = max;
+        let mut leases = self.leases();
+        let mut resolved_peers = Vec::new();
+        leases.resolve_pending(semaphore, max, self.now(), &mut resolved_peers);
+        self.publish_counts(&semaphores, &leases);
+        drop(leases);
+        drop(semaphores);
+        self.wakers.resolve_with(&resolved_peers, Ok(()));
+        warn!(
+            "Full count of semaphore '{}' changed from {} to {}.",
+            semaphore, old, max
+        );
+        Ok(old)
+    }
+
+    /// Adds a new semaphore at runtime. Fails with `SemaphoreExists` if a semaphore with the same
+    /// name is already configured, and with `InvalidSemaphoreName` for names the configuration
+    /// would reject.
+    pub fn add_semaphore(&self, name: &str, cfg: SemaphoreCfg) -> Result<(), ThrottleError> {
+        check_semaphore_name(name)?;
+        if cfg.max < 0 {
+            return Err(ThrottleError::InvalidLockCount { count: cfg.max });
+        }
+        let mut semaphores = self.semaphores_mut();
+        if semaphores.contains_key(name) {
+            return Err(ThrottleError::SemaphoreExists);
+        }
+        semaphores.insert(name.to_owned(), cfg);
+        warn!("Semaphore '{}' added with full count {}.", name, cfg.max);
+        Ok(())
+    }
+
+    /// Removes a semaphore at runtime. Fails with `SemaphoreInUse` as long as any peer holds or
+    /// waits for a lock to it.
+    ///
+    /// Returns the configuration of the removed semaphore.
+    pub fn remove_semaphore(&self, name: &str) -> Result<SemaphoreCfg, ThrottleError> {
+        let mut semaphores = self.semaphores_mut();
+        if !semaphores.contains_key(name) {
+            return Err(ThrottleError::UnknownSemaphore);
+        }
+        let mut leases = self.leases();
+        if leases.counts(name).peers != 0 {
+            return Err(ThrottleError::SemaphoreInUse);
+        }
+        leases.unfreeze(name);
+        drop(leases);
+        let cfg = semaphores.remove(name).unwrap();
+        drop(semaphores);
+        remove_metrics(name);
+        warn!("Semaphore '{}' removed.", name);
+        Ok(cfg)
+    }
+
+    /// Replaces the configuration of all semaphores with `new`, e.g. after the configuration file
+    /// has been read again. Increased full counts may resolve pending locks right away. Semaphores
+    /// missing in `new` are removed, unless peers still hold or wait for locks to them. Like
+    /// `remove_semaphore`, these are kept with their old configuration, until they are no longer
+    /// in use and removed explicitly, or the server restarts.
+    pub fn reload(&self, new: Semaphores) -> Reload {
+        let mut semaphores = self.semaphores_mut();
+        let mut leases = self.leases();
+        let mut reload = Reload::default();
+        let missing: Vec<String> = semaphores
+            .keys()
+            .filter(|name| !new.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in missing {
+            if leases.counts(&name).peers != 0 {
+                warn!(
+                    "Semaphore '{}' is no longer configured, but kept while it is in use.",
+                    name
+                );
+                reload.in_use.push(name);
+            } else {
+                leases.unfreeze(&name);
+                semaphores.remove(&name);
+                remove_metrics(&name);
+                warn!("Semaphore '{}' removed.", name);
+                reload.removed.push(name);
+            }
+        }
+        let now = self.now();
+        let mut resolved_peers = Vec::new();
+        for (name, cfg) in new {
+            match semaphores.insert(name.clone(), cfg) {
+                None => {
+                    warn!("Semaphore '{}' added with full count {}.", name, cfg.max);
+                    reload.added.push(name);
+                }
+                Some(old) if old != cfg => {
+                    if old.max != cfg.max {
+                        warn!(
+                            "Full count of semaphore '{}' changed from {} to {}.",
+                            name, old.max, cfg.max
+                        );
+                    }
+                    leases.resolve_pending(&name, cfg.max, now, &mut resolved_peers);
+                    reload.changed.push((name, old, cfg));
+                }
+                Some(_) => (),
             }
         }
+        self.publish_counts(&semaphores, &leases);
+        drop(leases);
+        drop(semaphores);
+        self.wakers.resolve_with(&resolved_peers, Ok(()));
+        reload.added.sort();
+        reload.changed.sort_by(|a, b| a.0.cmp(&b.0));
+        reload.removed.sort();
+        reload.in_use.sort();
+        reload
+    }
+
+    /// Freezes a semaphore. Existing locks are kept, but no new ones are granted and pending locks
+    /// are not resolved until the semaphore is unfrozen.
+    ///
+    /// Returns `false` if the semaphore had already been frozen.
+    pub fn freeze(&self, semaphore: &str) -> Result<bool, ThrottleError> {
+        let semaphores = self.semaphores();
+        if !semaphores.contains_key(semaphore) {
+            return Err(ThrottleError::UnknownSemaphore);
+        }
+        let changed = self.leases().freeze(semaphore);
+        if changed {
+            warn!("Semaphore '{}' frozen.", semaphore);
+        }
+        Ok(changed)
+    }
+
+    /// Unfreezes a semaphore and resolves pending locks to it right away.
+    ///
+    /// Returns `false` if the semaphore had not been frozen.
+    pub fn unfreeze(&self, semaphore: &str) -> Result<bool, ThrottleError> {
+        let semaphores = self.semaphores();
+        let max = semaphores
+            .get(semaphore)
+            .ok_or(ThrottleError::UnknownSemaphore)?
+            .max;
+        let mut leases = self.leases();
+        let changed = leases.unfreeze(semaphore);
+        let mut resolved_peers = Vec::new();
+        leases.resolve_pending(semaphore, max, self.now(), &mut resolved_peers);
+        self.publish_counts(&semaphores, &leases);
+        drop(leases);
+        drop(semaphores);
+        self.wakers.resolve_with(&resolved_peers, Ok(()));
+        if changed {
+            warn!("Semaphore '{}' unfrozen.", semaphore);
+        }
+        Ok(changed)
+    }
+
+    /// Read access to the configuration of the semaphores. Acquire it before locking `leases`. Like
+    /// the ledger, the configuration stays in use after a panic poisoned its lock, e.g. while
+    /// resolving pending locks after changing a full count.
+    fn semaphores(&self) -> RwLockReadGuard<'_, Semaphores> {
+        poison::read(&self.semaphores, "the semaphores")
+    }
+
+    /// Write access to the configuration of the semaphores. Acquire it before locking `leases`.
+    fn semaphores_mut(&self) -> RwLockWriteGuard<'_, Semaphores> {
+        poison::write(&self.semaphores, "the semaphores")
+    }
+
+    /// Exclusive access to the ledger. A panic while holding the lock poisons it. Rather than
+    /// failing every request until a restart, we carry on with the ledger as the panic left it.
+    /// The time spent waiting for the lock is observed in `LEDGER_LOCK_WAIT`.
+    fn leases(&self) -> MutexGuard<'_, Leases> {
+        let start = Instant::now();
+        let leases = poison::lock(&self.leases, "the ledger");
+        LEDGER_LOCK_WAIT.observe(start.elapsed().as_secs_f64());
+        leases
+    }
+
+    /// Called once the litter collection is started, so we know in which interval to expect runs.
+    pub fn litter_collection_started(&self, interval: Duration) {
+        poison::lock(&self.litter_collection, "the litter collection status")
+            .start(interval, self.now());
+    }
+
+    /// Remembers the outcome of the last litter collection run.
+    pub fn record_litter_collection(&self, run: litter_collection::Run) {
+        poison::lock(&self.litter_collection, "the litter collection status").record(run);
+    }
+
+    /// Wakes the litter collection early, or stops it.
+    pub fn litter_wakeup(&self) -> &litter_collection::Wakeup {
+        &self.litter_wakeup
+    }
+
+    /// Interval and outcome of the last run of the litter collection.
+    pub fn litter_collection_status(&self) -> litter_collection::Status {
+        *poison::lock(&self.litter_collection, "the litter collection status")
+    }
+
+    /// Accumulated counts for every configured semaphore, taken from a single snapshot.
+    pub fn list_semaphores(&self) -> HashMap<String, (SemaphoreCfg, Counts)> {
+        let semaphores = self.semaphores();
+        // Doing all these nasty allocations before acquiring the lock to leases
+        let mut counts: HashMap<_, _> = semaphores
+            .keys()
+            .map(|name| (name.clone(), Counts::default()))
+            .collect();
+        // Most of the work happens in here. Now counts contains the active and pending counts
+        self.leases().fill_counts(&mut counts);
+        counts
+            .into_iter()
+            .map(|(name, count)| {
+                let cfg = semaphores[&name];
+                (name, (cfg, count))
+            })
+            .collect()
+    }
+
+    /// Remainders of all semaphores, taken from a single snapshot. Same as calling `remainder` for
+    /// each semaphore, but locks the ledger only once.
+    pub fn remainders(&self) -> HashMap<String, i64> {
+        self.list_semaphores()
+            .into_iter()
+            .map(|(name, (cfg, counts))| (name, cfg.max.saturating_sub(counts.acquired)))
+            .collect()
     }
 
     /// Update the registered prometheus metrics with values reflecting the current state.State
     ///
     /// This method updates the global default prometheus regestry.
     pub fn update_metrics(&self) {
-        let mut counts = HashMap::new();
-        for (name, &sem) in &self.semaphores {
-            // Ok, currently we don't support changing the full_count at runtime, but let's keep it
-            // here for later use.
-            FULL_COUNT.with_label_values(&[name]).set(sem.max);
-            // Doing all these nasty allocations before acquiring the lock to leases
-            counts.insert(name.clone(), Counts::default());
-        }
-        // Most of the work happens in here. Now counts contains the active and pending counts
-        self.leases.lock().unwrap().fill_counts(&mut counts);
-        let now = Instant::now();
-        for (semaphore, count) in counts {
+        let now = self.now();
+        for (semaphore, (sem, count)) in self.list_semaphores() {
+            FULL_COUNT.with_label_values(&[&semaphore]).set(sem.max);
             COUNT.with_label_values(&[&semaphore]).set(count.acquired);
+            // Same computation as in `remainder`, but taken from the same snapshot as the other
+            // gauges. Negative in case of overbooking.
+            REMAINDER
+                .with_label_values(&[&semaphore])
+                .set(sem.max.saturating_sub(count.acquired));
             PENDING.with_label_values(&[&semaphore]).set(count.pending);
+            ACTIVE_PEERS
+                .with_label_values(&[&semaphore])
+                .set(count.active_peers);
+            PENDING_PEERS
+                .with_label_values(&[&semaphore])
+                .set(count.pending_peers);
+            IS_OVERBOOKED
+                .with_label_values(&[&semaphore])
+                .set((count.acquired > sem.max) as i64);
+            // Flag peers which are never going to expire, so leaked locks are visible.
+            let non_expiring = if sem.expires { 0 } else { count.peers };
+            NON_EXPIRING_PEERS
+                .with_label_values(&[&semaphore])
+                .set(non_expiring);
             LONGEST_PENDING_SEC
                 .with_label_values(&[&semaphore])
                 .set(count.longest_pending(now).as_secs() as i64)
         }
+        PEERS.set(self.leases().num_peers() as i64);
+        if let Some(age) = self.litter_collection_status().age(now) {
+            LITTER_LAST_RUN_SEC.set(age.as_secs() as i64);
+        }
+    }
+
+    /// Time passed since the state has been created.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Number of peers in the ledger
+    pub fn num_peers(&self) -> usize {
+        self.leases().num_peers()
+    }
+
+    /// Remembers an administrative change. Use `change_log::record` in handlers, rather than
+    /// calling this directly.
+    pub fn record_change(&self, change: Change) {
+        poison::lock(&self.changes, "the change log").push(change);
+    }
+
+    /// Administrative changes to the runtime configuration, oldest first.
+    pub fn changes(&self) -> Vec<Change> {
+        poison::lock(&self.changes, "the change log")
+            .entries()
+            .cloned()
+            .collect()
+    }
+
+    /// Number of settings overridden at runtime. These are lost on restart.
+    pub fn num_runtime_overrides(&self) -> usize {
+        poison::lock(&self.changes, "the change log").num_overrides()
     }
 
     /// Returns true if all the locks of the peer are acquired
     pub fn is_acquired(&self, peer_id: PeerId) -> Result<bool, ThrottleError> {
-        let leases = self.leases.lock().unwrap();
-        leases.has_pending(peer_id).map(|pending| !pending)
+        let leases = self.leases();
+        Ok(leases.peer_state(peer_id)? == PeerState::Acquired)
+    }
+
+    /// Semaphore, count and queue position of the pending lock of the peer, together with the
+    /// current remainder of its semaphore. `None` if all locks of the peer are acquired. Strictly
+    /// read only, i.e. neither extends the expiration of the peer, nor does it restore unknown
+    /// peers.
+    pub fn pending_lock(
+        &self,
+        peer_id: PeerId,
+    ) -> Result<Option<(String, i64, usize, i64)>, ThrottleError> {
+        let semaphores = self.semaphores();
+        let leases = self.leases();
+        let info = leases.peer_info(peer_id, self.now())?;
+        let position = info.position;
+        Ok(info.pending.map(|(semaphore, count)| {
+            let max = semaphores.get(&semaphore).map_or(0, |cfg| cfg.max);
+            let remainder = max.saturating_sub(leases.count(&semaphore));
+            let position = position.expect("Pending locks must have a position in the queue");
+            (semaphore, count, position, remainder)
+        }))
+    }
+
+    /// Time until the earliest acquired lock to the semaphore expires. A hint for pending clients
+    /// how long to back off. `None` if no expiring lock to the semaphore is acquired.
+    pub fn retry_after(&self, semaphore: &str) -> Option<Duration> {
+        let semaphores = self.semaphores();
+        let leases = self.leases();
+        leases
+            .earliest_expiry(semaphore, |s| expires(&semaphores, s))
+            .map(|valid_until| valid_until.saturating_duration_since(self.now()))
+    }
+
+    /// Earliest instant any peer is going to expire. `None` if no peer is going to.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.leases().next_expiry()
     }
 
     /// Releases a lock associated with the peer. Due to the relased lock, other locks may be
     /// acquired, futures may need to be woken.
-    pub fn release_lock(&self, peer_id: PeerId, semaphore: &str) -> Result<(), ThrottleError> {
-        let max = self
-            .semaphores
+    pub fn release_lock(
+        &self,
+        peer_id: PeerId,
+        semaphore: &str,
+    ) -> Result<Released, ThrottleError> {
+        let semaphores = self.semaphores();
+        let max = semaphores
             .get(semaphore)
             .ok_or(ThrottleError::UnknownSemaphore)?
             .max;
-        let mut leases = self.leases.lock().unwrap();
-        leases.release_lock(peer_id, semaphore)?;
-        let mut resolved_peers = Vec::new();
-        leases.resolve_pending(semaphore, max, &mut resolved_peers);
-        self.wakers.resolve_with(&resolved_peers, Ok(()));
-        Ok(())
+        let mut leases = self.leases();
+        let released = leases.release_lock(peer_id, semaphore)?;
+        if released == Released::Acquired {
+            RELEASES.with_label_values(&[semaphore]).inc();
+        }
+        if released != Released::NotHeld {
+            let mut resolved_peers = Vec::new();
+            leases.resolve_pending(semaphore, max, self.now(), &mut resolved_peers);
+            self.publish_counts(&semaphores, &leases);
+            drop(leases);
+            self.wakers.resolve_with(&resolved_peers, Ok(()));
+        }
+        Ok(released)
     }
-}
 
-lazy_static! {
-    static ref FULL_COUNT: IntGaugeVec = register_int_gauge_vec!(
-        "throttle_max",
-        "Maximum allowed lock count for this semaphore.",
-        &["semaphore"]
-    )
+    /// Releases the locks of all peers to the semaphore, e.g. to get rid of zombie processes which
+    /// keep heartbeating. Locks of the same peers to other semaphores are kept. Requests pending
+    /// for a released lock fail with `ForceReleased`.
+    ///
+    /// Returns the number of released acquired and pending locks.
+    pub fn release_semaphore(&self, semaphore: &str) -> Result<(usize, usize), ThrottleError> {
+        let semaphores = self.semaphores();
+        if !semaphores.contains_key(semaphore) {
+            return Err(ThrottleError::UnknownSemaphore);
+        }
+        let mut leases = self.leases();
+        let released = leases.release_semaphore(semaphore);
+        self.publish_counts(&semaphores, &leases);
+        drop(leases);
+        drop(semaphores);
+        let pending: Vec<_> = released
+            .iter()
+            .filter(|(_, released)| *released == Released::Pending)
+            .map(|&(peer_id, _)| peer_id)
+            .collect();
+        self.wakers
+            .resolve_with(&pending, Err(ThrottleError::ForceReleased));
+        let acquired = released.len() - pending.len();
+        warn!(
+            "Force released {} acquired and {} pending locks to semaphore '{}'.",
+            acquired,
+            pending.len(),
+            semaphore
+        );
+        Ok((acquired, pending.len()))
+    }
+
+    /// Answers all requests blocked waiting for a lock with `ShuttingDown`. Requests trying to
+    /// block afterwards fail right away. Call this before stopping the server, so clients can tell
+    /// the shutdown from a network failure.
+    pub fn shut_down(&self) {
+        warn!("Shutting down. Answering blocked requests.");
+        self.wakers.shut_down();
+    }
+
+    /// Subscribes to changes of the counts of a semaphore. Returns the current counts along with
+    /// the receiver, so no change is missed in between.
+    pub fn watch(&self, semaphore: &str) -> Result<(Event, Receiver<Event>), ThrottleError> {
+        let semaphores = self.semaphores();
+        let max = semaphores
+            .get(semaphore)
+            .ok_or(ThrottleError::UnknownSemaphore)?
+            .max;
+        let leases = self.leases();
+        let current = self
+            .events
+            .publish(semaphore, max, &leases.counts(semaphore));
+        // Subscribe after publishing, so `current` is not received twice. Holding the lock to
+        // `leases` ensures nothing changes in between.
+        let receiver = self.events.subscribe();
+        Ok((current, receiver))
+    }
+
+    /// Publishes the counts of all semaphores, which changed, to subscribers of events. Call this
+    /// while still holding the lock to `leases`, so events are published in order.
+    fn publish_counts(&self, semaphores: &Semaphores, leases: &Leases) {
+        if !self.events.has_subscribers() {
+            return;
+        }
+        let mut counts: HashMap<_, _> = semaphores
+            .keys()
+            .map(|name| (name.clone(), Counts::default()))
+            .collect();
+        leases.fill_counts(&mut counts);
+        for (name, counts) in counts {
+            self.events.publish(&name, semaphores[&name].max, &counts);
+        }
+    }
+}
+
+/// Instant at which a peer prolonged at `now` by `expires_in` expires. `InvalidExpiry` if it can
+/// not be represented, rather than panicking on overflow.
+fn valid_until(now: Instant, expires_in: Duration) -> Result<Instant, ThrottleError> {
+    now.checked_add(expires_in)
+        .ok_or(ThrottleError::InvalidExpiry { expires_in })
+}
+
+/// `false` if locks to the semaphore are configured to never expire.
+fn expires(semaphores: &Semaphores, semaphore: &str) -> bool {
+    semaphores
+        .get(semaphore)
+        .map(|sem| sem.expires)
+        .unwrap_or(true)
+}
+
+/// Configuration and state of a single semaphore, as returned by `State::semaphore`.
+pub struct SemaphoreSnapshot {
+    pub cfg: SemaphoreCfg,
+    pub counts: Counts,
+    pub frozen: bool,
+    /// Instant the snapshot has been taken. Ages, like the one of the longest pending lock, are
+    /// relative to it.
+    pub now: Instant,
+}
+
+/// Outcome of `State::reload`. Each list is ordered by the name of the semaphore.
+#[derive(Debug, Default)]
+pub struct Reload {
+    pub added: Vec<String>,
+    /// Name, old and new configuration of semaphores, those configuration changed.
+    pub changed: Vec<(String, SemaphoreCfg, SemaphoreCfg)>,
+    pub removed: Vec<String>,
+    /// Missing in the new configuration, but kept, since peers still hold or wait for locks to
+    /// them.
+    pub in_use: Vec<String>,
+}
+
+/// Removes the peer from the ledger and resolves pending locks of other peers, which can now be
+/// acquired.
+///
+/// # Return
+///
+/// Peers those pending locks have been resolved and the locks of the removed peer. `None` if the
+/// peer is unknown.
+fn remove_peer(
+    semaphores: &Semaphores,
+    leases: &mut Leases,
+    peer_id: PeerId,
+    now: Instant,
+) -> Option<(Vec<PeerId>, RemovedPeer)> {
+    leases.remove_peer(peer_id).map(|removed| {
+        // Keep book about all peers, those locks have been acquired, so we can notify their
+        // pending requests.
+        let mut resolved_peers = Vec::new();
+        for semaphore in removed.semaphores() {
+            let sem = semaphores
+                .get(semaphore)
+                .expect("An active semaphore must always be configured");
+            leases.resolve_pending(semaphore, sem.max, now, &mut resolved_peers);
+        }
+        (resolved_peers, removed)
+    })
+}
+
+/// Removes the labels of a semaphore from the metrics, so it no longer shows up in `/metrics`.
+fn remove_metrics(semaphore: &str) {
+    for gauge in &[
+        &*FULL_COUNT,
+        &*COUNT,
+        &*REMAINDER,
+        &*PENDING,
+        &*ACTIVE_PEERS,
+        &*PENDING_PEERS,
+        &*IS_OVERBOOKED,
+        &*LONGEST_PENDING_SEC,
+        &*NON_EXPIRING_PEERS,
+    ] {
+        // Not having been set yet, is fine.
+        let _ = gauge.remove_label_values(&[semaphore]);
+    }
+}
+
+lazy_static! {
+    static ref FULL_COUNT: IntGaugeVec = register_int_gauge_vec!(
+        "throttle_max",
+        "Maximum allowed lock count for this semaphore.",
+        &["semaphore"]
+    )
     .expect("Error registering throttle_full_count metric");
     static ref COUNT: IntGaugeVec = register_int_gauge_vec!(
         "throttle_acquired",
@@ -292,32 +1428,119 @@ lazy_static! {
         &["semaphore"]
     )
     .expect("Error registering throttle_count metric");
+    static ref REMAINDER: IntGaugeVec = register_int_gauge_vec!(
+        "throttle_remainder",
+        "Maximum allowed lock count minus the sum of all acquired locks. Negative if overbooked.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_remainder metric");
     static ref PENDING: IntGaugeVec = register_int_gauge_vec!(
         "throttle_pending",
         "Sum of all pending locks",
         &["semaphore"]
     )
     .expect("Error registering throttle_count metric");
+    static ref ACTIVE_PEERS: IntGaugeVec = register_int_gauge_vec!(
+        "throttle_active_peers",
+        "Number of peers holding a lock to the semaphore.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_active_peers metric");
+    static ref PENDING_PEERS: IntGaugeVec = register_int_gauge_vec!(
+        "throttle_pending_peers",
+        "Number of peers waiting for a lock to the semaphore.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_pending_peers metric");
+    static ref IS_OVERBOOKED: IntGaugeVec = register_int_gauge_vec!(
+        "throttle_overbooked",
+        "1 if the acquired locks exceed the full count of the semaphore, 0 otherwise.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_overbooked metric");
     static ref LONGEST_PENDING_SEC: IntGaugeVec = register_int_gauge_vec!(
         "throttle_longest_pending_sec",
         "Time the longest pending peer is waiting until now, to acquire a lock to a semaphore.",
         &["semaphore"]
     )
     .expect("Error registering throttle_count metric");
+    static ref NON_EXPIRING_PEERS: IntGaugeVec = register_int_gauge_vec!(
+        "throttle_non_expiring_peers",
+        "Number of peers holding or waiting for locks to a semaphore, which does not expire.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_non_expiring_peers metric");
+    static ref LITTER_LAST_RUN_SEC: IntGauge = register_int_gauge!(
+        "throttle_litter_last_run_seconds",
+        "Time passed since the last run of the litter collection."
+    )
+    .expect("Error registering throttle_litter_last_run_seconds metric");
+    static ref PEERS: IntGauge = register_int_gauge!(
+        "throttle_peers",
+        "Number of peers known to the server, regardless of their locks."
+    )
+    .expect("Error registering throttle_peers metric");
+    static ref LITTER_COLLECTED: IntCounter = register_int_counter!(
+        "throttle_litter_collected_total",
+        "Number of peers removed due to expiration."
+    )
+    .expect("Error registering throttle_litter_collected_total metric");
+    static ref OVERBOOKED: IntCounterVec = register_int_counter_vec!(
+        "throttle_overbooked_total",
+        "Number of times restoring a revenant pushed the acquired count above the full count.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_overbooked_total metric");
+    static ref HEARTBEAT_MISMATCH: IntCounterVec = register_int_counter_vec!(
+        "throttle_heartbeat_mismatch_total",
+        "Number of heartbeats claiming a lock count, which differs from the acquired one, for \
+        semaphores without strict_amounts.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_heartbeat_mismatch_total metric");
+    static ref RELEASES: IntCounterVec = register_int_counter_vec!(
+        "throttle_releases_total",
+        "Number of acquired locks released by their peers.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_releases_total metric");
+    static ref AUTO_CREATED: IntCounter = register_int_counter!(
+        "throttle_semaphores_auto_created_total",
+        "Number of semaphores created on the fly, because a lock to an unknown one was requested."
+    )
+    .expect("Error registering throttle_semaphores_auto_created_total metric");
+    /// All semaphores share one ledger. Requests to a busy semaphore slowing down requests to
+    /// unrelated ones show up here.
+    static ref LEDGER_LOCK_WAIT: Histogram = register_histogram!(
+        "throttle_ledger_lock_wait_seconds",
+        "Time requests waited for the lock of the ledger.",
+        vec![0.00001, 0.0001, 0.001, 0.01, 0.1, 1.0]
+    )
+    .expect("Error registering throttle_ledger_lock_wait_seconds metric");
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use crate::application_cfg::SemaphoreCfg;
-    use tokio;
+    use crate::{
+        clock::ManualClock,
+        id_generator::SequentialIds,
+        lock_metrics::{ACQUISITION_WAIT, EXPIRED_PENDING},
+    };
 
     #[tokio::test]
     async fn acquire_three_leases() {
         // Semaphore with count of 3
         let mut semaphores = Semaphores::new();
-        semaphores.insert(String::from("A"), SemaphoreCfg { max: 3, level: 0 });
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 3,
+                level: 0,
+                ..Default::default()
+            },
+        );
         let state = State::new(semaphores);
         let one_sec = Duration::from_secs(1);
 
@@ -337,7 +1560,14 @@ mod tests {
     async fn resolve_pending() {
         // Semaphore with count of 3
         let mut semaphores = Semaphores::new();
-        semaphores.insert(String::from("A"), SemaphoreCfg { max: 3, level: 0 });
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 3,
+                level: 0,
+                ..Default::default()
+            },
+        );
         let state = State::new(semaphores);
         let one_sec = Duration::from_secs(1);
 
@@ -372,7 +1602,14 @@ mod tests {
     async fn fairness() {
         // Semaphore with count of 3
         let mut semaphores = Semaphores::new();
-        semaphores.insert(String::from("A"), SemaphoreCfg { max: 3, level: 0 });
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 3,
+                level: 0,
+                ..Default::default()
+            },
+        );
         let state = State::new(semaphores);
         let one_sec = Duration::from_secs(1);
 
@@ -402,10 +1639,51 @@ mod tests {
         assert!(state.is_acquired(p[5]).unwrap());
     }
 
+    /// A large pending lock must not be overtaken by smaller locks arriving after it, even if they
+    /// would fit into the remainder.
+    #[tokio::test]
+    async fn head_of_line_blocking() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 3,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let small = state.new_peer(one_sec);
+        state.acquire(small, "A", 1, None, None).await.unwrap();
+        let medium = state.new_peer(one_sec);
+        state.acquire(medium, "A", 2, None, None).await.unwrap();
+        let large = state.new_peer(one_sec);
+        state.acquire(large, "A", 3, None, None).await.unwrap();
+        let late = state.new_peer(one_sec);
+        state.acquire(late, "A", 1, None, None).await.unwrap();
+
+        // Remainder of one would fit `late`, but `large` is first in line.
+        state.release(small);
+        assert!(!state.is_acquired(large).unwrap());
+        assert!(!state.is_acquired(late).unwrap());
+
+        state.release(medium);
+        assert!(state.is_acquired(large).unwrap());
+        assert!(!state.is_acquired(late).unwrap());
+    }
+
     #[tokio::test]
     async fn idempotent_acquire() {
         let mut semaphores = Semaphores::new();
-        semaphores.insert(String::from("A"), SemaphoreCfg { max: 1, level: 0 });
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                level: 0,
+                ..Default::default()
+            },
+        );
         let state = State::new(semaphores);
         let one_sec = Duration::from_secs(1);
 
@@ -418,11 +1696,53 @@ mod tests {
         assert!(!state.acquire(second, "A", 1, None, None).await.unwrap());
     }
 
+    #[test]
+    fn concurrent_idempotency_keys() {
+        let state = Arc::new(State::new(Semaphores::new()));
+        let one_sec = Duration::from_secs(1);
+
+        let too_long = Duration::from_secs(u64::MAX);
+        assert!(matches!(
+            state.create_peer_once("key", None, too_long, None, 0),
+            Err(ThrottleError::InvalidExpiry { .. })
+        ));
+        assert_eq!(state.num_peers(), 0);
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let state = state.clone();
+                std::thread::spawn(move || state.create_peer_once("key", None, one_sec, None, 0))
+            })
+            .collect();
+        let results: Vec<_> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap().unwrap())
+            .collect();
+        // Every request ends up with the same peer, and only one of them created it.
+        assert!(results.iter().all(|&(peer_id, _)| peer_id == results[0].0));
+        assert_eq!(results.iter().filter(|&&(_, created)| created).count(), 1);
+        assert_eq!(state.num_peers(), 1);
+    }
+
     #[tokio::test]
     async fn multiple_locks_per_peer() {
         let mut semaphores = Semaphores::new();
-        semaphores.insert(String::from("A"), SemaphoreCfg { max: 2, level: 1 });
-        semaphores.insert(String::from("B"), SemaphoreCfg { max: 1, level: 0 });
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 2,
+                level: 1,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(
+            String::from("B"),
+            SemaphoreCfg {
+                max: 1,
+                level: 0,
+                ..Default::default()
+            },
+        );
         let state = State::new(semaphores);
         let one_sec = Duration::from_secs(1);
 
@@ -440,12 +1760,68 @@ mod tests {
         assert!(state.is_acquired(second).unwrap());
     }
 
+    /// Releasing a peer holding locks to several semaphores, must resolve pending locks to each
+    /// of them.
+    #[tokio::test]
+    async fn release_resolves_every_semaphore() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                level: 1,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(
+            String::from("B"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let first = state.new_peer(one_sec);
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        state.acquire(first, "B", 1, None, None).await.unwrap();
+        let waits_for_a = state.new_peer(one_sec);
+        assert!(!state
+            .acquire(waits_for_a, "A", 1, None, None)
+            .await
+            .unwrap());
+        let waits_for_b = state.new_peer(one_sec);
+        assert!(!state
+            .acquire(waits_for_b, "B", 1, None, None)
+            .await
+            .unwrap());
+
+        state.release(first);
+        assert!(state.is_acquired(waits_for_a).unwrap());
+        assert!(state.is_acquired(waits_for_b).unwrap());
+    }
+
     /// Provoke two pending locks for the same peer.
     #[tokio::test]
     async fn two_pending_locks() {
         let mut semaphores = Semaphores::new();
-        semaphores.insert(String::from("A"), SemaphoreCfg { max: 1, level: 1 });
-        semaphores.insert(String::from("B"), SemaphoreCfg { max: 1, level: 0 });
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                level: 1,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(
+            String::from("B"),
+            SemaphoreCfg {
+                max: 1,
+                level: 0,
+                ..Default::default()
+            },
+        );
         let state = State::new(semaphores);
         let one_sec = Duration::from_secs(1);
 
@@ -465,7 +1841,14 @@ mod tests {
     #[tokio::test]
     async fn acquire_zero() {
         let mut semaphores = Semaphores::new();
-        semaphores.insert(String::from("A"), SemaphoreCfg { max: 1, level: 0 });
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                level: 0,
+                ..Default::default()
+            },
+        );
         let state = State::new(semaphores);
         let one_sec = Duration::from_secs(1);
 
@@ -479,15 +1862,22 @@ mod tests {
     #[tokio::test]
     async fn restore_with_lock_count_zero() {
         let mut semaphores = Semaphores::new();
-        semaphores.insert(String::from("A"), SemaphoreCfg { max: 1, level: 0 });
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                level: 0,
+                ..Default::default()
+            },
+        );
         let state = State::new(semaphores);
         let one_sec = Duration::from_secs(1);
 
-        let peer = 5;
+        let peer = PeerId::Int(5);
         let mut acquired = HashMap::new();
         acquired.insert(String::from("A"), 0);
         assert!(matches!(
-            state.restore(peer, one_sec, &acquired),
+            state.restore(peer, one_sec, &acquired, None, 0),
             Err(ThrottleError::InvalidLockCount { count: 0 })
         ));
     }
@@ -497,19 +1887,53 @@ mod tests {
         let state = State::new(Semaphores::new());
         let one_sec = Duration::from_secs(1);
 
-        let peer = 5;
+        let peer = PeerId::Int(5);
         let mut acquired = HashMap::new();
         acquired.insert(String::from("A"), 1);
         assert!(matches!(
-            state.restore(peer, one_sec, &acquired),
+            state.restore(peer, one_sec, &acquired, None, 0),
             Err(ThrottleError::UnknownSemaphore)
         ));
     }
 
+    /// Sequential ids skip the ids of peers restored after a restart of the server.
+    #[tokio::test]
+    async fn sequential_ids_skip_restored_peers() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 2,
+                ..Default::default()
+            },
+        );
+        let state = State::with_id_generator(semaphores, Box::new(SequentialIds::default()));
+        let one_sec = Duration::from_secs(1);
+
+        let revenant = PeerId::Int(1);
+        let mut acquired = HashMap::new();
+        acquired.insert(String::from("A"), 1);
+        state
+            .restore(revenant, one_sec, &acquired, None, 0)
+            .unwrap();
+
+        let peer = state.new_peer(one_sec);
+        assert_eq!(peer, PeerId::Int(2));
+        assert!(state.acquire(peer, "A", 1, None, None).await.unwrap());
+        assert_eq!(state.remainder("A").unwrap(), 0);
+    }
+
     #[tokio::test]
     async fn restore_cant_change_existing_peers() {
         let mut semaphores = Semaphores::new();
-        semaphores.insert(String::from("A"), SemaphoreCfg { max: 1, level: 0 });
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                level: 0,
+                ..Default::default()
+            },
+        );
         let state = State::new(semaphores);
         let one_sec = Duration::from_secs(1);
 
@@ -518,7 +1942,7 @@ mod tests {
         let mut acquired = HashMap::new();
         acquired.insert(String::from("A"), 1);
         assert!(matches!(
-            state.restore(peer, one_sec, &acquired),
+            state.restore(peer, one_sec, &acquired, None, 0),
             Err(ThrottleError::ChangeThroughRestore)
         ));
     }
@@ -526,8 +1950,22 @@ mod tests {
     #[tokio::test]
     async fn enforce_lock_hierachies() {
         let mut semaphores = Semaphores::new();
-        semaphores.insert(String::from("A"), SemaphoreCfg { max: 1, level: 1 });
-        semaphores.insert(String::from("B"), SemaphoreCfg { max: 1, level: 0 });
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                level: 1,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(
+            String::from("B"),
+            SemaphoreCfg {
+                max: 1,
+                level: 0,
+                ..Default::default()
+            },
+        );
         let state = State::new(semaphores);
         let one_sec = Duration::from_secs(1);
 
@@ -543,4 +1981,1471 @@ mod tests {
             })
         ));
     }
+
+    /// Repeating a blocking acquire with a body differing from the pending lock is a conflict.
+    /// Otherwise the client would wait for something else than the server.
+    #[tokio::test]
+    async fn repeated_acquire_must_match_lease() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 2,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(
+            String::from("B"),
+            SemaphoreCfg {
+                max: 2,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let holder = state.new_peer(one_sec);
+        state.acquire(holder, "A", 2, None, None).await.unwrap();
+        let waiting = state.new_peer(one_sec);
+        assert!(!state.acquire(waiting, "A", 1, None, None).await.unwrap());
+
+        // Same semaphore, different amount
+        assert!(matches!(
+            state.acquire(waiting, "A", 2, None, None).await,
+            Err(ThrottleError::Deadlock { .. })
+        ));
+        // Different semaphore
+        assert!(matches!(
+            state.acquire(waiting, "B", 1, None, None).await,
+            Err(ThrottleError::AlreadyPending)
+        ));
+        // The pending lock is left untouched, and acquired once capacity frees up.
+        assert!(!state.acquire(waiting, "A", 1, None, None).await.unwrap());
+        state.release(holder);
+        assert!(state.is_acquired(waiting).unwrap());
+    }
+
+    #[tokio::test]
+    async fn strict_amounts_revoke_peer() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 2,
+                strict_amounts: true,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let first = state.new_peer(one_sec);
+        state.acquire(first, "A", 2, None, None).await.unwrap();
+        let second = state.new_peer(one_sec);
+        assert!(!state.acquire(second, "A", 1, None, None).await.unwrap());
+
+        // Claiming the acquired amount is fine
+        let mut claimed = HashMap::new();
+        claimed.insert(String::from("A"), 2);
+        state.heartbeat(first, one_sec, Some(&claimed)).unwrap();
+        // Not claiming any locks at all skips the check
+        state.heartbeat(first, one_sec, None).unwrap();
+        // Claiming a count of zero is an error of the client, rather than a mismatch
+        let mut zero = HashMap::new();
+        zero.insert(String::from("A"), 0);
+        assert!(matches!(
+            state.heartbeat(first, one_sec, Some(&zero)),
+            Err(ThrottleError::InvalidLockCount { count: 0 })
+        ));
+
+        // Claiming a different amount revokes the peer and frees its locks
+        claimed.insert(String::from("A"), 1);
+        assert!(matches!(
+            state.heartbeat(first, one_sec, Some(&claimed)),
+            Err(ThrottleError::Revoked { .. })
+        ));
+        assert!(matches!(
+            state.heartbeat(first, one_sec, None),
+            Err(ThrottleError::Gone)
+        ));
+        assert!(state.is_acquired(second).unwrap());
+        // Clients restore peers unknown to the server. A revoked peer must stay revoked.
+        let mut acquired = HashMap::new();
+        acquired.insert(String::from("A"), 2);
+        assert!(matches!(
+            state.restore(first, one_sec, &acquired, None, 0),
+            Err(ThrottleError::Gone)
+        ));
+        assert_eq!(state.remainder("A").unwrap(), 1);
+    }
+
+    /// Without `strict_amounts` a differing claim is counted, but the heartbeat succeeds.
+    #[tokio::test]
+    async fn lenient_amounts_count_mismatch() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("lenient_amounts_count_mismatch"),
+            SemaphoreCfg {
+                max: 2,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+        let mismatches = || {
+            HEARTBEAT_MISMATCH
+                .with_label_values(&["lenient_amounts_count_mismatch"])
+                .get()
+        };
+
+        let peer = state.new_peer(one_sec);
+        state
+            .acquire(peer, "lenient_amounts_count_mismatch", 2, None, None)
+            .await
+            .unwrap();
+        let mut claimed = HashMap::new();
+        claimed.insert(String::from("lenient_amounts_count_mismatch"), 1);
+        state.heartbeat(peer, one_sec, Some(&claimed)).unwrap();
+        assert_eq!(mismatches(), 1);
+        assert!(state.is_acquired(peer).unwrap());
+    }
+
+    #[tokio::test]
+    async fn non_expiring_semaphore() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                expires: false,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(
+            String::from("B"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let mut state = State::new(semaphores);
+        let clock = Arc::new(ManualClock::new());
+        state.set_clock(clock.clone());
+        let expired = Duration::from_secs(0);
+
+        let holds_a = state.new_peer(expired);
+        state.acquire(holds_a, "A", 1, None, None).await.unwrap();
+        let holds_b = state.new_peer(expired);
+        state.acquire(holds_b, "B", 1, None, None).await.unwrap();
+        // Make sure the peers are expired
+        clock.advance(Duration::from_millis(1));
+
+        // Only the peer holding a lock to `B` is removed
+        assert_eq!(state.remove_expired(), 1);
+        assert!(state.is_acquired(holds_a).unwrap());
+        assert!(matches!(
+            state.is_acquired(holds_b),
+            Err(ThrottleError::UnknownPeer)
+        ));
+    }
+
+    /// Counts are maintained incrementally. Expiring peers and restoring them as revenants must not
+    /// let them drift from the ledger. `Leases` asserts this in debug builds, too.
+    #[tokio::test]
+    async fn counts_survive_expiry_and_revenants() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 3,
+                ..Default::default()
+            },
+        );
+        let mut state = State::new(semaphores);
+        let clock = Arc::new(ManualClock::new());
+        state.set_clock(clock.clone());
+        let expired = Duration::from_secs(0);
+        let one_sec = Duration::from_secs(1);
+
+        let first = state.new_peer(expired);
+        state.acquire(first, "A", 2, None, None).await.unwrap();
+        let second = state.new_peer(one_sec);
+        state.acquire(second, "A", 1, None, None).await.unwrap();
+        let pending = state.new_peer(one_sec);
+        assert!(!state.acquire(pending, "A", 2, None, None).await.unwrap());
+        assert_eq!(state.remainder("A").unwrap(), 0);
+        clock.advance(Duration::from_millis(1));
+
+        // Expiring the first peer resolves the pending one
+        assert_eq!(state.remove_expired(), 1);
+        assert!(state.is_acquired(pending).unwrap());
+        assert_eq!(state.remainder("A").unwrap(), 0);
+
+        // The first peer returns as a revenant, overbooking the semaphore
+        let mut acquired = HashMap::new();
+        acquired.insert(String::from("A"), 2);
+        state.restore(first, one_sec, &acquired, None, 0).unwrap();
+        assert_eq!(state.remainder("A").unwrap(), -2);
+        // Restoring is idempotent and does not count the revenant twice
+        state.restore(first, one_sec, &acquired, None, 0).unwrap();
+        assert_eq!(state.remainder("A").unwrap(), -2);
+
+        state.release(first);
+        state.release_lock(second, "A").unwrap();
+        assert_eq!(state.remainder("A").unwrap(), 1);
+        state.release(pending);
+        assert_eq!(state.remainder("A").unwrap(), 3);
+    }
+
+    /// Acquires, releases, expirations and revenants are counted by semaphore.
+    #[tokio::test]
+    async fn count_lock_events() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("Events"),
+            SemaphoreCfg {
+                max: 3,
+                ..Default::default()
+            },
+        );
+        let mut state = State::new(semaphores);
+        let clock = Arc::new(ManualClock::new());
+        state.set_clock(clock.clone());
+        let expired = Duration::from_secs(0);
+        let one_sec = Duration::from_secs(1);
+        // Some of these are private to `leases`, so scrape them the way `/metrics` does.
+        let counter = |name: &str| {
+            prometheus::gather()
+                .into_iter()
+                .find(|family| family.get_name() == name)
+                .and_then(|family| {
+                    family
+                        .get_metric()
+                        .iter()
+                        .find(|metric| metric.get_label()[0].get_value() == "Events")
+                        .map(|metric| metric.get_counter().get_value())
+                })
+                .unwrap_or(0.0)
+        };
+
+        let first = state.new_peer(expired);
+        state.acquire(first, "Events", 2, None, None).await.unwrap();
+        let second = state.new_peer(one_sec);
+        state
+            .acquire(second, "Events", 1, None, None)
+            .await
+            .unwrap();
+        let pending = state.new_peer(one_sec);
+        assert!(!state
+            .acquire(pending, "Events", 2, None, None)
+            .await
+            .unwrap());
+        assert_eq!(counter("throttle_acquires_total"), 2.0);
+
+        // Expiring the first peer lets the pending one acquire its lock.
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(state.remove_expired(), 1);
+        assert_eq!(counter("throttle_expirations_total"), 1.0);
+        assert_eq!(counter("throttle_acquires_total"), 3.0);
+
+        // Restoring is idempotent, so the revenant is only counted once.
+        let mut acquired = HashMap::new();
+        acquired.insert(String::from("Events"), 2);
+        state.restore(first, one_sec, &acquired, None, 0).unwrap();
+        state.restore(first, one_sec, &acquired, None, 0).unwrap();
+        assert_eq!(counter("throttle_revenants_total"), 1.0);
+
+        state.release(first);
+        state.release_lock(second, "Events").unwrap();
+        // Releasing a lock not held does not count.
+        state.release_lock(second, "Events").unwrap();
+        assert_eq!(counter("throttle_releases_total"), 2.0);
+    }
+
+    /// `remove_expired` only looks at peers in the index of expiries. Heartbeats and locks to
+    /// semaphores which do not expire must keep it accurate.
+    #[tokio::test]
+    async fn expiry_index() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                expires: false,
+                ..Default::default()
+            },
+        );
+        let mut state = State::new(semaphores);
+        let clock = Arc::new(ManualClock::new());
+        state.set_clock(clock.clone());
+        let expired = Duration::from_secs(0);
+        let one_min = Duration::from_secs(60);
+
+        let heartbeating = state.new_peer(expired);
+        let immortal = state.new_peer(expired);
+        state.acquire(immortal, "A", 1, None, None).await.unwrap();
+        let mortal = state.new_peer(one_min);
+        // A heartbeat rescues the peer, before it is collected
+        state.heartbeat(heartbeating, one_min, None).unwrap();
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(state.remove_expired(), 0);
+        assert!(state.next_expiry().unwrap() > state.now());
+
+        // Releasing the lock to the non expiring semaphore lets the overdue peer expire.
+        state.release_lock(immortal, "A").unwrap();
+        assert_eq!(state.remove_expired(), 1);
+        assert!(state.is_acquired(heartbeating).unwrap());
+        assert!(state.is_acquired(mortal).unwrap());
+    }
+
+    /// After a restart of the server, two revenants race for the one remaining slot of a semaphore
+    /// configured with `strict_restore`. Only one of them gets it back.
+    #[test]
+    fn strict_restore_race() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 2,
+                strict_restore: true,
+                ..Default::default()
+            },
+        );
+        let state = Arc::new(State::new(semaphores));
+        let one_min = Duration::from_secs(60);
+        let mut acquired = HashMap::new();
+        acquired.insert(String::from("A"), 1);
+        let first = PeerId::Int(1);
+        state.restore(first, one_min, &acquired, None, 0).unwrap();
+
+        let racers = [PeerId::Int(2), PeerId::Int(3)];
+        let handles: Vec<_> = racers
+            .iter()
+            .map(|&peer_id| {
+                let state = state.clone();
+                let acquired = acquired.clone();
+                std::thread::spawn(move || state.restore(peer_id, one_min, &acquired, None, 0))
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        let loser = if results[0].is_ok() {
+            racers[1]
+        } else {
+            racers[0]
+        };
+        assert!(matches!(
+            results.into_iter().find(|r| r.is_err()),
+            Some(Err(ThrottleError::LocksLost { .. }))
+        ));
+        // Not overbooked, the loser waits for its lock
+        assert_eq!(state.remainder("A").unwrap(), 0);
+        assert!(!state.is_acquired(loser).unwrap());
+
+        state.release(first);
+        assert!(state.is_acquired(loser).unwrap());
+    }
+
+    #[tokio::test]
+    async fn count_overbooking_revenants() {
+        let mut semaphores = Semaphores::new();
+        // Name is unique among the tests, as the counter is global.
+        semaphores.insert(
+            String::from("Overbooked"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let overbooked = || OVERBOOKED.with_label_values(&["Overbooked"]).get();
+        let one_sec = Duration::from_secs(1);
+        let mut acquired = HashMap::new();
+        acquired.insert(String::from("Overbooked"), 1);
+
+        // Fits within the full count
+        state
+            .restore(PeerId::Int(1), one_sec, &acquired, None, 0)
+            .unwrap();
+        assert_eq!(overbooked(), 0);
+        state
+            .restore(PeerId::Int(2), one_sec, &acquired, None, 0)
+            .unwrap();
+        assert_eq!(overbooked(), 1);
+        // Restoring the same peer again does not count twice
+        state
+            .restore(PeerId::Int(2), one_sec, &acquired, None, 0)
+            .unwrap();
+        assert_eq!(overbooked(), 1);
+        assert_eq!(state.remainder("Overbooked").unwrap(), -1);
+    }
+
+    /// One large pending lock and several small ones may sum up to the same amount, but differ in
+    /// the number of peers.
+    #[tokio::test]
+    async fn count_peers_and_amounts() {
+        let mut semaphores = Semaphores::new();
+        for name in &["A", "B"] {
+            semaphores.insert(
+                String::from(*name),
+                SemaphoreCfg {
+                    max: 4,
+                    ..Default::default()
+                },
+            );
+        }
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+        for &(semaphore, amounts) in &[("A", &[4, 4][..]), ("B", &[2, 2, 1, 1, 1, 1])] {
+            for &amount in amounts {
+                let peer = state.new_peer(one_sec);
+                state
+                    .acquire(peer, semaphore, amount, None, None)
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let counts = state.list_semaphores();
+        let a = &counts["A"].1;
+        let b = &counts["B"].1;
+        assert_eq!((a.acquired, a.pending), (b.acquired, b.pending));
+        assert_eq!((a.active_peers, a.pending_peers, a.peers), (1, 1, 2));
+        assert_eq!((b.active_peers, b.pending_peers, b.peers), (2, 4, 6));
+    }
+
+    /// A panic while the ledger is locked must not take down every following request.
+    #[test]
+    fn keep_serving_after_poisoning() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = Arc::new(State::new(semaphores));
+        let poisoner = state.clone();
+        let result = std::thread::spawn(move || {
+            let _leases = poisoner.leases();
+            panic!("Panic while holding the lock to the ledger");
+        })
+        .join();
+        assert!(result.is_err());
+
+        assert_eq!(state.remainder("A").unwrap(), 1);
+        // The poisoning is cleared, so the health check passes again
+        assert!(!state.leases.is_poisoned());
+        assert_eq!(state.num_peers(), 0);
+        let peer = state.new_peer(Duration::from_secs(1));
+        assert!(state.release(peer).is_some());
+    }
+
+    /// Same for a panic while the configuration of the semaphores is locked, e.g. by `set_max`.
+    #[test]
+    fn keep_serving_after_poisoning_semaphores() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = Arc::new(State::new(semaphores));
+        let poisoner = state.clone();
+        let result = std::thread::spawn(move || {
+            let _semaphores = poisoner.semaphores_mut();
+            panic!("Panic while holding the lock to the semaphores");
+        })
+        .join();
+        assert!(result.is_err());
+
+        assert_eq!(state.remainder("A").unwrap(), 1);
+        assert_eq!(state.set_max("A", 2).unwrap(), 1);
+        assert_eq!(state.remainder("A").unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn ledger_gauges() {
+        let mut semaphores = Semaphores::new();
+        // Name is unique among the tests, as the metrics are global.
+        semaphores.insert(
+            String::from("Ledger"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let mut state = State::new(semaphores);
+        let clock = Arc::new(ManualClock::new());
+        state.set_clock(clock.clone());
+        let gauges = || {
+            state.update_metrics();
+            (
+                PEERS.get(),
+                ACTIVE_PEERS.with_label_values(&["Ledger"]).get(),
+                PENDING_PEERS.with_label_values(&["Ledger"]).get(),
+            )
+        };
+        let collected_before = LITTER_COLLECTED.get();
+
+        let short = state.new_peer(Duration::from_secs(1));
+        let long = state.new_peer(Duration::from_secs(60));
+        let idle = state.new_peer(Duration::from_secs(60));
+        state.acquire(short, "Ledger", 1, None, None).await.unwrap();
+        state.acquire(long, "Ledger", 1, None, None).await.unwrap();
+        assert_eq!(gauges(), (3, 1, 1));
+
+        // `short` expires, `long` acquires its lock
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(state.remove_expired(), 1);
+        assert_eq!(gauges(), (2, 1, 0));
+
+        state.release(long);
+        state.release(idle);
+        assert_eq!(gauges(), (0, 0, 0));
+        // Other tests may expire peers concurrently
+        assert!(LITTER_COLLECTED.get() - collected_before >= 1);
+    }
+
+    /// Sums of large pending locks must neither panic nor wrap around into a small demand.
+    #[tokio::test]
+    async fn no_wraparound_near_i64_max() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: i64::MAX,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+        let holder = state.new_peer(one_sec);
+        assert!(state.acquire(holder, "A", 1, None, None).await.unwrap());
+        let first = state.new_peer(one_sec);
+        assert!(!state
+            .acquire(first, "A", i64::MAX, None, None)
+            .await
+            .unwrap());
+        let second = state.new_peer(one_sec);
+        assert!(!state
+            .acquire(second, "A", i64::MAX, None, None)
+            .await
+            .unwrap());
+        // The demand is saturated, so there is no room left, even for a small lock.
+        let small = state.new_peer(one_sec);
+        assert!(!state.acquire(small, "A", 1, None, None).await.unwrap());
+        assert_eq!(state.list_semaphores()["A"].1.pending, i64::MAX);
+
+        state.release(holder);
+        assert!(state.is_acquired(first).unwrap());
+        assert!(!state.is_acquired(second).unwrap());
+        assert_eq!(state.remainder("A").unwrap(), 0);
+    }
+
+    #[test]
+    fn revenants_do_not_overflow() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: i64::MAX,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(
+            String::from("B"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+        let mut huge = HashMap::new();
+        huge.insert(String::from("A"), i64::MAX);
+        state
+            .restore(PeerId::Int(1), one_sec, &huge, None, 0)
+            .unwrap();
+        // Overbooking any further would overflow the count.
+        assert!(matches!(
+            state.restore(PeerId::Int(2), one_sec, &huge, None, 0),
+            Err(ThrottleError::Never { .. })
+        ));
+        assert_eq!(state.remainder("A").unwrap(), 0);
+
+        // A revenant may not claim more than the full count.
+        let mut too_large = HashMap::new();
+        too_large.insert(String::from("B"), 2);
+        assert!(matches!(
+            state.restore(PeerId::Int(3), one_sec, &too_large, None, 0),
+            Err(ThrottleError::Never { asked: 2, max: 1 })
+        ));
+        assert!(state.peer_info(PeerId::Int(3)).is_err());
+    }
+
+    #[tokio::test]
+    async fn observe_acquisition_wait() {
+        let mut semaphores = Semaphores::new();
+        // Name is unique among the tests, as the metrics are global.
+        semaphores.insert(
+            String::from("Waiting"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let mut state = State::new(semaphores);
+        let clock = Arc::new(ManualClock::new());
+        state.set_clock(clock.clone());
+        let one_min = Duration::from_secs(60);
+        let wait = || {
+            let histogram = ACQUISITION_WAIT.with_label_values(&["Waiting"]);
+            (histogram.get_sample_count(), histogram.get_sample_sum())
+        };
+
+        let first = state.new_peer(one_min);
+        state
+            .acquire(first, "Waiting", 1, None, None)
+            .await
+            .unwrap();
+        let second = state.new_peer(one_min);
+        state
+            .acquire(second, "Waiting", 1, None, None)
+            .await
+            .unwrap();
+        let third = state.new_peer(Duration::from_secs(10));
+        state
+            .acquire(third, "Waiting", 1, None, None)
+            .await
+            .unwrap();
+        assert_eq!(wait(), (1, 0.0));
+
+        // The third peer expires while pending. It is not observed in the histogram.
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(state.remove_expired(), 1);
+        assert_eq!(EXPIRED_PENDING.with_label_values(&["Waiting"]).get(), 1);
+        state.release(first);
+        assert!(state.is_acquired(second).unwrap());
+        assert_eq!(wait(), (2, 30.0));
+    }
+
+    /// Heartbeats prolong the lifetime of a peer, starting from the instant of the heartbeat.
+    #[test]
+    fn heartbeat_extends_lifetime() {
+        let mut state = State::new(Semaphores::new());
+        let clock = Arc::new(ManualClock::new());
+        state.set_clock(clock.clone());
+        let ten_sec = Duration::from_secs(10);
+
+        let peer = state.new_peer(ten_sec);
+        clock.advance(Duration::from_secs(9));
+        state.heartbeat(peer, ten_sec, None).unwrap();
+        assert_eq!(state.next_expiry(), Some(state.now() + ten_sec));
+
+        // Would have expired without the heartbeat
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(state.remove_expired(), 0);
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(state.remove_expired(), 1);
+        assert_eq!(state.num_peers(), 0);
+    }
+
+    #[tokio::test]
+    async fn list_expiring_peers() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 10,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(
+            String::from("Immortal"),
+            SemaphoreCfg {
+                max: 10,
+                expires: false,
+                ..Default::default()
+            },
+        );
+        let mut state = State::new(semaphores);
+        let clock = Arc::new(ManualClock::new());
+        state.set_clock(clock.clone());
+        let ten_sec = Duration::from_secs(10);
+        let one_min = Duration::from_secs(60);
+
+        let soon = state.new_peer(ten_sec);
+        state.acquire(soon, "A", 2, None, None).await.unwrap();
+        let later = state.new_peer(one_min);
+        state.acquire(later, "A", 1, None, None).await.unwrap();
+        let never = state.new_peer(ten_sec);
+        state
+            .acquire(never, "Immortal", 1, None, None)
+            .await
+            .unwrap();
+        // Holds no locks, so there is nothing to be orphaned.
+        state.new_peer(ten_sec);
+
+        let expiring = state.list_expiring(Duration::from_secs(30));
+        assert_eq!(expiring.len(), 1);
+        assert_eq!(expiring[0].peer_id, soon);
+        assert_eq!(expiring[0].count, 2);
+        assert_eq!(expiring[0].expires_in, ten_sec);
+
+        // Missing the deadline keeps a peer listed, until it is collected.
+        clock.advance(Duration::from_secs(20));
+        let expiring = state.list_expiring(Duration::from_secs(0));
+        assert_eq!(expiring.len(), 1);
+        assert_eq!(expiring[0].expires_in, Duration::from_secs(0));
+        let expiring = state.list_expiring(one_min);
+        let peers: Vec<_> = expiring.iter().map(|lock| lock.peer_id).collect();
+        assert_eq!(peers, vec![soon, later]);
+    }
+
+    /// `within` is chosen by clients. Too large to be added to the current time, it lists every
+    /// expiring peer.
+    #[tokio::test]
+    async fn list_expiring_within_huge_duration() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 10,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let peer = state.new_peer(Duration::from_secs(60));
+        state.acquire(peer, "A", 1, None, None).await.unwrap();
+
+        let expiring = state.list_expiring(Duration::from_secs(u64::MAX));
+        assert_eq!(expiring.len(), 1);
+        assert_eq!(expiring[0].peer_id, peer);
+    }
+
+    #[tokio::test]
+    async fn reload_semaphores() {
+        let mut semaphores = Semaphores::new();
+        for name in &["A", "B", "C"] {
+            semaphores.insert(
+                String::from(*name),
+                SemaphoreCfg {
+                    max: 1,
+                    ..Default::default()
+                },
+            );
+        }
+        let state = State::new(semaphores.clone());
+        let one_min = Duration::from_secs(60);
+        let first = state.new_peer(one_min);
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        let second = state.new_peer(one_min);
+        assert!(!state.acquire(second, "A", 1, None, None).await.unwrap());
+        let third = state.new_peer(one_min);
+        state.acquire(third, "B", 1, None, None).await.unwrap();
+
+        // A grows, B and C are dropped from the configuration, D is new.
+        semaphores.get_mut("A").unwrap().max = 2;
+        semaphores.remove("B");
+        semaphores.remove("C");
+        semaphores.insert(
+            String::from("D"),
+            SemaphoreCfg {
+                max: 5,
+                ..Default::default()
+            },
+        );
+        let reload = state.reload(semaphores.clone());
+
+        assert_eq!(reload.added, ["D"]);
+        assert_eq!(reload.changed.len(), 1);
+        assert_eq!(reload.changed[0].0, "A");
+        assert_eq!(reload.removed, ["C"]);
+        assert_eq!(reload.in_use, ["B"]);
+        // The increased count lets the pending peer acquire its lock.
+        assert!(state.is_acquired(second).unwrap());
+        assert_eq!(state.remainder("D").unwrap(), 5);
+        assert!(state.remainder("C").is_err());
+        assert!(state.is_acquired(third).unwrap());
+
+        // Reloading the same configuration again changes nothing.
+        let reload = state.reload(semaphores);
+        assert!(reload.added.is_empty() && reload.changed.is_empty());
+        assert!(reload.removed.is_empty());
+        assert_eq!(reload.in_use, ["B"]);
+    }
+
+    /// A short heartbeat following a long one must not cut the lease short.
+    #[test]
+    fn heartbeat_never_shortens_lifetime() {
+        let mut state = State::new(Semaphores::new());
+        let clock = Arc::new(ManualClock::new());
+        state.set_clock(clock.clone());
+        let one_sec = Duration::from_secs(1);
+        let one_min = Duration::from_secs(60);
+
+        let peer = state.new_peer(one_sec);
+        state.heartbeat(peer, one_min, None).unwrap();
+        state.heartbeat(peer, one_sec, None).unwrap();
+        assert_eq!(state.next_expiry(), Some(state.now() + one_min));
+
+        // Past the deadline of the short heartbeat
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(state.remove_expired(), 0);
+        assert_eq!(state.num_peers(), 1);
+    }
+
+    /// Waiting times and back off hints are measured against the clock of the state.
+    #[tokio::test]
+    async fn pending_time_follows_clock() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let mut state = State::new(semaphores);
+        let clock = Arc::new(ManualClock::new());
+        state.set_clock(clock.clone());
+        let one_min = Duration::from_secs(60);
+
+        let holder = state.new_peer(one_min);
+        state.acquire(holder, "A", 1, None, None).await.unwrap();
+        let waiter = state.new_peer(2 * one_min);
+        assert!(!state.acquire(waiter, "A", 1, None, None).await.unwrap());
+
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(state.retry_after("A"), Some(Duration::from_secs(45)));
+        let counts = state.semaphore("A").unwrap().counts;
+        assert_eq!(counts.longest_pending(state.now()), Duration::from_secs(15));
+
+        // Once the holder expired, the waiter acquires its lock. Peers are valid up to and
+        // including their expiration instant.
+        clock.advance(Duration::from_secs(45));
+        assert_eq!(state.remove_expired(), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(state.remove_expired(), 1);
+        assert!(state.is_acquired(waiter).unwrap());
+        assert_eq!(state.retry_after("A"), Some(Duration::from_secs(59)));
+    }
+
+    #[tokio::test]
+    async fn grow_lock() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 4,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let first = state.new_peer(one_sec);
+        state.acquire(first, "A", 2, None, None).await.unwrap();
+        // Enough capacity to grow immediatly
+        assert!(state.change_amount(first, "A", 3, false).unwrap());
+        assert_eq!(state.remainder("A").unwrap(), 1);
+
+        let second = state.new_peer(one_sec);
+        state.acquire(second, "A", 1, None, None).await.unwrap();
+        // No capacity left
+        assert!(matches!(
+            state.change_amount(first, "A", 4, false),
+            Err(ThrottleError::InsufficientCapacity)
+        ));
+        // Wait for capacity instead. The current count stays acquired.
+        assert!(!state.change_amount(first, "A", 4, true).unwrap());
+        assert_eq!(state.remainder("A").unwrap(), 0);
+        // Releasing the second peer frees the capacity to grow the first one.
+        state.release(second);
+        assert!(state.is_acquired(first).unwrap());
+        assert_eq!(state.remainder("A").unwrap(), 0);
+        // Growing is idempotent
+        assert!(state.change_amount(first, "A", 4, false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn shrink_lock() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 4,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let first = state.new_peer(one_sec);
+        state.acquire(first, "A", 3, None, None).await.unwrap();
+        let second = state.new_peer(one_sec);
+        assert!(!state.acquire(second, "A", 2, None, None).await.unwrap());
+        // Acquiring the same lock again must not shrink it. Only `change_amount` does.
+        assert!(matches!(
+            state.acquire(first, "A", 2, None, None).await,
+            Err(ThrottleError::ShrinkingLockCount)
+        ));
+        // Shrinking frees up capacity for the pending peer right away
+        assert!(state.change_amount(first, "A", 2, false).unwrap());
+        assert!(state.is_acquired(second).unwrap());
+        assert_eq!(state.remainder("A").unwrap(), 0);
+
+        // Shrinking a lock waiting to grow, shrinks the pending part first
+        assert!(!state.change_amount(first, "A", 4, true).unwrap());
+        assert!(!state.change_amount(first, "A", 3, true).unwrap());
+        assert_eq!(state.remainder("A").unwrap(), 0);
+        // Shrinking below the acquired count drops the request to grow
+        assert!(state.change_amount(first, "A", 1, false).unwrap());
+        assert_eq!(state.remainder("A").unwrap(), 1);
+        assert!(matches!(
+            state.change_amount(first, "A", 0, false),
+            Err(ThrottleError::InvalidLockCount { count: 0 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn split_peer() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 8,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let peer = state.new_peer(one_sec);
+        state.acquire(peer, "A", 8, None, None).await.unwrap();
+        // Amounts must sum up to the original count
+        assert!(matches!(
+            state.split(peer, &[3, 4]),
+            Err(ThrottleError::InvalidSplit)
+        ));
+        // Also if their sum overflows
+        assert!(matches!(
+            state.split(peer, &[i64::MAX, i64::MAX, 10]),
+            Err(ThrottleError::InvalidSplit)
+        ));
+        let new_peers = state.split(peer, &[3, 5]).unwrap();
+        assert_eq!(new_peers.len(), 2);
+        assert_eq!(state.remainder("A").unwrap(), 0);
+        // The original peer is gone
+        assert!(matches!(
+            state.heartbeat(peer, one_sec, None),
+            Err(ThrottleError::Gone)
+        ));
+        // Restoring it would count its locks twice
+        let mut acquired = HashMap::new();
+        acquired.insert(String::from("A"), 8);
+        assert!(matches!(
+            state.restore(peer, one_sec, &acquired, None, 0),
+            Err(ThrottleError::Gone)
+        ));
+        assert_eq!(state.remainder("A").unwrap(), 0);
+        // Each new peer has a life of its own
+        state.release(new_peers[0]);
+        assert_eq!(state.remainder("A").unwrap(), 3);
+        assert!(state.is_acquired(new_peers[1]).unwrap());
+    }
+
+    #[tokio::test]
+    async fn release_single_lock() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                level: 1,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(
+            String::from("B"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let first = state.new_peer(one_sec);
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        let second = state.new_peer(one_sec);
+        state.acquire(second, "A", 1, None, None).await.unwrap();
+
+        assert_eq!(state.release_lock(first, "B").unwrap(), Released::NotHeld);
+        assert_eq!(state.release_lock(second, "A").unwrap(), Released::Pending);
+        assert_eq!(state.release_lock(first, "A").unwrap(), Released::Acquired);
+        assert!(matches!(
+            state.release_lock(PeerId::Int(u64::MAX), "A"),
+            Err(ThrottleError::UnknownPeer)
+        ));
+        // Peer survives releasing its lock
+        assert!(state.is_acquired(first).unwrap());
+    }
+
+    #[tokio::test]
+    async fn force_release_semaphore() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        semaphores.insert(
+            String::from("B"),
+            SemaphoreCfg {
+                max: 1,
+                // Both locks are held by the same peer, so B must be acquired first
+                level: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let first = state.new_peer(one_sec);
+        state.acquire(first, "B", 1, None, None).await.unwrap();
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        let second = state.new_peer(one_sec);
+
+        // Release while the second peer is waiting for its lock
+        let (pending, released) =
+            tokio::join!(state.acquire(second, "A", 1, Some(one_sec), None), async {
+                state.release_semaphore("A")
+            });
+        assert!(matches!(pending, Err(ThrottleError::ForceReleased)));
+        assert_eq!(released.unwrap(), (1, 1));
+        assert_eq!(state.remainder("A").unwrap(), 1);
+        // Locks to other semaphores are kept
+        assert_eq!(state.remainder("B").unwrap(), 0);
+        assert!(matches!(
+            state.release_semaphore("Unknown"),
+            Err(ThrottleError::UnknownSemaphore)
+        ));
+    }
+
+    #[tokio::test]
+    async fn shut_down_answers_blocked_requests() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+        let first = state.new_peer(one_sec);
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        let second = state.new_peer(one_sec);
+
+        let (blocked, ()) =
+            tokio::join!(state.acquire(second, "A", 1, Some(one_sec), None), async {
+                state.shut_down()
+            });
+        assert!(matches!(blocked, Err(ThrottleError::ShuttingDown)));
+        // Blocking after shutdown fails right away
+        assert!(matches!(
+            state.acquire(second, "A", 1, Some(one_sec), None).await,
+            Err(ThrottleError::ShuttingDown)
+        ));
+    }
+
+    /// Blocked requests await their lock, rather than parking the thread. Even on a single threaded
+    /// runtime unrelated requests are answered, while many requests are blocked.
+    #[tokio::test]
+    async fn blocked_requests_do_not_occupy_threads() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_min = Duration::from_secs(60);
+        let block_for = Duration::from_millis(100);
+        let first = state.new_peer(one_min);
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        let blocked: Vec<_> = (0..100)
+            .map(|_| state.new_peer(one_min))
+            .map(|peer| state.acquire(peer, "A", 1, Some(block_for), None))
+            .collect();
+
+        let (results, ()) = tokio::join!(futures::future::join_all(blocked), async {
+            // All blocked requests have been polled at this point.
+            assert_eq!(state.remainder("A").unwrap(), 0);
+            state.release(first);
+        });
+        // Releasing the first lock lets exactly one blocked request acquire it. The others time
+        // out and answer `false`.
+        let acquired = results.into_iter().filter(|r| *r.as_ref().unwrap()).count();
+        assert_eq!(acquired, 1);
+    }
+
+    /// The litter collection hands the capacity of expired peers to blocked requests. No explicit
+    /// release is needed to wake them.
+    #[tokio::test]
+    async fn expiry_promotes_blocked_request() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let mut state = State::new(semaphores);
+        let clock = Arc::new(ManualClock::new());
+        state.set_clock(clock.clone());
+        let holder = state.new_peer(Duration::from_secs(10));
+        state.acquire(holder, "A", 1, None, None).await.unwrap();
+        let blocked = state.new_peer(Duration::from_secs(60));
+
+        let (acquired, ()) = tokio::join!(
+            state.acquire(blocked, "A", 1, Some(Duration::from_secs(10)), None),
+            async {
+                // The blocked request has been polled at this point.
+                clock.advance(Duration::from_secs(11));
+                assert_eq!(state.remove_expired(), 1);
+            }
+        );
+        assert!(acquired.unwrap());
+        assert!(state.is_acquired(blocked).unwrap());
+    }
+
+    /// Counts how often a pending request is woken.
+    #[derive(Default)]
+    struct CountWakes(std::sync::atomic::AtomicUsize);
+
+    impl futures::task::ArcWake for CountWakes {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    /// Releasing a peer which only waited for a lock frees no capacity. The other pending requests
+    /// must sleep through it.
+    #[tokio::test]
+    async fn release_of_pending_peer_wakes_nobody() {
+        use std::{
+            future::Future,
+            sync::atomic::Ordering,
+            task::{Context, Poll},
+        };
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_min = Duration::from_secs(60);
+        let holder = state.new_peer(one_min);
+        state.acquire(holder, "A", 1, None, None).await.unwrap();
+        let first = state.new_peer(one_min);
+        let second = state.new_peer(one_min);
+
+        let wakes = Arc::new(CountWakes::default());
+        let waker = futures::task::waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut blocked = Box::pin(state.acquire(first, "A", 1, Some(one_min), None));
+        assert!(blocked.as_mut().poll(&mut cx).is_pending());
+        assert!(!state.acquire(second, "A", 1, None, None).await.unwrap());
+
+        // `second` leaves the queue without ever holding anything.
+        assert!(state.release(second).is_some());
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+
+        // `holder` frees capacity, which is granted to `first`.
+        assert!(state.release(holder).is_some());
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        assert!(matches!(
+            blocked.as_mut().poll(&mut cx),
+            Poll::Ready(Ok(true))
+        ));
+    }
+
+    #[tokio::test]
+    async fn add_and_remove_semaphores() {
+        let state = State::new(Semaphores::new());
+        let one_sec = Duration::from_secs(1);
+        let cfg = SemaphoreCfg {
+            max: 1,
+            ..Default::default()
+        };
+
+        state.add_semaphore("A", cfg).unwrap();
+        assert!(matches!(
+            state.add_semaphore("A", cfg),
+            Err(ThrottleError::SemaphoreExists)
+        ));
+        let peer = state.new_peer(one_sec);
+        state.acquire(peer, "A", 1, None, None).await.unwrap();
+        assert!(matches!(
+            state.remove_semaphore("A"),
+            Err(ThrottleError::SemaphoreInUse)
+        ));
+        state.release(peer);
+        assert_eq!(state.remove_semaphore("A").unwrap(), cfg);
+        assert!(matches!(
+            state.remainder("A"),
+            Err(ThrottleError::UnknownSemaphore)
+        ));
+    }
+
+    #[tokio::test]
+    async fn freeze_semaphore() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let first = state.new_peer(one_sec);
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        let second = state.new_peer(one_sec);
+        state.acquire(second, "A", 1, None, None).await.unwrap();
+
+        assert!(state.freeze("A").unwrap());
+        // No new locks while frozen
+        let third = state.new_peer(one_sec);
+        assert!(matches!(
+            state.acquire(third, "A", 1, None, None).await,
+            Err(ThrottleError::Frozen)
+        ));
+        // Pending locks are not resolved, while the semaphore drains
+        state.release(first);
+        assert!(!state.is_acquired(second).unwrap());
+        assert_eq!(state.remainder("A").unwrap(), 1);
+
+        assert!(state.unfreeze("A").unwrap());
+        assert!(state.is_acquired(second).unwrap());
+    }
+
+    /// A full count of zero serves as a kill switch.
+    #[tokio::test]
+    async fn close_and_reopen_semaphore() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let first = state.new_peer(one_sec);
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        let second = state.new_peer(one_sec);
+        assert!(!state.acquire(second, "A", 1, None, None).await.unwrap());
+
+        state.set_max("A", 0).unwrap();
+        // New locks are turned down, rather than pending forever. Even those exceeding the full
+        // count it is going to be reopened with.
+        let third = state.new_peer(one_sec);
+        assert!(matches!(
+            state.acquire(third, "A", 1, None, None).await,
+            Err(ThrottleError::Closed)
+        ));
+        assert!(matches!(
+            state.acquire(third, "A", 2, None, None).await,
+            Err(ThrottleError::Closed)
+        ));
+        assert!(matches!(
+            state.try_acquire(None, one_sec, None, 0, "A", 1),
+            Err(ThrottleError::Closed)
+        ));
+        // The waiting peer is neither promoted, nor turned down when asking again.
+        state.release(first);
+        assert!(!state.is_acquired(second).unwrap());
+        assert!(!state.acquire(second, "A", 1, None, None).await.unwrap());
+
+        // Reopening grants the pending lock.
+        state.set_max("A", 1).unwrap();
+        assert!(state.is_acquired(second).unwrap());
+        state.release(second);
+        assert!(state.acquire(third, "A", 1, None, None).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn client_chosen_peer_id() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+        let id = PeerId::Int(42);
+
+        // Retrying does not create a second peer
+        assert_eq!(state.create_peer(Some(id), one_sec, None, 0).unwrap(), id);
+        assert_eq!(state.create_peer(Some(id), one_sec, None, 0).unwrap(), id);
+        assert_eq!(state.num_peers(), 1);
+
+        // Once the peer holds locks, the id is taken
+        state.acquire(id, "A", 1, None, None).await.unwrap();
+        assert!(matches!(
+            state.create_peer(Some(id), one_sec, None, 0),
+            Err(ThrottleError::PeerExists)
+        ));
+    }
+
+    #[tokio::test]
+    async fn watch_counts() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let (current, mut receiver) = state.watch("A").unwrap();
+        assert_eq!((current.sequence, current.acquired), (1, 0));
+
+        let first = state.new_peer(one_sec);
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        let second = state.new_peer(one_sec);
+        state.acquire(second, "A", 1, None, None).await.unwrap();
+        state.release(first);
+
+        let event = receiver.recv().await.unwrap();
+        assert_eq!((event.sequence, event.acquired, event.pending), (2, 1, 0));
+        let event = receiver.recv().await.unwrap();
+        assert_eq!((event.sequence, event.acquired, event.pending), (3, 1, 1));
+        // Releasing the first peer promotes the pending lock of the second
+        let event = receiver.recv().await.unwrap();
+        assert_eq!((event.sequence, event.acquired, event.pending), (4, 1, 0));
+    }
+
+    #[tokio::test]
+    async fn higher_priority_acquires_first() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+
+        let blocker = state.new_peer(one_sec);
+        state.acquire(blocker, "A", 1, None, None).await.unwrap();
+        // Batch job arrives first, interactive job second
+        let batch = state.create_peer(None, one_sec, None, 0).unwrap();
+        state.acquire(batch, "A", 1, None, None).await.unwrap();
+        let interactive = state.create_peer(None, one_sec, None, 10).unwrap();
+        state
+            .acquire(interactive, "A", 1, None, None)
+            .await
+            .unwrap();
+
+        state.release(blocker);
+        assert!(state.is_acquired(interactive).unwrap());
+        assert!(!state.is_acquired(batch).unwrap());
+    }
+
+    #[tokio::test]
+    async fn queue_position() {
+        let mut semaphores = Semaphores::new();
+        semaphores.insert(
+            String::from("A"),
+            SemaphoreCfg {
+                max: 1,
+                ..Default::default()
+            },
+        );
+        let state = State::new(semaphores);
+        let one_sec = Duration::from_secs(1);
+        let position = |peer| state.pending_lock(peer).unwrap().map(|(_, _, p, _)| p);
+
+        let blocker = state.new_peer(one_sec);
+        state.acquire(blocker, "A", 1, None, None).await.unwrap();
+        let first = state.new_peer(one_sec);
+        state.acquire(first, "A", 1, None, None).await.unwrap();
+        let second = state.new_peer(one_sec);
+        state.acquire(second, "A", 1, None, None).await.unwrap();
+        assert_eq!(position(blocker), None);
+        assert_eq!(position(first), Some(1));
+        assert_eq!(position(second), Some(2));
+
+        // Peers with higher priority overtake the queue
+        let urgent = state.create_peer(None, one_sec, None, 10).unwrap();
+        state.acquire(urgent, "A", 1, None, None).await.unwrap();
+        assert_eq!(position(urgent), Some(1));
+        assert_eq!(position(second), Some(3));
+    }
+
+    #[tokio::test]
+    async fn auto_create_unknown_semaphores() {
+        let one_sec = Duration::from_secs(1);
+        // Strict by default
+        let state = State::new(Semaphores::new());
+        let peer = state.new_peer(one_sec);
+        assert!(matches!(
+            state.acquire(peer, "Unknown", 1, None, None).await,
+            Err(ThrottleError::UnknownSemaphore)
+        ));
+        assert!(state.list_semaphores().is_empty());
+
+        let mut state = State::new(Semaphores::new());
+        state.auto_create_semaphores(2);
+        let created_before = AUTO_CREATED.get();
+        let peer = state.new_peer(one_sec);
+        assert!(state.acquire(peer, "Dev", 2, None, None).await.unwrap());
+        let other = state.new_peer(one_sec);
+        // Created only once, with the default count, so this one is pending.
+        assert!(!state.acquire(other, "Dev", 1, None, None).await.unwrap());
+        assert_eq!(AUTO_CREATED.get() - created_before, 1);
+        let SemaphoreSnapshot { cfg, counts, .. } = state.semaphore("Dev").unwrap();
+        assert_eq!(cfg.max, 2);
+        assert_eq!(counts.acquired, 2);
+        assert!(state.list_semaphores().contains_key("Dev"));
+    }
+
+    #[test]
+    fn observe_ledger_lock_wait() {
+        let state = State::new(Semaphores::new());
+        let before = LEDGER_LOCK_WAIT.get_sample_count();
+        state.new_peer(Duration::from_secs(1));
+        assert!(LEDGER_LOCK_WAIT.get_sample_count() > before);
+    }
 }
@@ -0,0 +1,167 @@
+//! Serving the HTTP interface via TLS, using rustls. Plain HTTP is used unless a `[tls]` section
+//! is configured.
+
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    sign, Certificate, NoClientAuth, PrivateKey, ServerConfig, SignatureScheme, TLSError,
+};
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// `[tls]` section of the configuration file
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TlsCfg {
+    /// PEM file containing the certificate chain
+    pub cert_path: PathBuf,
+    /// PEM file containing the private key, either in PKCS#8 or in RSA format
+    pub key_path: PathBuf,
+}
+
+/// Reasons the TLS configuration can not be loaded on startup
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Can not read {path}: {source}")]
+    Unreadable { path: PathBuf, source: io::Error },
+    #[error("{0} contains no PEM encoded certificate.")]
+    NoCertificate(PathBuf),
+    #[error("{0} contains no PEM encoded PKCS#8 or RSA private key.")]
+    NoPrivateKey(PathBuf),
+    #[error("{path} does not contain a valid certificate: {reason}")]
+    InvalidCertificate {
+        path: PathBuf,
+        reason: webpki::Error,
+    },
+    #[error("{0} contains a private key of a type which is not supported.")]
+    UnsupportedKey(PathBuf),
+    #[error("Private key in {key_path} does not belong to the certificate in {cert_path}.")]
+    Mismatch {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    },
+    #[error("Certificate or private key rejected: {0}")]
+    Rejected(#[from] TLSError),
+}
+
+/// Signature schemes used to check the private key against the certificate, together with the
+/// algorithm verifying them.
+const PROBE_SCHEMES: &[(SignatureScheme, &webpki::SignatureAlgorithm)] = &[
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+/// Loads certificate chain and private key and builds the configuration of the server.
+pub fn server_config(cfg: &TlsCfg) -> Result<ServerConfig, TlsError> {
+    let chain = certs(&mut open(&cfg.cert_path)?)
+        .ok()
+        .filter(|chain| !chain.is_empty())
+        .ok_or_else(|| TlsError::NoCertificate(cfg.cert_path.clone()))?;
+    let key = private_key(&cfg.key_path)?;
+    check_key_pair(cfg, &chain[0], &key)?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, key)?;
+    Ok(config)
+}
+
+/// `set_single_cert` only checks that the key can be parsed. So we sign a probe with the key and
+/// verify it with the public key of the certificate, rather than failing every handshake later.
+fn check_key_pair(cfg: &TlsCfg, cert: &Certificate, key: &PrivateKey) -> Result<(), TlsError> {
+    let cert =
+        webpki::EndEntityCert::from(&cert.0).map_err(|reason| TlsError::InvalidCertificate {
+            path: cfg.cert_path.clone(),
+            reason,
+        })?;
+    let unsupported = || TlsError::UnsupportedKey(cfg.key_path.clone());
+    let key = sign::any_supported_type(key).map_err(|()| unsupported())?;
+    let schemes: Vec<_> = PROBE_SCHEMES.iter().map(|&(scheme, _)| scheme).collect();
+    let signer = key.choose_scheme(&schemes).ok_or_else(unsupported)?;
+    let (_, algorithm) = PROBE_SCHEMES
+        .iter()
+        .find(|&&(scheme, _)| scheme == signer.get_scheme())
+        .ok_or_else(unsupported)?;
+    let probe = b"throttle";
+    let signature = signer.sign(probe)?;
+    cert.verify_signature(algorithm, probe, &signature)
+        .map_err(|_| TlsError::Mismatch {
+            cert_path: cfg.cert_path.clone(),
+            key_path: cfg.key_path.clone(),
+        })
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|source| TlsError::Unreadable {
+            path: path.to_owned(),
+            source,
+        })
+}
+
+/// First private key in the file. PKCS#8 is tried first, RSA second.
+fn private_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let pkcs8 = pkcs8_private_keys(&mut open(path)?).unwrap_or_default();
+    let rsa = rsa_private_keys(&mut open(path)?).unwrap_or_default();
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .next()
+        .ok_or_else(|| TlsError::NoPrivateKey(path.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreadable_certificate() {
+        let cfg = TlsCfg {
+            cert_path: PathBuf::from("does/not/exist.pem"),
+            key_path: PathBuf::from("does/not/exist.key"),
+        };
+        assert!(matches!(
+            server_config(&cfg),
+            Err(TlsError::Unreadable { .. })
+        ));
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/tls")
+            .join(name)
+    }
+
+    #[test]
+    fn matching_key_pair() {
+        let cfg = TlsCfg {
+            cert_path: fixture("cert.pem"),
+            key_path: fixture("key.pem"),
+        };
+        assert!(server_config(&cfg).is_ok());
+    }
+
+    #[test]
+    fn mismatched_key_pair() {
+        let cfg = TlsCfg {
+            cert_path: fixture("cert.pem"),
+            key_path: fixture("other_key.pem"),
+        };
+        assert!(matches!(
+            server_config(&cfg),
+            Err(TlsError::Mismatch { .. })
+        ));
+    }
+}
@@ -1,7 +1,47 @@
-use actix_web::get;
+use actix_web::{get, web::Json};
+use lazy_static::lazy_static;
+use prometheus::IntGaugeVec;
+use serde::Serialize;
 use version::version;
 
+/// Git commit the binary has been built from. Set by the build script. `unknown` if built outside
+/// of a git repository.
+const GIT_HASH: &str = env!("THROTTLE_GIT_HASH");
+
+/// Optional functionality compiled into this binary. Currently both are always built in.
+const FEATURES: &[&str] = &["gelf", "metrics"];
+
+lazy_static! {
+    /// Always one. Labels carry version, git hash and features, so version skew across a fleet
+    /// can be queried.
+    static ref BUILD_INFO: IntGaugeVec = register_int_gauge_vec!(
+        "throttle_build_info",
+        "Version, git hash and features of the throttle binary.",
+        &["version", "git_hash", "features"]
+    )
+    .expect("Error registering throttle_build_info metric");
+}
+
+/// Version and build information
+#[derive(Serialize)]
+struct BuildInfo {
+    version: &'static str,
+    git_hash: &'static str,
+    features: &'static [&'static str],
+}
+
 #[get("/version")]
-async fn get_version() -> &'static str {
-    version!()
+async fn get_version() -> Json<BuildInfo> {
+    Json(BuildInfo {
+        version: version!(),
+        git_hash: GIT_HASH,
+        features: FEATURES,
+    })
+}
+
+/// Use this to initialize metrics eagerly, i.e. before `/metrics` is called for the first time.
+pub fn initialize_metrics() {
+    BUILD_INFO
+        .with_label_values(&[version!(), GIT_HASH, &FEATURES.join(",")])
+        .set(1);
 }
@@ -1,10 +1,14 @@
-use crate::error::ThrottleError;
+use crate::{error::ThrottleError, leases::PeerId, poison};
+use lazy_static::lazy_static;
+use prometheus::IntCounterVec;
 use std::{
     future::Future,
     pin::Pin,
     sync::{Arc, Mutex, Weak},
     task::{Context, Poll, Waker},
+    time::Duration,
 };
+use tokio::time::{self, Delay};
 
 // State shared between Future and Wakers
 struct Shared {
@@ -12,34 +16,78 @@ struct Shared {
     waker: Option<Waker>,
 }
 
-/// A Future which is completed, once all the pending locks of the peer are acquired.
+lazy_static! {
+    /// Notifications issued. `peer` counts pending requests woken because their peer has been
+    /// resolved, `semaphore` changes of the counts of a semaphore broadcast to its watchers and
+    /// `all` pending requests woken at once during shutdown.
+    pub static ref NOTIFY: IntCounterVec = register_int_counter_vec!(
+        "throttle_notify_total",
+        "Number of notifications issued to pending requests and watchers of semaphores.",
+        &["scope"]
+    )
+    .expect("Error registering throttle_notify_total metric");
+    /// Pending requests, which have been polled without their peer being resolved. Polls due to
+    /// the request timing out are not counted.
+    static ref SPURIOUS_WAKEUPS: IntCounterVec = register_int_counter_vec!(
+        "throttle_spurious_wakeups_total",
+        "Number of times a pending request woke up, without anything to do.",
+        &["semaphore"]
+    )
+    .expect("Error registering throttle_spurious_wakeups_total metric");
+}
+
+/// Use this to initialize metrics eagerly, i.e. before the first request is pending.
+pub fn initialize_metrics() {
+    for &scope in &["all", "semaphore", "peer"] {
+        NOTIFY.with_label_values(&[scope]);
+    }
+}
+
+/// A Future which is completed, once all the pending locks of the peer are acquired. `None` if
+/// this takes too long.
 ///
 /// One of these futures exists for each request pending in `block_unitl_acquired`.
 struct FutureAcquired {
     shared: Arc<Mutex<Shared>>,
+    /// Semaphore the peer is waiting for. Used to label diagnostics.
+    semaphore: String,
+    /// Completes the future with `None` once the request times out.
+    timeout: Delay,
+    /// `true` after the first call to `poll`.
+    polled: bool,
 }
 
 impl Future for FutureAcquired {
-    type Output = Result<(), ThrottleError>;
+    type Output = Option<Result<(), ThrottleError>>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut shared = self.shared.lock().unwrap();
-        match &shared.result {
-            None => {
-                if let Some(ref mut waker) = &mut shared.waker {
-                    // If a woker has been previously set, let's reuse the resources from the old
-                    // one, rather than allocating a new one.
-                    waker.clone_from(&cx.waker())
-                } else {
-                    shared.waker = Some(cx.waker().clone());
-                }
-                Poll::Pending
-            }
-            Some(_) => Poll::Ready(shared.result.take().unwrap()),
+        let this = self.get_mut();
+        let mut shared = poison::lock(&this.shared, "a pending request");
+        if let Some(result) = shared.result.take() {
+            return Poll::Ready(Some(result));
+        }
+        // Woken by the timeout, which is not spurious either.
+        if Pin::new(&mut this.timeout).poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        if this.polled {
+            SPURIOUS_WAKEUPS.with_label_values(&[&this.semaphore]).inc();
+        }
+        this.polled = true;
+        if let Some(ref mut waker) = &mut shared.waker {
+            // If a woker has been previously set, let's reuse the resources from the old
+            // one, rather than allocating a new one.
+            waker.clone_from(cx.waker())
+        } else {
+            shared.waker = Some(cx.waker().clone());
         }
+        Poll::Pending
     }
 }
 
+/// A peer, and the state shared with one of its pending requests.
+type Registration = (PeerId, Weak<Mutex<Shared>>);
+
 /// Wakers for `FutureAcquired` instances.
 ///
 /// A waker is used to tell the task execute that a futures task may have proceeded and it is
@@ -50,48 +98,168 @@ impl Future for FutureAcquired {
 /// request for a single peer, so we have to account for mulitple pending requests for a single
 /// peer.
 pub struct Wakers {
-    wakers: Mutex<Vec<(u64, Weak<Mutex<Shared>>)>>,
+    /// `None` once the server is shutting down. No more futures are registered then.
+    wakers: Mutex<Option<Vec<Registration>>>,
 }
 
 impl Wakers {
     pub fn new() -> Self {
         Self {
-            wakers: Mutex::new(Vec::new()),
+            wakers: Mutex::new(Some(Vec::new())),
         }
     }
-    /// A future associated with a peer, which can be resolved using `resolve_with`.
+    /// A future associated with a peer, which can be resolved using `resolve_with`. `semaphore`
+    /// is the one the peer is waiting for. Completes with `None` if the future has not been
+    /// resolved within `timeout`.
     ///
-    /// Attention: Do not call this method while holding a lock to `leases`.
-    pub async fn wait_for_resolving(&self, peer_id: u64) -> Result<(), ThrottleError> {
+    /// The future is registered right away, rather than once it is polled. Call this while still
+    /// holding the lock to `leases`, so a resolution can not slip in between. Attention: Do not
+    /// await the future while holding that lock.
+    pub fn wait_for_resolving(
+        &self,
+        peer_id: PeerId,
+        semaphore: &str,
+        timeout: Duration,
+    ) -> impl Future<Output = Option<Result<(), ThrottleError>>> {
+        let registered = self.register(peer_id).map(|shared| FutureAcquired {
+            shared,
+            semaphore: semaphore.to_owned(),
+            timeout: time::delay_for(timeout),
+            polled: false,
+        });
+        async move {
+            match registered {
+                Ok(future_acquired) => future_acquired.await,
+                Err(error) => Some(Err(error)),
+            }
+        }
+    }
+
+    /// Registers a new pending request of `peer_id`. Fails with `ShuttingDown` once the server is
+    /// shutting down.
+    fn register(&self, peer_id: PeerId) -> Result<Arc<Mutex<Shared>>, ThrottleError> {
         let strong = Arc::new(Mutex::new(Shared {
             result: None,
             waker: None,
         }));
-        let weak = Arc::downgrade(&strong);
-        {
-            let mut wakers = self.wakers.lock().unwrap();
-            wakers.retain(|(_peer, r)| r.strong_count() != 0);
-            wakers.push((peer_id, weak));
-        }
-        FutureAcquired { shared: strong }.await
+        let mut wakers = poison::lock(&self.wakers, "the wakers");
+        let wakers = wakers.as_mut().ok_or(ThrottleError::ShuttingDown)?;
+        wakers.retain(|(_peer, r)| r.strong_count() != 0);
+        wakers.push((peer_id, Arc::downgrade(&strong)));
+        Ok(strong)
     }
 
     /// Resolves the pending futures
     ///
     /// * `peers`: Futures associated with these peers are resolved
     /// * `result`: The result these futures will return in their `.await` call
-    pub fn resolve_with(&self, peers: &[u64], result: Result<(), ThrottleError>) {
-        let mut wakers = self.wakers.lock().unwrap();
-        for (peer, weak) in wakers.iter_mut() {
+    pub fn resolve_with(&self, peers: &[PeerId], result: Result<(), ThrottleError>) {
+        let mut wakers = poison::lock(&self.wakers, "the wakers");
+        for (peer, weak) in wakers.iter_mut().flatten() {
             if peers.contains(peer) {
                 if let Some(strong) = weak.upgrade() {
-                    let mut shared = strong.lock().unwrap();
-                    if let Some(waker) = shared.waker.take() {
-                        shared.result = Some(result);
-                        waker.wake()
+                    let mut shared = poison::lock(&strong, "a pending request");
+                    // Set the result even if the future has not been polled yet, so it does not
+                    // miss it. The first resolution wins.
+                    if shared.result.is_none() {
+                        shared.result = Some(result.clone());
+                        NOTIFY.with_label_values(&["peer"]).inc();
+                        if let Some(waker) = shared.waker.take() {
+                            waker.wake()
+                        }
                     }
                 }
             }
         }
     }
+
+    /// Resolves all pending futures with `ShuttingDown`, so blocked requests are answered, before
+    /// the server stops. Futures registered afterwards fail right away.
+    pub fn shut_down(&self) {
+        let wakers = poison::lock(&self.wakers, "the wakers")
+            .take()
+            .unwrap_or_default();
+        NOTIFY.with_label_values(&["all"]).inc();
+        for (_peer, weak) in wakers {
+            if let Some(strong) = weak.upgrade() {
+                let mut shared = poison::lock(&strong, "a pending request");
+                // Set the result even if the future has not been polled yet, so it does not miss
+                // it.
+                shared.result = Some(Err(ThrottleError::ShuttingDown));
+                if let Some(waker) = shared.waker.take() {
+                    waker.wake()
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    const PEER: PeerId = PeerId::Int(1);
+
+    fn spurious_wakeups(semaphore: &str) -> i64 {
+        SPURIOUS_WAKEUPS.with_label_values(&[semaphore]).get()
+    }
+
+    fn notifications(scope: &str) -> i64 {
+        NOTIFY.with_label_values(&[scope]).get()
+    }
+
+    #[tokio::test]
+    async fn count_spurious_wakeups() {
+        let wakers = Wakers::new();
+        let mut future = Box::pin(FutureAcquired {
+            shared: wakers.register(PEER).unwrap(),
+            semaphore: String::from("spurious"),
+            timeout: time::delay_for(Duration::from_secs(60)),
+            polled: false,
+        });
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(spurious_wakeups("spurious"), 0);
+        // Polled again, without the peer being resolved.
+        assert!(future.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(spurious_wakeups("spurious"), 1);
+
+        let before = notifications("peer");
+        wakers.resolve_with(&[PEER], Ok(()));
+        assert!(notifications("peer") > before);
+        assert!(future.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(spurious_wakeups("spurious"), 1);
+    }
+
+    #[tokio::test]
+    async fn timeout_is_not_spurious() {
+        let wakers = Wakers::new();
+        let timeout = Duration::from_millis(10);
+        // Spawned, since `block_on` polls its future, whether it has been woken or not.
+        let pending = tokio::spawn(wakers.wait_for_resolving(PEER, "timeout", timeout));
+        assert!(pending.await.unwrap().is_none());
+        assert_eq!(spurious_wakeups("timeout"), 0);
+    }
+
+    #[tokio::test]
+    async fn resolved_before_first_poll() {
+        let wakers = Wakers::new();
+        let pending = wakers.wait_for_resolving(PEER, "A", Duration::from_secs(60));
+        wakers.resolve_with(&[PEER], Ok(()));
+        assert!(matches!(pending.await, Some(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn shut_down_notifies_all() {
+        let wakers = Wakers::new();
+        let pending = wakers.wait_for_resolving(PEER, "A", Duration::from_secs(60));
+        let before = notifications("all");
+        wakers.shut_down();
+        assert!(notifications("all") > before);
+        assert!(matches!(
+            pending.await,
+            Some(Err(ThrottleError::ShuttingDown))
+        ));
+    }
 }